@@ -0,0 +1,107 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::Sink;
+use tokio::net::TcpListener;
+
+use pgwire::api::auth::noop::NoopStartupHandler;
+use pgwire::api::cancel::{
+    query_canceled_error, CancelHandler, CancelRegistry, DefaultCancelHandler,
+};
+use pgwire::api::copy::NoopCopyHandler;
+use pgwire::api::query::{PlaceholderExtendedQueryHandler, SimpleQueryHandler};
+use pgwire::api::results::{Response, Tag};
+use pgwire::api::{ClientInfo, NoopErrorHandler, PgWireServerHandlers};
+use pgwire::error::{PgWireError, PgWireResult};
+use pgwire::messages::PgWireBackendMessage;
+use pgwire::tokio::process_socket;
+
+pub struct SleepProcessor {
+    registry: Arc<CancelRegistry>,
+}
+
+impl NoopStartupHandler for SleepProcessor {}
+
+#[async_trait]
+impl SimpleQueryHandler for SleepProcessor {
+    async fn do_query<'a, C>(
+        &self,
+        client: &mut C,
+        _query: &'a str,
+    ) -> PgWireResult<Vec<Response<'a>>>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        // every query takes 30 seconds, press Ctrl-C in psql to cancel it
+        let guard = self.registry.register(client);
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(30)) => {
+                Ok(vec![Response::Execution(Tag::new("SLEEP"))])
+            }
+            _ = guard.cancelled() => Err(query_canceled_error()),
+        }
+    }
+}
+
+struct SleepProcessorFactory {
+    handler: Arc<SleepProcessor>,
+    cancel_handler: Arc<DefaultCancelHandler>,
+}
+
+impl PgWireServerHandlers for SleepProcessorFactory {
+    type StartupHandler = SleepProcessor;
+    type SimpleQueryHandler = SleepProcessor;
+    type ExtendedQueryHandler = PlaceholderExtendedQueryHandler;
+    type CopyHandler = NoopCopyHandler;
+    type ErrorHandler = NoopErrorHandler;
+
+    fn simple_query_handler(&self) -> Arc<Self::SimpleQueryHandler> {
+        self.handler.clone()
+    }
+
+    fn extended_query_handler(&self) -> Arc<Self::ExtendedQueryHandler> {
+        Arc::new(PlaceholderExtendedQueryHandler)
+    }
+
+    fn startup_handler(&self) -> Arc<Self::StartupHandler> {
+        self.handler.clone()
+    }
+
+    fn copy_handler(&self) -> Arc<Self::CopyHandler> {
+        Arc::new(NoopCopyHandler)
+    }
+
+    fn error_handler(&self) -> Arc<Self::ErrorHandler> {
+        Arc::new(NoopErrorHandler)
+    }
+
+    fn cancel_handler(&self) -> Arc<impl CancelHandler> {
+        self.cancel_handler.clone()
+    }
+}
+
+#[tokio::main]
+pub async fn main() {
+    // the registry is shared by all connections so a cancel request, which
+    // arrives on its own connection, can reach the running query
+    let registry = Arc::new(CancelRegistry::new());
+    let factory = Arc::new(SleepProcessorFactory {
+        handler: Arc::new(SleepProcessor {
+            registry: registry.clone(),
+        }),
+        cancel_handler: Arc::new(DefaultCancelHandler::new(registry)),
+    });
+
+    let server_addr = "127.0.0.1:5432";
+    let listener = TcpListener::bind(server_addr).await.unwrap();
+    println!("Listening to {}", server_addr);
+    loop {
+        let incoming_socket = listener.accept().await.unwrap();
+        let factory_ref = factory.clone();
+        tokio::spawn(async move { process_socket(incoming_socket.0, None, factory_ref).await });
+    }
+}
@@ -189,7 +189,7 @@ fn encode_row_data(
         results.push(encoder.finish());
     }
 
-    stream::iter(results.into_iter())
+    stream::iter(results)
 }
 
 fn get_params(portal: &Portal<String>) -> Vec<Box<dyn ToSql>> {
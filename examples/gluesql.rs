@@ -8,7 +8,9 @@ use gluesql::prelude::*;
 use pgwire::api::auth::noop::NoopStartupHandler;
 use pgwire::api::copy::NoopCopyHandler;
 use pgwire::api::query::{PlaceholderExtendedQueryHandler, SimpleQueryHandler};
-use pgwire::api::results::{DataRowEncoder, FieldFormat, FieldInfo, QueryResponse, Response, Tag};
+use pgwire::api::results::{
+    CommandTag, DataRowEncoder, FieldFormat, FieldInfo, QueryResponse, Response,
+};
 use pgwire::api::{ClientInfo, NoopErrorHandler, PgWireServerHandlers, Type};
 use pgwire::error::{PgWireError, PgWireResult};
 use pgwire::tokio::process_socket;
@@ -135,23 +137,29 @@ impl SimpleQueryHandler for GluesqlProcessor {
 
                             Ok(Response::Query(QueryResponse::new(
                                 fields,
-                                stream::iter(results.into_iter()),
+                                stream::iter(results),
                             )))
                         }
-                        Payload::Insert(rows) => Ok(Response::Execution(
-                            Tag::new("INSERT").with_oid(0).with_rows(*rows),
-                        )),
+                        Payload::Insert(rows) => {
+                            Ok(Response::Execution(CommandTag::Insert(0, *rows).into()))
+                        }
                         Payload::Delete(rows) => {
-                            Ok(Response::Execution(Tag::new("DELETE").with_rows(*rows)))
+                            Ok(Response::Execution(CommandTag::Delete(*rows).into()))
                         }
                         Payload::Update(rows) => {
-                            Ok(Response::Execution(Tag::new("UPDATE").with_rows(*rows)))
+                            Ok(Response::Execution(CommandTag::Update(*rows).into()))
+                        }
+                        Payload::Create => Ok(Response::Execution(CommandTag::CreateTable.into())),
+                        Payload::AlterTable => {
+                            Ok(Response::Execution(CommandTag::AlterTable.into()))
+                        }
+                        Payload::DropTable(_) => {
+                            Ok(Response::Execution(CommandTag::DropTable.into()))
+                        }
+                        Payload::CreateIndex => {
+                            Ok(Response::Execution(CommandTag::CreateIndex.into()))
                         }
-                        Payload::Create => Ok(Response::Execution(Tag::new("CREATE TABLE"))),
-                        Payload::AlterTable => Ok(Response::Execution(Tag::new("ALTER TABLE"))),
-                        Payload::DropTable(_) => Ok(Response::Execution(Tag::new("DROP TABLE"))),
-                        Payload::CreateIndex => Ok(Response::Execution(Tag::new("CREATE INDEX"))),
-                        Payload::DropIndex => Ok(Response::Execution(Tag::new("DROP INDEX"))),
+                        Payload::DropIndex => Ok(Response::Execution(CommandTag::DropIndex.into())),
                         _ => {
                             unimplemented!()
                         }
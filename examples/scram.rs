@@ -1,4 +1,4 @@
-use std::fs::{self, File};
+use std::fs::File;
 use std::io::{BufReader, Error as IOError, ErrorKind};
 use std::sync::Arc;
 
@@ -7,10 +7,10 @@ use async_trait::async_trait;
 use rustls_pemfile::{certs, pkcs8_private_keys};
 use rustls_pki_types::{CertificateDer, PrivateKeyDer};
 use tokio::net::TcpListener;
-use tokio_rustls::rustls::ServerConfig;
-use tokio_rustls::TlsAcceptor;
 
-use pgwire::api::auth::scram::{gen_salted_password, SASLScramAuthStartupHandler};
+use pgwire::api::auth::scram::{
+    gen_salted_password, SASLScramAuthStartupHandler, ScramTlsAcceptor,
+};
 use pgwire::api::auth::{AuthSource, DefaultServerParameterProvider, LoginInfo, Password};
 use pgwire::api::copy::NoopCopyHandler;
 use pgwire::api::query::{PlaceholderExtendedQueryHandler, SimpleQueryHandler};
@@ -55,8 +55,8 @@ impl AuthSource for DummyAuthDB {
     }
 }
 
-/// configure TlsAcceptor and get server cert for SCRAM channel binding
-fn setup_tls() -> Result<TlsAcceptor, IOError> {
+/// configure TlsAcceptor and server cert for SCRAM channel binding
+fn setup_tls() -> Result<ScramTlsAcceptor, IOError> {
     let cert = certs(&mut BufReader::new(File::open("examples/ssl/server.crt")?))
         .collect::<Result<Vec<CertificateDer>, IOError>>()?;
 
@@ -65,17 +65,12 @@ fn setup_tls() -> Result<TlsAcceptor, IOError> {
         .collect::<Result<Vec<PrivateKeyDer>, IOError>>()?
         .remove(0);
 
-    let config = ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(cert, key)
-        .map_err(|err| IOError::new(ErrorKind::InvalidInput, err))?;
-
-    Ok(TlsAcceptor::from(Arc::new(config)))
+    ScramTlsAcceptor::new(cert, key).map_err(|err| IOError::new(ErrorKind::InvalidInput, err))
 }
 
 struct DummyProcessorFactory {
     handler: Arc<DummyProcessor>,
-    cert: Vec<u8>,
+    tls_acceptor: ScramTlsAcceptor,
 }
 
 impl PgWireServerHandlers for DummyProcessorFactory {
@@ -99,9 +94,7 @@ impl PgWireServerHandlers for DummyProcessorFactory {
             Arc::new(DefaultServerParameterProvider::default()),
         );
         authenticator.set_iterations(ITERATIONS);
-        authenticator
-            .configure_certificate(self.cert.as_ref())
-            .unwrap();
+        authenticator.configure_tls_acceptor(&self.tls_acceptor);
 
         Arc::new(authenticator)
     }
@@ -117,14 +110,14 @@ impl PgWireServerHandlers for DummyProcessorFactory {
 
 #[tokio::main]
 pub async fn main() {
-    let cert = fs::read("examples/ssl/server.crt").unwrap();
+    let scram_tls_acceptor = setup_tls().unwrap();
+    let tls_acceptor = scram_tls_acceptor.acceptor();
     let factory = Arc::new(DummyProcessorFactory {
         handler: Arc::new(DummyProcessor),
-        cert,
+        tls_acceptor: scram_tls_acceptor,
     });
 
     let server_addr = "127.0.0.1:5432";
-    let tls_acceptor = setup_tls().unwrap();
     let listener = TcpListener::bind(server_addr).await.unwrap();
     println!("Listening to {}", server_addr);
     loop {
@@ -44,7 +44,7 @@ impl SimpleQueryHandler for DummyProcessor {
                 (Some(2), None),
             ];
             let schema_ref = schema.clone();
-            let data_row_stream = stream::iter(data.into_iter()).map(move |r| {
+            let data_row_stream = stream::iter(data).map(move |r| {
                 let mut encoder = DataRowEncoder::new(schema_ref.clone());
                 encoder.encode_field(&r.0)?;
                 encoder.encode_field(&r.1)?;
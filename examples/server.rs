@@ -3,7 +3,6 @@ use std::sync::Arc;
 
 use async_trait::async_trait;
 use futures::{stream, Sink, SinkExt, StreamExt};
-use tokio::net::TcpListener;
 
 use pgwire::api::auth::noop::NoopStartupHandler;
 use pgwire::api::copy::NoopCopyHandler;
@@ -14,7 +13,7 @@ use pgwire::error::ErrorInfo;
 use pgwire::error::{PgWireError, PgWireResult};
 use pgwire::messages::response::NoticeResponse;
 use pgwire::messages::PgWireBackendMessage;
-use pgwire::tokio::process_socket;
+use pgwire::tokio::{ListenAddr, PgWireListener};
 
 pub struct DummyProcessor;
 
@@ -53,7 +52,7 @@ impl SimpleQueryHandler for DummyProcessor {
                 (Some(2), None),
             ];
             let schema_ref = schema.clone();
-            let data_row_stream = stream::iter(data.into_iter()).map(move |r| {
+            let data_row_stream = stream::iter(data).map(move |r| {
                 let mut encoder = DataRowEncoder::new(schema_ref.clone());
                 encoder.encode_field(&r.0)?;
                 encoder.encode_field(&r.1)?;
@@ -109,12 +108,11 @@ pub async fn main() {
         handler: Arc::new(DummyProcessor),
     });
 
-    let server_addr = "127.0.0.1:5432";
-    let listener = TcpListener::bind(server_addr).await.unwrap();
-    println!("Listening to {}", server_addr);
-    loop {
-        let incoming_socket = listener.accept().await.unwrap();
-        let factory_ref = factory.clone();
-        tokio::spawn(async move { process_socket(incoming_socket.0, None, factory_ref).await });
+    let listener = PgWireListener::bind([ListenAddr::Tcp("127.0.0.1:5432".parse().unwrap())])
+        .await
+        .unwrap();
+    for addr in listener.local_addrs().unwrap() {
+        println!("Listening to {}", addr);
     }
+    listener.serve(factory, std::future::pending()).await;
 }
@@ -0,0 +1,107 @@
+//! Server-wide admission control of queries.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::error::{ErrorInfo, PgWireError, PgWireResult};
+
+/// Limit number of queries executing concurrently across all connections.
+///
+/// The server acquires a permit before handling `Query` and `Execute`
+/// messages and holds it until the response is sent. When all permits are in
+/// use, the query is rejected with error `53000` right away, or after waiting
+/// for `queue_timeout` if it's set. Set it with
+/// `ServerConfig::admission_controller` to protect a backend store that
+/// can't take too much load.
+#[derive(Debug)]
+pub struct AdmissionController {
+    permits: Arc<Semaphore>,
+    max_concurrent_queries: usize,
+    queue_timeout: Option<Duration>,
+}
+
+/// Permit of an admitted query, released on drop.
+#[derive(Debug)]
+pub struct AdmissionPermit {
+    _permit: OwnedSemaphorePermit,
+}
+
+impl AdmissionController {
+    /// Allow at most `max_concurrent_queries` queries executing at the same
+    /// time, and reject others immediately.
+    pub fn new(max_concurrent_queries: usize) -> AdmissionController {
+        AdmissionController {
+            permits: Arc::new(Semaphore::new(max_concurrent_queries)),
+            max_concurrent_queries,
+            queue_timeout: None,
+        }
+    }
+
+    /// Queue queries for at most `timeout` when all permits are in use,
+    /// instead of rejecting them immediately.
+    pub fn with_queue_timeout(mut self, timeout: Duration) -> AdmissionController {
+        self.queue_timeout = Some(timeout);
+        self
+    }
+
+    /// Number of queries that can be admitted now
+    pub fn available_permits(&self) -> usize {
+        self.permits.available_permits()
+    }
+
+    /// Acquire a permit for a query.
+    pub async fn admit(&self) -> PgWireResult<AdmissionPermit> {
+        let permit = match self.queue_timeout {
+            None => self.permits.clone().try_acquire_owned().ok(),
+            Some(timeout) => tokio::time::timeout(timeout, self.permits.clone().acquire_owned())
+                .await
+                .ok()
+                .and_then(Result::ok),
+        };
+
+        permit
+            .map(|permit| AdmissionPermit { _permit: permit })
+            .ok_or_else(|| self.rejected())
+    }
+
+    fn rejected(&self) -> PgWireError {
+        PgWireError::UserError(Box::new(ErrorInfo::new(
+            "ERROR".to_owned(),
+            "53000".to_owned(),
+            format!(
+                "too many concurrent queries, the server allows at most {}",
+                self.max_concurrent_queries
+            ),
+        )))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_admission_controller() {
+        let controller = AdmissionController::new(1);
+        let permit = controller.admit().await.unwrap();
+        assert_eq!(0, controller.available_permits());
+
+        let Err(PgWireError::UserError(error)) = controller.admit().await else {
+            panic!("query should be rejected");
+        };
+        assert_eq!("53000", error.code);
+
+        drop(permit);
+        assert!(controller.admit().await.is_ok());
+
+        let controller = AdmissionController::new(1).with_queue_timeout(Duration::from_secs(5));
+        let permit = controller.admit().await.unwrap();
+        let (second, _) = futures::join!(controller.admit(), async move {
+            tokio::task::yield_now().await;
+            drop(permit);
+        });
+        assert!(second.is_ok());
+    }
+}
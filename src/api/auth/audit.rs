@@ -0,0 +1,124 @@
+//! Audit events of authentication.
+//!
+//! Set `ServerConfig::auth_audit` to receive an `AuthEvent` for each login
+//! that succeeded or failed in the authentication handlers of this crate, for
+//! example to feed a SIEM system.
+
+use std::fmt::Debug;
+use std::net::SocketAddr;
+
+use crate::api::{ClientInfo, METADATA_DATABASE, METADATA_USER};
+
+/// Result of a login attempt
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthOutcome {
+    Success,
+    /// Failed with the reason
+    Failure(String),
+}
+
+/// A login attempt
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthEvent {
+    pub user: Option<String>,
+    pub database: Option<String>,
+    /// Authentication mechanism, like `password`, `md5`, `SCRAM-SHA-256`,
+    /// `trust` and `cert`
+    pub mechanism: String,
+    /// Address of client
+    pub addr: SocketAddr,
+    pub outcome: AuthOutcome,
+}
+
+impl AuthEvent {
+    /// Test if the login succeeded
+    pub fn is_success(&self) -> bool {
+        self.outcome == AuthOutcome::Success
+    }
+}
+
+/// Receive authentication events.
+///
+/// It's called on the connection task during startup, so expensive work like
+/// writing to remote systems should be sent to another task.
+pub trait AuthAuditHandler: Debug + Send + Sync {
+    fn on_auth_event(&self, event: &AuthEvent);
+}
+
+/// Send event of a login attempt to `ServerConfig::auth_audit`
+pub fn audit<C: ClientInfo>(client: &C, mechanism: &str, outcome: AuthOutcome) {
+    let Some(handler) = &client.server_config().auth_audit else {
+        return;
+    };
+
+    let event = AuthEvent {
+        user: client.metadata().get(METADATA_USER).cloned(),
+        database: client.metadata().get(METADATA_DATABASE).cloned(),
+        mechanism: mechanism.to_owned(),
+        addr: client.socket_addr(),
+        outcome,
+    };
+    handler.on_auth_event(&event);
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::api::auth::cleartext::CleartextPasswordAuthStartupHandler;
+    use crate::api::auth::trust::TrustStartupHandler;
+    use crate::api::auth::DefaultServerParameterProvider;
+    use crate::api::config::ServerConfig;
+    use crate::testkit::fixture::{send_password, start_login, StaticPassword, TestHandlers};
+    use crate::testkit::MockClient;
+
+    #[derive(Debug, Default)]
+    struct AuditLog(Mutex<Vec<AuthEvent>>);
+
+    impl AuthAuditHandler for AuditLog {
+        fn on_auth_event(&self, event: &AuthEvent) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_auth_audit() {
+        let log = Arc::new(AuditLog::default());
+        let config = Arc::new(ServerConfig {
+            auth_audit: Some(log.clone()),
+            ..Default::default()
+        });
+
+        let cleartext =
+            TestHandlers::echo().with_startup(CleartextPasswordAuthStartupHandler::new(
+                StaticPassword("pencil"),
+                DefaultServerParameterProvider::default(),
+            ));
+        let mut client = MockClient::start_with_config(cleartext, config.clone());
+        start_login(&mut client, "tom").await;
+        send_password(&mut client, "wrong".to_owned()).await;
+
+        let trust = TestHandlers::echo().with_startup(TrustStartupHandler::new(
+            Arc::new(DefaultServerParameterProvider::default()),
+            vec!["127.0.0.0/8".parse().unwrap()],
+        ));
+        let mut client = MockClient::start_with_config(trust, config);
+        start_login(&mut client, "jerry").await;
+        client.receive_until_ready().await.unwrap();
+
+        let events = log.0.lock().unwrap();
+        assert_eq!(2, events.len());
+        assert_eq!(Some("tom"), events[0].user.as_deref());
+        assert_eq!("password", events[0].mechanism);
+        assert_eq!(
+            AuthOutcome::Failure("password mismatch".to_owned()),
+            events[0].outcome
+        );
+        assert_eq!(MockClient::PEER_ADDR, events[0].addr);
+        assert!(events[1].is_success());
+        assert_eq!("trust", events[1].mechanism);
+    }
+}
@@ -0,0 +1,253 @@
+//! Identity extraction from client certificates of mutual TLS connections.
+//!
+//! The certificate chain is available from `ClientInfo::client_certificates`
+//! once client presented its certificate during TLS handshake. Note that
+//! verification of the chain is done by rustls, according to the
+//! `ClientCertVerifier` configured in your `TlsAcceptor`.
+
+use std::net::IpAddr;
+
+use x509_certificate::certificate::X509Certificate;
+
+#[cfg(feature = "_aws-lc-rs")]
+use aws_lc_rs::digest;
+#[cfg(feature = "_ring")]
+use ring::digest;
+
+use crate::api::auth::audit::{audit, AuthOutcome};
+use crate::api::{ClientInfo, METADATA_USER};
+use crate::error::{ErrorInfo, PgWireError, PgWireResult};
+
+/// DER encoded OID of `subjectAltName` extension, 2.5.29.17
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+/// Identity information of a certificate
+#[non_exhaustive]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CertificateIdentity {
+    /// Common Name (CN) of certificate subject
+    pub common_name: Option<String>,
+    /// `dNSName` entries of subject alternative names
+    pub dns_names: Vec<String>,
+    /// `rfc822Name` entries of subject alternative names
+    pub emails: Vec<String>,
+    /// `uniformResourceIdentifier` entries of subject alternative names
+    pub uris: Vec<String>,
+    /// `iPAddress` entries of subject alternative names
+    pub ip_addresses: Vec<IpAddr>,
+    /// SHA-256 digest of the DER encoded certificate
+    pub sha256_fingerprint: Vec<u8>,
+}
+
+impl CertificateIdentity {
+    /// Parse identity from a DER encoded certificate
+    pub fn from_der(der: &[u8]) -> PgWireResult<CertificateIdentity> {
+        let cert =
+            X509Certificate::from_der(der).map_err(|e| PgWireError::ApiError(Box::new(e)))?;
+
+        let mut identity = CertificateIdentity {
+            common_name: cert.subject_common_name(),
+            sha256_fingerprint: digest::digest(&digest::SHA256, der).as_ref().to_vec(),
+            ..Default::default()
+        };
+
+        for ext in cert.iter_extensions() {
+            let oid: &[u8] = ext.id.as_ref();
+            if oid == OID_SUBJECT_ALT_NAME {
+                identity.parse_general_names(&ext.value.to_bytes())?;
+            }
+        }
+
+        Ok(identity)
+    }
+
+    /// Hex encoded SHA-256 fingerprint, in uppercase and colon separated form
+    /// like `openssl x509 -fingerprint -sha256`.
+    pub fn fingerprint_hex(&self) -> String {
+        self.sha256_fingerprint
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<_>>()
+            .join(":")
+    }
+
+    fn parse_general_names(&mut self, der: &[u8]) -> PgWireResult<()> {
+        // GeneralNames ::= SEQUENCE SIZE (1..MAX) OF GeneralName
+        let (tag, mut names, _) = read_tlv(der)?;
+        if tag != 0x30 {
+            return Err(invalid_san());
+        }
+
+        while !names.is_empty() {
+            let (tag, value, rest) = read_tlv(names)?;
+            match tag {
+                // rfc822Name [1] IA5String
+                0x81 => self.emails.push(ia5_string(value)?),
+                // dNSName [2] IA5String
+                0x82 => self.dns_names.push(ia5_string(value)?),
+                // uniformResourceIdentifier [6] IA5String
+                0x86 => self.uris.push(ia5_string(value)?),
+                // iPAddress [7] OCTET STRING
+                0x87 => match value.len() {
+                    4 => {
+                        let octets: [u8; 4] = value.try_into().unwrap();
+                        self.ip_addresses.push(IpAddr::from(octets));
+                    }
+                    16 => {
+                        let octets: [u8; 16] = value.try_into().unwrap();
+                        self.ip_addresses.push(IpAddr::from(octets));
+                    }
+                    _ => return Err(invalid_san()),
+                },
+                // otherName, x400Address, directoryName, ediPartyName and
+                // registeredID are ignored
+                _ => {}
+            }
+            names = rest;
+        }
+
+        Ok(())
+    }
+}
+
+fn invalid_san() -> PgWireError {
+    PgWireError::ApiError("Invalid subjectAltName extension".into())
+}
+
+fn ia5_string(value: &[u8]) -> PgWireResult<String> {
+    if value.is_ascii() {
+        Ok(String::from_utf8_lossy(value).into_owned())
+    } else {
+        Err(invalid_san())
+    }
+}
+
+/// Read a DER tag-length-value, returns tag, value and remaining bytes
+fn read_tlv(buf: &[u8]) -> PgWireResult<(u8, &[u8], &[u8])> {
+    if buf.len() < 2 {
+        return Err(invalid_san());
+    }
+
+    let tag = buf[0];
+    let (len, offset) = if buf[1] < 0x80 {
+        (buf[1] as usize, 2)
+    } else {
+        let len_bytes = (buf[1] & 0x7f) as usize;
+        if len_bytes == 0 || len_bytes > 4 || buf.len() < 2 + len_bytes {
+            return Err(invalid_san());
+        }
+        let len = buf[2..2 + len_bytes]
+            .iter()
+            .fold(0usize, |acc, b| (acc << 8) | *b as usize);
+        (len, 2 + len_bytes)
+    };
+
+    if buf.len() < offset + len {
+        return Err(invalid_san());
+    }
+
+    Ok((tag, &buf[offset..offset + len], &buf[offset + len..]))
+}
+
+/// Get identity of client certificate, returns `None` if client didn't
+/// present any certificate.
+pub fn client_identity<C>(client: &C) -> PgWireResult<Option<CertificateIdentity>>
+where
+    C: ClientInfo,
+{
+    client
+        .client_certificates()
+        .and_then(|certs| certs.first())
+        .map(|cert| CertificateIdentity::from_der(cert.as_ref()))
+        .transpose()
+}
+
+/// Map certificate identity to database users
+pub trait CertificateUserMapper: Send + Sync {
+    /// Returns true if client with given certificate identity is allowed to
+    /// login as `user`.
+    fn is_user_allowed(&self, identity: &CertificateIdentity, user: &str) -> bool;
+}
+
+/// Allow login only when the certificate's CN equals to the user name, which
+/// is the default behaviour of postgres `cert` authentication.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CommonNameUserMapper;
+
+impl CertificateUserMapper for CommonNameUserMapper {
+    fn is_user_allowed(&self, identity: &CertificateIdentity, user: &str) -> bool {
+        identity.common_name.as_deref() == Some(user)
+    }
+}
+
+/// Verify client certificate identity against the user in startup message.
+///
+/// Returns the identity of the client when the mapper accepts it, otherwise
+/// a `FATAL` error which can be sent to client directly.
+pub fn verify_client_certificate<C, M>(client: &C, mapper: &M) -> PgWireResult<CertificateIdentity>
+where
+    C: ClientInfo,
+    M: CertificateUserMapper,
+{
+    let user = client
+        .metadata()
+        .get(METADATA_USER)
+        .ok_or(PgWireError::UserNameRequired)?;
+
+    let message = match client_identity(client)? {
+        Some(identity) if mapper.is_user_allowed(&identity, user) => {
+            audit(client, "cert", AuthOutcome::Success);
+            return Ok(identity);
+        }
+        Some(_) => format!("certificate authentication failed for user \"{}\"", user),
+        None => "connection requires a valid client certificate".to_owned(),
+    };
+
+    audit(client, "cert", AuthOutcome::Failure(message.clone()));
+    Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+        "FATAL".to_owned(),
+        "28000".to_owned(),
+        message,
+    ))))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_general_names() {
+        let der = [
+            0x30, 0x18, // SEQUENCE
+            0x82, 0x09, b'l', b'o', b'c', b'a', b'l', b'h', b'o', b's', b't', // dNSName
+            0x81, 0x05, b'a', b'@', b'b', b'.', b'c', // rfc822Name
+            0x87, 0x04, 127, 0, 0, 1, // iPAddress
+        ];
+
+        let mut identity = CertificateIdentity::default();
+        identity.parse_general_names(&der).unwrap();
+
+        assert_eq!(vec!["localhost".to_owned()], identity.dns_names);
+        assert_eq!(vec!["a@b.c".to_owned()], identity.emails);
+        assert_eq!(
+            vec!["127.0.0.1".parse::<IpAddr>().unwrap()],
+            identity.ip_addresses
+        );
+        assert!(identity.uris.is_empty());
+
+        assert!(identity.parse_general_names(&der[..10]).is_err());
+    }
+
+    #[test]
+    fn test_common_name_user_mapper() {
+        let identity = CertificateIdentity {
+            common_name: Some("tom".to_owned()),
+            sha256_fingerprint: vec![0xab, 0x01],
+            ..Default::default()
+        };
+
+        assert!(CommonNameUserMapper.is_user_allowed(&identity, "tom"));
+        assert!(!CommonNameUserMapper.is_user_allowed(&identity, "jerry"));
+        assert_eq!("AB:01", identity.fingerprint_hex());
+    }
+}
@@ -3,11 +3,11 @@ use std::fmt::Debug;
 use async_trait::async_trait;
 use futures::sink::{Sink, SinkExt};
 
+use super::audit::{audit, AuthOutcome};
 use super::{
-    AuthSource, ClientInfo, LoginInfo, PgWireConnectionState, ServerParameterProvider,
-    StartupHandler,
+    AuthSource, ClientInfo, PgWireConnectionState, ServerParameterProvider, StartupHandler,
 };
-use crate::error::{ErrorInfo, PgWireError, PgWireResult};
+use crate::error::{PgWireError, PgWireResult};
 use crate::messages::response::ErrorResponse;
 use crate::messages::startup::Authentication;
 use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
@@ -16,6 +16,17 @@ use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
 pub struct CleartextPasswordAuthStartupHandler<A, P> {
     auth_source: A,
     parameter_provider: P,
+    #[new(default)]
+    require_secure: bool,
+}
+
+impl<A, P> CleartextPasswordAuthStartupHandler<A, P> {
+    /// Reject connections not secured by TLS with `28000` before requesting
+    /// the password, so it's never sent in plaintext.
+    pub fn with_require_secure(mut self, require_secure: bool) -> Self {
+        self.require_secure = require_secure;
+        self
+    }
 }
 
 #[async_trait]
@@ -36,6 +47,15 @@ impl<V: AuthSource, P: ServerParameterProvider> StartupHandler
             PgWireFrontendMessage::Startup(ref startup) => {
                 super::save_startup_parameters_to_metadata(client, startup);
                 client.set_state(PgWireConnectionState::AuthenticationInProgress);
+                if self.require_secure && !client.is_secure() {
+                    return super::policy::reject(
+                        client,
+                        "password",
+                        "28000",
+                        super::INSECURE_PASSWORD_MESSAGE,
+                    )
+                    .await;
+                }
                 client
                     .send(PgWireBackendMessage::Authentication(
                         Authentication::CleartextPassword,
@@ -44,16 +64,22 @@ impl<V: AuthSource, P: ServerParameterProvider> StartupHandler
             }
             PgWireFrontendMessage::PasswordMessageFamily(pwd) => {
                 let pwd = pwd.into_password()?;
-                let login_info = LoginInfo::from_client_info(client);
-                let pass = self.auth_source.get_password(&login_info).await?;
-                if pass.password == pwd.password.as_bytes() {
+                let passwords = super::get_passwords(client, &self.auth_source, "password").await?;
+                if passwords
+                    .iter()
+                    .any(|pass| pass.password == pwd.password.as_bytes())
+                {
                     super::finish_authentication(client, &self.parameter_provider).await?;
+                    audit(client, "password", AuthOutcome::Success);
                 } else {
-                    let error_info = ErrorInfo::new(
-                        "FATAL".to_owned(),
-                        "28P01".to_owned(),
-                        "Password authentication failed".to_owned(),
+                    audit(
+                        client,
+                        "password",
+                        AuthOutcome::Failure("password mismatch".to_owned()),
                     );
+                    let error_info =
+                        super::auth_failure(client, "28P01", "Password authentication failed")
+                            .await;
                     let error = ErrorResponse::from(error_info);
 
                     client
@@ -67,3 +93,24 @@ impl<V: AuthSource, P: ServerParameterProvider> StartupHandler
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::auth::DefaultServerParameterProvider;
+    use crate::testkit::fixture::{error_code, start_login, StaticPassword, TestHandlers};
+    use crate::testkit::MockClient;
+
+    #[tokio::test]
+    async fn test_require_secure() {
+        let handler = CleartextPasswordAuthStartupHandler::new(
+            StaticPassword("pencil"),
+            DefaultServerParameterProvider::default(),
+        )
+        .with_require_secure(true);
+        let mut client = MockClient::start(TestHandlers::echo().with_startup(handler));
+        let message = start_login(&mut client, "tom").await;
+        assert_eq!(Some("28000".to_owned()), error_code(&message));
+        assert!(client.receive().await.unwrap().is_none());
+    }
+}
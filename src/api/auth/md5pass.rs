@@ -5,11 +5,11 @@ use async_trait::async_trait;
 use futures::sink::{Sink, SinkExt};
 use tokio::sync::Mutex;
 
+use super::audit::{audit, AuthOutcome};
 use super::{
-    AuthSource, ClientInfo, LoginInfo, PgWireConnectionState, ServerParameterProvider,
-    StartupHandler,
+    AuthSource, ClientInfo, PgWireConnectionState, ServerParameterProvider, StartupHandler,
 };
-use crate::error::{ErrorInfo, PgWireError, PgWireResult};
+use crate::error::{PgWireError, PgWireResult};
 use crate::messages::response::ErrorResponse;
 use crate::messages::startup::Authentication;
 use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
@@ -17,7 +17,8 @@ use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
 pub struct Md5PasswordAuthStartupHandler<A, P> {
     auth_source: Arc<A>,
     parameter_provider: Arc<P>,
-    cached_password: Mutex<Vec<u8>>,
+    cached_passwords: Mutex<Vec<Vec<u8>>>,
+    require_secure: bool,
 }
 
 impl<A, P> Md5PasswordAuthStartupHandler<A, P> {
@@ -25,9 +26,17 @@ impl<A, P> Md5PasswordAuthStartupHandler<A, P> {
         Md5PasswordAuthStartupHandler {
             auth_source,
             parameter_provider,
-            cached_password: Mutex::new(vec![]),
+            cached_passwords: Mutex::new(vec![]),
+            require_secure: false,
         }
     }
+
+    /// Reject connections not secured by TLS with `28000` before requesting
+    /// the password, the md5 hash is weak against offline cracking.
+    pub fn with_require_secure(mut self, require_secure: bool) -> Self {
+        self.require_secure = require_secure;
+        self
+    }
 }
 
 #[async_trait]
@@ -48,38 +57,62 @@ impl<A: AuthSource, P: ServerParameterProvider> StartupHandler
             PgWireFrontendMessage::Startup(ref startup) => {
                 super::save_startup_parameters_to_metadata(client, startup);
                 client.set_state(PgWireConnectionState::AuthenticationInProgress);
+                if self.require_secure && !client.is_secure() {
+                    return super::policy::reject(
+                        client,
+                        "md5",
+                        "28000",
+                        super::INSECURE_PASSWORD_MESSAGE,
+                    )
+                    .await;
+                }
 
-                let login_info = LoginInfo::from_client_info(client);
-                let salt_and_pass = self.auth_source.get_password(&login_info).await?;
-
-                let salt = salt_and_pass
+                let passwords =
+                    super::get_passwords(client, self.auth_source.as_ref(), "md5").await?;
+
+                let Some(first) = passwords.first() else {
+                    return super::policy::reject(
+                        client,
+                        "md5",
+                        "28P01",
+                        "Password authentication failed",
+                    )
+                    .await;
+                };
+                let salt = first
                     .salt
-                    .as_ref()
+                    .clone()
                     .expect("Salt is required for Md5Password authentication");
 
-                self.cached_password
-                    .lock()
-                    .await
-                    .clone_from(&salt_and_pass.password);
+                *self.cached_passwords.lock().await = super::passwords_with_first_salt(&passwords)
+                    .map(|p| p.password.clone())
+                    .collect();
 
                 client
                     .send(PgWireBackendMessage::Authentication(
-                        Authentication::MD5Password(salt.clone()),
+                        Authentication::MD5Password(salt),
                     ))
                     .await?;
             }
             PgWireFrontendMessage::PasswordMessageFamily(pwd) => {
                 let pwd = pwd.into_password()?;
-                let cached_pass = self.cached_password.lock().await;
+                let cached_passwords = self.cached_passwords.lock().await;
 
-                if pwd.password.as_bytes() == *cached_pass {
+                if cached_passwords
+                    .iter()
+                    .any(|pass| pwd.password.as_bytes() == pass.as_slice())
+                {
                     super::finish_authentication(client, self.parameter_provider.as_ref()).await?;
+                    audit(client, "md5", AuthOutcome::Success);
                 } else {
-                    let error_info = ErrorInfo::new(
-                        "FATAL".to_owned(),
-                        "28P01".to_owned(),
-                        "Password authentication failed".to_owned(),
+                    audit(
+                        client,
+                        "md5",
+                        AuthOutcome::Failure("password mismatch".to_owned()),
                     );
+                    let error_info =
+                        super::auth_failure(client, "28P01", "Password authentication failed")
+                            .await;
                     let error = ErrorResponse::from(error_info);
 
                     client
@@ -102,12 +135,31 @@ impl<A: AuthSource, P: ServerParameterProvider> StartupHandler
 /// `md5(concat(password, username))` so that your can store hashed password in
 /// storage.
 pub fn hash_md5_password(username: &str, password: &str, salt: &[u8]) -> String {
-    let hashed_bytes = format!("{:x}", md5::compute(format!("{password}{username}")));
-    let mut bytes = Vec::with_capacity(hashed_bytes.len() + 4);
-    bytes.extend_from_slice(hashed_bytes.as_ref());
+    let verifier = gen_md5_verifier(username, password);
+    hash_md5_verifier(&verifier, salt).expect("md5 verifier")
+}
+
+/// Generate the md5 verifier of password stored by postgres in
+/// `pg_authid.rolpassword`
+///
+/// concat('md5', md5(concat(password, username)))
+pub fn gen_md5_verifier(username: &str, password: &str) -> String {
+    format!("md5{:x}", md5::compute(format!("{password}{username}")))
+}
+
+/// Compute the expected response of `MD5Password` challenge from a stored
+/// md5 verifier, as generated by `gen_md5_verifier`. Returns `None` if the
+/// verifier is not in md5 format.
+pub fn hash_md5_verifier(verifier: &str, salt: &[u8]) -> Option<String> {
+    let hashed = verifier.strip_prefix("md5")?;
+    if hashed.len() != 32 || !hashed.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(hashed.len() + salt.len());
+    bytes.extend_from_slice(hashed.as_bytes());
     bytes.extend_from_slice(salt);
 
-    format!("md5{:x}", md5::compute(bytes))
+    Some(format!("md5{:x}", md5::compute(bytes)))
 }
 
 #[cfg(test)]
@@ -123,4 +175,17 @@ mod tests {
 
         assert_eq!(result, super::hash_md5_password(username, password, &salt));
     }
+
+    #[test]
+    fn test_md5_verifier() {
+        let salt = vec![20, 247, 107, 249];
+        let verifier = super::gen_md5_verifier("zmjiang", "themanwhochangedchina");
+        assert_eq!(35, verifier.len());
+        assert_eq!(
+            Some("md521fe459d77d3e3ea9c9fcd5c11030d30".to_owned()),
+            super::hash_md5_verifier(&verifier, &salt)
+        );
+        assert_eq!(None, super::hash_md5_verifier("md5abc", &salt));
+        assert_eq!(None, super::hash_md5_verifier("SCRAM-SHA-256$", &salt));
+    }
 }
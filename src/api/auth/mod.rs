@@ -1,11 +1,15 @@
 use std::collections::HashMap;
 use std::fmt::Debug;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::Duration;
 
 use async_trait::async_trait;
 use futures::sink::{Sink, SinkExt};
+use tokio::time::Instant;
 
+use super::show::NON_PARAMETER_KEYS;
 use super::{ClientInfo, PgWireConnectionState, METADATA_DATABASE, METADATA_USER};
-use crate::error::{PgWireError, PgWireResult};
+use crate::error::{ErrorInfo, PgWireError, PgWireResult};
 use crate::messages::response::{ReadyForQuery, TransactionStatus};
 use crate::messages::startup::{Authentication, BackendKeyData, ParameterStatus, Startup};
 use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
@@ -87,9 +91,19 @@ impl ServerParameterProvider for DefaultServerParameterProvider {
 pub struct Password {
     salt: Option<Vec<u8>>,
     password: Vec<u8>,
+    #[new(default)]
+    iterations: Option<usize>,
 }
 
 impl Password {
+    /// Iteration count the SCRAM salted password is hashed with, instead of
+    /// the one of the startup handler. For users migrated between iteration
+    /// policies.
+    pub fn with_iterations(mut self, iterations: usize) -> Password {
+        self.iterations = Some(iterations);
+        self
+    }
+
     pub fn salt(&self) -> Option<&[u8]> {
         self.salt.as_deref()
     }
@@ -97,6 +111,10 @@ impl Password {
     pub fn password(&self) -> &[u8] {
         &self.password
     }
+
+    pub fn iterations(&self) -> Option<usize> {
+        self.iterations
+    }
 }
 
 #[derive(Debug, new)]
@@ -144,13 +162,84 @@ pub trait AuthSource: Send + Sync {
     ///
     /// `Password` has a an optional salt field when it's hashed.
     async fn get_password(&self, login: &LoginInfo) -> PgWireResult<Password>;
+
+    /// Get all passwords accepted for the login, for example the current and
+    /// previous secret during credential rotation. Authentication succeeds if
+    /// any of them matches.
+    ///
+    /// Only one salt can be sent to client, so for md5 and SCRAM, passwords
+    /// are only accepted when they have the same salt as the first one.
+    ///
+    /// Returns the password from `get_password` by default.
+    async fn get_passwords(&self, login: &LoginInfo) -> PgWireResult<Vec<Password>> {
+        Ok(vec![self.get_password(login).await?])
+    }
+}
+
+/// Passwords with the same salt as the first one
+pub(crate) fn passwords_with_first_salt(passwords: &[Password]) -> impl Iterator<Item = &Password> {
+    let salt = passwords.first().and_then(|p| p.salt());
+    passwords.iter().filter(move |p| p.salt() == salt)
+}
+
+/// Get passwords of the login from `AuthSource`.
+///
+/// Errors of the source, like an unknown user, are audited and reported as a
+/// failed login with `auth_failure`, so they take the same time and get the
+/// same error as a wrong password under `AuthFailurePolicy`.
+pub(crate) async fn get_passwords<C, A>(
+    client: &mut C,
+    auth_source: &A,
+    mechanism: &str,
+) -> PgWireResult<Vec<Password>>
+where
+    C: ClientInfo + Send,
+    A: AuthSource + ?Sized,
+{
+    let user = client.metadata().get(METADATA_USER).cloned();
+    let database = client.metadata().get(METADATA_DATABASE).cloned();
+    let host = client.socket_addr().ip().to_string();
+    let login_info = LoginInfo::new(user.as_deref(), database.as_deref(), host);
+    let result = auth_source.get_passwords(&login_info).await;
+    match result {
+        Ok(passwords) => Ok(passwords),
+        Err(e) => {
+            let (code, message) = match e {
+                PgWireError::UserError(info) => (info.code, info.message),
+                e => ("28P01".to_owned(), e.to_string()),
+            };
+            audit::audit(
+                client,
+                mechanism,
+                audit::AuthOutcome::Failure(message.clone()),
+            );
+            Err(PgWireError::UserError(Box::new(
+                auth_failure(client, &code, &message).await,
+            )))
+        }
+    }
 }
 
+/// When client sent the startup message, failed logins are delayed until
+/// `AuthFailurePolicy::delay` after it.
+#[derive(Debug, Clone, Copy)]
+struct AuthStarted(Instant);
+
+/// Save startup parameters to client metadata.
+///
+/// Settings in the `options` parameter, like `-c search_path=app`, are saved
+/// as individual entries too, see `parse_options`. Parameters given
+/// explicitly take precedence over them, same as postgres. The raw `options`
+/// is kept as well.
 pub fn save_startup_parameters_to_metadata<C>(client: &mut C, startup_message: &Startup)
 where
     C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
     C::Error: Debug,
 {
+    client.extensions_mut().insert(AuthStarted(Instant::now()));
+    if let Some(options) = startup_message.parameters.get("options") {
+        client.metadata_mut().extend(parse_options(options));
+    }
     client.metadata_mut().extend(
         startup_message
             .parameters
@@ -159,6 +248,179 @@ where
     );
 }
 
+/// Parse settings from the libpq `options` startup parameter.
+///
+/// Options are separated by whitespace, and a backslash escapes the next
+/// character, like `-c search_path=a\ b`. Settings are given as `-c
+/// name=value`, `-cname=value` or `--name=value`, where dashes in the name of
+/// the last form are read as underscores. Other command-line switches are
+/// ignored, and so are settings of startup parameters that are not GUCs, like
+/// `user` and `database`, which can't be changed from `options`.
+pub fn parse_options(options: &str) -> Vec<(String, String)> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_arg = false;
+    let mut chars = options.chars();
+    while let Some(c) = chars.next() {
+        if c.is_ascii_whitespace() {
+            if in_arg {
+                args.push(std::mem::take(&mut current));
+                in_arg = false;
+            }
+            continue;
+        }
+        in_arg = true;
+        if c == '\\' {
+            if let Some(escaped) = chars.next() {
+                current.push(escaped);
+            }
+        } else {
+            current.push(c);
+        }
+    }
+    if in_arg {
+        args.push(current);
+    }
+
+    let mut settings = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let setting = if arg == "-c" {
+            args.next()
+        } else if let Some(setting) = arg.strip_prefix("-c") {
+            Some(setting.to_owned())
+        } else {
+            arg.strip_prefix("--")
+                .map(|setting| match setting.split_once('=') {
+                    Some((name, value)) => format!("{}={value}", name.replace('-', "_")),
+                    None => setting.to_owned(),
+                })
+        };
+        if let Some((name, value)) = setting.as_deref().and_then(|s| s.split_once('=')) {
+            let is_parameter = !NON_PARAMETER_KEYS
+                .iter()
+                .any(|key| key.eq_ignore_ascii_case(name));
+            if !name.is_empty() && is_parameter {
+                settings.push((name.to_owned(), value.to_owned()));
+            }
+        }
+    }
+    settings
+}
+
+/// How failed logins are reported to client, to prevent user enumeration and
+/// timing attacks.
+///
+/// It's applied to password based authentication handlers of this crate.
+#[non_exhaustive]
+#[derive(Debug, Clone, Default, PartialEq, Eq, new)]
+pub struct AuthFailurePolicy {
+    /// Report failure no earlier than this duration after the startup
+    /// message, so all failures take the same time no matter why and at
+    /// which step they failed.
+    pub delay: Option<Duration>,
+    /// Report all failures as `28P01` `password authentication failed for
+    /// user "<user>"`, without the actual reason.
+    pub uniform_error: bool,
+}
+
+impl AuthFailurePolicy {
+    /// Build the error of a failed login
+    pub fn error(&self, user: Option<&str>, code: &str, message: &str) -> ErrorInfo {
+        if self.uniform_error {
+            ErrorInfo::new(
+                "FATAL".to_owned(),
+                "28P01".to_owned(),
+                format!(
+                    "password authentication failed for user \"{}\"",
+                    user.unwrap_or_default()
+                ),
+            )
+        } else {
+            ErrorInfo::new("FATAL".to_owned(), code.to_owned(), message.to_owned())
+        }
+    }
+}
+
+/// Wait for `AuthFailurePolicy::delay` and build error of a failed login
+pub(crate) async fn auth_failure<C>(client: &mut C, code: &str, message: &str) -> ErrorInfo
+where
+    C: ClientInfo + Send,
+{
+    let policy = &client.server_config().auth_failure_policy;
+    let error = policy.error(
+        client.metadata().get(METADATA_USER).map(String::as_str),
+        code,
+        message,
+    );
+    if let Some(deadline) = failure_deadline(client) {
+        tokio::time::sleep_until(deadline).await;
+    }
+    error
+}
+
+/// `AuthFailurePolicy::delay` after the startup message, or after now if the
+/// handler didn't save startup parameters.
+pub(crate) fn failure_deadline<C: ClientInfo>(client: &C) -> Option<Instant> {
+    let delay = client.server_config().auth_failure_policy.delay?;
+    let started = client
+        .extensions()
+        .get::<AuthStarted>()
+        .map(|started| started.0)
+        .unwrap_or_else(Instant::now);
+    Some(started + delay)
+}
+
+/// Validate the database requested by client, before the connection is
+/// accepted.
+///
+/// It's called after the credential is verified, so unauthenticated clients
+/// can't probe databases. Connections rejected by the validator fail with
+/// `3D000` invalid_catalog_name, like connecting to a database that doesn't
+/// exist in postgres.
+#[async_trait]
+pub trait DatabaseValidator: Debug + Send + Sync {
+    /// Return `false` if the database doesn't exist, or the user is not
+    /// mapped to it. The database is `None` when client didn't specify one,
+    /// postgres uses the user name in that case.
+    async fn validate_database(&self, login: &LoginInfo<'_>) -> PgWireResult<bool>;
+}
+
+/// Check database of the connection with `ServerConfig::database_validator`
+async fn validate_database<C>(client: &mut C) -> PgWireResult<()>
+where
+    C: ClientInfo + Send,
+{
+    let Some(validator) = client.server_config().database_validator.clone() else {
+        return Ok(());
+    };
+
+    let user = client.metadata().get(METADATA_USER).cloned();
+    let database = client.metadata().get(METADATA_DATABASE).cloned();
+    let host = client.socket_addr().ip().to_string();
+    let login = LoginInfo::new(user.as_deref(), database.as_deref(), host);
+    if validator.validate_database(&login).await? {
+        Ok(())
+    } else {
+        Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+            "FATAL".to_owned(),
+            "3D000".to_owned(),
+            format!(
+                "database \"{}\" does not exist",
+                database.or(user).unwrap_or_default()
+            ),
+        ))))
+    }
+}
+
+/// Error message of handlers refusing to exchange password over insecure
+/// connection
+pub(crate) const INSECURE_PASSWORD_MESSAGE: &str =
+    "password authentication requires an SSL connection";
+
+/// Pid sent in `BackendKeyData`, increased for each connection
+static NEXT_PID: AtomicI32 = AtomicI32::new(1);
+
 pub(crate) async fn finish_authentication0<C, P>(
     client: &mut C,
     server_parameter_provider: &P,
@@ -169,6 +431,8 @@ where
     PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
     P: ServerParameterProvider,
 {
+    validate_database(client).await?;
+
     client
         .feed(PgWireBackendMessage::Authentication(Authentication::Ok))
         .await?;
@@ -183,13 +447,33 @@ where
         }
     }
 
-    // TODO: store this backend key
+    if let Some(mode) = client.server_config().read_only_mode.clone() {
+        let parameters = match client.client_handle() {
+            Some(handle) => mode.register(handle),
+            None => mode.parameters(),
+        };
+        for (k, v) in parameters {
+            client.metadata_mut().insert(k.to_owned(), v.to_owned());
+            client
+                .feed(PgWireBackendMessage::ParameterStatus(ParameterStatus::new(
+                    k.to_owned(),
+                    v.to_owned(),
+                )))
+                .await?;
+        }
+    }
+
+    // unique for each connection like postgres backends, so cancel requests
+    // can find the connection by pid
+    let pid = NEXT_PID.fetch_add(1, Ordering::Relaxed) & i32::MAX;
+    let secret_key = rand::random::<i32>();
+    client.set_pid_and_secret_key(pid, secret_key);
     client
         .feed(PgWireBackendMessage::BackendKeyData(BackendKeyData::new(
-            std::process::id() as i32,
-            rand::random::<i32>(),
+            pid, secret_key,
         )))
         .await?;
+    crate::api::session::save_session_defaults(client);
 
     Ok(())
 }
@@ -216,8 +500,118 @@ where
     Ok(())
 }
 
+pub mod audit;
+#[cfg(feature = "client-cert")]
+pub mod cert;
 pub mod cleartext;
 pub mod md5pass;
 pub mod noop;
+pub mod policy;
 #[cfg(feature = "scram")]
 pub mod scram;
+pub mod trust;
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::api::config::ServerConfig;
+    use crate::messages::PgWireFrontendMessage;
+    use crate::testkit::fixture::{
+        error_code, single_value, single_value_response, start_login, FnQueryHandler, TestHandlers,
+    };
+    use crate::testkit::MockClient;
+
+    #[derive(Debug)]
+    struct KnownDatabases;
+
+    #[async_trait]
+    impl DatabaseValidator for KnownDatabases {
+        async fn validate_database(&self, login: &LoginInfo<'_>) -> PgWireResult<bool> {
+            Ok(login.database() == Some("test"))
+        }
+    }
+
+    #[test]
+    fn test_parse_options() {
+        assert_eq!(
+            vec![
+                ("search_path".to_owned(), "app".to_owned()),
+                ("statement_timeout".to_owned(), "5s".to_owned()),
+            ],
+            parse_options("-c search_path=app  -cstatement_timeout=5s")
+        );
+        assert_eq!(
+            vec![("application_name".to_owned(), "my app".to_owned())],
+            parse_options("--application-name=my\\ app")
+        );
+        assert_eq!(
+            vec![("DateStyle".to_owned(), "ISO, MDY".to_owned())],
+            parse_options("-e -c DateStyle=ISO,\\ MDY -c broken")
+        );
+        assert!(parse_options("").is_empty());
+        // startup parameters that are not GUCs
+        assert_eq!(
+            vec![("search_path".to_owned(), "app".to_owned())],
+            parse_options(
+                "-c user=admin --database=postgres -c search_path=app -cREPLICATION=true -c options=x"
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_startup_options() {
+        // answers `SHOW name` from client metadata
+        let handler = FnQueryHandler::with_metadata(|metadata, query| {
+            let name = query.trim_start_matches("SHOW ");
+            let value = metadata.get(name).map(String::as_str).unwrap_or_default();
+            Ok(vec![single_value_response(name, value)])
+        });
+        let mut client = MockClient::start(TestHandlers::new(handler));
+        let mut startup = Startup::new();
+        startup
+            .parameters
+            .insert("user".to_owned(), "tom".to_owned());
+        startup.parameters.insert(
+            "options".to_owned(),
+            "-c search_path=app --statement-timeout=5s -c database=admin".to_owned(),
+        );
+        client
+            .send(PgWireFrontendMessage::Startup(startup))
+            .await
+            .unwrap();
+        client.receive_until_ready().await.unwrap();
+
+        assert_eq!(
+            b"app".to_vec(),
+            single_value(&mut client, "SHOW search_path").await
+        );
+        assert_eq!(
+            b"5s".to_vec(),
+            single_value(&mut client, "SHOW statement_timeout").await
+        );
+        // not a setting, the database is not given in startup parameters
+        assert!(single_value(&mut client, "SHOW database").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_database_validator() {
+        let config = Arc::new(ServerConfig {
+            database_validator: Some(Arc::new(KnownDatabases)),
+            ..Default::default()
+        });
+
+        let mut client = MockClient::start_with_config(TestHandlers::echo(), config.clone());
+        let message = start_login(&mut client, "tom").await;
+        assert_eq!(Some("3D000".to_owned()), error_code(&message));
+        assert!(client.receive().await.unwrap().is_none());
+
+        let mut client = MockClient::start_with_config(TestHandlers::echo(), config);
+        let messages = client.startup("tom", Some("test")).await.unwrap();
+        assert_eq!(
+            PgWireBackendMessage::Authentication(Authentication::Ok),
+            messages[0]
+        );
+    }
+}
@@ -1,16 +1,28 @@
+use std::collections::HashMap;
 use std::fmt::Debug;
 
 use async_trait::async_trait;
 use futures::sink::{Sink, SinkExt};
 
-use super::{ClientInfo, DefaultServerParameterProvider, StartupHandler};
+use super::audit::{audit, AuthOutcome};
+use super::{ClientInfo, DefaultServerParameterProvider, ServerParameterProvider, StartupHandler};
 use crate::api::PgWireConnectionState;
 use crate::error::{PgWireError, PgWireResult};
 use crate::messages::response::{ReadyForQuery, TransactionStatus};
 use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
 
+/// Startup handler that accepts all connections without authentication.
 #[async_trait]
 pub trait NoopStartupHandler: StartupHandler {
+    /// Parameters sent to client as `ParameterStatus` when the connection is
+    /// accepted. `DefaultServerParameterProvider` is used by default.
+    fn server_parameters<C>(&self, client: &C) -> Option<HashMap<String, String>>
+    where
+        C: ClientInfo,
+    {
+        DefaultServerParameterProvider::default().server_parameters(client)
+    }
+
     async fn post_startup<C>(
         &self,
         _client: &mut C,
@@ -25,6 +37,33 @@ pub trait NoopStartupHandler: StartupHandler {
     }
 }
 
+/// `NoopStartupHandler` that sends parameters from a `ServerParameterProvider`.
+#[derive(Debug, new)]
+pub struct NoopAuthStartupHandler<P> {
+    parameter_provider: P,
+}
+
+impl<P: ServerParameterProvider> NoopStartupHandler for NoopAuthStartupHandler<P> {
+    fn server_parameters<C>(&self, client: &C) -> Option<HashMap<String, String>>
+    where
+        C: ClientInfo,
+    {
+        self.parameter_provider.server_parameters(client)
+    }
+}
+
+/// Use `NoopStartupHandler::server_parameters` as `ServerParameterProvider`
+struct HandlerParameterProvider<'a, H>(&'a H);
+
+impl<H: NoopStartupHandler> ServerParameterProvider for HandlerParameterProvider<'_, H> {
+    fn server_parameters<C>(&self, client: &C) -> Option<HashMap<String, String>>
+    where
+        C: ClientInfo,
+    {
+        NoopStartupHandler::server_parameters(self.0, client)
+    }
+}
+
 #[async_trait]
 impl<H> StartupHandler for H
 where
@@ -42,8 +81,8 @@ where
     {
         if let PgWireFrontendMessage::Startup(ref startup) = message {
             super::save_startup_parameters_to_metadata(client, startup);
-            super::finish_authentication0(client, &DefaultServerParameterProvider::default())
-                .await?;
+            super::finish_authentication0(client, &HandlerParameterProvider(self)).await?;
+            audit(client, "trust", AuthOutcome::Success);
 
             self.post_startup(client, message).await?;
 
@@ -0,0 +1,445 @@
+//! Choose authentication method for each login attempt.
+
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::sink::{Sink, SinkExt};
+use tokio::sync::Mutex;
+
+use super::audit::{audit, AuthOutcome};
+use super::{
+    AuthSource, ClientInfo, LoginInfo, PgWireConnectionState, ServerParameterProvider,
+    StartupHandler,
+};
+use crate::error::{PgWireError, PgWireResult};
+use crate::messages::response::ErrorResponse;
+use crate::messages::startup::Authentication;
+use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
+
+/// Authentication method of a login attempt
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthMethod {
+    /// Accept the connection without password
+    Trust,
+    /// Request password in cleartext, compared with the password from
+    /// `AuthSource`
+    Cleartext,
+    /// Request md5 hashed password. `AuthSource` has to return the password
+    /// hashed by `hash_md5_password` with its salt.
+    Md5,
+    /// Delegate to the SASL startup handler, for example
+    /// `SASLScramAuthStartupHandler`
+    Sasl,
+    /// Reject the connection with the message
+    Reject(String),
+}
+
+/// Decide authentication method for each login, like `pg_hba.conf` of
+/// postgres.
+#[async_trait]
+pub trait AuthPolicy: Send + Sync {
+    async fn auth_method(&self, login: &LoginInfo) -> PgWireResult<AuthMethod>;
+}
+
+/// Use the same method for all logins
+#[async_trait]
+impl AuthPolicy for AuthMethod {
+    async fn auth_method(&self, _login: &LoginInfo) -> PgWireResult<AuthMethod> {
+        Ok(self.clone())
+    }
+}
+
+/// SASL handler of `PolicyAuthStartupHandler` when SASL is not configured,
+/// it rejects the connection.
+#[derive(Debug, Default)]
+pub struct NoSaslHandler;
+
+#[async_trait]
+impl StartupHandler for NoSaslHandler {
+    async fn on_startup<C>(
+        &self,
+        client: &mut C,
+        _message: PgWireFrontendMessage,
+    ) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        reject(
+            client,
+            "sasl",
+            "28000",
+            "SASL authentication is not configured",
+        )
+        .await
+    }
+}
+
+#[derive(Debug)]
+enum PolicyState {
+    Initial,
+    Cleartext,
+    // cached md5 hashed passwords
+    Md5(Vec<Vec<u8>>),
+    Sasl,
+}
+
+/// Startup handler that asks `AuthPolicy` which method to use for the login,
+/// and sends the corresponding `Authentication` request to client.
+pub struct PolicyAuthStartupHandler<A, P, Pol, S = NoSaslHandler> {
+    auth_source: Arc<A>,
+    parameter_provider: Arc<P>,
+    policy: Arc<Pol>,
+    sasl_handler: Arc<S>,
+    state: Mutex<PolicyState>,
+}
+
+impl<A, P, Pol> PolicyAuthStartupHandler<A, P, Pol> {
+    pub fn new(auth_source: Arc<A>, parameter_provider: Arc<P>, policy: Arc<Pol>) -> Self {
+        PolicyAuthStartupHandler {
+            auth_source,
+            parameter_provider,
+            policy,
+            sasl_handler: Arc::new(NoSaslHandler),
+            state: Mutex::new(PolicyState::Initial),
+        }
+    }
+}
+
+impl<A, P, Pol, S> PolicyAuthStartupHandler<A, P, Pol, S> {
+    /// Set handler for logins using `AuthMethod::Sasl`
+    pub fn with_sasl_handler<S2>(
+        self,
+        sasl_handler: Arc<S2>,
+    ) -> PolicyAuthStartupHandler<A, P, Pol, S2> {
+        PolicyAuthStartupHandler {
+            auth_source: self.auth_source,
+            parameter_provider: self.parameter_provider,
+            policy: self.policy,
+            sasl_handler,
+            state: self.state,
+        }
+    }
+}
+
+#[async_trait]
+impl<A, P, Pol, S> StartupHandler for PolicyAuthStartupHandler<A, P, Pol, S>
+where
+    A: AuthSource,
+    P: ServerParameterProvider,
+    Pol: AuthPolicy,
+    S: StartupHandler,
+{
+    async fn on_startup<C>(
+        &self,
+        client: &mut C,
+        message: PgWireFrontendMessage,
+    ) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let mut state = self.state.lock().await;
+        match (&*state, message) {
+            (PolicyState::Initial, PgWireFrontendMessage::Startup(startup)) => {
+                super::save_startup_parameters_to_metadata(client, &startup);
+                client.set_state(PgWireConnectionState::AuthenticationInProgress);
+
+                let login_info = LoginInfo::from_client_info(client);
+                let method = self.policy.auth_method(&login_info).await?;
+                match method {
+                    AuthMethod::Trust => {
+                        super::finish_authentication(client, self.parameter_provider.as_ref())
+                            .await?;
+                        audit(client, "trust", AuthOutcome::Success);
+                    }
+                    AuthMethod::Cleartext => {
+                        *state = PolicyState::Cleartext;
+                        client
+                            .send(PgWireBackendMessage::Authentication(
+                                Authentication::CleartextPassword,
+                            ))
+                            .await?;
+                    }
+                    AuthMethod::Md5 => {
+                        let passwords =
+                            super::get_passwords(client, self.auth_source.as_ref(), "md5").await?;
+                        let Some(first) = passwords.first() else {
+                            return reject(
+                                client,
+                                "md5",
+                                "28P01",
+                                "Password authentication failed",
+                            )
+                            .await;
+                        };
+                        let salt = first
+                            .salt()
+                            .expect("Salt is required for Md5Password authentication")
+                            .to_vec();
+                        *state = PolicyState::Md5(
+                            super::passwords_with_first_salt(&passwords)
+                                .map(|p| p.password().to_vec())
+                                .collect(),
+                        );
+                        client
+                            .send(PgWireBackendMessage::Authentication(
+                                Authentication::MD5Password(salt),
+                            ))
+                            .await?;
+                    }
+                    AuthMethod::Sasl => {
+                        *state = PolicyState::Sasl;
+                        self.sasl_handler
+                            .on_startup(client, PgWireFrontendMessage::Startup(startup))
+                            .await?;
+                    }
+                    AuthMethod::Reject(message) => {
+                        reject(client, "reject", "28000", &message).await?;
+                    }
+                }
+            }
+            (PolicyState::Cleartext, PgWireFrontendMessage::PasswordMessageFamily(pwd)) => {
+                let pwd = pwd.into_password()?;
+                let passwords =
+                    super::get_passwords(client, self.auth_source.as_ref(), "password").await?;
+                if passwords
+                    .iter()
+                    .any(|pass| pass.password() == pwd.password.as_bytes())
+                {
+                    super::finish_authentication(client, self.parameter_provider.as_ref()).await?;
+                    audit(client, "password", AuthOutcome::Success);
+                } else {
+                    reject(
+                        client,
+                        "password",
+                        "28P01",
+                        "Password authentication failed",
+                    )
+                    .await?;
+                }
+            }
+            (
+                PolicyState::Md5(cached_passwords),
+                PgWireFrontendMessage::PasswordMessageFamily(pwd),
+            ) => {
+                let pwd = pwd.into_password()?;
+                if cached_passwords
+                    .iter()
+                    .any(|pass| pwd.password.as_bytes() == pass.as_slice())
+                {
+                    super::finish_authentication(client, self.parameter_provider.as_ref()).await?;
+                    audit(client, "md5", AuthOutcome::Success);
+                } else {
+                    reject(client, "md5", "28P01", "Password authentication failed").await?;
+                }
+            }
+            (PolicyState::Sasl, message) => {
+                self.sasl_handler.on_startup(client, message).await?;
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+/// Report failed login to client and close the connection
+pub(super) async fn reject<C>(
+    client: &mut C,
+    mechanism: &str,
+    code: &str,
+    message: &str,
+) -> PgWireResult<()>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    audit(client, mechanism, AuthOutcome::Failure(message.to_owned()));
+    let error_info = super::auth_failure(client, code, message).await;
+    client
+        .feed(PgWireBackendMessage::ErrorResponse(ErrorResponse::from(
+            error_info,
+        )))
+        .await?;
+    client.close().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::api::auth::md5pass::hash_md5_password;
+    use crate::api::auth::{AuthFailurePolicy, DefaultServerParameterProvider, Password};
+    use crate::api::config::ServerConfig;
+    use crate::error::ErrorInfo;
+    use crate::testkit::fixture::{
+        error_code, error_field, send_password, start_login, FnQueryHandler, TestHandlers,
+    };
+    use crate::testkit::MockClient;
+
+    /// Password of every user is `pencil`, md5 hashed for user `md5`.
+    /// User `legacy` is also accepted with `eraser` during rotation, user
+    /// `ghost` has no password and user `stranger` doesn't exist.
+    struct TestAuthSource;
+
+    #[async_trait]
+    impl AuthSource for TestAuthSource {
+        async fn get_password(&self, login: &LoginInfo) -> PgWireResult<Password> {
+            let user = login.user().unwrap_or_default();
+            let salt = vec![1, 2, 3, 4];
+            let password = if user == "md5" {
+                hash_md5_password(user, "pencil", &salt)
+            } else {
+                "pencil".to_owned()
+            };
+            Ok(Password::new(Some(salt), password.into_bytes()))
+        }
+
+        async fn get_passwords(&self, login: &LoginInfo) -> PgWireResult<Vec<Password>> {
+            match login.user() {
+                Some("ghost") => return Ok(vec![]),
+                Some("stranger") => {
+                    return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                        "FATAL".to_owned(),
+                        "28000".to_owned(),
+                        "role \"stranger\" does not exist".to_owned(),
+                    ))))
+                }
+                _ => {}
+            }
+            let mut passwords = vec![self.get_password(login).await?];
+            if login.user() == Some("legacy") {
+                passwords.push(Password::new(None, b"eraser".to_vec()));
+            }
+            Ok(passwords)
+        }
+    }
+
+    struct UserPolicy;
+
+    #[async_trait]
+    impl AuthPolicy for UserPolicy {
+        async fn auth_method(&self, login: &LoginInfo) -> PgWireResult<AuthMethod> {
+            Ok(match login.user() {
+                Some("trusted") => AuthMethod::Trust,
+                Some("legacy") | Some("stranger") => AuthMethod::Cleartext,
+                Some("md5") | Some("ghost") => AuthMethod::Md5,
+                _ => AuthMethod::Reject("user is not allowed".to_owned()),
+            })
+        }
+    }
+
+    type PolicyHandler =
+        PolicyAuthStartupHandler<TestAuthSource, DefaultServerParameterProvider, UserPolicy>;
+
+    fn policy_handlers() -> TestHandlers<PolicyHandler, FnQueryHandler> {
+        TestHandlers::echo().with_startup(PolicyAuthStartupHandler::new(
+            Arc::new(TestAuthSource),
+            Arc::new(DefaultServerParameterProvider::default()),
+            Arc::new(UserPolicy),
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_auth_policy() {
+        let mut client = MockClient::start(policy_handlers());
+        assert_eq!(
+            PgWireBackendMessage::Authentication(Authentication::Ok),
+            start_login(&mut client, "trusted").await
+        );
+
+        let mut client = MockClient::start(policy_handlers());
+        assert_eq!(
+            PgWireBackendMessage::Authentication(Authentication::CleartextPassword),
+            start_login(&mut client, "legacy").await
+        );
+        let messages = send_password(&mut client, "pencil".to_owned()).await;
+        assert_eq!(
+            PgWireBackendMessage::Authentication(Authentication::Ok),
+            messages[0]
+        );
+
+        let mut client = MockClient::start(policy_handlers());
+        start_login(&mut client, "legacy").await;
+        let messages = send_password(&mut client, "eraser".to_owned()).await;
+        assert_eq!(
+            PgWireBackendMessage::Authentication(Authentication::Ok),
+            messages[0]
+        );
+
+        let mut client = MockClient::start(policy_handlers());
+        assert_eq!(
+            PgWireBackendMessage::Authentication(Authentication::MD5Password(vec![1, 2, 3, 4])),
+            start_login(&mut client, "md5").await
+        );
+        let messages = send_password(&mut client, "wrong".to_owned()).await;
+        assert_eq!(Some("28P01".to_owned()), error_code(&messages[0]));
+
+        let mut client = MockClient::start(policy_handlers());
+        start_login(&mut client, "md5").await;
+        let password = hash_md5_password("md5", "pencil", &[1, 2, 3, 4]);
+        let messages = send_password(&mut client, password).await;
+        assert_eq!(
+            PgWireBackendMessage::Authentication(Authentication::Ok),
+            messages[0]
+        );
+
+        // no password is a failed login, like a wrong one
+        let mut client = MockClient::start(policy_handlers());
+        let message = start_login(&mut client, "ghost").await;
+        assert_eq!(Some("28P01".to_owned()), error_code(&message));
+        assert!(client.receive().await.unwrap().is_none());
+
+        let mut client = MockClient::start(policy_handlers());
+        let message = start_login(&mut client, "nobody").await;
+        assert_eq!(Some("28000".to_owned()), error_code(&message));
+        assert!(client.receive().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_auth_failure_policy() {
+        let config = Arc::new(ServerConfig {
+            auth_failure_policy: AuthFailurePolicy::new(Some(Duration::from_millis(50)), true),
+            ..Default::default()
+        });
+
+        // rejected user gets the same error as wrong password
+        let mut client = MockClient::start_with_config(policy_handlers(), config.clone());
+        let started = Instant::now();
+        let message = start_login(&mut client, "nobody").await;
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(Some("28P01".to_owned()), error_code(&message));
+        assert_eq!(
+            Some("password authentication failed for user \"nobody\"".to_owned()),
+            error_field(&message, b'M')
+        );
+
+        // delayed from the startup message
+        let mut client = MockClient::start_with_config(policy_handlers(), config.clone());
+        let started = Instant::now();
+        start_login(&mut client, "legacy").await;
+        let messages = send_password(&mut client, "wrong".to_owned()).await;
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(Some("28P01".to_owned()), error_code(&messages[0]));
+
+        // errors of auth source are failed logins too
+        let mut client = MockClient::start_with_config(policy_handlers(), config);
+        let started = Instant::now();
+        start_login(&mut client, "stranger").await;
+        let messages = send_password(&mut client, "pencil".to_owned()).await;
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(Some("28P01".to_owned()), error_code(&messages[0]));
+        assert_eq!(
+            Some("password authentication failed for user \"stranger\"".to_owned()),
+            error_field(&messages[0], b'M')
+        );
+    }
+}
@@ -10,6 +10,8 @@ use base64::Engine;
 use bytes::Bytes;
 use futures::{Sink, SinkExt};
 use tokio::sync::Mutex;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::ServerConfig;
 use x509_certificate::certificate::CapturedX509Certificate;
 use x509_certificate::SignatureAlgorithm;
 
@@ -18,19 +20,125 @@ use aws_lc_rs::{digest, hmac, pbkdf2};
 #[cfg(feature = "_ring")]
 use ring::{digest, hmac, pbkdf2};
 
+use crate::api::auth::policy::{AuthMethod, AuthPolicy};
 use crate::api::auth::{AuthSource, LoginInfo, Password};
 use crate::api::{ClientInfo, PgWireConnectionState};
 use crate::error::{PgWireError, PgWireResult};
-use crate::messages::startup::Authentication;
+use crate::messages::startup::{Authentication, PasswordMessageFamily};
 use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
+use crate::tokio::TlsAcceptor;
 
+use super::audit::{audit, AuthOutcome};
 use super::{ServerParameterProvider, StartupHandler};
 
 #[derive(Debug)]
 pub enum ScramState {
     Initial,
-    // cached password, channel_binding and partial auth-message
-    ServerFirstSent(Password, String, String),
+    // cached verifiers, channel_binding and partial auth-message
+    ServerFirstSent(Vec<ScramVerifier>, String, String),
+}
+
+/// Prefix of SCRAM verifiers stored by postgres
+const SCRAM_VERIFIER_PREFIX: &str = "SCRAM-SHA-256$";
+
+/// SCRAM-SHA-256 verifier of a password, in the format stored by postgres in
+/// `pg_authid.rolpassword`:
+///
+/// ```text
+/// SCRAM-SHA-256$<iterations>:<salt>$<StoredKey>:<ServerKey>
+/// ```
+///
+/// `AuthSource` can return a verifier as the password, without salt, for
+/// example `Password::new(None, rolpassword.into_bytes())`. Authentication
+/// is then done with `StoredKey` and `ServerKey` directly, and iterations of
+/// the verifier is sent to client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScramVerifier {
+    pub iterations: usize,
+    pub salt: Vec<u8>,
+    pub stored_key: Vec<u8>,
+    pub server_key: Vec<u8>,
+}
+
+impl ScramVerifier {
+    /// Parse a verifier in postgres format
+    pub fn parse(verifier: &str) -> PgWireResult<ScramVerifier> {
+        let invalid = || PgWireError::InvalidScramMessage(verifier.to_owned());
+
+        let (iterations_and_salt, keys) = verifier
+            .strip_prefix(SCRAM_VERIFIER_PREFIX)
+            .and_then(|s| s.split_once('$'))
+            .ok_or_else(invalid)?;
+        let (iterations, salt) = iterations_and_salt.split_once(':').ok_or_else(invalid)?;
+        let (stored_key, server_key) = keys.split_once(':').ok_or_else(invalid)?;
+
+        Ok(ScramVerifier {
+            iterations: iterations.parse().map_err(|_| invalid())?,
+            salt: STANDARD.decode(salt).map_err(|_| invalid())?,
+            stored_key: STANDARD.decode(stored_key).map_err(|_| invalid())?,
+            server_key: STANDARD.decode(server_key).map_err(|_| invalid())?,
+        })
+    }
+
+    /// Create verifier from salted password, as returned by
+    /// `gen_salted_password`
+    pub fn from_salted_password(
+        salted_password: &[u8],
+        salt: &[u8],
+        iterations: usize,
+    ) -> ScramVerifier {
+        let client_key = hmac(salted_password, b"Client Key");
+        ScramVerifier {
+            iterations,
+            salt: salt.to_vec(),
+            stored_key: h(&client_key),
+            server_key: hmac(salted_password, b"Server Key"),
+        }
+    }
+
+    /// Create verifier from cleartext password
+    pub fn from_password(password: &str, salt: &[u8], iterations: usize) -> ScramVerifier {
+        let salted_password = gen_salted_password(password, salt, iterations);
+        Self::from_salted_password(&salted_password, salt, iterations)
+    }
+
+    /// Check client proof of the auth message
+    fn verify_proof(&self, proof: &[u8], auth_msg: &[u8]) -> bool {
+        let client_signature = hmac(&self.stored_key, auth_msg);
+        if proof.len() != client_signature.len() {
+            return false;
+        }
+        let client_key = xor(proof, &client_signature);
+        h(&client_key) == self.stored_key
+    }
+}
+
+impl std::fmt::Display for ScramVerifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}{}:{}${}:{}",
+            SCRAM_VERIFIER_PREFIX,
+            self.iterations,
+            STANDARD.encode(&self.salt),
+            STANDARD.encode(&self.stored_key),
+            STANDARD.encode(&self.server_key)
+        )
+    }
+}
+
+/// Whether SCRAM authentication requires channel binding
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChannelBindingPolicy {
+    /// Offer `SCRAM-SHA-256-PLUS` on TLS connections when a certificate is
+    /// configured, and accept clients without channel binding
+    #[default]
+    Prefer,
+    /// Only offer and accept `SCRAM-SHA-256-PLUS`. Connections without TLS,
+    /// or a handler without certificate, are rejected with `28000`, so a
+    /// man-in-the-middle can't downgrade authentication.
+    Require,
 }
 
 #[derive(Debug)]
@@ -43,6 +151,40 @@ pub struct SASLScramAuthStartupHandler<A, P> {
     server_cert_sig: Option<Arc<String>>,
     /// iterations
     iterations: usize,
+    /// whether channel binding is required
+    channel_binding_policy: ChannelBindingPolicy,
+    /// md5 or cleartext authentication for clients without supported mechanism
+    fallback: Option<SaslFallback>,
+    /// mechanism and cached passwords of fallback authentication
+    fallback_passwords: Mutex<Option<(&'static str, Vec<Password>)>>,
+}
+
+/// Fall back to md5 or cleartext password authentication when client doesn't
+/// choose a SASL mechanism supported by the server, for older drivers.
+///
+/// `AuthPolicy` decides which method to use for the login, only
+/// `AuthMethod::Md5` and `AuthMethod::Cleartext` are allowed, the connection
+/// is rejected for other methods. Passwords are from the `AuthSource` of the
+/// fallback, because they are stored differently from SCRAM salted passwords.
+#[derive(Clone)]
+pub struct SaslFallback {
+    policy: Arc<dyn AuthPolicy>,
+    auth_source: Arc<dyn AuthSource>,
+}
+
+impl SaslFallback {
+    pub fn new(policy: Arc<dyn AuthPolicy>, auth_source: Arc<dyn AuthSource>) -> SaslFallback {
+        SaslFallback {
+            policy,
+            auth_source,
+        }
+    }
+}
+
+impl Debug for SaslFallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SaslFallback").finish_non_exhaustive()
+    }
 }
 
 /// Compute salted password from raw password as defined in
@@ -62,11 +204,191 @@ pub fn gen_salted_password(password: &str, salt: &[u8], iters: usize) -> Vec<u8>
     hi(pass_bytes, salt, iters)
 }
 
+/// Default iterations of SCRAM verifiers, same as `scram_iterations` of
+/// postgres
+pub const DEFAULT_SCRAM_ITERATIONS: usize = 4096;
+
+/// Generate the SCRAM-SHA-256 verifier of password with a random 16 bytes
+/// salt, in the format stored by postgres in `pg_authid.rolpassword`.
+///
+/// The verifier can be returned by `AuthSource` as is, see `ScramVerifier`.
+pub fn gen_scram_verifier(password: &str, iterations: usize) -> String {
+    let salt = rand::random::<[u8; 16]>();
+    ScramVerifier::from_password(password, &salt, iterations).to_string()
+}
+
 pub fn random_nonce() -> String {
     STANDARD.encode(rand::random::<[u8; 18]>())
 }
 
 impl<A, P> SASLScramAuthStartupHandler<A, P> {
+    /// Channel binding is only possible on TLS connections with the server
+    /// certificate configured
+    fn supports_channel_binding(&self, is_secure: bool) -> bool {
+        is_secure && self.server_cert_sig.is_some()
+    }
+
+    fn supported_mechanisms(&self, is_secure: bool) -> Vec<String> {
+        let plus = self.supports_channel_binding(is_secure);
+        match self.channel_binding_policy {
+            ChannelBindingPolicy::Require => vec!["SCRAM-SHA-256-PLUS".to_owned()],
+            ChannelBindingPolicy::Prefer if plus => {
+                vec!["SCRAM-SHA-256".to_owned(), "SCRAM-SHA-256-PLUS".to_owned()]
+            }
+            ChannelBindingPolicy::Prefer => vec!["SCRAM-SHA-256".to_owned()],
+        }
+    }
+
+    /// Check the mechanism and the gs2 channel binding flag of client-first
+    /// message, returns code and message of the error if they are not
+    /// allowed.
+    fn check_channel_binding(
+        &self,
+        is_secure: bool,
+        mechanism: &str,
+        cbind_flag: &str,
+    ) -> Result<(), (&'static str, &'static str)> {
+        let binding = cbind_flag.starts_with("p=");
+        if (mechanism == "SCRAM-SHA-256-PLUS") != binding {
+            return Err((
+                "08P01",
+                "SCRAM channel binding flag doesn't match the selected mechanism",
+            ));
+        }
+        // RFC 5802: `y` means client supports channel binding but thinks the
+        // server doesn't, it's a downgrade if we offered it
+        if cbind_flag == "y" && self.supports_channel_binding(is_secure) {
+            return Err(("08P01", "SCRAM channel binding negotiation error"));
+        }
+        if self.channel_binding_policy == ChannelBindingPolicy::Require && !binding {
+            return Err(("28000", "channel binding is required"));
+        }
+        Ok(())
+    }
+}
+
+impl<A, P: ServerParameterProvider> SASLScramAuthStartupHandler<A, P> {
+    /// Start fallback authentication when client chose a mechanism not
+    /// supported, or check password of the fallback. Returns the message if
+    /// it's still for SCRAM.
+    async fn try_fallback<C>(
+        &self,
+        client: &mut C,
+        msg: PasswordMessageFamily,
+    ) -> PgWireResult<Option<PasswordMessageFamily>>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let Some(ref fallback) = self.fallback else {
+            return Ok(Some(msg));
+        };
+        if !matches!(*self.state.lock().await, ScramState::Initial) {
+            return Ok(Some(msg));
+        }
+
+        let mut fallback_passwords = self.fallback_passwords.lock().await;
+        if let Some((mechanism, ref passwords)) = *fallback_passwords {
+            let pwd = msg.into_password()?;
+            if passwords
+                .iter()
+                .any(|pass| pwd.password.as_bytes() == pass.password())
+            {
+                super::finish_authentication(client, self.parameter_provider.as_ref()).await?;
+                audit(client, mechanism, AuthOutcome::Success);
+                return Ok(None);
+            } else {
+                audit(
+                    client,
+                    mechanism,
+                    AuthOutcome::Failure("password mismatch".to_owned()),
+                );
+                return Err(auth_error(client, "28P01", "Password authentication failed").await);
+            }
+        }
+
+        let resp = msg.into_sasl_initial_response()?;
+        if self
+            .supported_mechanisms(client.is_secure())
+            .contains(&resp.auth_method)
+        {
+            return Ok(Some(PasswordMessageFamily::SASLInitialResponse(resp)));
+        }
+
+        let login_info = LoginInfo::from_client_info(client);
+        let method = fallback.policy.auth_method(&login_info).await?;
+        if !matches!(method, AuthMethod::Md5 | AuthMethod::Cleartext) {
+            let message = format!("SASL mechanism {} is not supported", resp.auth_method);
+            audit(
+                client,
+                &resp.auth_method,
+                AuthOutcome::Failure(message.clone()),
+            );
+            return Err(auth_error(client, "28000", &message).await);
+        }
+
+        let mechanism = if method == AuthMethod::Md5 {
+            "md5"
+        } else {
+            "password"
+        };
+        let passwords =
+            super::get_passwords(client, fallback.auth_source.as_ref(), mechanism).await?;
+        let request = if method == AuthMethod::Md5 {
+            let Some(first) = passwords.first() else {
+                audit(
+                    client,
+                    "md5",
+                    AuthOutcome::Failure("password not found".to_owned()),
+                );
+                return Err(auth_error(client, "28P01", "Password authentication failed").await);
+            };
+            let salt = first
+                .salt()
+                .expect("Salt is required for Md5Password authentication");
+            let request = Authentication::MD5Password(salt.to_vec());
+            *fallback_passwords = Some((
+                "md5",
+                super::passwords_with_first_salt(&passwords)
+                    .cloned()
+                    .collect(),
+            ));
+            request
+        } else {
+            *fallback_passwords = Some(("password", passwords));
+            Authentication::CleartextPassword
+        };
+
+        client
+            .send(PgWireBackendMessage::Authentication(request))
+            .await?;
+        Ok(None)
+    }
+}
+
+impl<A, P> SASLScramAuthStartupHandler<A, P> {
+    /// Get verifier of a password from `AuthSource`, it's either a postgres
+    /// verifier or a salted password. Iterations of a salted password are
+    /// `Password::iterations` if set, the handler-wide setting otherwise.
+    fn verifier(&self, pass: &Password) -> PgWireResult<ScramVerifier> {
+        if pass
+            .password()
+            .starts_with(SCRAM_VERIFIER_PREFIX.as_bytes())
+        {
+            let verifier = std::str::from_utf8(pass.password())
+                .map_err(|e| PgWireError::InvalidScramMessage(e.to_string()))?;
+            ScramVerifier::parse(verifier)
+        } else {
+            let salt = pass.salt().expect("Salt required for SCRAM auth source");
+            Ok(ScramVerifier::from_salted_password(
+                pass.password(),
+                salt,
+                pass.iterations().unwrap_or(self.iterations),
+            ))
+        }
+    }
+
     fn compute_channel_binding(&self, client_channel_binding: &str) -> String {
         if client_channel_binding.starts_with("p=tls-server-end-point") {
             format!(
@@ -101,30 +423,44 @@ impl<A: AuthSource, P: ServerParameterProvider> StartupHandler
             PgWireFrontendMessage::Startup(ref startup) => {
                 super::save_startup_parameters_to_metadata(client, startup);
                 client.set_state(PgWireConnectionState::AuthenticationInProgress);
-                let supported_mechanisms = if self.server_cert_sig.is_some() {
-                    vec!["SCRAM-SHA-256".to_owned(), "SCRAM-SHA-256-PLUS".to_owned()]
-                } else {
-                    vec!["SCRAM-SHA-256".to_owned()]
-                };
+                if self.channel_binding_policy == ChannelBindingPolicy::Require
+                    && !self.supports_channel_binding(client.is_secure())
+                {
+                    return super::policy::reject(
+                        client,
+                        "SCRAM-SHA-256-PLUS",
+                        "28000",
+                        "channel binding is required, but the connection is not secured by TLS",
+                    )
+                    .await;
+                }
                 client
                     .send(PgWireBackendMessage::Authentication(Authentication::SASL(
-                        supported_mechanisms,
+                        self.supported_mechanisms(client.is_secure()),
                     )))
                     .await?;
             }
             PgWireFrontendMessage::PasswordMessageFamily(msg) => {
-                let salt_and_salted_pass = {
+                let Some(msg) = self.try_fallback(client, msg).await? else {
+                    return Ok(());
+                };
+
+                let verifiers = {
                     let state = self.state.lock().await;
                     match *state {
                         ScramState::Initial => {
-                            let login_info = LoginInfo::from_client_info(client);
-                            self.auth_db.get_password(&login_info).await?
+                            super::get_passwords(client, self.auth_db.as_ref(), "SCRAM-SHA-256")
+                                .await?
+                                .iter()
+                                .map(|pass| self.verifier(pass))
+                                .collect::<PgWireResult<Vec<_>>>()?
                         }
-                        ScramState::ServerFirstSent(ref pass, _, _) => pass.clone(),
+                        ScramState::ServerFirstSent(ref verifiers, _, _) => verifiers.clone(),
                     }
                 };
 
                 let mut success = false;
+                let mut outcome = None;
                 let resp = {
                     // this should never block
                     let mut state = self.state.lock().await;
@@ -144,26 +480,54 @@ impl<A: AuthSource, P: ServerParameterProvider> StartupHandler
                                 .and_then(|data| {
                                     ClientFirst::try_new(String::from_utf8_lossy(data).as_ref())
                                 })?;
+                            if let Err((code, message)) = self.check_channel_binding(
+                                client.is_secure(),
+                                &resp.auth_method,
+                                &client_first.cbind_flag,
+                            ) {
+                                audit(
+                                    client,
+                                    &resp.auth_method,
+                                    AuthOutcome::Failure(message.to_owned()),
+                                );
+                                return Err(auth_error(client, code, message).await);
+                            }
                             // dbg!(&client_first);
 
                             // create server_first and send
                             let mut new_nonce = client_first.nonce.clone();
                             new_nonce.push_str(random_nonce().as_str());
 
+                            let Some(first) = verifiers.first() else {
+                                audit(
+                                    client,
+                                    &resp.auth_method,
+                                    AuthOutcome::Failure("password not found".to_owned()),
+                                );
+                                return Err(auth_error(
+                                    client,
+                                    "28P01",
+                                    "Password authentication failed",
+                                )
+                                .await);
+                            };
                             let server_first = ServerFirst::new(
                                 new_nonce,
-                                STANDARD.encode(
-                                    salt_and_salted_pass
-                                        .salt
-                                        .as_ref()
-                                        .expect("Salt required for SCRAM auth source"),
-                                ),
-                                self.iterations,
+                                STANDARD.encode(&first.salt),
+                                first.iterations,
                             );
                             let server_first_message = server_first.message();
 
+                            // only one salt and iteration count can be sent
+                            let verifiers = verifiers
+                                .iter()
+                                .filter(|v| {
+                                    v.salt == first.salt && v.iterations == first.iterations
+                                })
+                                .cloned()
+                                .collect();
                             *state = ScramState::ServerFirstSent(
-                                salt_and_salted_pass,
+                                verifiers,
                                 client_first.channel_binding(),
                                 format!("{},{}", client_first.bare(), &server_first_message),
                             );
@@ -180,30 +544,41 @@ impl<A: AuthSource, P: ServerParameterProvider> StartupHandler
                                 ClientFinal::try_new(String::from_utf8_lossy(&resp.data).as_ref())?;
                             // dbg!(&client_final);
 
+                            let mechanism = if channel_binding_prefix.starts_with("p=") {
+                                "SCRAM-SHA-256-PLUS"
+                            } else {
+                                "SCRAM-SHA-256"
+                            };
                             let channel_binding =
                                 self.compute_channel_binding(channel_binding_prefix);
                             client_final.validate_channel_binding(&channel_binding)?;
 
-                            let salted_password = salt_and_salted_pass.password;
-                            let client_key = hmac(salted_password.as_ref(), b"Client Key");
-                            let stored_key = h(client_key.as_ref());
                             let auth_msg =
                                 format!("{},{}", partial_auth_msg, client_final.without_proof());
-                            let client_signature = hmac(stored_key.as_ref(), auth_msg.as_bytes());
+                            let proof = STANDARD.decode(&client_final.proof).map_err(|_| {
+                                PgWireError::InvalidScramMessage(client_final.proof.clone())
+                            })?;
+                            // any of the passwords is accepted
+                            let matched = verifiers
+                                .iter()
+                                .find(|v| v.verify_proof(&proof, auth_msg.as_bytes()));
 
-                            let computed_client_proof = STANDARD.encode(
-                                xor(client_key.as_ref(), client_signature.as_ref()).as_slice(),
-                            );
-
-                            if computed_client_proof == client_final.proof {
-                                let server_key = hmac(salted_password.as_ref(), b"Server Key");
+                            if let Some(verifier) = matched {
                                 let server_signature =
-                                    hmac(server_key.as_ref(), auth_msg.as_bytes());
+                                    hmac(verifier.server_key.as_ref(), auth_msg.as_bytes());
                                 let server_final =
                                     ServerFinalSuccess::new(STANDARD.encode(server_signature));
                                 success = true;
+                                outcome = Some((mechanism, AuthOutcome::Success));
                                 Authentication::SASLFinal(Bytes::from(server_final.message()))
                             } else {
+                                outcome = Some((
+                                    mechanism,
+                                    AuthOutcome::Failure("invalid proof".to_owned()),
+                                ));
+                                if let Some(deadline) = super::failure_deadline(client) {
+                                    tokio::time::sleep_until(deadline).await;
+                                }
                                 let server_final =
                                     ServerFinalError::new("invalid-proof".to_owned());
                                 Authentication::SASLFinal(Bytes::from(server_final.message()))
@@ -219,6 +594,9 @@ impl<A: AuthSource, P: ServerParameterProvider> StartupHandler
                 if success {
                     super::finish_authentication(client, self.parameter_provider.as_ref()).await?;
                 }
+                if let Some((mechanism, outcome)) = outcome {
+                    audit(client, mechanism, outcome);
+                }
             }
             _ => {}
         }
@@ -234,10 +612,19 @@ impl<A, P> SASLScramAuthStartupHandler<A, P> {
             parameter_provider,
             state: Mutex::new(ScramState::Initial),
             server_cert_sig: None,
-            iterations: 4096,
+            iterations: DEFAULT_SCRAM_ITERATIONS,
+            channel_binding_policy: ChannelBindingPolicy::default(),
+            fallback: None,
+            fallback_passwords: Mutex::new(None),
         }
     }
 
+    /// Fall back to md5 or cleartext authentication when client doesn't
+    /// support any SASL mechanism of this handler.
+    pub fn set_fallback(&mut self, fallback: Option<SaslFallback>) {
+        self.fallback = fallback;
+    }
+
     /// enable channel binding (SCRAM-SHA-256-PLUS) by configuring server
     /// certificate.
     ///
@@ -249,6 +636,20 @@ impl<A, P> SASLScramAuthStartupHandler<A, P> {
         Ok(())
     }
 
+    /// enable channel binding with the end-entity certificate in DER format,
+    /// the first certificate of the chain given to rustls.
+    pub fn configure_certificate_der(&mut self, cert: &CertificateDer<'_>) -> PgWireResult<()> {
+        let sig = compute_cert_signature_der(cert.as_ref())?;
+        self.server_cert_sig = Some(Arc::new(STANDARD.encode(sig)));
+        Ok(())
+    }
+
+    /// enable channel binding with the certificate of `ScramTlsAcceptor`, so
+    /// it's always the certificate presented to client.
+    pub fn configure_tls_acceptor(&mut self, acceptor: &ScramTlsAcceptor) {
+        self.server_cert_sig = Some(acceptor.cert_signature.clone());
+    }
+
     /// Set password hash iteration count, according to SCRAM RFC, a minimal of
     /// 4096 is required.
     ///
@@ -256,10 +657,25 @@ impl<A, P> SASLScramAuthStartupHandler<A, P> {
     /// client to hash with this iteration count. You have to implement password
     /// hashing in your `AuthSource` implementation, either after fetching
     /// cleartext password, or before storing hashed password. And this number
-    /// should be identical to your `AuthSource` implementation.
+    /// should be identical to your `AuthSource` implementation, unless the
+    /// count of each user is returned with `Password::with_iterations` or in a
+    /// postgres verifier.
     pub fn set_iterations(&mut self, iterations: usize) {
         self.iterations = iterations;
     }
+
+    /// Require channel binding, see `ChannelBindingPolicy::Require`. A
+    /// certificate must be configured for TLS clients to authenticate.
+    pub fn set_channel_binding_policy(&mut self, policy: ChannelBindingPolicy) {
+        self.channel_binding_policy = policy;
+    }
+}
+
+async fn auth_error<C>(client: &mut C, code: &str, message: &str) -> PgWireError
+where
+    C: ClientInfo + Send,
+{
+    PgWireError::UserError(Box::new(super::auth_failure(client, code, message).await))
 }
 
 #[allow(dead_code)]
@@ -427,6 +843,55 @@ fn xor(lhs: &[u8], rhs: &[u8]) -> Vec<u8> {
         .collect()
 }
 
+/// `TlsAcceptor` and certificate signature for SCRAM channel binding, built
+/// from the same certificate chain so they always match.
+///
+/// Use `acceptor` for `process_socket`, and configure
+/// `SASLScramAuthStartupHandler` with `configure_tls_acceptor`.
+#[derive(Clone)]
+pub struct ScramTlsAcceptor {
+    acceptor: TlsAcceptor,
+    cert_signature: Arc<String>,
+}
+
+impl ScramTlsAcceptor {
+    /// Create TLS acceptor with certificate chain and private key, ALPN
+    /// `postgresql` is enabled for direct SSL connections.
+    pub fn new(
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> PgWireResult<ScramTlsAcceptor> {
+        let end_entity = certs
+            .first()
+            .ok_or_else(|| PgWireError::ApiError("certificate chain is empty".to_owned().into()))?;
+        let cert_signature = STANDARD.encode(compute_cert_signature_der(end_entity.as_ref())?);
+
+        let mut config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| PgWireError::ApiError(Box::new(e)))?;
+        config.alpn_protocols = vec![b"postgresql".to_vec()];
+
+        Ok(ScramTlsAcceptor {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            cert_signature: Arc::new(cert_signature),
+        })
+    }
+
+    /// Get the `TlsAcceptor`
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.clone()
+    }
+}
+
+impl Debug for ScramTlsAcceptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ScramTlsAcceptor")
+            .field("cert_signature", &self.cert_signature)
+            .finish_non_exhaustive()
+    }
+}
+
 /// Compute signature of server certificate for `tls-server-end-point` channel
 /// binding.
 ///
@@ -440,7 +905,16 @@ fn xor(lhs: &[u8], rhs: &[u8]) -> Vec<u8> {
 fn compute_cert_signature(cert: &[u8]) -> PgWireResult<Vec<u8>> {
     let certs = CapturedX509Certificate::from_pem_multiple(cert)
         .map_err(|e| PgWireError::ApiError(Box::new(e)))?;
-    let x509 = &certs[0];
+    cert_signature(&certs[0])
+}
+
+fn compute_cert_signature_der(cert: &[u8]) -> PgWireResult<Vec<u8>> {
+    let x509 = CapturedX509Certificate::from_der(cert.to_vec())
+        .map_err(|e| PgWireError::ApiError(Box::new(e)))?;
+    cert_signature(&x509)
+}
+
+fn cert_signature(x509: &CapturedX509Certificate) -> PgWireResult<Vec<u8>> {
     let raw = x509.constructed_data();
     match x509.signature_algorithm() {
         Some(SignatureAlgorithm::RsaSha1)
@@ -457,3 +931,95 @@ fn compute_cert_signature(cert: &[u8]) -> PgWireResult<Vec<u8>> {
         _ => Err(PgWireError::UnsupportedCertificateSignatureAlgorithm),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scram_verifier() {
+        let verifier_str = "SCRAM-SHA-256$4096:MDEyMzQ1Njc4OWFiY2RlZg==$nQpbZ77WudtqufPwikHXGRt6g2QJ4zns8bZLw273DRM=:jn2amWP1q1h+jgjy0YTO14S6/F02SV7taipOeB7ef20=";
+        let verifier = ScramVerifier::parse(verifier_str).unwrap();
+        assert_eq!(4096, verifier.iterations);
+        assert_eq!(b"0123456789abcdef", verifier.salt.as_slice());
+        assert_eq!(verifier_str, verifier.to_string());
+
+        assert_eq!(
+            verifier,
+            ScramVerifier::from_password("pencil", b"0123456789abcdef", 4096)
+        );
+
+        assert!(ScramVerifier::parse("md5abcdef").is_err());
+        assert!(ScramVerifier::parse("SCRAM-SHA-256$4096:salt").is_err());
+    }
+
+    #[test]
+    fn test_channel_binding_policy() {
+        let mut handler = SASLScramAuthStartupHandler::new(Arc::new(()), Arc::new(()));
+        assert_eq!(vec!["SCRAM-SHA-256"], handler.supported_mechanisms(true));
+        assert!(handler
+            .check_channel_binding(false, "SCRAM-SHA-256", "n")
+            .is_ok());
+        assert!(handler
+            .check_channel_binding(true, "SCRAM-SHA-256", "y")
+            .is_ok());
+        assert!(handler
+            .check_channel_binding(true, "SCRAM-SHA-256", "p=tls-server-end-point")
+            .is_err());
+
+        handler.server_cert_sig = Some(Arc::new("sig".to_owned()));
+        assert_eq!(
+            vec!["SCRAM-SHA-256", "SCRAM-SHA-256-PLUS"],
+            handler.supported_mechanisms(true)
+        );
+        assert_eq!(vec!["SCRAM-SHA-256"], handler.supported_mechanisms(false));
+        // downgrade
+        assert!(handler
+            .check_channel_binding(true, "SCRAM-SHA-256", "y")
+            .is_err());
+        assert!(handler
+            .check_channel_binding(false, "SCRAM-SHA-256", "y")
+            .is_ok());
+
+        handler.set_channel_binding_policy(ChannelBindingPolicy::Require);
+        assert_eq!(
+            vec!["SCRAM-SHA-256-PLUS"],
+            handler.supported_mechanisms(true)
+        );
+        assert_eq!(
+            Err(("28000", "channel binding is required")),
+            handler.check_channel_binding(true, "SCRAM-SHA-256", "n")
+        );
+        assert!(handler
+            .check_channel_binding(true, "SCRAM-SHA-256-PLUS", "p=tls-server-end-point")
+            .is_ok());
+    }
+
+    #[test]
+    fn test_password_iterations() {
+        let handler = SASLScramAuthStartupHandler::new(Arc::new(()), Arc::new(()));
+        let salt = b"0123456789abcdef".to_vec();
+        let salted_password = gen_salted_password("pencil", &salt, 8192);
+
+        let password = Password::new(Some(salt.clone()), salted_password.clone());
+        assert_eq!(4096, handler.verifier(&password).unwrap().iterations);
+
+        let password = Password::new(Some(salt.clone()), salted_password).with_iterations(8192);
+        assert_eq!(
+            ScramVerifier::from_password("pencil", &salt, 8192),
+            handler.verifier(&password).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_gen_scram_verifier() {
+        let verifier = gen_scram_verifier("pencil", DEFAULT_SCRAM_ITERATIONS);
+        assert!(verifier.starts_with("SCRAM-SHA-256$4096:"));
+        let parsed = ScramVerifier::parse(&verifier).unwrap();
+        assert_eq!(16, parsed.salt.len());
+        assert_eq!(
+            parsed,
+            ScramVerifier::from_password("pencil", &parsed.salt, DEFAULT_SCRAM_ITERATIONS)
+        );
+    }
+}
@@ -0,0 +1,213 @@
+//! Accept connections without password from trusted networks.
+
+use std::fmt::{self, Debug, Display};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::sink::Sink;
+
+use super::audit::{audit, AuthOutcome};
+use super::{ClientInfo, PgWireConnectionState, ServerParameterProvider, StartupHandler};
+use crate::api::METADATA_USER;
+use crate::error::{PgWireError, PgWireResult};
+use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
+
+/// An IPv4 or IPv6 network, like `10.0.0.0/8` or `fd00::/8`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Create network from address and prefix length, bits of the address
+    /// beyond the prefix are ignored.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> PgWireResult<Cidr> {
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix_len > max_len {
+            return Err(PgWireError::InvalidCidr(format!("{addr}/{prefix_len}")));
+        }
+        Ok(Cidr { addr, prefix_len })
+    }
+
+    /// Test if the address is in this network. IPv4-mapped IPv6 addresses
+    /// are matched as IPv4 addresses.
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => prefix_matches(
+                u32::from(net).into(),
+                u32::from(addr).into(),
+                32,
+                self.prefix_len,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                prefix_matches(net.into(), addr.into(), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: u128, addr: u128, bits: u8, prefix_len: u8) -> bool {
+    if prefix_len == 0 {
+        return true;
+    }
+    let shift = bits - prefix_len;
+    net >> shift == addr >> shift
+}
+
+/// Parse network in `address/prefix` notation, an address without prefix is
+/// a single host.
+impl FromStr for Cidr {
+    type Err = PgWireError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || PgWireError::InvalidCidr(s.to_owned());
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (
+                addr.parse::<IpAddr>().map_err(|_| invalid())?,
+                Some(prefix_len.parse::<u8>().map_err(|_| invalid())?),
+            ),
+            None => (s.parse::<IpAddr>().map_err(|_| invalid())?, None),
+        };
+        let prefix_len = prefix_len.unwrap_or(if addr.is_ipv4() { 32 } else { 128 });
+        Cidr::new(addr, prefix_len).map_err(|_| invalid())
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Startup handler that accepts connections without password exchange, but
+/// only from the allowed networks. Connections from other peers are rejected
+/// with `28000`.
+///
+/// Unix domain socket connections are served by `process_stream`, which has
+/// no peer address. Pass an unspecified address like `0.0.0.0:0` to it and
+/// enable `with_unix_socket` to trust them.
+///
+/// To require password from other networks instead of rejecting them, use
+/// `Cidr::contains` in an `AuthPolicy` of `PolicyAuthStartupHandler`.
+#[derive(Debug)]
+pub struct TrustStartupHandler<P> {
+    parameter_provider: Arc<P>,
+    allowed: Vec<Cidr>,
+    unix_socket: bool,
+}
+
+impl<P> TrustStartupHandler<P> {
+    pub fn new(parameter_provider: Arc<P>, allowed: Vec<Cidr>) -> Self {
+        TrustStartupHandler {
+            parameter_provider,
+            allowed,
+            unix_socket: false,
+        }
+    }
+
+    /// Trust peers with unspecified address, that is connections from unix
+    /// domain socket.
+    pub fn with_unix_socket(mut self, unix_socket: bool) -> Self {
+        self.unix_socket = unix_socket;
+        self
+    }
+
+    /// Test if the peer is allowed to connect without password
+    pub fn is_trusted(&self, addr: &SocketAddr) -> bool {
+        let ip = addr.ip();
+        if ip.is_unspecified() {
+            return self.unix_socket;
+        }
+        self.allowed.iter().any(|cidr| cidr.contains(&ip))
+    }
+}
+
+#[async_trait]
+impl<P: ServerParameterProvider> StartupHandler for TrustStartupHandler<P> {
+    async fn on_startup<C>(
+        &self,
+        client: &mut C,
+        message: PgWireFrontendMessage,
+    ) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        if let PgWireFrontendMessage::Startup(ref startup) = message {
+            super::save_startup_parameters_to_metadata(client, startup);
+            client.set_state(PgWireConnectionState::AuthenticationInProgress);
+
+            if self.is_trusted(&client.socket_addr()) {
+                super::finish_authentication(client, self.parameter_provider.as_ref()).await?;
+                audit(client, "trust", AuthOutcome::Success);
+            } else {
+                let message = format!(
+                    "no trust entry for host \"{}\", user \"{}\"",
+                    client.socket_addr().ip(),
+                    client
+                        .metadata()
+                        .get(METADATA_USER)
+                        .map(String::as_str)
+                        .unwrap_or_default()
+                );
+                super::policy::reject(client, "trust", "28000", &message).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::auth::DefaultServerParameterProvider;
+    use crate::messages::startup::Authentication;
+    use crate::testkit::fixture::{error_code, start_login, TestHandlers};
+    use crate::testkit::MockClient;
+
+    #[test]
+    fn test_cidr() {
+        let net = "10.1.0.0/16".parse::<Cidr>().unwrap();
+        assert!(net.contains(&"10.1.2.3".parse().unwrap()));
+        assert!(net.contains(&"::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!net.contains(&"10.2.0.1".parse().unwrap()));
+        assert_eq!("10.1.0.0/16", net.to_string());
+
+        let host = "::1".parse::<Cidr>().unwrap();
+        assert!(host.contains(&"::1".parse().unwrap()));
+        assert!(!host.contains(&"127.0.0.1".parse().unwrap()));
+
+        let all = "0.0.0.0/0".parse::<Cidr>().unwrap();
+        assert!(all.contains(&"192.168.1.1".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("localhost".parse::<Cidr>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_trust_startup_handler() {
+        let handlers = |allowed: &str| {
+            TestHandlers::echo().with_startup(TrustStartupHandler::new(
+                Arc::new(DefaultServerParameterProvider::default()),
+                vec![allowed.parse().unwrap()],
+            ))
+        };
+
+        let mut client = MockClient::start(handlers("127.0.0.0/8"));
+        assert_eq!(
+            PgWireBackendMessage::Authentication(Authentication::Ok),
+            start_login(&mut client, "tom").await
+        );
+        client.receive_until_ready().await.unwrap();
+
+        let mut client = MockClient::start(handlers("10.0.0.0/8"));
+        let message = start_login(&mut client, "tom").await;
+        assert_eq!(Some("28000".to_owned()), error_code(&message));
+    }
+}
@@ -0,0 +1,335 @@
+use std::collections::HashMap;
+use std::hint::black_box;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use async_trait::async_trait;
+use tokio_util::sync::CancellationToken;
+
+use super::ClientInfo;
+use crate::error::{ErrorInfo, PgWireError};
+use crate::messages::startup::CancelRequest;
+
+/// handler for cancel requests
+///
+/// Cancel request is sent by client on a new connection, with `pid` and
+/// `secret_key` from `BackendKeyData` of the connection running the query to
+/// cancel. The new connection is closed after the request is handled.
+#[async_trait]
+pub trait CancelHandler: Send + Sync {
+    async fn on_cancel_request(&self, cancel_request: CancelRequest);
+}
+
+/// A noop implementation for `CancelHandler`, cancel requests are ignored.
+pub struct NoopCancelHandler;
+
+#[async_trait]
+impl CancelHandler for NoopCancelHandler {
+    async fn on_cancel_request(&self, _cancel_request: CancelRequest) {}
+}
+
+/// Compare two byte strings in constant time.
+///
+/// The time taken depends only on the length of inputs, not on their
+/// content, so it's safe for comparing secrets like cancel keys.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    let diff = a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y));
+    black_box(diff) == 0
+}
+
+/// Compare two `secret_key` of `BackendKeyData` in constant time.
+pub fn secret_key_eq(a: i32, b: i32) -> bool {
+    constant_time_eq(&a.to_be_bytes(), &b.to_be_bytes())
+}
+
+/// Registry of cancellation tokens for running queries, keyed by
+/// `BackendKeyData` of their connections.
+///
+/// The server registers each query in `ServerConfig::cancel_registry` and
+/// cancels it on `CancelRequest`, its token is available to handlers as
+/// `ClientInfo::cancellation_token`. For a registry of your own, share an
+/// instance between your query handler and `DefaultCancelHandler`. Register
+/// the query with `CancelRegistry::register` in `do_query` and wait for
+/// `CancelGuard::cancelled` alongside the query execution.
+///
+/// Queries are looked up by pid only, the secret key is then verified with
+/// `secret_key_eq` so the lookup doesn't leak timing information of the key.
+/// Connections without `BackendKeyData`, whose pid is 0, are not registered.
+#[derive(Debug, Default)]
+pub struct CancelRegistry {
+    tokens: Mutex<HashMap<i32, Registration>>,
+    next_id: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Registration {
+    id: u64,
+    secret_key: i32,
+    token: CancellationToken,
+}
+
+static GLOBAL_REGISTRY: OnceLock<Arc<CancelRegistry>> = OnceLock::new();
+
+impl CancelRegistry {
+    pub fn new() -> CancelRegistry {
+        CancelRegistry::default()
+    }
+
+    /// Registry shared in the process, the default of
+    /// `ServerConfig::cancel_registry` so cancel requests reach queries on
+    /// other connections.
+    pub fn global() -> Arc<CancelRegistry> {
+        GLOBAL_REGISTRY.get_or_init(Default::default).clone()
+    }
+
+    /// Register a running query of the client. The query is unregistered
+    /// when returned guard is dropped.
+    pub fn register<C: ClientInfo>(&self, client: &C) -> CancelGuard<'_> {
+        let (pid, secret_key) = client.pid_and_secret_key();
+        let token = CancellationToken::new();
+        let id = self.insert(pid, secret_key, token.clone());
+
+        CancelGuard {
+            registry: self,
+            pid,
+            id,
+            token,
+        }
+    }
+
+    /// Register `token` of connection `pid`, returns id of the registration
+    /// for `remove`, or `None` if `pid` is 0.
+    pub(crate) fn insert(
+        &self,
+        pid: i32,
+        secret_key: i32,
+        token: CancellationToken,
+    ) -> Option<u64> {
+        if pid == 0 {
+            return None;
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.tokens.lock().unwrap().insert(
+            pid,
+            Registration {
+                id,
+                secret_key,
+                token,
+            },
+        );
+        Some(id)
+    }
+
+    /// Remove registration `id` of connection `pid`, unless it has been
+    /// replaced by another one.
+    pub(crate) fn remove(&self, pid: i32, id: Option<u64>) {
+        let Some(id) = id else {
+            return;
+        };
+        let mut tokens = self.tokens.lock().unwrap();
+        if tokens.get(&pid).is_some_and(|r| r.id == id) {
+            tokens.remove(&pid);
+        }
+    }
+
+    /// Cancel running query of the connection identified by `pid` and
+    /// `secret_key`. Returns false if there is no such query.
+    pub fn cancel(&self, pid: i32, secret_key: i32) -> bool {
+        match self.tokens.lock().unwrap().get(&pid) {
+            Some(registration) if secret_key_eq(registration.secret_key, secret_key) => {
+                registration.token.cancel();
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A query registered in `CancelRegistry`
+#[derive(Debug)]
+pub struct CancelGuard<'a> {
+    registry: &'a CancelRegistry,
+    pid: i32,
+    id: Option<u64>,
+    token: CancellationToken,
+}
+
+impl CancelGuard<'_> {
+    /// Get the cancellation token of this query
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+
+    /// Return true if the query has been cancelled
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Wait until the query is cancelled
+    pub async fn cancelled(&self) {
+        self.token.cancelled().await
+    }
+}
+
+impl Drop for CancelGuard<'_> {
+    fn drop(&mut self) {
+        self.registry.remove(self.pid, self.id);
+    }
+}
+
+/// `CancelHandler` that cancels queries registered in `CancelRegistry`.
+#[derive(Debug, new)]
+pub struct DefaultCancelHandler {
+    registry: Arc<CancelRegistry>,
+}
+
+#[async_trait]
+impl CancelHandler for DefaultCancelHandler {
+    async fn on_cancel_request(&self, cancel_request: CancelRequest) {
+        self.registry
+            .cancel(cancel_request.pid, cancel_request.secret_key);
+    }
+}
+
+/// Error returned to client when its query is cancelled, same as postgres.
+pub fn query_canceled_error() -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        "ERROR".to_owned(),
+        "57014".to_owned(),
+        "canceling statement due to user request".to_owned(),
+    )))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fmt::Debug;
+
+    use futures::{Sink, SinkExt};
+
+    use super::*;
+    use crate::api::query::SimpleQueryHandler;
+    use crate::api::results::Response;
+    use crate::api::DefaultClient;
+    use crate::error::{NoticeInfo, PgWireResult};
+    use crate::messages::simplequery::Query;
+    use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
+    use crate::testkit::fixture::{error_code, TestHandlers};
+    use crate::testkit::MockClient;
+
+    /// Sends a notice, then waits until the query is cancelled
+    struct SleepHandler;
+
+    #[async_trait]
+    impl SimpleQueryHandler for SleepHandler {
+        async fn do_query<'a, C>(
+            &self,
+            client: &mut C,
+            _query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            let token = client.cancellation_token().expect("query is running");
+            client
+                .send(PgWireBackendMessage::NoticeResponse(
+                    NoticeInfo::new(
+                        "NOTICE".to_owned(),
+                        "00000".to_owned(),
+                        "sleeping".to_owned(),
+                    )
+                    .into(),
+                ))
+                .await?;
+            token.cancelled().await;
+            Err(query_canceled_error())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cancel_registry() {
+        let registry = Arc::new(CancelRegistry::new());
+        let handler = DefaultCancelHandler::new(registry.clone());
+
+        let mut client = DefaultClient::<()>::new("127.0.0.1:5432".parse().unwrap(), false);
+        client.set_pid_and_secret_key(1, 42);
+
+        let guard = registry.register(&client);
+        // wrong secret key
+        handler.on_cancel_request(CancelRequest::new(1, 41)).await;
+        assert!(!guard.is_cancelled());
+
+        handler.on_cancel_request(CancelRequest::new(1, 42)).await;
+        assert!(guard.is_cancelled());
+        guard.cancelled().await;
+
+        drop(guard);
+        assert!(!registry.cancel(1, 42));
+
+        // a finished query doesn't remove the newer registration of its pid
+        let guard = registry.register(&client);
+        let newer = registry.register(&client);
+        drop(guard);
+        assert!(registry.cancel(1, 42));
+        assert!(newer.is_cancelled());
+        drop(newer);
+
+        // connections without backend key data are not registered
+        let client = DefaultClient::<()>::new("127.0.0.1:5432".parse().unwrap(), false);
+        let guard = registry.register(&client);
+        assert!(!registry.cancel(0, 0));
+        assert!(!guard.is_cancelled());
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secrets"));
+        assert!(constant_time_eq(b"", b""));
+
+        assert!(secret_key_eq(42, 42));
+        assert!(!secret_key_eq(42, -42));
+    }
+
+    #[tokio::test]
+    async fn test_cancel_query() {
+        let mut client = MockClient::start(TestHandlers::echo().with_simple(SleepHandler));
+        let messages = client.startup("tom", None).await.unwrap();
+        let Some(PgWireBackendMessage::BackendKeyData(key_data)) = messages
+            .into_iter()
+            .find(|m| matches!(m, PgWireBackendMessage::BackendKeyData(_)))
+        else {
+            panic!("backend key data expected");
+        };
+
+        client
+            .send(PgWireFrontendMessage::Query(Query::new(
+                "SELECT pg_sleep(60)",
+            )))
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.receive().await.unwrap(),
+            Some(PgWireBackendMessage::NoticeResponse(_))
+        ));
+
+        let mut canceller = MockClient::start(TestHandlers::echo());
+        canceller
+            .send(PgWireFrontendMessage::CancelRequest(CancelRequest::new(
+                key_data.pid,
+                key_data.secret_key,
+            )))
+            .await
+            .unwrap();
+        canceller.close().await.unwrap();
+
+        let messages = client.receive_until_ready().await.unwrap();
+        assert_eq!(Some("57014".to_owned()), error_code(&messages[0]));
+    }
+}
@@ -0,0 +1,183 @@
+//! Capture of wire messages for debugging.
+//!
+//! Set `ServerConfig::capture` to record every frontend and backend message
+//! of each session. With `capture` feature, `JsonLinesCapture` writes records
+//! to a file as JSON lines, which can be loaded with `read_capture` and
+//! replayed against your handlers by `pgwire::testkit::replay`.
+
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+
+/// Which side sent the message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "capture", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "capture", serde(rename_all = "lowercase"))]
+pub enum CaptureDirection {
+    Frontend,
+    Backend,
+}
+
+/// A message captured on the wire
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq, new)]
+pub struct CaptureRecord {
+    /// Microseconds since unix epoch
+    pub timestamp: i64,
+    /// Address of the client, identifies the session
+    pub session: SocketAddr,
+    pub direction: CaptureDirection,
+    /// Encoded message, including message type and length
+    pub data: Bytes,
+}
+
+impl CaptureRecord {
+    /// Create a record with current time
+    pub fn now(session: SocketAddr, direction: CaptureDirection, data: Bytes) -> CaptureRecord {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as i64)
+            .unwrap_or_default();
+        CaptureRecord::new(timestamp, session, direction, data)
+    }
+
+    /// Type byte of the message, `None` for startup packets which have no
+    /// message type
+    pub fn message_type(&self) -> Option<u8> {
+        match self.data.first() {
+            // startup, ssl request and cancel request packets start with
+            // their length
+            Some(0) | None => None,
+            Some(t) => Some(*t),
+        }
+    }
+}
+
+/// Receiver of captured messages
+///
+/// `record` is called on the connection's task, so implementations should
+/// not block for long.
+pub trait CaptureSink: Debug + Send + Sync {
+    fn record(&self, record: CaptureRecord);
+}
+
+#[cfg(feature = "capture")]
+mod json {
+    use std::io::{BufRead, Write};
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::error::{PgWireError, PgWireResult};
+
+    #[derive(serde::Serialize, serde::Deserialize)]
+    struct JsonRecord {
+        timestamp: i64,
+        session: SocketAddr,
+        direction: CaptureDirection,
+        #[serde(rename = "type", skip_serializing_if = "Option::is_none", default)]
+        message_type: Option<char>,
+        data: String,
+    }
+
+    /// Write captured messages as JSON lines, one object per message like
+    /// `{"timestamp":..,"session":"127.0.0.1:5432","direction":"frontend","type":"Q","data":"<hex>"}`
+    #[derive(Debug)]
+    pub struct JsonLinesCapture<W> {
+        writer: Mutex<W>,
+    }
+
+    impl<W: Write> JsonLinesCapture<W> {
+        pub fn new(writer: W) -> JsonLinesCapture<W> {
+            JsonLinesCapture {
+                writer: Mutex::new(writer),
+            }
+        }
+
+        /// Get back the writer
+        pub fn into_inner(self) -> W {
+            self.writer.into_inner().unwrap_or_else(|e| e.into_inner())
+        }
+    }
+
+    impl<W: Write + Debug + Send> CaptureSink for JsonLinesCapture<W> {
+        fn record(&self, record: CaptureRecord) {
+            let json = JsonRecord {
+                timestamp: record.timestamp,
+                session: record.session,
+                direction: record.direction,
+                message_type: record.message_type().map(char::from),
+                data: hex::encode(&record.data),
+            };
+
+            if let Ok(mut writer) = self.writer.lock() {
+                // capture is best-effort, and should never break the session
+                if serde_json::to_writer(&mut *writer, &json).is_ok() {
+                    let _ = writer.write_all(b"\n");
+                    let _ = writer.flush();
+                }
+            }
+        }
+    }
+
+    /// Read records written by `JsonLinesCapture`
+    pub fn read_capture<R: BufRead>(reader: R) -> PgWireResult<Vec<CaptureRecord>> {
+        let mut records = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let json: JsonRecord =
+                serde_json::from_str(&line).map_err(|e| PgWireError::ApiError(Box::new(e)))?;
+            let data = hex::decode(&json.data).map_err(|e| PgWireError::ApiError(Box::new(e)))?;
+            records.push(CaptureRecord::new(
+                json.timestamp,
+                json.session,
+                json.direction,
+                Bytes::from(data),
+            ));
+        }
+
+        Ok(records)
+    }
+}
+
+#[cfg(feature = "capture")]
+pub use json::{read_capture, JsonLinesCapture};
+
+#[cfg(all(test, feature = "capture"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_json_lines_capture() {
+        let session = "127.0.0.1:5432".parse().unwrap();
+        let records = vec![
+            CaptureRecord::new(
+                1,
+                session,
+                CaptureDirection::Frontend,
+                Bytes::from_static(b"\0\0\0\x08\x04\xd2\x16\x2f"),
+            ),
+            CaptureRecord::new(
+                2,
+                session,
+                CaptureDirection::Backend,
+                Bytes::from_static(b"Z\0\0\0\x05I"),
+            ),
+        ];
+
+        let capture = JsonLinesCapture::new(Vec::new());
+        for record in records.iter() {
+            capture.record(record.clone());
+        }
+        let output = capture.into_inner();
+        assert!(String::from_utf8_lossy(&output).contains("\"type\":\"Z\""));
+
+        assert_eq!(records, read_capture(output.as_slice()).unwrap());
+        assert_eq!(None, records[0].message_type());
+    }
+}
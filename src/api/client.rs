@@ -1,9 +1,13 @@
 mod auth;
 pub(crate) mod config;
+pub mod query;
+mod version;
 
 use std::sync::Arc;
 
 pub use config::Config;
+pub use query::QueryResult;
+pub use version::ServerVersion;
 
 /// The collection of all client handlers
 pub trait PgWireClientHandlers {
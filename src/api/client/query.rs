@@ -0,0 +1,128 @@
+//! Results of queries sent by the client.
+
+use crate::error::{PgWireError, PgWireResult};
+use crate::messages::data::DataRow;
+use crate::messages::PgWireBackendMessage;
+
+/// Rows and command tag of a query, collected from backend messages.
+///
+/// `one`, `opt` and `rows_affected` check the result like `query_one`,
+/// `query_opt` and `execute` of tokio-postgres.
+#[non_exhaustive]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct QueryResult {
+    pub rows: Vec<DataRow>,
+    /// Tag of the last `CommandComplete`
+    pub tag: Option<String>,
+}
+
+impl QueryResult {
+    /// Collect data rows and command tag of a query, up to `ReadyForQuery`.
+    /// `ErrorResponse` from server is returned as `UserError`.
+    pub fn from_messages<I>(messages: I) -> PgWireResult<QueryResult>
+    where
+        I: IntoIterator<Item = PgWireBackendMessage>,
+    {
+        let mut result = QueryResult::default();
+        for message in messages {
+            match message {
+                PgWireBackendMessage::DataRow(row) => result.rows.push(row),
+                PgWireBackendMessage::CommandComplete(complete) => result.tag = Some(complete.tag),
+                PgWireBackendMessage::ErrorResponse(error) => {
+                    return Err(PgWireError::UserError(Box::new(error.into())))
+                }
+                PgWireBackendMessage::ReadyForQuery(_) => break,
+                _ => {}
+            }
+        }
+        Ok(result)
+    }
+
+    /// The only row, fails with `UnexpectedRowCount` if the query returned
+    /// none or more than one.
+    pub fn one(mut self) -> PgWireResult<DataRow> {
+        if self.rows.len() != 1 {
+            return Err(PgWireError::UnexpectedRowCount(
+                self.rows.len(),
+                "exactly one",
+            ));
+        }
+        Ok(self.rows.remove(0))
+    }
+
+    /// The row if any, fails with `UnexpectedRowCount` if the query returned
+    /// more than one.
+    pub fn opt(mut self) -> PgWireResult<Option<DataRow>> {
+        if self.rows.len() > 1 {
+            return Err(PgWireError::UnexpectedRowCount(
+                self.rows.len(),
+                "at most one",
+            ));
+        }
+        Ok(self.rows.pop())
+    }
+
+    /// Number of rows affected, from the command tag. It's `0` for commands
+    /// without row count.
+    pub fn rows_affected(&self) -> u64 {
+        self.tag
+            .as_deref()
+            .and_then(|tag| tag.rsplit(' ').next())
+            .and_then(|rows| rows.parse().ok())
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::messages::response::{
+        CommandComplete, ErrorResponse, ReadyForQuery, TransactionStatus,
+    };
+
+    fn messages(rows: usize, tag: &str) -> Vec<PgWireBackendMessage> {
+        let mut messages = (0..rows)
+            .map(|_| PgWireBackendMessage::DataRow(DataRow::default()))
+            .collect::<Vec<_>>();
+        messages.push(PgWireBackendMessage::CommandComplete(CommandComplete::new(
+            tag.to_owned(),
+        )));
+        messages.push(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
+            TransactionStatus::Idle,
+        )));
+        messages
+    }
+
+    #[test]
+    fn test_query_result() {
+        let result = |rows, tag| QueryResult::from_messages(messages(rows, tag)).unwrap();
+
+        assert!(result(1, "SELECT 1").one().is_ok());
+        assert!(matches!(
+            result(0, "SELECT 0").one(),
+            Err(PgWireError::UnexpectedRowCount(0, _))
+        ));
+        assert!(matches!(
+            result(2, "SELECT 2").one(),
+            Err(PgWireError::UnexpectedRowCount(2, _))
+        ));
+
+        assert!(result(0, "SELECT 0").opt().unwrap().is_none());
+        assert!(result(1, "SELECT 1").opt().unwrap().is_some());
+        assert!(result(3, "SELECT 3").opt().is_err());
+
+        assert_eq!(5, result(0, "INSERT 0 5").rows_affected());
+        assert_eq!(3, result(3, "SELECT 3").rows_affected());
+        assert_eq!(0, result(0, "VACUUM").rows_affected());
+
+        let error = PgWireBackendMessage::ErrorResponse(ErrorResponse::new(vec![
+            (b'S', "ERROR".to_owned()),
+            (b'C', "57P01".to_owned()),
+            (b'M', "terminating connection".to_owned()),
+        ]));
+        match QueryResult::from_messages([error]) {
+            Err(PgWireError::UserError(info)) => assert_eq!("57P01", info.code),
+            other => panic!("unexpected result {other:?}"),
+        }
+    }
+}
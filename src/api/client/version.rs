@@ -0,0 +1,182 @@
+//! Version of the connected server.
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use crate::error::PgWireError;
+use crate::messages::startup::ParameterStatus;
+
+/// Version of postgres server, parsed from `server_version` or
+/// `server_version_num` parameter reported in `ParameterStatus`.
+///
+/// Since postgres 10 the version has two parts, like `16.2`, and `minor` is
+/// the patch release. Older versions have three parts, like `9.6.24`, and
+/// `minor` is the second part.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ServerVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl ServerVersion {
+    pub fn new(major: u32, minor: u32, patch: u32) -> ServerVersion {
+        ServerVersion {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    /// Parse numeric version like `160002` from `server_version_num`
+    pub fn from_version_num(num: &str) -> Result<ServerVersion, PgWireError> {
+        let num = num
+            .trim()
+            .parse::<u32>()
+            .map_err(|_| PgWireError::InvalidServerVersion(num.to_owned()))?;
+        if num >= 100000 {
+            Ok(ServerVersion::new(num / 10000, num % 10000, 0))
+        } else {
+            Ok(ServerVersion::new(num / 10000, num / 100 % 100, num % 100))
+        }
+    }
+
+    /// Get server version from reported parameters, `server_version_num` is
+    /// preferred when both are available.
+    pub fn from_parameters<'a, I>(parameters: I) -> Option<ServerVersion>
+    where
+        I: IntoIterator<Item = &'a ParameterStatus>,
+    {
+        let mut version = None;
+        for param in parameters {
+            match param.name.as_str() {
+                "server_version_num" => {
+                    if let Ok(v) = ServerVersion::from_version_num(&param.value) {
+                        return Some(v);
+                    }
+                }
+                "server_version" => {
+                    version = param.value.parse().ok();
+                }
+                _ => {}
+            }
+        }
+        version
+    }
+
+    /// Numeric form of the version, as reported by `server_version_num`
+    pub fn version_num(&self) -> u32 {
+        if self.major >= 10 {
+            self.major * 10000 + self.minor
+        } else {
+            self.major * 10000 + self.minor * 100 + self.patch
+        }
+    }
+
+    /// Test if the server is at least of the given major version
+    pub fn at_least(&self, major: u32) -> bool {
+        self.major >= major
+    }
+
+    /// Server accepts TLS handshake without `SslRequest`, that is
+    /// `SslNegotiation::Direct`. Added in postgres 17.
+    pub fn supports_direct_ssl_negotiation(&self) -> bool {
+        self.at_least(17)
+    }
+
+    /// Server speaks protocol 3.2, which has variable length cancel keys in
+    /// `BackendKeyData`. Added in postgres 18.
+    pub fn supports_protocol_3_2(&self) -> bool {
+        self.at_least(18)
+    }
+
+    /// Server supports `SCRAM-SHA-256` authentication. Added in postgres 10.
+    pub fn supports_scram(&self) -> bool {
+        self.at_least(10)
+    }
+}
+
+/// Parse `server_version` like `16.2`, `9.6.24`, `17beta1` or
+/// `16.2 (Debian 16.2-1.pgdg120+2)`. Pre-release suffix and build info are
+/// ignored.
+impl FromStr for ServerVersion {
+    type Err = PgWireError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || PgWireError::InvalidServerVersion(s.to_owned());
+
+        let version = s.split_whitespace().next().ok_or_else(invalid)?;
+        let mut parts = [0u32; 3];
+        let mut count = 0;
+        for part in version.split('.') {
+            if count == parts.len() {
+                return Err(invalid());
+            }
+            // keep leading digits, drop suffix like `beta1`, `rc1`, `devel`
+            let digits_end = part
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(part.len());
+            if digits_end == 0 {
+                return Err(invalid());
+            }
+            parts[count] = part[..digits_end].parse().map_err(|_| invalid())?;
+            count += 1;
+            if digits_end < part.len() {
+                break;
+            }
+        }
+
+        Ok(ServerVersion::new(parts[0], parts[1], parts[2]))
+    }
+}
+
+impl Display for ServerVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.major >= 10 {
+            write!(f, "{}.{}", self.major, self.minor)
+        } else {
+            write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_server_version() {
+        assert_eq!(ServerVersion::new(16, 2, 0), "16.2".parse().unwrap());
+        assert_eq!(ServerVersion::new(9, 6, 24), "9.6.24".parse().unwrap());
+        assert_eq!(ServerVersion::new(17, 0, 0), "17beta1".parse().unwrap());
+        assert_eq!(ServerVersion::new(18, 0, 0), "18devel".parse().unwrap());
+        assert_eq!(
+            ServerVersion::new(16, 2, 0),
+            "16.2 (Debian 16.2-1.pgdg120+2)".parse().unwrap()
+        );
+        assert!("".parse::<ServerVersion>().is_err());
+        assert!("beta".parse::<ServerVersion>().is_err());
+
+        assert_eq!(
+            ServerVersion::new(16, 2, 0),
+            ServerVersion::from_version_num("160002").unwrap()
+        );
+        assert_eq!(
+            ServerVersion::new(9, 6, 24),
+            ServerVersion::from_version_num("90624").unwrap()
+        );
+        assert_eq!(90624, ServerVersion::new(9, 6, 24).version_num());
+        assert_eq!("9.6.24", ServerVersion::new(9, 6, 24).to_string());
+
+        let params = vec![
+            ParameterStatus::new("server_version".to_owned(), "17.1".to_owned()),
+            ParameterStatus::new("server_version_num".to_owned(), "170002".to_owned()),
+        ];
+        let version = ServerVersion::from_parameters(&params).unwrap();
+        assert_eq!(ServerVersion::new(17, 2, 0), version);
+        assert!(version.supports_direct_ssl_negotiation());
+        assert!(!version.supports_protocol_3_2());
+        assert!(version > ServerVersion::new(9, 6, 24));
+    }
+}
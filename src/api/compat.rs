@@ -0,0 +1,482 @@
+//! Known quirks of mainstream clients.
+//!
+//! Drivers and tools send queries of their own before running any user
+//! query: npgsql loads types from `pg_type`, psycopg2 and JDBC set
+//! transaction characteristics, SQLAlchemy checks
+//! `standard_conforming_strings`, Metabase and DBeaver probe server version
+//! and current schema. A new backend rarely supports these queries, so the
+//! connection fails before the user can do anything. `CompatQueryHandler`
+//! answers them with fixed responses, each kind can be turned on and off with
+//! `Quirk`.
+
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::Sink;
+
+use super::auth::DefaultServerParameterProvider;
+use super::portal::Portal;
+use super::query::{split_statements, ExtendedQueryHandler, SimpleQueryHandler, StatementOrPortal};
+use super::results::{
+    DataRowEncoder, DescribePortalResponse, DescribeStatementResponse, FieldInfo, QueryResponse,
+    Response, Tag,
+};
+use super::stmt::StoredStatement;
+use super::store::PortalStore;
+use super::{ClientInfo, ClientPortalStore, Type, METADATA_DATABASE, METADATA_USER};
+use crate::error::{PgWireError, PgWireResult};
+use crate::messages::PgWireBackendMessage;
+use postgres_types::Kind;
+
+/// A known client quirk answered by `CompatQueryHandler`
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Quirk {
+    /// npgsql loads types, composite fields and enum labels from `pg_type`,
+    /// `pg_attribute` and `pg_enum` on the first connection. Built-in types
+    /// are returned, with no composite or enum types.
+    NpgsqlTypeLoading,
+    /// `SET TRANSACTION ...` and `SET SESSION CHARACTERISTICS AS TRANSACTION
+    /// ...`, sent by psycopg2 and JDBC after `BEGIN` or on connect, are
+    /// accepted and ignored.
+    SetTransaction,
+    /// `SHOW standard_conforming_strings` and `SHOW transaction isolation
+    /// level` from SQLAlchemy, answered with `on` and `read committed`.
+    ShowCompatParameters,
+    /// `SELECT version()`, `SELECT current_schema()` and `SELECT
+    /// current_database()` from Metabase, DBeaver and other tools.
+    IntrospectionProbes,
+}
+
+impl Quirk {
+    /// All quirks, enabled by `CompatQueryHandler::new`
+    pub const ALL: &'static [Quirk] = &[
+        Quirk::NpgsqlTypeLoading,
+        Quirk::SetTransaction,
+        Quirk::ShowCompatParameters,
+        Quirk::IntrospectionProbes,
+    ];
+}
+
+/// Built-in types returned to npgsql
+static BUILTIN_TYPES: &[Type] = &[
+    Type::BOOL,
+    Type::BYTEA,
+    Type::CHAR,
+    Type::NAME,
+    Type::INT8,
+    Type::INT2,
+    Type::INT4,
+    Type::TEXT,
+    Type::OID,
+    Type::JSON,
+    Type::FLOAT4,
+    Type::FLOAT8,
+    Type::BPCHAR,
+    Type::VARCHAR,
+    Type::DATE,
+    Type::TIME,
+    Type::TIMESTAMP,
+    Type::TIMESTAMPTZ,
+    Type::INTERVAL,
+    Type::TIMETZ,
+    Type::NUMERIC,
+    Type::UUID,
+    Type::JSONB,
+    Type::BOOL_ARRAY,
+    Type::BYTEA_ARRAY,
+    Type::INT8_ARRAY,
+    Type::INT2_ARRAY,
+    Type::INT4_ARRAY,
+    Type::TEXT_ARRAY,
+    Type::FLOAT4_ARRAY,
+    Type::FLOAT8_ARRAY,
+    Type::VARCHAR_ARRAY,
+    Type::DATE_ARRAY,
+    Type::TIMESTAMP_ARRAY,
+    Type::TIMESTAMPTZ_ARRAY,
+    Type::NUMERIC_ARRAY,
+    Type::UUID_ARRAY,
+    Type::JSONB_ARRAY,
+];
+
+/// Lower case query with comments removed and whitespace collapsed, for
+/// matching only.
+fn normalize(statement: &str) -> String {
+    statement
+        .lines()
+        .map(|line| line.split("--").next().unwrap_or_default())
+        .flat_map(str::split_whitespace)
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+fn text_fields(names: &[&str], datatype: Type) -> Arc<Vec<FieldInfo>> {
+    Arc::new(
+        names
+            .iter()
+            .map(|name| FieldInfo::builder(*name, datatype.clone()).build())
+            .collect(),
+    )
+}
+
+fn single_value<'a>(name: &str, value: &str) -> PgWireResult<Response<'a>> {
+    let fields = text_fields(&[name], Type::TEXT);
+    let mut encoder = DataRowEncoder::new(fields.clone());
+    encoder.encode_field(&value)?;
+    Ok(Response::Query(QueryResponse::from_rows(
+        fields,
+        vec![encoder.finish()?],
+    )))
+}
+
+fn npgsql_types<'a>() -> PgWireResult<Response<'a>> {
+    let fields = Arc::new(vec![
+        FieldInfo::builder("nspname", Type::NAME).build(),
+        FieldInfo::builder("oid", Type::OID).build(),
+        FieldInfo::builder("typname", Type::NAME).build(),
+        FieldInfo::builder("typtype", Type::CHAR).build(),
+        FieldInfo::builder("typnotnull", Type::BOOL).build(),
+        FieldInfo::builder("elemtypoid", Type::OID).build(),
+    ]);
+
+    let rows = BUILTIN_TYPES
+        .iter()
+        .map(|ty| {
+            // npgsql reports arrays as `a` instead of `b` of pg_type
+            let (typtype, elem) = match ty.kind() {
+                Kind::Array(elem) => ("a", elem.oid()),
+                Kind::Range(_) => ("r", 0),
+                Kind::Pseudo => ("p", 0),
+                _ => ("b", 0),
+            };
+            let mut encoder = DataRowEncoder::new(fields.clone());
+            encoder.encode_field(&ty.schema())?;
+            encoder.encode_field(&ty.oid())?;
+            encoder.encode_field(&ty.name())?;
+            encoder.encode_field(&typtype)?;
+            encoder.encode_field(&false)?;
+            encoder.encode_field(&elem)?;
+            encoder.finish()
+        })
+        .collect::<PgWireResult<Vec<_>>>()?;
+
+    Ok(Response::Query(QueryResponse::from_rows(fields, rows)))
+}
+
+/// Wrapper of query handlers that answers known client quirks in simple
+/// query, without calling `do_query` of the inner handler.
+///
+/// A query with multiple statements is passed to the inner handler as is,
+/// unless one of its statements is a quirk. In that case each of the other
+/// statements is passed to the inner handler separately, so responses stay
+/// in order. Quirks in extended query are not intercepted.
+#[derive(Debug, Clone)]
+pub struct CompatQueryHandler<H> {
+    inner: Arc<H>,
+    quirks: Vec<Quirk>,
+    server_version: String,
+}
+
+impl<H> CompatQueryHandler<H> {
+    /// Handle all quirks in `Quirk::ALL`
+    pub fn new(inner: Arc<H>) -> CompatQueryHandler<H> {
+        CompatQueryHandler {
+            inner,
+            quirks: Quirk::ALL.to_vec(),
+            server_version: DefaultServerParameterProvider::default().server_version,
+        }
+    }
+
+    /// Replace the handled quirks
+    pub fn with_quirks(mut self, quirks: Vec<Quirk>) -> CompatQueryHandler<H> {
+        self.quirks = quirks;
+        self
+    }
+
+    /// Version returned by `SELECT version()`, which should be the same as
+    /// `server_version` reported at startup
+    pub fn with_server_version(mut self, server_version: String) -> CompatQueryHandler<H> {
+        self.server_version = server_version;
+        self
+    }
+
+    /// Get the inner handler
+    pub fn inner(&self) -> &Arc<H> {
+        &self.inner
+    }
+
+    fn enabled(&self, quirk: Quirk) -> bool {
+        self.quirks.contains(&quirk)
+    }
+
+    /// Response of statement if it's one of the enabled quirks
+    fn quirk_response<'a, C: ClientInfo>(
+        &self,
+        client: &C,
+        statement: &str,
+    ) -> Option<PgWireResult<Response<'a>>> {
+        let query = normalize(statement);
+
+        if self.enabled(Quirk::SetTransaction)
+            && (query.starts_with("set transaction ")
+                || query.starts_with("set session characteristics as transaction "))
+        {
+            return Some(Ok(Response::Execution(Tag::new("SET"))));
+        }
+
+        if self.enabled(Quirk::ShowCompatParameters) {
+            match query.as_str() {
+                "show standard_conforming_strings" => {
+                    return Some(single_value("standard_conforming_strings", "on"))
+                }
+                "show transaction isolation level" | "show transaction_isolation" => {
+                    return Some(single_value("transaction_isolation", "read committed"))
+                }
+                _ => {}
+            }
+        }
+
+        if self.enabled(Quirk::IntrospectionProbes) {
+            match query.as_str() {
+                "select version()" | "select pg_catalog.version()" => {
+                    return Some(single_value(
+                        "version",
+                        &format!("PostgreSQL {}", self.server_version),
+                    ))
+                }
+                "select current_schema()" | "select pg_catalog.current_schema()" => {
+                    return Some(single_value("current_schema", "public"))
+                }
+                "select current_database()" | "select pg_catalog.current_database()" => {
+                    let metadata = client.metadata();
+                    let database = metadata
+                        .get(METADATA_DATABASE)
+                        .or_else(|| metadata.get(METADATA_USER))
+                        .map(String::as_str)
+                        .unwrap_or("postgres");
+                    return Some(single_value("current_database", database));
+                }
+                _ => {}
+            }
+        }
+
+        if self.enabled(Quirk::NpgsqlTypeLoading) && query.starts_with("select ") {
+            if query.contains("pg_type") && query.contains("elemtypoid") {
+                return Some(npgsql_types());
+            }
+            if query.contains("pg_attribute") && query.contains("atttypid") {
+                return Some(Ok(Response::Query(QueryResponse::from_rows(
+                    text_fields(&["oid", "attname", "atttypid"], Type::TEXT),
+                    vec![],
+                ))));
+            }
+            if query.contains("pg_enum") && query.contains("enumlabel") {
+                return Some(Ok(Response::Query(QueryResponse::from_rows(
+                    text_fields(&["oid", "enumlabel"], Type::TEXT),
+                    vec![],
+                ))));
+            }
+        }
+
+        None
+    }
+}
+
+#[async_trait]
+impl<H: SimpleQueryHandler> SimpleQueryHandler for CompatQueryHandler<H> {
+    async fn do_query<'a, 'b: 'a, C>(
+        &'b self,
+        client: &mut C,
+        query: &'a str,
+    ) -> PgWireResult<Vec<Response<'a>>>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let statements = split_statements(query);
+        let quirks = statements
+            .iter()
+            .map(|s| self.quirk_response(client, s))
+            .collect::<Vec<_>>();
+        if quirks.iter().all(Option::is_none) {
+            return self.inner.do_query(client, query).await;
+        }
+
+        let mut responses = Vec::with_capacity(statements.len());
+        for (statement, quirk) in statements.into_iter().zip(quirks) {
+            match quirk {
+                Some(response) => responses.push(response?),
+                None => responses.extend(self.inner.do_query(client, statement).await?),
+            }
+            // the rest of query is skipped after an error
+            if matches!(responses.last(), Some(Response::Error(_))) {
+                break;
+            }
+        }
+        Ok(responses)
+    }
+}
+
+#[async_trait]
+impl<H: ExtendedQueryHandler> ExtendedQueryHandler for CompatQueryHandler<H> {
+    type Statement = H::Statement;
+    type QueryParser = H::QueryParser;
+
+    fn query_parser(&self) -> Arc<Self::QueryParser> {
+        self.inner.query_parser()
+    }
+
+    fn check_portal_store_usage<C>(&self, client: &C) -> PgWireResult<()>
+    where
+        C: ClientInfo + ClientPortalStore,
+        C::PortalStore: PortalStore,
+    {
+        self.inner.check_portal_store_usage(client)
+    }
+
+    async fn do_describe<C>(
+        &self,
+        client: &mut C,
+        target: StatementOrPortal<'_, Self::Statement>,
+    ) -> PgWireResult<DescribeStatementResponse>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.inner.do_describe(client, target).await
+    }
+
+    async fn do_describe_statement<C>(
+        &self,
+        client: &mut C,
+        target: &StoredStatement<Self::Statement>,
+    ) -> PgWireResult<DescribeStatementResponse>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.inner.do_describe_statement(client, target).await
+    }
+
+    async fn do_describe_portal<C>(
+        &self,
+        client: &mut C,
+        target: &Portal<Self::Statement>,
+    ) -> PgWireResult<DescribePortalResponse>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.inner.do_describe_portal(client, target).await
+    }
+
+    async fn do_query<'a, 'b: 'a, C>(
+        &'b self,
+        client: &mut C,
+        portal: &'a Portal<Self::Statement>,
+        max_rows: usize,
+    ) -> PgWireResult<Response<'a>>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.inner.do_query(client, portal, max_rows).await
+    }
+
+    fn supports_query_batch(&self) -> bool {
+        self.inner.supports_query_batch()
+    }
+
+    async fn do_query_batch<'a, 'b: 'a, C>(
+        &'b self,
+        client: &mut C,
+        portals: &'a [Arc<Portal<Self::Statement>>],
+    ) -> PgWireResult<Vec<Response<'a>>>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.inner.do_query_batch(client, portals).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::messages::response::{CommandComplete, ReadyForQuery, TransactionStatus};
+    use crate::testkit::fixture::{single_value, FnQueryHandler, TestHandlers};
+    use crate::testkit::MockClient;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(
+            "select ns.nspname, t.oid from pg_type",
+            normalize("-- Load types\nSELECT ns.nspname, t.oid -- comment\n  FROM pg_type")
+        );
+        assert_eq!(
+            "show transaction isolation level",
+            normalize(" SHOW  TRANSACTION\tISOLATION LEVEL")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compat_quirks() {
+        let inner = FnQueryHandler::new(|query| {
+            Ok(vec![if query == "BEGIN" {
+                Response::TransactionStart(Tag::new(query))
+            } else {
+                Response::Execution(Tag::new(query))
+            }])
+        });
+        let handlers = TestHandlers::new(CompatQueryHandler::new(Arc::new(inner)));
+        let mut client = MockClient::start(handlers);
+        client.startup("tom", None).await.unwrap();
+
+        let messages = client
+            .simple_query("BEGIN; SET TRANSACTION ISOLATION LEVEL SERIALIZABLE")
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![
+                PgWireBackendMessage::CommandComplete(CommandComplete::new("BEGIN".to_owned())),
+                PgWireBackendMessage::CommandComplete(CommandComplete::new("SET".to_owned())),
+                PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
+                    TransactionStatus::Transaction
+                )),
+            ],
+            messages
+        );
+
+        assert_eq!(
+            b"on".to_vec(),
+            single_value(&mut client, "show standard_conforming_strings").await
+        );
+
+        let messages = client
+            .simple_query("-- Load types\nSELECT ns.nspname, t.oid, t.typname, t.typtype, t.typnotnull, t.elemtypoid FROM pg_type AS t")
+            .await
+            .unwrap();
+        assert!(messages.len() > 10);
+
+        // other queries go to do_query
+        let messages = client.simple_query("SELECT 1").await.unwrap();
+        assert_eq!(
+            PgWireBackendMessage::CommandComplete(CommandComplete::new("SELECT 1".to_owned())),
+            messages[0]
+        );
+    }
+}
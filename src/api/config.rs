@@ -0,0 +1,267 @@
+use std::fmt::Debug;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use super::admission::AdmissionController;
+use super::auth::audit::AuthAuditHandler;
+use super::auth::{AuthFailurePolicy, DatabaseValidator};
+use super::cancel::CancelRegistry;
+use super::capture::CaptureSink;
+use super::custom_types::TypeRegistry;
+use super::events::EventBus;
+use super::overload::LoadShedder;
+use super::ratelimit::HandshakeRateLimiter;
+use super::readonly::ReadOnlyMode;
+use super::store::PortalStoreLimits;
+use crate::error::ErrorInfo;
+
+/// Decide if the connection should be closed after sending an error
+/// returned by handlers.
+///
+/// Errors with explicit fatality, set by `PgWireError::fatal` or
+/// `PgWireError::non_fatal`, don't go through the policy. When the policy
+/// closes the connection for an error of `ERROR` severity, it's sent to
+/// client as `FATAL`.
+pub trait DisconnectPolicy: Debug + Send + Sync {
+    fn should_disconnect(&self, error: &ErrorInfo) -> bool;
+}
+
+/// Disconnect like postgres: on `FATAL` and `PANIC` errors, and on errors
+/// that always terminate the session in postgres, such as `57P01`
+/// admin_shutdown.
+#[derive(Debug, Clone, Default)]
+pub struct DefaultDisconnectPolicy {
+    /// Additional SQLSTATEs that close the connection
+    pub sqlstates: Vec<String>,
+}
+
+/// SQLSTATEs that postgres always reports with `FATAL` severity
+const DISCONNECT_SQLSTATES: &[&str] = &[
+    "57P01", // admin_shutdown
+    "57P02", // crash_shutdown
+    "57P05", // idle_session_timeout
+    "25P03", // idle_in_transaction_session_timeout
+];
+
+impl DisconnectPolicy for DefaultDisconnectPolicy {
+    fn should_disconnect(&self, error: &ErrorInfo) -> bool {
+        error.is_fatal()
+            || DISCONNECT_SQLSTATES.contains(&error.code.as_str())
+            || self.sqlstates.contains(&error.code)
+    }
+}
+
+/// TCP keepalive probes of accepted sockets, so dead peers, for example
+/// behind a NAT that dropped the mapping, are detected before the OS default
+/// of hours.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpKeepalive {
+    /// Idle time before the first probe
+    pub idle: Duration,
+    /// Time between probes, OS default if `None`. Ignored on platforms
+    /// without the option.
+    pub interval: Option<Duration>,
+    /// Unanswered probes before the connection is dropped, OS default if
+    /// `None`. Ignored on platforms without the option.
+    pub retries: Option<u32>,
+}
+
+impl TcpKeepalive {
+    /// Send the first probe after `idle`
+    pub fn new(idle: Duration) -> TcpKeepalive {
+        TcpKeepalive {
+            idle,
+            interval: None,
+            retries: None,
+        }
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> TcpKeepalive {
+        self.interval = Some(interval);
+        self
+    }
+
+    pub fn with_retries(mut self, retries: u32) -> TcpKeepalive {
+        self.retries = Some(retries);
+        self
+    }
+}
+
+/// Options applied to accepted TCP sockets by `process_socket`.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Disable Nagle's algorithm, `true` by default since responses are
+    /// flushed explicitly.
+    pub nodelay: bool,
+    /// Enable TCP keepalive, `None` keeps the OS setting.
+    pub keepalive: Option<TcpKeepalive>,
+    /// `SO_SNDBUF` in bytes, `None` keeps the OS default.
+    pub send_buffer_size: Option<usize>,
+    /// `SO_RCVBUF` in bytes, `None` keeps the OS default.
+    pub recv_buffer_size: Option<usize>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive: None,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+        }
+    }
+}
+
+/// Server side options applied to each connection.
+///
+/// Use `ServerConfig::default()` and override the fields you need, then pass
+/// it to `pgwire::tokio::process_socket_with_config`.
+#[non_exhaustive]
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Flush the connection after this many `DataRow` messages are buffered
+    /// in `send_query_response`. `0` disables the row based threshold.
+    pub data_row_flush_rows: usize,
+    /// Flush the connection once buffered `DataRow` messages reach this many
+    /// bytes. `0` disables the byte based threshold.
+    pub data_row_flush_bytes: usize,
+    /// Max bytes of messages pending in the write buffer of a connection.
+    /// Once reached, senders wait until the buffer is flushed to the socket,
+    /// so a fast backend streaming rows to a slow client doesn't grow the
+    /// buffer, even with flush thresholds above disabled. `0` flushes every
+    /// message.
+    pub write_buffer_high_watermark: usize,
+    /// Limits of statements and portals cached for each connection.
+    pub portal_store_limits: PortalStoreLimits,
+    /// Cache result of `do_describe_statement` on the stored statement, so
+    /// repeated `Describe` of the same statement doesn't call the handler
+    /// again. Disabled by default, enable it only when the describe result
+    /// doesn't depend on session state like `search_path`.
+    pub cache_describe_statement: bool,
+    /// Record all messages sent and received, for debugging.
+    pub capture: Option<Arc<dyn CaptureSink>>,
+    /// Decide which user errors close the connection.
+    pub disconnect_policy: Arc<dyn DisconnectPolicy>,
+    /// Limit connection attempts from each source IP in `process_socket`.
+    pub handshake_rate_limiter: Option<Arc<HandshakeRateLimiter>>,
+    /// Limit queries executing concurrently, share the same instance for
+    /// all connections.
+    pub admission_controller: Option<Arc<AdmissionController>>,
+    /// Registry of running queries, used to cancel them on `CancelRequest`.
+    /// Connections must share the same registry to cancel queries of each
+    /// other, it's `CancelRegistry::global()` by default.
+    pub cancel_registry: Arc<CancelRegistry>,
+    /// Custom types supported by the server, with their codecs.
+    pub type_registry: Arc<TypeRegistry>,
+    /// Receive success and failure events of authentication.
+    pub auth_audit: Option<Arc<dyn AuthAuditHandler>>,
+    /// Delay and error message of failed logins.
+    pub auth_failure_policy: AuthFailurePolicy,
+    /// Reject connections to unknown databases during startup.
+    pub database_validator: Option<Arc<dyn DatabaseValidator>>,
+    /// Publish lifecycle events of connections, share the same instance for
+    /// all connections.
+    pub event_bus: Option<Arc<EventBus>>,
+    /// Answer `DISCARD ALL`, `DISCARD PLANS` and `DEALLOCATE` in simple query
+    /// without calling `do_query`, see `session` module.
+    pub handle_session_commands: bool,
+    /// Max bytes of response data buffered for each connection, see
+    /// `memory` module. Rows of suspended portals and `COPY` data beyond it
+    /// are rejected with error `53200`, and pending `DataRow` messages are
+    /// flushed. `None` for unlimited.
+    pub max_buffered_bytes: Option<usize>,
+    /// Switch of read-only mode, see `readonly` module. Share the same
+    /// instance for all connections to flip them together.
+    pub read_only_mode: Option<Arc<ReadOnlyMode>>,
+    /// Reject new startups with `57P03` during overload or maintenance, see
+    /// `overload` module. Share the same instance for all connections.
+    pub load_shedder: Option<Arc<LoadShedder>>,
+    /// Close the connection when writing to the client makes no progress
+    /// for this long, because the client stopped reading. It's reported as
+    /// `DisconnectReason::WriteTimeout`. `None` waits forever.
+    pub write_timeout: Option<Duration>,
+    /// Options of accepted TCP sockets.
+    pub socket_options: SocketOptions,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            data_row_flush_rows: 0,
+            data_row_flush_bytes: 8 * 1024,
+            write_buffer_high_watermark: 64 * 1024,
+            portal_store_limits: PortalStoreLimits::default(),
+            cache_describe_statement: false,
+            capture: None,
+            disconnect_policy: Arc::new(DefaultDisconnectPolicy::default()),
+            handshake_rate_limiter: None,
+            admission_controller: None,
+            cancel_registry: CancelRegistry::global(),
+            type_registry: Arc::new(TypeRegistry::default()),
+            auth_audit: None,
+            auth_failure_policy: AuthFailurePolicy::default(),
+            database_validator: None,
+            event_bus: None,
+            handle_session_commands: true,
+            max_buffered_bytes: None,
+            read_only_mode: None,
+            load_shedder: None,
+            write_timeout: None,
+            socket_options: SocketOptions::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Shared `ServerConfig::default()`, for `ClientInfo` implementations
+    /// without their own config.
+    pub(crate) fn default_ref() -> &'static ServerConfig {
+        static DEFAULT: OnceLock<ServerConfig> = OnceLock::new();
+        DEFAULT.get_or_init(ServerConfig::default)
+    }
+
+    /// Test if buffered rows should be flushed to the client.
+    pub fn should_flush_data_rows(&self, rows: usize, bytes: usize) -> bool {
+        (self.data_row_flush_rows > 0 && rows >= self.data_row_flush_rows)
+            || (self.data_row_flush_bytes > 0 && bytes >= self.data_row_flush_bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_should_flush_data_rows() {
+        let config = ServerConfig::default();
+        assert!(!config.should_flush_data_rows(1000, 100));
+        assert!(config.should_flush_data_rows(1, 8 * 1024));
+
+        let config = ServerConfig {
+            data_row_flush_rows: 100,
+            data_row_flush_bytes: 0,
+            ..Default::default()
+        };
+        assert!(!config.should_flush_data_rows(99, 1024 * 1024));
+        assert!(config.should_flush_data_rows(100, 0));
+    }
+
+    #[test]
+    fn test_default_disconnect_policy() {
+        let error = |severity: &str, code: &str| {
+            ErrorInfo::new(severity.to_owned(), code.to_owned(), "error".to_owned())
+        };
+        let policy = DefaultDisconnectPolicy::default();
+        assert!(!policy.should_disconnect(&error("ERROR", "42601")));
+        assert!(policy.should_disconnect(&error("FATAL", "28P01")));
+        assert!(policy.should_disconnect(&error("PANIC", "XX000")));
+        assert!(policy.should_disconnect(&error("ERROR", "57P01")));
+
+        let policy = DefaultDisconnectPolicy {
+            sqlstates: vec!["53300".to_owned()],
+        };
+        assert!(policy.should_disconnect(&error("ERROR", "53300")));
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::Sink;
+
+use super::ClientInfo;
+use crate::error::{ErrorInfo, PgWireError, PgWireResult};
+use crate::messages::{PgWireBackendMessage, UnknownMessage};
+
+/// Decision of `ConnectionHandler::on_connect`
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum ConnectDecision {
+    /// Continue with the startup process
+    Accept,
+    /// Send the error to client and close the connection
+    Reject(Box<ErrorInfo>),
+    /// Close the connection without any response
+    Close,
+}
+
+/// Reason of a connection being closed
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// Client sent `Terminate` message
+    Terminate,
+    /// Socket closed by client without `Terminate`
+    ConnectionClosed,
+    /// Connection closed due to IO error or a fatal error
+    Error,
+    /// A handler panicked while processing a message of the connection
+    Panic,
+    /// Client stopped reading responses for `ServerConfig::write_timeout`
+    WriteTimeout,
+}
+
+/// Outcome of a connection, returned by `process_socket` when the connection
+/// is closed
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionSummary {
+    /// Address of client
+    pub addr: SocketAddr,
+    /// Time since the connection was accepted
+    pub duration: Duration,
+    /// Number of simple queries and `Execute` messages processed
+    pub queries: u64,
+    /// Bytes of frontend messages received
+    pub bytes_received: u64,
+    /// Bytes of backend messages sent
+    pub bytes_sent: u64,
+    /// Why the connection was closed, `None` for connections without a
+    /// session: cancel requests and connections refused by
+    /// `ConnectionHandler::on_connect`
+    pub reason: Option<DisconnectReason>,
+    /// Messages received and sent, by message type
+    pub messages: MessageStats,
+}
+
+/// Count and total bytes of messages of one type
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MessageTypeStats {
+    pub count: u64,
+    pub bytes: u64,
+}
+
+/// Messages received and sent on a connection, keyed by message type byte,
+/// like `b'Q'` for `Query` and `b'D'` for `DataRow`.
+///
+/// Packets without type byte, which are startup message, `SSLRequest`,
+/// `CancelRequest` and `SslResponse`, are counted under `0`.
+#[non_exhaustive]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MessageStats {
+    pub received: BTreeMap<u8, MessageTypeStats>,
+    pub sent: BTreeMap<u8, MessageTypeStats>,
+}
+
+impl MessageStats {
+    /// Stats of received messages of the type
+    pub fn received(&self, message_type: u8) -> MessageTypeStats {
+        self.received
+            .get(&message_type)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Stats of sent messages of the type
+    pub fn sent(&self, message_type: u8) -> MessageTypeStats {
+        self.sent.get(&message_type).copied().unwrap_or_default()
+    }
+
+    pub(crate) fn record_received(&mut self, message_type: u8, bytes: usize) {
+        record(&mut self.received, message_type, bytes);
+    }
+
+    pub(crate) fn record_sent(&mut self, message_type: u8, bytes: usize) {
+        record(&mut self.sent, message_type, bytes);
+    }
+}
+
+fn record(stats: &mut BTreeMap<u8, MessageTypeStats>, message_type: u8, bytes: usize) {
+    let entry = stats.entry(message_type).or_default();
+    entry.count += 1;
+    entry.bytes += bytes as u64;
+}
+
+/// handler for connection lifecycle events
+#[async_trait]
+pub trait ConnectionHandler: Send + Sync {
+    /// Called when a new connection is accepted, after TLS negotiation and
+    /// before the client sends its startup message.
+    ///
+    /// `socket_addr` and `is_secure` of the client are available at this
+    /// point, which can be used for IP allow/deny lists or per-source
+    /// throttling. The connection is closed unless `ConnectDecision::Accept`
+    /// is returned.
+    async fn on_connect<C>(&self, _client: &C) -> ConnectDecision
+    where
+        C: ClientInfo + Send + Sync,
+    {
+        ConnectDecision::Accept
+    }
+
+    /// Called when the client connection is closed, either by client sending
+    /// `Terminate` or the socket closed unexpectedly.
+    ///
+    /// This is the place to release session scoped resources like temp tables,
+    /// locks or pooled upstream connections.
+    async fn on_terminate<C>(&self, _client: &C, _reason: DisconnectReason)
+    where
+        C: ClientInfo + Send + Sync,
+    {
+    }
+
+    /// Called when client sends a message of type unknown to pgwire.
+    ///
+    /// Implement this for vendor specific messages, by sending responses to
+    /// `client`, or just log and return `Ok(())` to skip the message. The
+    /// default implementation returns `PgWireError::InvalidMessageType`, which
+    /// is a fatal error that closes the connection.
+    async fn on_unknown_message<C>(
+        &self,
+        _client: &mut C,
+        message: UnknownMessage,
+    ) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        Err(PgWireError::InvalidMessageType(message.message_type))
+    }
+}
+
+/// A noop implementation for `ConnectionHandler`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoopConnectionHandler;
+
+impl ConnectionHandler for NoopConnectionHandler {}
@@ -1,6 +1,10 @@
+use std::fmt::Debug;
+use std::str::FromStr;
+
 use async_trait::async_trait;
+use bytes::{Buf, Bytes, BytesMut};
 use futures::sink::{Sink, SinkExt};
-use std::fmt::Debug;
+use postgres_types::{FromSqlOwned, Type};
 
 use crate::error::{ErrorInfo, PgWireError, PgWireResult};
 use crate::messages::copy::{
@@ -8,13 +12,44 @@ use crate::messages::copy::{
 };
 use crate::messages::PgWireBackendMessage;
 
-use super::results::CopyResponse;
+use super::memory;
+use super::results::{CopyResponse, FieldFormat, FieldInfo};
 use super::ClientInfo;
 
 /// handler for copy messages
 #[async_trait]
 pub trait CopyHandler: Send + Sync {
-    async fn on_copy_data<C>(&self, _client: &mut C, _copy_data: CopyData) -> PgWireResult<()>
+    /// Called on each `CopyData` message.
+    ///
+    /// The default implementation splits data into rows with the
+    /// `CopyDecoder` installed by `send_copy_in_response`, according to the
+    /// format codes of the `CopyInResponse`, and calls `on_copy_rows` with
+    /// decoded rows. Incomplete rows are buffered up to
+    /// `ServerConfig::max_buffered_bytes`.
+    async fn on_copy_data<C>(&self, client: &mut C, copy_data: CopyData) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let rows = if let Some(decoder) = client.extensions_mut().get_mut::<CopyDecoder>() {
+            decoder.feed(&copy_data);
+            decoder.decode_rows()?
+        } else {
+            return Ok(());
+        };
+        // a row larger than the limit can't be decoded
+        memory::check_buffered_memory(client, 0)?;
+
+        if !rows.is_empty() {
+            self.on_copy_rows(client, rows).await?;
+        }
+        Ok(())
+    }
+
+    /// Called with rows decoded from `CopyData` by the default `on_copy_data`
+    /// implementation.
+    async fn on_copy_rows<C>(&self, _client: &mut C, _rows: Vec<CopyRow>) -> PgWireResult<()>
     where
         C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
         C::Error: Debug,
@@ -23,21 +58,25 @@ pub trait CopyHandler: Send + Sync {
         Ok(())
     }
 
-    async fn on_copy_done<C>(&self, _client: &mut C, _done: CopyDone) -> PgWireResult<()>
+    async fn on_copy_done<C>(&self, client: &mut C, _done: CopyDone) -> PgWireResult<()>
     where
         C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
         C::Error: Debug,
         PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
     {
+        if let Some(decoder) = client.extensions_mut().remove::<CopyDecoder>() {
+            decoder.finish()?;
+        }
         Ok(())
     }
 
-    async fn on_copy_fail<C>(&self, _client: &mut C, fail: CopyFail) -> PgWireError
+    async fn on_copy_fail<C>(&self, client: &mut C, fail: CopyFail) -> PgWireError
     where
         C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
         C::Error: Debug,
         PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
     {
+        client.extensions_mut().remove::<CopyDecoder>();
         PgWireError::UserError(Box::new(ErrorInfo::new(
             "ERROR".to_owned(),
             "XX000".to_owned(),
@@ -52,6 +91,7 @@ where
     C::Error: Debug,
     PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
 {
+    client.extensions_mut().insert(CopyDecoder::new(&resp));
     let resp = CopyInResponse::new(resp.format, resp.columns as i16, resp.column_formats);
     client
         .send(PgWireBackendMessage::CopyInResponse(resp))
@@ -85,7 +125,542 @@ where
     Ok(())
 }
 
+/// Builder of `CopyResponse` for `Response::CopyIn`, `Response::CopyOut` and
+/// `Response::CopyBoth`.
+///
+/// Postgres requires all columns to be textual for a textual copy, while
+/// binary copy uses binary for each column. The builder derives per-column
+/// format codes from the overall format, or from a schema with
+/// `from_schema`, and checks they agree when `build` is called.
+///
+/// ```
+/// use pgwire::api::copy::CopyResponseBuilder;
+/// use pgwire::api::results::{FieldFormat, Response};
+///
+/// let resp = CopyResponseBuilder::new(FieldFormat::Text)
+///     .columns(3)
+///     .build()
+///     .unwrap();
+/// let _ = Response::CopyIn(resp);
+/// ```
+#[derive(Debug, Clone)]
+pub struct CopyResponseBuilder {
+    format: FieldFormat,
+    columns: Option<usize>,
+    column_formats: Option<Vec<FieldFormat>>,
+}
+
+impl CopyResponseBuilder {
+    /// Create a builder with overall format of the copy
+    pub fn new(format: FieldFormat) -> CopyResponseBuilder {
+        CopyResponseBuilder {
+            format,
+            columns: None,
+            column_formats: None,
+        }
+    }
+
+    /// Create a builder for textual copy, like `text` or `csv` format of
+    /// `COPY` statement
+    pub fn text() -> CopyResponseBuilder {
+        Self::new(FieldFormat::Text)
+    }
+
+    /// Create a builder for binary copy
+    pub fn binary() -> CopyResponseBuilder {
+        Self::new(FieldFormat::Binary)
+    }
+
+    /// Create a builder with columns and their formats from `schema`. The
+    /// copy is binary if any of the columns is binary.
+    pub fn from_schema(schema: &[FieldInfo]) -> CopyResponseBuilder {
+        let column_formats = schema.iter().map(|f| f.format()).collect::<Vec<_>>();
+        let format = if column_formats.contains(&FieldFormat::Binary) {
+            FieldFormat::Binary
+        } else {
+            FieldFormat::Text
+        };
+
+        Self::new(format).column_formats(column_formats)
+    }
+
+    /// Set number of columns
+    pub fn columns(mut self, columns: usize) -> CopyResponseBuilder {
+        self.columns = Some(columns);
+        self
+    }
+
+    /// Set format of each column
+    pub fn column_formats(mut self, column_formats: Vec<FieldFormat>) -> CopyResponseBuilder {
+        self.column_formats = Some(column_formats);
+        self
+    }
+
+    /// Validate and build the `CopyResponse`
+    pub fn build(self) -> PgWireResult<CopyResponse> {
+        let column_formats = match (self.columns, self.column_formats) {
+            (Some(columns), Some(formats)) if columns != formats.len() => {
+                return Err(PgWireError::InvalidCopyResponse(format!(
+                    "{} column formats provided for {columns} columns",
+                    formats.len()
+                )));
+            }
+            (_, Some(formats)) => formats,
+            (Some(columns), None) => vec![self.format; columns],
+            (None, None) => vec![],
+        };
+
+        if column_formats.len() > i16::MAX as usize {
+            return Err(PgWireError::InvalidCopyResponse(format!(
+                "too many columns: {}",
+                column_formats.len()
+            )));
+        }
+        if self.format == FieldFormat::Text && column_formats.contains(&FieldFormat::Binary) {
+            return Err(PgWireError::InvalidCopyResponse(
+                "binary column is not allowed in textual copy".to_owned(),
+            ));
+        }
+
+        Ok(CopyResponse::new(
+            self.format.value() as i8,
+            column_formats.len(),
+            column_formats.iter().map(FieldFormat::value).collect(),
+        ))
+    }
+}
+
 #[derive(Clone, Copy, Debug, Default)]
 pub struct NoopCopyHandler;
 
 impl CopyHandler for NoopCopyHandler {}
+
+const BINARY_COPY_SIGNATURE: &[u8] = b"PGCOPY\n\xff\r\n\0";
+
+fn bad_copy_format(message: &str) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        "ERROR".to_owned(),
+        "22P04".to_owned(),
+        message.to_owned(),
+    )))
+}
+
+/// A row decoded from COPY data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CopyRow {
+    fields: Vec<Option<Bytes>>,
+    formats: Vec<FieldFormat>,
+}
+
+impl CopyRow {
+    /// Get number of fields
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    /// Get raw bytes of the field, `None` for null value
+    pub fn raw(&self, idx: usize) -> PgWireResult<Option<&Bytes>> {
+        self.fields
+            .get(idx)
+            .map(Option::as_ref)
+            .ok_or(PgWireError::ParameterIndexOutOfBound(idx))
+    }
+
+    /// Get format of the field
+    pub fn format(&self, idx: usize) -> FieldFormat {
+        self.formats.get(idx).copied().unwrap_or(FieldFormat::Text)
+    }
+
+    /// Attempt to get value of field at given index as type `T`.
+    ///
+    /// Binary fields are decoded with `FromSql`, and text fields are parsed
+    /// with `FromStr`.
+    pub fn get<T>(&self, idx: usize, pg_type: &Type) -> PgWireResult<Option<T>>
+    where
+        T: FromSqlOwned + FromStr,
+        <T as FromStr>::Err: std::error::Error + Send + Sync + 'static,
+    {
+        match self.format(idx) {
+            FieldFormat::Binary => self.get_binary(idx, pg_type),
+            FieldFormat::Text => self.get_text(idx),
+        }
+    }
+
+    /// Decode binary field at given index as type `T`.
+    pub fn get_binary<T>(&self, idx: usize, pg_type: &Type) -> PgWireResult<Option<T>>
+    where
+        T: FromSqlOwned,
+    {
+        if !T::accepts(pg_type) {
+            return Err(PgWireError::InvalidRustTypeForParameter(
+                pg_type.name().to_owned(),
+            ));
+        }
+
+        self.raw(idx)?
+            .map(|v| T::from_sql(pg_type, v).map_err(PgWireError::FailedToParseParameter))
+            .transpose()
+    }
+
+    /// Parse text field at given index as type `T`.
+    pub fn get_text<T>(&self, idx: usize) -> PgWireResult<Option<T>>
+    where
+        T: FromStr,
+        <T as FromStr>::Err: std::error::Error + Send + Sync + 'static,
+    {
+        self.raw(idx)?
+            .map(|v| {
+                std::str::from_utf8(v)
+                    .map_err(|e| PgWireError::FailedToParseParameter(Box::new(e)))?
+                    .parse::<T>()
+                    .map_err(|e| PgWireError::FailedToParseParameter(Box::new(e)))
+            })
+            .transpose()
+    }
+}
+
+/// Decoder that splits `CopyData` stream into rows
+///
+/// Rows may span multiple `CopyData` messages so incomplete data is buffered
+/// until next `feed`. Text format follows postgres `COPY ... FROM STDIN` text
+/// format with tab delimiter, and binary format follows postgres binary
+/// `COPY` format.
+#[derive(Debug)]
+pub struct CopyDecoder {
+    format: FieldFormat,
+    formats: Vec<FieldFormat>,
+    buf: BytesMut,
+    header_read: bool,
+    finished: bool,
+}
+
+impl CopyDecoder {
+    /// Create decoder for the format codes of a `CopyInResponse`
+    pub fn new(resp: &CopyResponse) -> CopyDecoder {
+        let format = FieldFormat::from(resp.format as i16);
+        let formats = if resp.column_formats.is_empty() {
+            vec![format; resp.columns]
+        } else {
+            resp.column_formats
+                .iter()
+                .map(|f| FieldFormat::from(*f))
+                .collect()
+        };
+
+        CopyDecoder {
+            format,
+            formats,
+            buf: BytesMut::new(),
+            header_read: false,
+            finished: false,
+        }
+    }
+
+    /// Append data to the buffer
+    pub fn feed(&mut self, copy_data: &CopyData) {
+        self.buf.extend_from_slice(&copy_data.data);
+    }
+
+    /// Bytes of data buffered for incomplete rows
+    pub fn buffered_len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Decode all complete rows in the buffer
+    pub fn decode_rows(&mut self) -> PgWireResult<Vec<CopyRow>> {
+        let mut rows = Vec::new();
+        while let Some(row) = self.decode_row()? {
+            rows.push(row);
+        }
+        Ok(rows)
+    }
+
+    /// Check if there is incomplete data left at the end of COPY
+    pub fn finish(&self) -> PgWireResult<()> {
+        if self.buf.is_empty() || self.finished {
+            Ok(())
+        } else {
+            Err(bad_copy_format("unexpected end of COPY data"))
+        }
+    }
+
+    fn decode_row(&mut self) -> PgWireResult<Option<CopyRow>> {
+        if self.finished {
+            return Ok(None);
+        }
+
+        let fields = match self.format {
+            FieldFormat::Text => self.decode_text_row()?,
+            FieldFormat::Binary => self.decode_binary_row()?,
+        };
+
+        if let Some(ref fields) = fields {
+            if fields.len() != self.formats.len() {
+                return Err(bad_copy_format(&format!(
+                    "expected {} columns in COPY data, got {}",
+                    self.formats.len(),
+                    fields.len()
+                )));
+            }
+        }
+
+        Ok(fields.map(|fields| CopyRow {
+            fields,
+            formats: self.formats.clone(),
+        }))
+    }
+
+    fn decode_text_row(&mut self) -> PgWireResult<Option<Vec<Option<Bytes>>>> {
+        let Some(pos) = self.buf.iter().position(|b| *b == b'\n') else {
+            return Ok(None);
+        };
+
+        let mut line = self.buf.split_to(pos + 1);
+        line.truncate(pos);
+        if line.last() == Some(&b'\r') {
+            line.truncate(pos - 1);
+        }
+
+        // end-of-data marker
+        if line.as_ref() == b"\\." {
+            self.finished = true;
+            return Ok(None);
+        }
+
+        line[..]
+            .split(|b| *b == b'\t')
+            .map(|field| {
+                if field == b"\\N" {
+                    Ok(None)
+                } else {
+                    unescape_text_field(field).map(Some)
+                }
+            })
+            .collect::<PgWireResult<Vec<_>>>()
+            .map(Some)
+    }
+
+    fn decode_binary_row(&mut self) -> PgWireResult<Option<Vec<Option<Bytes>>>> {
+        if !self.header_read {
+            // signature, flags and header extension length
+            if self.buf.len() < BINARY_COPY_SIGNATURE.len() + 8 {
+                return Ok(None);
+            }
+            if &self.buf[..BINARY_COPY_SIGNATURE.len()] != BINARY_COPY_SIGNATURE {
+                return Err(bad_copy_format("COPY file signature not recognized"));
+            }
+            let ext_len_offset = BINARY_COPY_SIGNATURE.len() + 4;
+            let ext_len = (&self.buf[ext_len_offset..]).get_i32();
+            if ext_len < 0 {
+                return Err(bad_copy_format("invalid COPY file header"));
+            }
+            let header_len = ext_len_offset + 4 + ext_len as usize;
+            if self.buf.len() < header_len {
+                return Ok(None);
+            }
+            self.buf.advance(header_len);
+            self.header_read = true;
+        }
+
+        // check if the whole tuple is available before consuming it
+        if self.buf.len() < 2 {
+            return Ok(None);
+        }
+        let mut peek = &self.buf[..];
+        let field_count = peek.get_i16();
+        if field_count == -1 {
+            self.buf.advance(2);
+            self.finished = true;
+            return Ok(None);
+        }
+        if field_count < 0 {
+            return Err(bad_copy_format("invalid COPY tuple field count"));
+        }
+
+        let mut tuple_len = 2;
+        for _ in 0..field_count {
+            if peek.remaining() < 4 {
+                return Ok(None);
+            }
+            let len = peek.get_i32();
+            tuple_len += 4;
+            if len > 0 {
+                let len = len as usize;
+                if peek.remaining() < len {
+                    return Ok(None);
+                }
+                peek.advance(len);
+                tuple_len += len;
+            }
+        }
+
+        let mut tuple = self.buf.split_to(tuple_len).freeze();
+        tuple.advance(2);
+        let fields = (0..field_count)
+            .map(|_| {
+                let len = tuple.get_i32();
+                if len < 0 {
+                    None
+                } else {
+                    Some(tuple.split_to(len as usize))
+                }
+            })
+            .collect();
+
+        Ok(Some(fields))
+    }
+}
+
+fn unescape_text_field(field: &[u8]) -> PgWireResult<Bytes> {
+    if !field.contains(&b'\\') {
+        return Ok(Bytes::copy_from_slice(field));
+    }
+
+    let mut result = Vec::with_capacity(field.len());
+    let mut iter = field.iter().copied().peekable();
+    while let Some(b) = iter.next() {
+        if b != b'\\' {
+            result.push(b);
+            continue;
+        }
+
+        match iter.next() {
+            Some(b'b') => result.push(0x08),
+            Some(b'f') => result.push(0x0c),
+            Some(b'n') => result.push(b'\n'),
+            Some(b'r') => result.push(b'\r'),
+            Some(b't') => result.push(b'\t'),
+            Some(b'v') => result.push(0x0b),
+            Some(b'x') => {
+                let mut value = 0u8;
+                let mut digits = 0;
+                while digits < 2 {
+                    match iter.peek().and_then(|c| (*c as char).to_digit(16)) {
+                        Some(d) => {
+                            value = value * 16 + d as u8;
+                            iter.next();
+                            digits += 1;
+                        }
+                        None => break,
+                    }
+                }
+                if digits == 0 {
+                    result.push(b'x');
+                } else {
+                    result.push(value);
+                }
+            }
+            Some(c @ b'0'..=b'7') => {
+                let mut value = (c - b'0') as u32;
+                for _ in 0..2 {
+                    match iter.peek() {
+                        Some(d @ b'0'..=b'7') => {
+                            value = value * 8 + (d - b'0') as u32;
+                            iter.next();
+                        }
+                        _ => break,
+                    }
+                }
+                result.push(value as u8);
+            }
+            Some(c) => result.push(c),
+            None => return Err(bad_copy_format("unexpected end of line in COPY data")),
+        }
+    }
+
+    Ok(Bytes::from(result))
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::BufMut;
+
+    use super::*;
+
+    #[test]
+    fn test_copy_response_builder() {
+        let resp = CopyResponseBuilder::binary().columns(2).build().unwrap();
+        assert_eq!(1, resp.format);
+        assert_eq!(2, resp.columns);
+        assert_eq!(vec![1, 1], resp.column_formats);
+
+        let schema = vec![
+            FieldInfo::new("id".to_owned(), None, None, Type::INT4, FieldFormat::Text),
+            FieldInfo::new("name".to_owned(), None, None, Type::TEXT, FieldFormat::Text),
+        ];
+        let resp = CopyResponseBuilder::from_schema(&schema).build().unwrap();
+        assert_eq!(0, resp.format);
+        assert_eq!(vec![0, 0], resp.column_formats);
+
+        assert!(CopyResponseBuilder::text()
+            .columns(3)
+            .column_formats(vec![FieldFormat::Text; 2])
+            .build()
+            .is_err());
+        assert!(CopyResponseBuilder::text()
+            .column_formats(vec![FieldFormat::Text, FieldFormat::Binary])
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_decode_text_rows() {
+        let mut decoder = CopyDecoder::new(&CopyResponse::new(0, 3, vec![0, 0, 0]));
+
+        decoder.feed(&CopyData::new(Bytes::from_static(b"1\tTom\t\\N\n2\tJe")));
+        let rows = decoder.decode_rows().unwrap();
+        assert_eq!(1, rows.len());
+        assert_eq!(Some(1i32), rows[0].get_text(0).unwrap());
+        assert_eq!(Some("Tom".to_owned()), rows[0].get_text(1).unwrap());
+        assert_eq!(None, rows[0].raw(2).unwrap());
+        assert!(decoder.finish().is_err());
+
+        decoder.feed(&CopyData::new(Bytes::from_static(
+            b"rry\\tJr\t\\101\n\\.\n",
+        )));
+        let rows = decoder.decode_rows().unwrap();
+        assert_eq!(1, rows.len());
+        assert_eq!(Some(2i32), rows[0].get(0, &Type::INT4).unwrap());
+        assert_eq!(Some("Jerry\tJr".to_owned()), rows[0].get_text(1).unwrap());
+        assert_eq!(Some("A".to_owned()), rows[0].get_text(2).unwrap());
+        assert!(decoder.finish().is_ok());
+
+        let mut decoder = CopyDecoder::new(&CopyResponse::new(0, 2, vec![0, 0]));
+        decoder.feed(&CopyData::new(Bytes::from_static(b"1\n")));
+        assert!(decoder.decode_rows().is_err());
+    }
+
+    #[test]
+    fn test_decode_binary_rows() {
+        let mut data = BytesMut::new();
+        data.put_slice(BINARY_COPY_SIGNATURE);
+        data.put_i32(0);
+        data.put_i32(0);
+        // row
+        data.put_i16(2);
+        data.put_i32(4);
+        data.put_i32(42);
+        data.put_i32(-1);
+        // trailer
+        data.put_i16(-1);
+        let data = data.freeze();
+
+        let mut decoder = CopyDecoder::new(&CopyResponse::new(1, 2, vec![1, 1]));
+        // feed in small chunks to test buffering
+        let mut rows = Vec::new();
+        for chunk in data.chunks(5) {
+            decoder.feed(&CopyData::new(Bytes::copy_from_slice(chunk)));
+            rows.extend(decoder.decode_rows().unwrap());
+        }
+
+        assert_eq!(1, rows.len());
+        assert_eq!(2, rows[0].len());
+        assert_eq!(Some(42i32), rows[0].get(0, &Type::INT4).unwrap());
+        assert_eq!(None::<i32>, rows[0].get(1, &Type::INT4).unwrap());
+        assert!(decoder.finish().is_ok());
+    }
+}
@@ -0,0 +1,312 @@
+//! Types not defined in `postgres_types::Type`, like user defined types and
+//! types of extensions.
+//!
+//! Use `CustomType::to_type` to get a `Type` for `FieldInfo` and parameter
+//! types of `DescribeStatementResponse`, so `RowDescription` and
+//! `ParameterDescription` carry the oid of the custom type.
+//!
+//! Register custom types and their codecs in `ServerConfig::type_registry` to
+//! support them end to end: parameter types of `Parse` are resolved with the
+//! registry, `Portal::parameter` and `DataRowEncoder` convert values of the
+//! registered types between text and binary format with their codecs.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use bytes::BytesMut;
+use postgres_types::{Kind, Oid, Type};
+
+/// Category of a type, as `typcategory` of `pg_type`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TypeCategory {
+    Array,
+    Boolean,
+    Composite,
+    DateTime,
+    Enum,
+    Geometric,
+    NetworkAddress,
+    Numeric,
+    Pseudo,
+    Range,
+    String,
+    Timespan,
+    UserDefined,
+    BitString,
+    Unknown,
+}
+
+impl TypeCategory {
+    /// Get the single character code used by `pg_type.typcategory`
+    pub fn code(&self) -> char {
+        match self {
+            Self::Array => 'A',
+            Self::Boolean => 'B',
+            Self::Composite => 'C',
+            Self::DateTime => 'D',
+            Self::Enum => 'E',
+            Self::Geometric => 'G',
+            Self::NetworkAddress => 'I',
+            Self::Numeric => 'N',
+            Self::Pseudo => 'P',
+            Self::Range => 'R',
+            Self::String => 'S',
+            Self::Timespan => 'T',
+            Self::UserDefined => 'U',
+            Self::BitString => 'V',
+            Self::Unknown => 'X',
+        }
+    }
+}
+
+/// A type identified by an arbitrary oid and name, such as `geometry` of
+/// PostGIS or an enum created by `CREATE TYPE`.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq, new)]
+pub struct CustomType {
+    pub name: String,
+    pub oid: Oid,
+    pub category: TypeCategory,
+    #[new(value = "\"public\".to_owned()")]
+    pub schema: String,
+}
+
+impl CustomType {
+    /// Set schema of the type, it's `public` by default
+    pub fn with_schema(mut self, schema: impl Into<String>) -> CustomType {
+        self.schema = schema.into();
+        self
+    }
+
+    /// Get `Type` of this custom type
+    pub fn to_type(&self) -> Type {
+        let kind = match self.category {
+            TypeCategory::Pseudo => Kind::Pseudo,
+            _ => Kind::Simple,
+        };
+        Type::new(self.name.clone(), self.oid, kind, self.schema.clone())
+    }
+}
+
+impl From<&CustomType> for Type {
+    fn from(custom_type: &CustomType) -> Type {
+        custom_type.to_type()
+    }
+}
+
+impl From<CustomType> for Type {
+    fn from(custom_type: CustomType) -> Type {
+        custom_type.to_type()
+    }
+}
+
+/// Conversion of a custom type between text and binary format.
+///
+/// Values of custom types are handled in text format by handlers: they are
+/// encoded with `ToSqlText` and parameters are read as text. The codec
+/// converts them when client uses binary format.
+pub trait TypeCodec: Debug + Send + Sync {
+    /// Convert a value in text format to binary format
+    fn text_to_binary(
+        &self,
+        text: &str,
+        out: &mut BytesMut,
+    ) -> Result<(), Box<dyn Error + Send + Sync>>;
+
+    /// Convert a value in binary format to text format
+    fn binary_to_text(&self, binary: &[u8]) -> Result<String, Box<dyn Error + Send + Sync>>;
+}
+
+#[derive(Debug, Clone)]
+struct RegisteredType {
+    custom_type: CustomType,
+    codec: Option<Arc<dyn TypeCodec>>,
+}
+
+/// Custom types known by the server, keyed by oid.
+///
+/// ```
+/// use pgwire::api::custom_types::{CustomType, TypeCategory, TypeRegistry};
+///
+/// let mut registry = TypeRegistry::new();
+/// registry.register(CustomType::new(
+///     "mood".to_owned(),
+///     17001,
+///     TypeCategory::Enum,
+/// ));
+/// assert_eq!("mood", registry.type_from_oid(17001).name());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct TypeRegistry {
+    types: HashMap<Oid, RegisteredType>,
+}
+
+impl TypeRegistry {
+    pub fn new() -> TypeRegistry {
+        TypeRegistry::default()
+    }
+
+    /// Register a type whose values are only exchanged in text format
+    pub fn register(&mut self, custom_type: CustomType) {
+        self.types.insert(
+            custom_type.oid,
+            RegisteredType {
+                custom_type,
+                codec: None,
+            },
+        );
+    }
+
+    /// Register a type with codec for binary format
+    pub fn register_with_codec<T>(&mut self, custom_type: CustomType, codec: T)
+    where
+        T: TypeCodec + 'static,
+    {
+        self.types.insert(
+            custom_type.oid,
+            RegisteredType {
+                custom_type,
+                codec: Some(Arc::new(codec)),
+            },
+        );
+    }
+
+    /// Get registered type by oid
+    pub fn get(&self, oid: Oid) -> Option<&CustomType> {
+        self.types.get(&oid).map(|t| &t.custom_type)
+    }
+
+    /// Get registered type by name
+    pub fn get_by_name(&self, name: &str) -> Option<&CustomType> {
+        self.types
+            .values()
+            .map(|t| &t.custom_type)
+            .find(|t| t.name == name)
+    }
+
+    /// Get codec of a registered type
+    pub fn codec(&self, oid: Oid) -> Option<&dyn TypeCodec> {
+        self.types.get(&oid).and_then(|t| t.codec.as_deref())
+    }
+
+    /// Iterate all registered types, for emulating catalogs like `pg_type`
+    pub fn types(&self) -> impl Iterator<Item = &CustomType> {
+        self.types.values().map(|t| &t.custom_type)
+    }
+
+    /// Like `type_from_oid`, but registered types are resolved with their
+    /// names.
+    pub fn type_from_oid(&self, oid: Oid) -> Type {
+        match self.get(oid) {
+            Some(custom_type) => custom_type.to_type(),
+            None => type_from_oid(oid),
+        }
+    }
+}
+
+/// Get `Type` for an oid sent by client, for example parameter types of
+/// `Parse`.
+///
+/// Oid `0` means the type is unspecified and is `Type::UNKNOWN`. Oids not
+/// known by `postgres_types` are kept as types named by the oid, so they are
+/// reported back to client as is.
+pub fn type_from_oid(oid: Oid) -> Type {
+    if oid == 0 {
+        return Type::UNKNOWN;
+    }
+
+    Type::from_oid(oid)
+        .unwrap_or_else(|| Type::new(oid.to_string(), oid, Kind::Simple, "public".to_owned()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::portal::{Format, Portal};
+    use crate::api::results::{DataRowEncoder, FieldFormat, FieldInfo};
+    use crate::messages::data::FieldDescription;
+    use bytes::{BufMut, Bytes};
+
+    #[test]
+    fn test_custom_type() {
+        let geometry = CustomType::new("geometry".to_owned(), 17000, TypeCategory::UserDefined);
+        let ty = geometry.to_type();
+        assert_eq!(17000, ty.oid());
+        assert_eq!("geometry", ty.name());
+        assert_eq!("public", ty.schema());
+        assert_eq!('U', geometry.category.code());
+
+        let field = FieldInfo::builder("geom", ty).build();
+        assert_eq!(17000, FieldDescription::from(&field).type_id);
+
+        assert_eq!(Type::INT4, type_from_oid(23));
+        assert_eq!(Type::UNKNOWN, type_from_oid(0));
+        assert_eq!(17000, type_from_oid(17000).oid());
+    }
+
+    const MOODS: [&str; 3] = ["sad", "ok", "happy"];
+
+    // enum encoded as its index in binary format
+    #[derive(Debug)]
+    struct MoodCodec;
+
+    impl TypeCodec for MoodCodec {
+        fn text_to_binary(
+            &self,
+            text: &str,
+            out: &mut BytesMut,
+        ) -> Result<(), Box<dyn Error + Send + Sync>> {
+            let idx = MOODS
+                .iter()
+                .position(|m| *m == text)
+                .ok_or("invalid mood")?;
+            out.put_u8(idx as u8);
+            Ok(())
+        }
+
+        fn binary_to_text(&self, binary: &[u8]) -> Result<String, Box<dyn Error + Send + Sync>> {
+            binary
+                .first()
+                .and_then(|idx| MOODS.get(*idx as usize))
+                .map(|m| (*m).to_owned())
+                .ok_or_else(|| "invalid mood".into())
+        }
+    }
+
+    #[test]
+    fn test_type_registry() {
+        let mood = CustomType::new("mood".to_owned(), 17001, TypeCategory::Enum);
+        let mut registry = TypeRegistry::new();
+        registry.register_with_codec(mood.clone(), MoodCodec);
+        let registry = Arc::new(registry);
+
+        assert_eq!(Some(&mood), registry.get_by_name("mood"));
+        assert_eq!(1, registry.types().count());
+        assert_eq!("mood", registry.type_from_oid(17001).name());
+        assert_eq!(Type::INT4, registry.type_from_oid(23));
+
+        let fields = Arc::new(vec![FieldInfo::builder("mood", mood.to_type())
+            .format(FieldFormat::Binary)
+            .build()]);
+        let mut encoder = DataRowEncoder::new(fields).with_type_registry(registry.clone());
+        encoder.encode_field(&"happy").unwrap();
+        let row = encoder.finish().unwrap();
+        // length followed by the index
+        assert_eq!(&[0, 0, 0, 1, 2], &row.data[..]);
+
+        let mut portal = Portal::<String>::default().with_type_registry(registry);
+        portal.parameter_format = Format::UnifiedBinary;
+        portal.parameters = vec![Some(Bytes::from_static(&[1])), None].into();
+        assert_eq!(
+            Some("ok".to_owned()),
+            portal.parameter::<String>(0, &mood.to_type()).unwrap()
+        );
+        assert_eq!(
+            None,
+            portal.parameter::<String>(1, &mood.to_type()).unwrap()
+        );
+        assert!(portal.parameter::<i32>(0, &mood.to_type()).is_err());
+    }
+}
@@ -0,0 +1,105 @@
+//! Broadcast of connection lifecycle events.
+//!
+//! Set `ServerConfig::event_bus` and `subscribe` to it, to build activity
+//! views, admin consoles or stats collectors outside of the handler path.
+
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+use tokio::sync::broadcast;
+
+use super::connection::DisconnectReason;
+use super::ClientInfo;
+
+/// What happened to the connection
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionEventKind {
+    /// Connection accepted, before TLS negotiation
+    Accepted,
+    /// TLS handshake finished
+    TlsEstablished,
+    /// Startup process finished and the connection is ready for query
+    Authenticated {
+        user: Option<String>,
+        database: Option<String>,
+    },
+    /// A simple query or an `Execute` started. Text of simple query is
+    /// included.
+    QueryStarted { query: Option<String> },
+    /// The query finished
+    QueryFinished { duration: Duration, success: bool },
+    /// Connection closed
+    Terminated(DisconnectReason),
+}
+
+/// An event of a connection
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionEvent {
+    /// Address of client
+    pub addr: SocketAddr,
+    /// Pid of the connection, `0` before the connection is authenticated
+    pub pid: i32,
+    pub at: SystemTime,
+    pub kind: ConnectionEventKind,
+}
+
+/// Broadcast channel of `ConnectionEvent`, shared by all connections.
+///
+/// Events are dropped when there is no subscriber. A subscriber falling
+/// behind more than `capacity` events receives `RecvError::Lagged` and
+/// misses the oldest events, so the server is never blocked by slow
+/// subscribers.
+#[derive(Debug)]
+pub struct EventBus {
+    sender: broadcast::Sender<ConnectionEvent>,
+}
+
+impl EventBus {
+    pub fn new(capacity: usize) -> EventBus {
+        let (sender, _) = broadcast::channel(capacity);
+        EventBus { sender }
+    }
+
+    /// Receive events sent after this call
+    pub fn subscribe(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Number of active subscribers
+    pub fn subscriber_count(&self) -> usize {
+        self.sender.receiver_count()
+    }
+
+    /// Send an event to all subscribers
+    pub fn publish(&self, event: ConnectionEvent) {
+        // no subscriber is not an error
+        let _ = self.sender.send(event);
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        EventBus::new(1024)
+    }
+}
+
+/// Send event of the client to `ServerConfig::event_bus`. `kind` is only
+/// built when the bus is configured.
+pub(crate) fn publish<C, F>(client: &C, kind: F)
+where
+    C: ClientInfo,
+    F: FnOnce() -> ConnectionEventKind,
+{
+    let Some(bus) = &client.server_config().event_bus else {
+        return;
+    };
+
+    bus.publish(ConnectionEvent {
+        addr: client.socket_addr(),
+        pid: client.pid_and_secret_key().0,
+        at: SystemTime::now(),
+        kind: kind(),
+    });
+}
@@ -0,0 +1,97 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+
+/// A type map for attaching arbitrary session state to a client
+///
+/// Values are keyed by their type, so each type can be stored at most once.
+/// Use newtype wrappers to store several values of the same type.
+#[derive(Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    pub fn new() -> Extensions {
+        Extensions::default()
+    }
+
+    /// Insert a value, returns the previous value of the same type if any.
+    pub fn insert<T: Send + Sync + 'static>(&mut self, val: T) -> Option<T> {
+        self.map
+            .insert(TypeId::of::<T>(), Box::new(val))
+            .and_then(|prev| prev.downcast().ok().map(|b| *b))
+    }
+
+    /// Get reference to value of type `T`
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.map
+            .get(&TypeId::of::<T>())
+            .and_then(|v| v.downcast_ref())
+    }
+
+    /// Get mutable reference to value of type `T`
+    pub fn get_mut<T: Send + Sync + 'static>(&mut self) -> Option<&mut T> {
+        self.map
+            .get_mut(&TypeId::of::<T>())
+            .and_then(|v| v.downcast_mut())
+    }
+
+    /// Remove value of type `T` and return it
+    pub fn remove<T: Send + Sync + 'static>(&mut self) -> Option<T> {
+        self.map
+            .remove(&TypeId::of::<T>())
+            .and_then(|v| v.downcast().ok().map(|b| *b))
+    }
+
+    pub fn contains<T: Send + Sync + 'static>(&self) -> bool {
+        self.map.contains_key(&TypeId::of::<T>())
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.map.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct TenantId(u64);
+
+    #[test]
+    fn test_extensions() {
+        let mut ext = Extensions::new();
+        assert!(ext.is_empty());
+
+        assert_eq!(None, ext.insert(TenantId(1)));
+        assert_eq!(None, ext.insert("claims".to_owned()));
+        assert_eq!(2, ext.len());
+
+        assert_eq!(Some(&TenantId(1)), ext.get::<TenantId>());
+        ext.get_mut::<TenantId>().unwrap().0 = 2;
+        assert_eq!(Some(TenantId(2)), ext.insert(TenantId(3)));
+
+        assert!(ext.contains::<String>());
+        assert_eq!(Some("claims".to_owned()), ext.remove::<String>());
+        assert!(!ext.contains::<String>());
+        assert_eq!(None, ext.get::<u32>());
+    }
+}
@@ -0,0 +1,218 @@
+//! Typed values of session parameters affecting execution.
+//!
+//! Session parameters are stored in client metadata as strings, as they are
+//! received in startup packet, `options` or `SET`. `execution_settings`
+//! parses the common ones, like `statement_timeout` and `search_path`, and
+//! caches the result in client extensions until the raw values change, so
+//! backends can check them on every query without parsing.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::ClientInfo;
+use crate::error::{ErrorInfo, PgWireError, PgWireResult};
+
+/// Parameters parsed into `ExecutionSettings`
+pub const EXECUTION_PARAMETERS: &[&str] = &[
+    "statement_timeout",
+    "lock_timeout",
+    "idle_in_transaction_session_timeout",
+    "search_path",
+];
+
+/// Typed values of execution parameters of a session
+#[non_exhaustive]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExecutionSettings {
+    /// `statement_timeout`, `None` if disabled
+    pub statement_timeout: Option<Duration>,
+    /// `lock_timeout`, `None` if disabled
+    pub lock_timeout: Option<Duration>,
+    /// `idle_in_transaction_session_timeout`, `None` if disabled
+    pub idle_in_transaction_session_timeout: Option<Duration>,
+    /// Schemas of `search_path` in order, unquoted. Empty if not set.
+    pub search_path: Vec<String>,
+}
+
+impl ExecutionSettings {
+    /// Parse execution parameters from client metadata. Missing parameters
+    /// take the default of postgres, which disables the timeouts.
+    pub fn from_metadata(metadata: &HashMap<String, String>) -> PgWireResult<ExecutionSettings> {
+        let timeout = |name: &str| match metadata.get(name) {
+            Some(value) => parse_timeout(name, value),
+            None => Ok(None),
+        };
+        Ok(ExecutionSettings {
+            statement_timeout: timeout("statement_timeout")?,
+            lock_timeout: timeout("lock_timeout")?,
+            idle_in_transaction_session_timeout: timeout("idle_in_transaction_session_timeout")?,
+            search_path: metadata
+                .get("search_path")
+                .map(|value| parse_search_path(value))
+                .unwrap_or_default(),
+        })
+    }
+}
+
+/// Parsed settings with the raw values they are parsed from
+struct CachedExecutionSettings {
+    raw: Vec<Option<String>>,
+    settings: Arc<ExecutionSettings>,
+}
+
+fn raw_values(metadata: &HashMap<String, String>) -> Vec<Option<String>> {
+    EXECUTION_PARAMETERS
+        .iter()
+        .map(|name| metadata.get(*name).cloned())
+        .collect()
+}
+
+/// Execution settings of the client, parsed from metadata only when any of
+/// `EXECUTION_PARAMETERS` changed since the last call.
+///
+/// An invalid value, which can only come from startup parameters or a
+/// backend writing metadata directly, is rejected with `22023`.
+pub fn execution_settings<C>(client: &mut C) -> PgWireResult<Arc<ExecutionSettings>>
+where
+    C: ClientInfo + ?Sized,
+{
+    if let Some(cached) = client.extensions().get::<CachedExecutionSettings>() {
+        let unchanged = EXECUTION_PARAMETERS
+            .iter()
+            .zip(&cached.raw)
+            .all(|(name, raw)| client.metadata().get(*name) == raw.as_ref());
+        if unchanged {
+            return Ok(cached.settings.clone());
+        }
+    }
+
+    let settings = Arc::new(ExecutionSettings::from_metadata(client.metadata())?);
+    let raw = raw_values(client.metadata());
+    client.extensions_mut().insert(CachedExecutionSettings {
+        raw,
+        settings: settings.clone(),
+    });
+    Ok(settings)
+}
+
+fn invalid_value(name: &str, value: &str) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        "ERROR".to_owned(),
+        "22023".to_owned(),
+        format!("invalid value for parameter \"{name}\": \"{value}\""),
+    )))
+}
+
+/// Parse a timeout parameter like `statement_timeout`.
+///
+/// Values without unit are milliseconds, and units `us`, `ms`, `s`, `min`,
+/// `h` and `d` are accepted like postgres. `0` disables the timeout and
+/// returns `None`.
+pub fn parse_timeout(name: &str, value: &str) -> PgWireResult<Option<Duration>> {
+    let trimmed = value.trim();
+    let split = trimmed
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(trimmed.len());
+    let (number, unit) = trimmed.split_at(split);
+    let number = number
+        .parse::<f64>()
+        .map_err(|_| invalid_value(name, value))?;
+    let millis = match unit.trim_start() {
+        "" | "ms" => 1.0,
+        "us" => 0.001,
+        "s" => 1_000.0,
+        "min" => 60_000.0,
+        "h" => 3_600_000.0,
+        "d" => 86_400_000.0,
+        _ => return Err(invalid_value(name, value)),
+    };
+
+    // postgres rounds to milliseconds and limits timeouts to i32::MAX
+    let millis = (number * millis).round();
+    if millis > i32::MAX as f64 {
+        return Err(invalid_value(name, value));
+    }
+    if millis == 0.0 {
+        Ok(None)
+    } else {
+        Ok(Some(Duration::from_millis(millis as u64)))
+    }
+}
+
+/// Split `search_path` into schemas. Quoted schemas keep their case, others
+/// are lower cased like identifiers.
+pub fn parse_search_path(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|schema| !schema.is_empty())
+        .map(
+            |schema| match schema.strip_prefix('"').and_then(|s| s.strip_suffix('"')) {
+                Some(quoted) => quoted.replace("\"\"", "\""),
+                None => schema.to_lowercase(),
+            },
+        )
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::DefaultClient;
+
+    #[test]
+    fn test_parse_timeout() {
+        let timeout = |value| parse_timeout("statement_timeout", value);
+        assert_eq!(Some(Duration::from_millis(500)), timeout("500").unwrap());
+        assert_eq!(Some(Duration::from_secs(5)), timeout("5s").unwrap());
+        assert_eq!(Some(Duration::from_secs(90)), timeout("1.5 min").unwrap());
+        assert_eq!(Some(Duration::from_secs(7200)), timeout("2h").unwrap());
+        assert_eq!(None, timeout("0").unwrap());
+        assert_eq!(None, timeout("100us").unwrap());
+        assert!(timeout("-1").is_err());
+        assert!(timeout("5 seconds").is_err());
+        assert!(timeout("30d").is_err());
+    }
+
+    #[test]
+    fn test_execution_settings() {
+        let mut metadata = HashMap::new();
+        assert_eq!(
+            ExecutionSettings::default(),
+            ExecutionSettings::from_metadata(&metadata).unwrap()
+        );
+
+        metadata.insert("lock_timeout".to_owned(), "1s".to_owned());
+        metadata.insert(
+            "search_path".to_owned(),
+            "\"$user\", Public, \"App\"".to_owned(),
+        );
+        let settings = ExecutionSettings::from_metadata(&metadata).unwrap();
+        assert_eq!(Some(Duration::from_secs(1)), settings.lock_timeout);
+        assert_eq!(None, settings.statement_timeout);
+        assert_eq!(vec!["$user", "public", "App"], settings.search_path);
+    }
+
+    #[test]
+    fn test_cached_execution_settings() {
+        let mut client = DefaultClient::<()>::new("127.0.0.1:5432".parse().unwrap(), false);
+        client
+            .metadata_mut()
+            .insert("statement_timeout".to_owned(), "5s".to_owned());
+        let settings = execution_settings(&mut client).unwrap();
+        assert_eq!(Some(Duration::from_secs(5)), settings.statement_timeout);
+        assert!(Arc::ptr_eq(
+            &settings,
+            &execution_settings(&mut client).unwrap()
+        ));
+
+        client
+            .metadata_mut()
+            .insert("statement_timeout".to_owned(), "0".to_owned());
+        assert_eq!(
+            None,
+            execution_settings(&mut client).unwrap().statement_timeout
+        );
+    }
+}
@@ -0,0 +1,170 @@
+//! Out-of-band messages from background tasks.
+//!
+//! The sink of a connection is owned by its processing loop, and handlers
+//! only get it while processing a message. A `ClientHandle` queues
+//! `NoticeResponse`, `ParameterStatus` and `NotificationResponse` for the
+//! connection from anywhere, for example a task watching configuration
+//! changes or a `LISTEN`/`NOTIFY` implementation.
+//!
+//! Queued messages are only delivered when the connection is idle, that is
+//! in `PgWireConnectionState::ReadyForQuery`, so they never interleave with
+//! the response of a query, extended query batch or copy. Messages queued
+//! during authentication wait until the session is ready.
+
+use tokio::sync::mpsc;
+
+use crate::error::{NoticeInfo, PgWireError, PgWireResult};
+use crate::messages::response::NotificationResponse;
+use crate::messages::startup::ParameterStatus;
+use crate::messages::PgWireBackendMessage;
+
+/// Max messages queued for a connection before `ClientHandle` rejects new
+/// ones
+pub const OUT_OF_BAND_QUEUE_SIZE: usize = 1024;
+
+/// Cheap, clonable handle for sending messages to a connection out of band.
+///
+/// Get it with `ClientInfo::client_handle`. All sends fail with
+/// `ConnectionClosed` after the connection is closed.
+#[derive(Debug, Clone)]
+pub struct ClientHandle {
+    sender: mpsc::Sender<PgWireBackendMessage>,
+}
+
+/// Receiving end of `ClientHandle`, polled by the connection loop
+pub(crate) type OutOfBandReceiver = mpsc::Receiver<PgWireBackendMessage>;
+
+impl ClientHandle {
+    pub(crate) fn channel() -> (ClientHandle, OutOfBandReceiver) {
+        let (sender, receiver) = mpsc::channel(OUT_OF_BAND_QUEUE_SIZE);
+        (ClientHandle { sender }, receiver)
+    }
+
+    fn send(&self, message: PgWireBackendMessage) -> PgWireResult<()> {
+        self.sender.try_send(message).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => PgWireError::OutOfBandQueueFull,
+            mpsc::error::TrySendError::Closed(_) => PgWireError::ConnectionClosed,
+        })
+    }
+
+    /// Queue a `NoticeResponse`
+    pub fn send_notice(&self, notice: NoticeInfo) -> PgWireResult<()> {
+        self.send(PgWireBackendMessage::NoticeResponse(notice.into()))
+    }
+
+    /// Queue a `ParameterStatus`. The value is stored in client metadata when
+    /// the message is delivered, like `Response::ParameterStatus`.
+    pub fn send_parameter_status(
+        &self,
+        name: impl Into<String>,
+        value: impl Into<String>,
+    ) -> PgWireResult<()> {
+        self.send(PgWireBackendMessage::ParameterStatus(ParameterStatus::new(
+            name.into(),
+            value.into(),
+        )))
+    }
+
+    /// Queue a `NotificationResponse` of `LISTEN`/`NOTIFY`
+    pub fn send_notification(&self, notification: NotificationResponse) -> PgWireResult<()> {
+        self.send(PgWireBackendMessage::NotificationResponse(notification))
+    }
+
+    /// Test if the connection is closed
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fmt::Debug;
+
+    use async_trait::async_trait;
+    use futures::Sink;
+
+    use super::*;
+    use crate::api::auth::DefaultServerParameterProvider;
+    use crate::api::query::SimpleQueryHandler;
+    use crate::api::results::{Response, Tag};
+    use crate::api::show::{show_response, ShowStatement};
+    use crate::api::ClientInfo;
+    use crate::messages::response::{CommandComplete, ReadyForQuery, TransactionStatus};
+    use crate::testkit::fixture::{single_value, TestHandlers};
+    use crate::testkit::MockClient;
+
+    /// Queues out-of-band messages on every query except `SHOW`
+    struct OutOfBandHandler;
+
+    #[async_trait]
+    impl SimpleQueryHandler for OutOfBandHandler {
+        async fn do_query<'a, C>(
+            &self,
+            client: &mut C,
+            query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            if let Some(show) = ShowStatement::parse(query) {
+                let provider = DefaultServerParameterProvider::default();
+                return Ok(vec![show_response(client, &provider, &show)?]);
+            }
+            // delivered after this query completes
+            let handle = client.client_handle().expect("handle of connection");
+            handle.send_notice(NoticeInfo::new(
+                "NOTICE".to_owned(),
+                "00000".to_owned(),
+                "out of band".to_owned(),
+            ))?;
+            handle.send_parameter_status("TimeZone", "UTC")?;
+            handle.send_notification(NotificationResponse::new(
+                1,
+                "channel".to_owned(),
+                "payload".to_owned(),
+            ))?;
+            Ok(vec![Response::Execution(Tag::new("NOTIFY"))])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_handle() {
+        let mut client = MockClient::start(TestHandlers::echo().with_simple(OutOfBandHandler));
+        client.startup("tom", None).await.unwrap();
+
+        let messages = client.simple_query("NOTIFY channel").await.unwrap();
+        assert_eq!(
+            vec![
+                PgWireBackendMessage::CommandComplete(CommandComplete::new("NOTIFY".to_owned())),
+                PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(TransactionStatus::Idle)),
+            ],
+            messages
+        );
+
+        assert!(matches!(
+            client.receive().await.unwrap(),
+            Some(PgWireBackendMessage::NoticeResponse(_))
+        ));
+        assert_eq!(
+            Some(PgWireBackendMessage::ParameterStatus(ParameterStatus::new(
+                "TimeZone".to_owned(),
+                "UTC".to_owned()
+            ))),
+            client.receive().await.unwrap()
+        );
+        assert_eq!(
+            Some(PgWireBackendMessage::NotificationResponse(
+                NotificationResponse::new(1, "channel".to_owned(), "payload".to_owned())
+            )),
+            client.receive().await.unwrap()
+        );
+
+        // parameter status is saved to session
+        assert_eq!(
+            b"UTC".to_vec(),
+            single_value(&mut client, "SHOW TimeZone").await
+        );
+    }
+}
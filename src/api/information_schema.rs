@@ -0,0 +1,602 @@
+//! Minimal `information_schema` generated from a schema model.
+//!
+//! BI tools and ORMs discover tables and columns from `information_schema`
+//! instead of `pg_catalog`. Describe your tables with `TableDef` and
+//! `ColumnDef`, and call `InformationSchema::query` from
+//! `SimpleQueryHandler::do_query` to answer queries on the `tables`,
+//! `columns` and `key_column_usage` views:
+//!
+//! ```
+//! use pgwire::api::information_schema::{ColumnDef, InformationSchema, TableDef};
+//! use pgwire::api::Type;
+//!
+//! let schema = InformationSchema::new(
+//!     "postgres",
+//!     vec![TableDef::new("public", "users")
+//!         .column(ColumnDef::new("id", Type::INT4).not_null())
+//!         .column(ColumnDef::new("name", Type::VARCHAR).type_modifier(255 + 4))
+//!         .primary_key(vec!["id".to_owned()])],
+//! );
+//!
+//! let response = schema.query(
+//!     "SELECT column_name, data_type FROM information_schema.columns \
+//!      WHERE table_name = 'users' ORDER BY ordinal_position",
+//! );
+//! assert!(response.is_some());
+//! ```
+//!
+//! Only single table queries with a column list or `*`, and `WHERE`
+//! conditions of `=`, `<>`, `IN` and `NOT IN` joined by `AND` are supported.
+//! `ORDER BY` is accepted but ignored, rows are always in the order of
+//! definitions. Other queries return `None` and should be handled by the
+//! backend as usual.
+
+use std::sync::Arc;
+
+use postgres_types::{Kind, Type};
+
+use super::results::{DataRowEncoder, FieldInfo, QueryResponse, Response};
+use super::typmod;
+use crate::error::PgWireResult;
+
+/// Kind of a table, as `table_type` of `information_schema.tables`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TableType {
+    BaseTable,
+    View,
+}
+
+impl TableType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TableType::BaseTable => "BASE TABLE",
+            TableType::View => "VIEW",
+        }
+    }
+}
+
+/// Definition of a column
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnDef {
+    pub name: String,
+    pub datatype: Type,
+    pub nullable: bool,
+    /// Default expression, like `nextval('users_id_seq'::regclass)`
+    pub default: Option<String>,
+    /// Type modifier, `-1` if there is none
+    pub type_modifier: i32,
+}
+
+impl ColumnDef {
+    /// Nullable column without default value or type modifier
+    pub fn new(name: impl Into<String>, datatype: Type) -> ColumnDef {
+        ColumnDef {
+            name: name.into(),
+            datatype,
+            nullable: true,
+            default: None,
+            type_modifier: -1,
+        }
+    }
+
+    pub fn not_null(mut self) -> ColumnDef {
+        self.nullable = false;
+        self
+    }
+
+    pub fn default(mut self, default: impl Into<String>) -> ColumnDef {
+        self.default = Some(default.into());
+        self
+    }
+
+    /// Type modifier as in `FieldInfo`, like `255 + 4` for `varchar(255)`,
+    /// see `typmod` module
+    pub fn type_modifier(mut self, type_modifier: i32) -> ColumnDef {
+        self.type_modifier = type_modifier;
+        self
+    }
+}
+
+/// Definition of a table or view
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableDef {
+    pub schema: String,
+    pub name: String,
+    pub table_type: TableType,
+    pub columns: Vec<ColumnDef>,
+    /// Columns of primary key, in key order
+    pub primary_key: Vec<String>,
+}
+
+impl TableDef {
+    pub fn new(schema: impl Into<String>, name: impl Into<String>) -> TableDef {
+        TableDef {
+            schema: schema.into(),
+            name: name.into(),
+            table_type: TableType::BaseTable,
+            columns: Vec::new(),
+            primary_key: Vec::new(),
+        }
+    }
+
+    pub fn table_type(mut self, table_type: TableType) -> TableDef {
+        self.table_type = table_type;
+        self
+    }
+
+    pub fn column(mut self, column: ColumnDef) -> TableDef {
+        self.columns.push(column);
+        self
+    }
+
+    pub fn primary_key(mut self, columns: Vec<String>) -> TableDef {
+        self.primary_key = columns;
+        self
+    }
+}
+
+/// Rows of a view in text format
+#[derive(Debug)]
+struct View {
+    fields: Vec<(&'static str, Type)>,
+    rows: Vec<Vec<Option<String>>>,
+}
+
+/// `information_schema` views of a database
+#[derive(Debug, Clone)]
+pub struct InformationSchema {
+    catalog: String,
+    tables: Vec<TableDef>,
+}
+
+/// Name of type used by `data_type` column
+fn data_type_name(ty: &Type) -> String {
+    match *ty {
+        Type::BOOL => "boolean".to_owned(),
+        Type::INT2 => "smallint".to_owned(),
+        Type::INT4 => "integer".to_owned(),
+        Type::INT8 => "bigint".to_owned(),
+        Type::FLOAT4 => "real".to_owned(),
+        Type::FLOAT8 => "double precision".to_owned(),
+        Type::VARCHAR => "character varying".to_owned(),
+        Type::BPCHAR => "character".to_owned(),
+        Type::TIME => "time without time zone".to_owned(),
+        Type::TIMETZ => "time with time zone".to_owned(),
+        Type::TIMESTAMP => "timestamp without time zone".to_owned(),
+        Type::TIMESTAMPTZ => "timestamp with time zone".to_owned(),
+        _ => match ty.kind() {
+            Kind::Array(_) => "ARRAY".to_owned(),
+            Kind::Enum(_) | Kind::Composite(_) | Kind::Domain(_) => "USER-DEFINED".to_owned(),
+            _ => ty.name().to_owned(),
+        },
+    }
+}
+
+/// `character_maximum_length`, `numeric_precision` and `numeric_scale`
+fn type_size(column: &ColumnDef) -> [Option<i32>; 3] {
+    let typmod = column.type_modifier;
+    match column.datatype {
+        Type::VARCHAR | Type::BPCHAR => [typmod::char_length_of(typmod), None, None],
+        Type::NUMERIC => match typmod::numeric_precision_scale(typmod) {
+            Some((precision, scale)) => [None, Some(precision), Some(scale)],
+            None => [None, None, None],
+        },
+        Type::INT2 => [None, Some(16), Some(0)],
+        Type::INT4 => [None, Some(32), Some(0)],
+        Type::INT8 => [None, Some(64), Some(0)],
+        Type::FLOAT4 => [None, Some(24), None],
+        Type::FLOAT8 => [None, Some(53), None],
+        _ => [None, None, None],
+    }
+}
+
+impl InformationSchema {
+    /// `catalog` is the database name, used by `table_catalog` columns
+    pub fn new(catalog: impl Into<String>, tables: Vec<TableDef>) -> InformationSchema {
+        InformationSchema {
+            catalog: catalog.into(),
+            tables,
+        }
+    }
+
+    pub fn table_defs(&self) -> &[TableDef] {
+        &self.tables
+    }
+
+    fn view(&self, name: &str) -> Option<View> {
+        let catalog = || Some(self.catalog.clone());
+        match name {
+            "tables" => Some(View {
+                fields: vec![
+                    ("table_catalog", Type::NAME),
+                    ("table_schema", Type::NAME),
+                    ("table_name", Type::NAME),
+                    ("table_type", Type::VARCHAR),
+                ],
+                rows: self
+                    .tables
+                    .iter()
+                    .map(|t| {
+                        vec![
+                            catalog(),
+                            Some(t.schema.clone()),
+                            Some(t.name.clone()),
+                            Some(t.table_type.as_str().to_owned()),
+                        ]
+                    })
+                    .collect(),
+            }),
+            "columns" => Some(View {
+                fields: vec![
+                    ("table_catalog", Type::NAME),
+                    ("table_schema", Type::NAME),
+                    ("table_name", Type::NAME),
+                    ("column_name", Type::NAME),
+                    ("ordinal_position", Type::INT4),
+                    ("column_default", Type::VARCHAR),
+                    ("is_nullable", Type::VARCHAR),
+                    ("data_type", Type::VARCHAR),
+                    ("character_maximum_length", Type::INT4),
+                    ("numeric_precision", Type::INT4),
+                    ("numeric_scale", Type::INT4),
+                    ("udt_schema", Type::NAME),
+                    ("udt_name", Type::NAME),
+                ],
+                rows: self
+                    .tables
+                    .iter()
+                    .flat_map(|t| {
+                        t.columns.iter().enumerate().map(move |(i, c)| {
+                            let [length, precision, scale] = type_size(c);
+                            vec![
+                                catalog(),
+                                Some(t.schema.clone()),
+                                Some(t.name.clone()),
+                                Some(c.name.clone()),
+                                Some((i + 1).to_string()),
+                                c.default.clone(),
+                                Some(if c.nullable { "YES" } else { "NO" }.to_owned()),
+                                Some(data_type_name(&c.datatype)),
+                                length.map(|v| v.to_string()),
+                                precision.map(|v| v.to_string()),
+                                scale.map(|v| v.to_string()),
+                                Some(c.datatype.schema().to_owned()),
+                                Some(c.datatype.name().to_owned()),
+                            ]
+                        })
+                    })
+                    .collect(),
+            }),
+            "key_column_usage" => Some(View {
+                fields: vec![
+                    ("constraint_catalog", Type::NAME),
+                    ("constraint_schema", Type::NAME),
+                    ("constraint_name", Type::NAME),
+                    ("table_catalog", Type::NAME),
+                    ("table_schema", Type::NAME),
+                    ("table_name", Type::NAME),
+                    ("column_name", Type::NAME),
+                    ("ordinal_position", Type::INT4),
+                ],
+                rows: self
+                    .tables
+                    .iter()
+                    .flat_map(|t| {
+                        // named like postgres does for primary keys
+                        let constraint = format!("{}_pkey", t.name);
+                        t.primary_key.iter().enumerate().map(move |(i, c)| {
+                            vec![
+                                catalog(),
+                                Some(t.schema.clone()),
+                                Some(constraint.clone()),
+                                catalog(),
+                                Some(t.schema.clone()),
+                                Some(t.name.clone()),
+                                Some(c.clone()),
+                                Some((i + 1).to_string()),
+                            ]
+                        })
+                    })
+                    .collect(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Answer a query on `information_schema.tables`, `columns` or
+    /// `key_column_usage`. Returns `None` if the query is not on these views
+    /// or not supported.
+    ///
+    /// Values are encoded in text format, so the response is only valid for
+    /// simple query.
+    pub fn query<'a>(&self, query: &str) -> Option<PgWireResult<Response<'a>>> {
+        let (fields, rows) = self.select(query)?;
+        let fields = Arc::new(
+            fields
+                .into_iter()
+                .map(|(name, datatype)| FieldInfo::builder(name, datatype).build())
+                .collect::<Vec<_>>(),
+        );
+
+        let rows = rows
+            .into_iter()
+            .map(|row| {
+                let mut encoder = DataRowEncoder::new(fields.clone());
+                for value in row {
+                    encoder.encode_field(&value)?;
+                }
+                encoder.finish()
+            })
+            .collect::<PgWireResult<Vec<_>>>();
+
+        Some(rows.map(|rows| Response::Query(QueryResponse::from_rows(fields, rows))))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn select(&self, query: &str) -> Option<(Vec<(&'static str, Type)>, Vec<Vec<Option<String>>>)> {
+        let select = Select::parse(query)?;
+        let view = self.view(&select.view)?;
+        let column_index = |name: &str| view.fields.iter().position(|(f, _)| *f == name);
+
+        let projection = match &select.columns {
+            None => (0..view.fields.len()).collect::<Vec<_>>(),
+            Some(columns) => columns
+                .iter()
+                .map(|c| column_index(c))
+                .collect::<Option<Vec<_>>>()?,
+        };
+        let conditions = select
+            .conditions
+            .iter()
+            .map(|c| Some((column_index(&c.column)?, c)))
+            .collect::<Option<Vec<_>>>()?;
+
+        let fields = projection.iter().map(|i| view.fields[*i].clone()).collect();
+        let rows = view
+            .rows
+            .into_iter()
+            .filter(|row| {
+                conditions
+                    .iter()
+                    .all(|(i, c)| c.matches(row[*i].as_deref()))
+            })
+            .map(|row| projection.iter().map(|i| row[*i].clone()).collect())
+            .collect();
+        Some((fields, rows))
+    }
+}
+
+/// A supported `SELECT` on `information_schema`
+#[derive(Debug, PartialEq, Eq)]
+struct Select {
+    /// `None` for `*`
+    columns: Option<Vec<String>>,
+    view: String,
+    conditions: Vec<Condition>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Condition {
+    column: String,
+    values: Vec<String>,
+    negated: bool,
+}
+
+impl Condition {
+    fn matches(&self, value: Option<&str>) -> bool {
+        match value {
+            // NULL never matches, like SQL
+            None => false,
+            Some(value) => self.values.iter().any(|v| v == value) != self.negated,
+        }
+    }
+
+    fn parse(condition: &str) -> Option<Condition> {
+        let lower = condition.to_ascii_lowercase();
+        let (column, negated, values) = if let Some(i) = lower.find(" not in ") {
+            (&condition[..i], true, list(&condition[i + 8..])?)
+        } else if let Some(i) = lower.find(" in ") {
+            (&condition[..i], false, list(&condition[i + 4..])?)
+        } else if let Some(i) = condition.find("<>").or_else(|| condition.find("!=")) {
+            (&condition[..i], true, vec![literal(&condition[i + 2..])?])
+        } else if let Some(i) = condition.find('=') {
+            (&condition[..i], false, vec![literal(&condition[i + 1..])?])
+        } else {
+            return None;
+        };
+
+        Some(Condition {
+            column: column_name(column)?,
+            values,
+            negated,
+        })
+    }
+}
+
+/// Quoted string literal, with an optional `::type` cast
+fn literal(s: &str) -> Option<String> {
+    let s = s.trim();
+    let s = s.split_once("::").map_or(s, |(v, _)| v).trim_end();
+    let s = s.strip_prefix('\'')?.strip_suffix('\'')?;
+    Some(s.replace("''", "'"))
+}
+
+/// `('a', 'b')`
+fn list(s: &str) -> Option<Vec<String>> {
+    let s = s.trim().strip_prefix('(')?.strip_suffix(')')?;
+    s.split(',').map(literal).collect()
+}
+
+/// Column name, quoted or not, without table qualifier
+fn column_name(s: &str) -> Option<String> {
+    let s = s.trim();
+    let s = s.rsplit_once('.').map_or(s, |(_, c)| c);
+    if let Some(quoted) = s.strip_prefix('"') {
+        return Some(quoted.strip_suffix('"')?.to_owned());
+    }
+    if s.is_empty() || !s.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return None;
+    }
+    Some(s.to_lowercase())
+}
+
+impl Select {
+    fn parse(query: &str) -> Option<Select> {
+        let query = query.trim().trim_end_matches(';').trim_end();
+        // ascii lowercase keeps byte offsets, so they apply to `query`
+        let lower = query.to_ascii_lowercase();
+        if !lower.starts_with("select ") {
+            return None;
+        }
+
+        let from = lower.find(" from ")?;
+        let projection = query["select ".len()..from].trim();
+        let columns = if projection == "*" {
+            None
+        } else {
+            Some(
+                projection
+                    .split(',')
+                    .map(column_name)
+                    .collect::<Option<Vec<_>>>()?,
+            )
+        };
+
+        let rest = query[from + " from ".len()..].trim_start();
+        let rest_lower = rest.to_ascii_lowercase();
+        let view = rest_lower.strip_prefix("information_schema.")?;
+        let view_end = view.find(char::is_whitespace).unwrap_or(view.len());
+        let view_name = view[..view_end].to_owned();
+
+        let mut clauses = &rest["information_schema.".len() + view_end..];
+        if let Some(i) = clauses.to_ascii_lowercase().find(" order by ") {
+            clauses = &clauses[..i];
+        }
+        let clauses = clauses.trim();
+        let clauses_lower = clauses.to_ascii_lowercase();
+
+        let mut conditions = Vec::new();
+        if !clauses.is_empty() {
+            if !clauses_lower.starts_with("where ") {
+                return None;
+            }
+            let mut start = "where ".len();
+            loop {
+                match clauses_lower[start..].find(" and ") {
+                    Some(i) => {
+                        conditions.push(Condition::parse(&clauses[start..start + i])?);
+                        start += i + " and ".len();
+                    }
+                    None => {
+                        conditions.push(Condition::parse(&clauses[start..])?);
+                        break;
+                    }
+                }
+            }
+        }
+
+        Some(Select {
+            columns,
+            view: view_name,
+            conditions,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn schema() -> InformationSchema {
+        InformationSchema::new(
+            "postgres",
+            vec![
+                TableDef::new("public", "users")
+                    .column(ColumnDef::new("id", Type::INT4).not_null())
+                    .column(
+                        ColumnDef::new("name", Type::VARCHAR)
+                            .type_modifier(typmod::char_length(255).unwrap()),
+                    )
+                    .primary_key(vec!["id".to_owned()]),
+                TableDef::new("audit", "events")
+                    .column(ColumnDef::new("at", Type::TIMESTAMPTZ))
+                    .column(
+                        ColumnDef::new("amount", Type::NUMERIC)
+                            .type_modifier(typmod::numeric(10, 2).unwrap()),
+                    ),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_information_schema() {
+        let schema = schema();
+
+        let (fields, rows) = schema
+            .select("SELECT table_name, table_type FROM information_schema.tables WHERE table_schema = 'public'")
+            .unwrap();
+        assert_eq!(2, fields.len());
+        assert_eq!(
+            vec![vec![
+                Some("users".to_owned()),
+                Some("BASE TABLE".to_owned())
+            ]],
+            rows
+        );
+
+        let (_, rows) = schema
+            .select(
+                "select column_name, data_type, character_maximum_length, numeric_precision, numeric_scale \
+                 from information_schema.columns \
+                 where table_schema not in ('pg_catalog', 'information_schema') and is_nullable = 'YES' \
+                 order by ordinal_position;",
+            )
+            .unwrap();
+        assert_eq!(
+            vec![
+                vec![
+                    Some("name".to_owned()),
+                    Some("character varying".to_owned()),
+                    Some("255".to_owned()),
+                    None,
+                    None
+                ],
+                vec![
+                    Some("at".to_owned()),
+                    Some("timestamp with time zone".to_owned()),
+                    None,
+                    None,
+                    None
+                ],
+                vec![
+                    Some("amount".to_owned()),
+                    Some("numeric".to_owned()),
+                    None,
+                    Some("10".to_owned()),
+                    Some("2".to_owned())
+                ],
+            ],
+            rows
+        );
+
+        let (fields, rows) = schema
+            .select("SELECT * FROM information_schema.key_column_usage WHERE table_name = 'users'::name")
+            .unwrap();
+        assert_eq!(8, fields.len());
+        assert_eq!(Some("users_pkey".to_owned()), rows[0][2]);
+        assert_eq!(Some("id".to_owned()), rows[0][6]);
+
+        assert!(schema
+            .select("SELECT * FROM information_schema.views")
+            .is_none());
+        assert!(schema
+            .select("SELECT foo FROM information_schema.tables")
+            .is_none());
+        assert!(schema
+            .select("SELECT * FROM information_schema.tables t JOIN x ON true")
+            .is_none());
+        assert!(schema.select("SELECT * FROM users").is_none());
+    }
+}
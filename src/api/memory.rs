@@ -0,0 +1,144 @@
+//! Memory buffered by a connection for its client.
+//!
+//! Besides statements and portals limited by `PortalStoreLimits`, a
+//! connection buffers response data: encoded messages waiting in the write
+//! buffer, rows of suspended portals, and incomplete rows of `COPY FROM
+//! STDIN`. `ClientInfo::buffered_memory` reports them, and
+//! `ServerConfig::max_buffered_bytes` caps them so a single client can't
+//! exhaust memory of a shared server.
+
+use super::copy::CopyDecoder;
+use super::extensions::Extensions;
+use super::query;
+use super::ClientInfo;
+use crate::error::{ErrorInfo, PgWireError, PgWireResult};
+
+/// Bytes buffered by a connection, by source
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BufferedMemory {
+    /// encoded messages not flushed yet, like pending `DataRow`
+    pub write_buffer: usize,
+    /// rows of suspended portals held in memory, see
+    /// `QueryResponse::buffered_bytes`
+    pub suspended_portals: usize,
+    /// `CopyData` received but not decoded into rows yet
+    pub copy: usize,
+}
+
+impl BufferedMemory {
+    /// Buffered memory tracked in client extensions, without the write
+    /// buffer
+    pub(crate) fn from_extensions(extensions: &Extensions) -> BufferedMemory {
+        BufferedMemory {
+            write_buffer: 0,
+            suspended_portals: query::suspended_portals_bytes(extensions),
+            copy: extensions
+                .get::<CopyDecoder>()
+                .map_or(0, |decoder| decoder.buffered_len()),
+        }
+    }
+
+    /// Total bytes buffered
+    pub fn total(&self) -> usize {
+        self.write_buffer + self.suspended_portals + self.copy
+    }
+}
+
+/// Error returned when buffered memory of a connection exceeds
+/// `ServerConfig::max_buffered_bytes`, `53200` like postgres
+pub fn out_of_memory_error(requested: usize, limit: usize) -> PgWireError {
+    let mut info = ErrorInfo::new(
+        "ERROR".to_owned(),
+        "53200".to_owned(),
+        "out of memory".to_owned(),
+    );
+    info.detail = Some(format!(
+        "Buffering {requested} bytes for the connection exceeds the limit of {limit} bytes."
+    ));
+    PgWireError::UserError(Box::new(info))
+}
+
+/// Check if the client can buffer `additional` bytes more, according to
+/// `ServerConfig::max_buffered_bytes`
+pub fn check_buffered_memory<C>(client: &C, additional: usize) -> PgWireResult<()>
+where
+    C: ClientInfo + ?Sized,
+{
+    if let Some(limit) = client.server_config().max_buffered_bytes {
+        let requested = client.buffered_memory().total() + additional;
+        if requested > limit {
+            return Err(out_of_memory_error(requested, limit));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use crate::api::config::ServerConfig;
+    use crate::api::results::Response;
+    use crate::messages::extendedquery::{Bind, Execute, Parse, Sync as PgSync};
+    use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
+    use crate::testkit::fixture::{error_code, numbers, FnQueryHandler, TestHandlers};
+    use crate::testkit::MockClient;
+
+    #[tokio::test]
+    async fn test_max_buffered_bytes() {
+        // each remaining row takes 12 bytes
+        let config = Arc::new(ServerConfig {
+            max_buffered_bytes: Some(30),
+            ..Default::default()
+        });
+        let handler = FnQueryHandler::new(|_| Ok(vec![Response::Suspended(numbers(3))]));
+        let mut client = MockClient::start_with_config(TestHandlers::new(handler), config);
+        client.startup("tom", None).await.unwrap();
+
+        let execute = |portal: &str, max_rows| {
+            [
+                PgWireFrontendMessage::Bind(Bind::new(
+                    Some(portal.to_owned()),
+                    None,
+                    vec![],
+                    vec![],
+                    vec![],
+                )),
+                PgWireFrontendMessage::Execute(Execute::new(Some(portal.to_owned()), max_rows)),
+            ]
+        };
+        client
+            .send(PgWireFrontendMessage::Parse(Parse::new(
+                None,
+                "SELECT n".to_owned(),
+                vec![],
+            )))
+            .await
+            .unwrap();
+        client.send_all(execute("a", 1)).await.unwrap();
+        // the second suspended portal exceeds the limit
+        client.send_all(execute("b", 1)).await.unwrap();
+        client
+            .send(PgWireFrontendMessage::Sync(PgSync::new()))
+            .await
+            .unwrap();
+        let messages = client.receive_until_ready().await.unwrap();
+        assert!(messages
+            .iter()
+            .any(|m| matches!(m, PgWireBackendMessage::PortalSuspended(_))));
+        assert_eq!(
+            Some("53200".to_owned()),
+            messages.iter().find_map(error_code)
+        );
+
+        // suspended portals are released at sync out of transaction
+        client.send_all(execute("c", 1)).await.unwrap();
+        client
+            .send(PgWireFrontendMessage::Sync(PgSync::new()))
+            .await
+            .unwrap();
+        let messages = client.receive_until_ready().await.unwrap();
+        assert_eq!(None, messages.iter().find_map(error_code));
+    }
+}
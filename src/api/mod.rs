@@ -1,24 +1,58 @@
 //! APIs for building postgresql compatible servers.
 
 use std::collections::HashMap;
+use std::marker::PhantomData;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::SystemTime;
 
+use async_trait::async_trait;
+use bytes::BytesMut;
 pub use postgres_types::Type;
+#[cfg(any(feature = "_ring", feature = "_aws-lc-rs"))]
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_util::sync::CancellationToken;
 
-use crate::error::PgWireError;
+use crate::error::{PgWireError, PgWireResult};
 use crate::messages::response::TransactionStatus;
+use crate::messages::startup::Startup;
 
+pub mod admission;
 pub mod auth;
+pub mod cancel;
+pub mod capture;
 #[cfg(feature = "client-api")]
 pub mod client;
+pub mod compat;
+pub mod config;
+pub mod connection;
 pub mod copy;
+pub mod custom_types;
+pub mod events;
+pub mod extensions;
+pub mod guc;
+pub mod handle;
+pub mod information_schema;
+pub mod memory;
+pub mod overload;
+pub mod pool;
 pub mod portal;
+pub mod proxy;
 pub mod query;
+pub mod ratelimit;
+pub mod readonly;
+pub mod replication;
 pub mod results;
+pub mod router;
+pub mod session;
+pub mod set;
+pub mod show;
 pub mod stmt;
 pub mod store;
+pub mod tenant;
+pub mod timeout;
 pub mod transaction;
+pub mod typmod;
 
 pub const DEFAULT_NAME: &str = "POSTGRESQL_DEFAULT_NAME";
 
@@ -51,6 +85,92 @@ pub trait ClientInfo {
     fn metadata(&self) -> &HashMap<String, String>;
 
     fn metadata_mut(&mut self) -> &mut HashMap<String, String>;
+
+    /// Typed session state attached by handlers. Like `metadata`, there is
+    /// no default because the implementation has to own the storage.
+    fn extensions(&self) -> &extensions::Extensions;
+
+    fn extensions_mut(&mut self) -> &mut extensions::Extensions;
+
+    /// Options of this connection. Returns `ServerConfig::default()` if the
+    /// implementation doesn't carry its own config.
+    fn server_config(&self) -> &config::ServerConfig {
+        config::ServerConfig::default_ref()
+    }
+
+    /// Get pid and secret key sent to client in `BackendKeyData`, `(0, 0)` if
+    /// the connection doesn't support cancellation.
+    fn pid_and_secret_key(&self) -> (i32, i32) {
+        (0, 0)
+    }
+
+    fn set_pid_and_secret_key(&mut self, _pid: i32, _secret_key: i32) {}
+
+    /// Time when the connection was established, `UNIX_EPOCH` if the
+    /// implementation doesn't track it.
+    fn connected_at(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH
+    }
+
+    /// Time when the client finished authentication and became ready for
+    /// query, `None` if it's still in startup
+    fn authenticated_at(&self) -> Option<SystemTime> {
+        None
+    }
+
+    /// Time when the last message was received from client. Defaults to
+    /// `connected_at`.
+    fn last_activity_at(&self) -> SystemTime {
+        self.connected_at()
+    }
+
+    /// Write buffer of the connection, for encoding messages in place like
+    /// `query::feed_data_row` does. Messages written to it are sent with the
+    /// next flush. Returns `None` if messages can only be sent through
+    /// `Sink`.
+    fn write_buffer_mut(&mut self) -> Option<&mut BytesMut> {
+        None
+    }
+
+    /// Count a message of `bytes`, type byte included, written into
+    /// `write_buffer_mut` in `message_stats` and bytes sent, like messages
+    /// sent through `Sink`.
+    fn record_sent(&mut self, _message_type: u8, _bytes: usize) {}
+
+    /// Cancellation token of the query in progress, `None` if there is no
+    /// query running.
+    ///
+    /// The token is cancelled when a `CancelRequest` for this connection
+    /// arrives, when writing to the client fails, or when the connection task
+    /// is dropped, so handlers can abort long-running work early.
+    fn cancellation_token(&self) -> Option<CancellationToken> {
+        None
+    }
+
+    /// Count and bytes of messages received and sent on this connection, by
+    /// message type. `None` if the connection doesn't track them.
+    fn message_stats(&self) -> Option<&connection::MessageStats> {
+        None
+    }
+
+    /// Bytes buffered by this connection for the client, excluding
+    /// statements and portals
+    fn buffered_memory(&self) -> memory::BufferedMemory {
+        memory::BufferedMemory::from_extensions(self.extensions())
+    }
+
+    /// Handle for sending messages to this connection out of band, from
+    /// background tasks. `None` if the connection doesn't support it.
+    fn client_handle(&self) -> Option<handle::ClientHandle> {
+        self.extensions().get::<handle::ClientHandle>().cloned()
+    }
+
+    /// Get certificate chain presented by client in TLS handshake, the first
+    /// one is the client's own certificate.
+    #[cfg(any(feature = "_ring", feature = "_aws-lc-rs"))]
+    fn client_certificates(&self) -> Option<&[CertificateDer<'static>]> {
+        None
+    }
 }
 
 /// Client Portal Store
@@ -58,23 +178,45 @@ pub trait ClientPortalStore {
     type PortalStore;
 
     fn portal_store(&self) -> &Self::PortalStore;
+
+    /// Get usage of statements and portals stored for this client
+    fn portal_store_stats(&self) -> store::PortalStoreStats
+    where
+        Self::PortalStore: store::PortalStore,
+    {
+        store::PortalStore::stats(self.portal_store())
+    }
 }
 
 pub const METADATA_USER: &str = "user";
 pub const METADATA_DATABASE: &str = "database";
 
+/// `ClientInfo` of connections processed by `process_socket`.
+///
+/// Statements and portals are stored in `MemPortalStore` by default, use
+/// `with_portal_store` for other `PortalStore` implementations.
 #[non_exhaustive]
 #[derive(Debug)]
-pub struct DefaultClient<S> {
+pub struct DefaultClient<S, P = store::MemPortalStore<S>> {
     pub socket_addr: SocketAddr,
     pub is_secure: bool,
     pub state: PgWireConnectionState,
     pub transaction_status: TransactionStatus,
     pub metadata: HashMap<String, String>,
-    pub portal_store: store::MemPortalStore<S>,
+    pub extensions: extensions::Extensions,
+    pub portal_store: P,
+    pub server_config: Arc<config::ServerConfig>,
+    pub pid_and_secret_key: (i32, i32),
+    pub connected_at: SystemTime,
+    pub authenticated_at: Option<SystemTime>,
+    pub last_activity_at: SystemTime,
+    pub cancellation_token: Option<CancellationToken>,
+    #[cfg(any(feature = "_ring", feature = "_aws-lc-rs"))]
+    pub client_certificates: Option<Vec<CertificateDer<'static>>>,
+    _statement: PhantomData<S>,
 }
 
-impl<S> ClientInfo for DefaultClient<S> {
+impl<S, P> ClientInfo for DefaultClient<S, P> {
     fn socket_addr(&self) -> SocketAddr {
         self.socket_addr
     }
@@ -88,6 +230,11 @@ impl<S> ClientInfo for DefaultClient<S> {
     }
 
     fn set_state(&mut self, new_state: PgWireConnectionState) {
+        if self.authenticated_at.is_none()
+            && matches!(new_state, PgWireConnectionState::ReadyForQuery)
+        {
+            self.authenticated_at = Some(SystemTime::now());
+        }
         self.state = new_state;
     }
 
@@ -99,6 +246,14 @@ impl<S> ClientInfo for DefaultClient<S> {
         &mut self.metadata
     }
 
+    fn extensions(&self) -> &extensions::Extensions {
+        &self.extensions
+    }
+
+    fn extensions_mut(&mut self) -> &mut extensions::Extensions {
+        &mut self.extensions
+    }
+
     fn transaction_status(&self) -> TransactionStatus {
         self.transaction_status
     }
@@ -106,23 +261,93 @@ impl<S> ClientInfo for DefaultClient<S> {
     fn set_transaction_status(&mut self, new_status: TransactionStatus) {
         self.transaction_status = new_status
     }
+
+    fn server_config(&self) -> &config::ServerConfig {
+        &self.server_config
+    }
+
+    fn pid_and_secret_key(&self) -> (i32, i32) {
+        self.pid_and_secret_key
+    }
+
+    fn set_pid_and_secret_key(&mut self, pid: i32, secret_key: i32) {
+        self.pid_and_secret_key = (pid, secret_key);
+    }
+
+    fn connected_at(&self) -> SystemTime {
+        self.connected_at
+    }
+
+    fn authenticated_at(&self) -> Option<SystemTime> {
+        self.authenticated_at
+    }
+
+    fn last_activity_at(&self) -> SystemTime {
+        self.last_activity_at
+    }
+
+    fn cancellation_token(&self) -> Option<CancellationToken> {
+        self.cancellation_token.clone()
+    }
+
+    #[cfg(any(feature = "_ring", feature = "_aws-lc-rs"))]
+    fn client_certificates(&self) -> Option<&[CertificateDer<'static>]> {
+        self.client_certificates.as_deref()
+    }
 }
 
 impl<S> DefaultClient<S> {
     pub fn new(socket_addr: SocketAddr, is_secure: bool) -> DefaultClient<S> {
+        Self::with_config(
+            socket_addr,
+            is_secure,
+            Arc::new(config::ServerConfig::default()),
+        )
+    }
+
+    /// Create client with given `ServerConfig`
+    pub fn with_config(
+        socket_addr: SocketAddr,
+        is_secure: bool,
+        server_config: Arc<config::ServerConfig>,
+    ) -> DefaultClient<S> {
+        let portal_store = store::MemPortalStore::with_limits(server_config.portal_store_limits);
+        DefaultClient::with_portal_store(socket_addr, is_secure, server_config, portal_store)
+    }
+}
+
+impl<S, P> DefaultClient<S, P> {
+    /// Create client storing statements and portals in `portal_store`
+    pub fn with_portal_store(
+        socket_addr: SocketAddr,
+        is_secure: bool,
+        server_config: Arc<config::ServerConfig>,
+        portal_store: P,
+    ) -> DefaultClient<S, P> {
+        let now = SystemTime::now();
         DefaultClient {
             socket_addr,
             is_secure,
             state: PgWireConnectionState::default(),
             transaction_status: TransactionStatus::Idle,
             metadata: HashMap::new(),
-            portal_store: store::MemPortalStore::new(),
+            extensions: extensions::Extensions::new(),
+            portal_store,
+            server_config,
+            pid_and_secret_key: (0, 0),
+            connected_at: now,
+            authenticated_at: None,
+            last_activity_at: now,
+            cancellation_token: None,
+            #[cfg(any(feature = "_ring", feature = "_aws-lc-rs"))]
+            client_certificates: None,
+            _statement: PhantomData,
         }
     }
 }
 
-impl<S> ClientPortalStore for DefaultClient<S> {
-    type PortalStore = store::MemPortalStore<S>;
+impl<S, P> ClientPortalStore for DefaultClient<S, P> {
+    type PortalStore = P;
 
     fn portal_store(&self) -> &Self::PortalStore {
         &self.portal_store
@@ -147,6 +372,40 @@ pub struct NoopErrorHandler;
 
 impl ErrorHandler for NoopErrorHandler {}
 
+/// Handlers serving a single connection, created by
+/// `PgWireServerHandlers::session_handlers`
+pub struct SessionHandlers<H: PgWireServerHandlers + ?Sized> {
+    pub startup_handler: Arc<H::StartupHandler>,
+    pub simple_query_handler: Arc<H::SimpleQueryHandler>,
+    pub extended_query_handler: Arc<H::ExtendedQueryHandler>,
+    pub copy_handler: Arc<H::CopyHandler>,
+}
+
+impl<H: PgWireServerHandlers + ?Sized> SessionHandlers<H> {
+    /// Handlers returned by `startup_handler`, `simple_query_handler`,
+    /// `extended_query_handler` and `copy_handler` of `handlers`
+    pub fn from_handlers(handlers: &H) -> SessionHandlers<H> {
+        SessionHandlers {
+            startup_handler: handlers.startup_handler(),
+            simple_query_handler: handlers.simple_query_handler(),
+            extended_query_handler: handlers.extended_query_handler(),
+            copy_handler: handlers.copy_handler(),
+        }
+    }
+}
+
+impl<H: PgWireServerHandlers + ?Sized> Clone for SessionHandlers<H> {
+    fn clone(&self) -> Self {
+        SessionHandlers {
+            startup_handler: self.startup_handler.clone(),
+            simple_query_handler: self.simple_query_handler.clone(),
+            extended_query_handler: self.extended_query_handler.clone(),
+            copy_handler: self.copy_handler.clone(),
+        }
+    }
+}
+
+#[async_trait]
 pub trait PgWireServerHandlers {
     type StartupHandler: auth::StartupHandler;
     type SimpleQueryHandler: query::SimpleQueryHandler;
@@ -163,11 +422,49 @@ pub trait PgWireServerHandlers {
     fn copy_handler(&self) -> Arc<Self::CopyHandler>;
 
     fn error_handler(&self) -> Arc<Self::ErrorHandler>;
+
+    /// Handler of `CancelRequest`.
+    ///
+    /// Queries registered in `ServerConfig::cancel_registry` are cancelled
+    /// before the handler is called, so the default `NoopCancelHandler` is
+    /// enough unless the request needs to be handled in another way.
+    fn cancel_handler(&self) -> Arc<impl cancel::CancelHandler> {
+        Arc::new(cancel::NoopCancelHandler)
+    }
+
+    /// Handler of connection lifecycle events, defaults to
+    /// `NoopConnectionHandler` which accepts all connections.
+    fn connection_handler(&self) -> Arc<impl connection::ConnectionHandler> {
+        Arc::new(connection::NoopConnectionHandler)
+    }
+
+    /// Create handlers of a connection when its startup message arrives,
+    /// before authentication.
+    ///
+    /// Override this to keep per-session state in the handlers, like one
+    /// upstream connection for each client, built from the peer address, TLS
+    /// certificates and startup parameters. An error is sent to client as
+    /// `FATAL` and closes the connection. The default implementation uses
+    /// handlers returned by `startup_handler`, `simple_query_handler`,
+    /// `extended_query_handler` and `copy_handler`. Error, cancel and
+    /// connection handlers are always shared by the whole connection, from
+    /// before startup.
+    async fn session_handlers<C>(
+        &self,
+        _client: &C,
+        _startup: &Startup,
+    ) -> PgWireResult<SessionHandlers<Self>>
+    where
+        C: ClientInfo + Sync,
+    {
+        Ok(SessionHandlers::from_handlers(self))
+    }
 }
 
+#[async_trait]
 impl<T> PgWireServerHandlers for Arc<T>
 where
-    T: PgWireServerHandlers,
+    T: PgWireServerHandlers + Send + Sync,
 {
     type StartupHandler = T::StartupHandler;
     type SimpleQueryHandler = T::SimpleQueryHandler;
@@ -194,4 +491,147 @@ where
     fn error_handler(&self) -> Arc<Self::ErrorHandler> {
         (**self).error_handler()
     }
+
+    fn cancel_handler(&self) -> Arc<impl cancel::CancelHandler> {
+        (**self).cancel_handler()
+    }
+
+    fn connection_handler(&self) -> Arc<impl connection::ConnectionHandler> {
+        (**self).connection_handler()
+    }
+
+    async fn session_handlers<C>(
+        &self,
+        client: &C,
+        startup: &Startup,
+    ) -> PgWireResult<SessionHandlers<Self>>
+    where
+        C: ClientInfo + Sync,
+    {
+        let handlers = (**self).session_handlers(client, startup).await?;
+        Ok(SessionHandlers {
+            startup_handler: handlers.startup_handler,
+            simple_query_handler: handlers.simple_query_handler,
+            extended_query_handler: handlers.extended_query_handler,
+            copy_handler: handlers.copy_handler,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::connection::DisconnectReason;
+    use crate::api::copy::NoopCopyHandler;
+    use crate::messages::response::CommandComplete;
+    use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
+    use crate::testkit::fixture::{
+        error_code, error_field, FnQueryHandler, NoopStartup, TestHandlers,
+    };
+    use crate::testkit::MockClient;
+
+    /// Answers queries with the application name and address of the client,
+    /// from a query handler created for each connection
+    struct PerSessionHandlers(TestHandlers<NoopStartup, FnQueryHandler>);
+
+    #[async_trait]
+    impl PgWireServerHandlers for PerSessionHandlers {
+        type StartupHandler = NoopStartup;
+        type SimpleQueryHandler = FnQueryHandler;
+        type ExtendedQueryHandler = FnQueryHandler;
+        type CopyHandler = NoopCopyHandler;
+        type ErrorHandler = NoopErrorHandler;
+
+        fn simple_query_handler(&self) -> Arc<Self::SimpleQueryHandler> {
+            self.0.simple_query_handler()
+        }
+
+        fn extended_query_handler(&self) -> Arc<Self::ExtendedQueryHandler> {
+            self.0.extended_query_handler()
+        }
+
+        fn startup_handler(&self) -> Arc<Self::StartupHandler> {
+            self.0.startup_handler()
+        }
+
+        fn copy_handler(&self) -> Arc<Self::CopyHandler> {
+            self.0.copy_handler()
+        }
+
+        fn error_handler(&self) -> Arc<Self::ErrorHandler> {
+            self.0.error_handler()
+        }
+
+        async fn session_handlers<C>(
+            &self,
+            client: &C,
+            startup: &Startup,
+        ) -> PgWireResult<SessionHandlers<Self>>
+        where
+            C: ClientInfo + Sync,
+        {
+            let Some(application) = startup.parameters.get("application_name") else {
+                return Err(PgWireError::ApiError("application_name is required".into()));
+            };
+            let mut handlers = SessionHandlers::from_handlers(self);
+            handlers.simple_query_handler = Arc::new(FnQueryHandler::tag(&format!(
+                "{application}@{}",
+                client.socket_addr().ip()
+            )));
+            Ok(handlers)
+        }
+    }
+
+    #[test]
+    fn test_client_activity_timestamps() {
+        let mut client = DefaultClient::<()>::new("127.0.0.1:5432".parse().unwrap(), false);
+        assert!(client.authenticated_at().is_none());
+        assert_eq!(client.connected_at(), client.last_activity_at());
+
+        client.set_state(PgWireConnectionState::AuthenticationInProgress);
+        assert!(client.authenticated_at().is_none());
+
+        client.set_state(PgWireConnectionState::ReadyForQuery);
+        let authenticated_at = client.authenticated_at().unwrap();
+        assert!(authenticated_at >= client.connected_at());
+
+        client.set_state(PgWireConnectionState::QueryInProgress);
+        client.set_state(PgWireConnectionState::ReadyForQuery);
+        assert_eq!(Some(authenticated_at), client.authenticated_at());
+    }
+
+    #[tokio::test]
+    async fn test_session_handlers() {
+        let startup = |application: Option<&str>| {
+            let mut startup = Startup::new();
+            startup
+                .parameters
+                .insert("user".to_owned(), "tom".to_owned());
+            if let Some(application) = application {
+                startup
+                    .parameters
+                    .insert("application_name".to_owned(), application.to_owned());
+            }
+            PgWireFrontendMessage::Startup(startup)
+        };
+
+        let mut client = MockClient::start(PerSessionHandlers(TestHandlers::echo()));
+        client.send(startup(Some("psql"))).await.unwrap();
+        client.receive_until_ready().await.unwrap();
+        let messages = client.simple_query("SELECT 1").await.unwrap();
+        assert_eq!(
+            PgWireBackendMessage::CommandComplete(CommandComplete::new(
+                "psql@127.0.0.1".to_owned()
+            )),
+            messages[0]
+        );
+
+        let mut client = MockClient::start(PerSessionHandlers(TestHandlers::echo()));
+        client.send(startup(None)).await.unwrap();
+        let messages = client.receive_until_ready().await.unwrap();
+        assert_eq!(Some("XX000".to_owned()), error_code(&messages[0]));
+        assert_eq!(Some("FATAL".to_owned()), error_field(&messages[0], b'S'));
+        let summary = client.close().await.unwrap();
+        assert_eq!(Some(DisconnectReason::Error), summary.reason);
+    }
 }
@@ -0,0 +1,289 @@
+//! Load shedding of new connections.
+//!
+//! Set `ServerConfig::load_shedder` to answer new startups with `FATAL`
+//! `57P03` cannot_connect_now while the server is overloaded or in a
+//! maintenance window, like postgres does when it's starting up. Connections
+//! already established are not affected, and `CancelRequest` is always
+//! processed so clients can still cancel their queries.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::error::{ErrorInfo, PgWireError, PgWireResult};
+
+/// Current load of the server, tracked by `LoadShedder`
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ServerLoad {
+    /// Connections accepted but not authenticated yet, including the one
+    /// being checked
+    pub pending_connections: usize,
+    /// Queries executing across all connections
+    pub in_flight_queries: usize,
+}
+
+/// Decide if the server is overloaded, for example from CPU usage or state
+/// of the backend store.
+///
+/// It's implemented for closures taking `&ServerLoad`.
+pub trait OverloadDetector: Send + Sync {
+    /// Return the reason, sent to client as error detail, to reject new
+    /// connections. `None` to accept them.
+    fn overloaded(&self, load: &ServerLoad) -> Option<String>;
+}
+
+impl<F> OverloadDetector for F
+where
+    F: Fn(&ServerLoad) -> Option<String> + Send + Sync,
+{
+    fn overloaded(&self, load: &ServerLoad) -> Option<String> {
+        self(load)
+    }
+}
+
+/// Reject new connections during overload or maintenance.
+///
+/// Share the same instance for all connections, so it counts connections
+/// and queries of the whole server.
+///
+/// ```
+/// use pgwire::api::overload::LoadShedder;
+///
+/// let shedder = LoadShedder::new()
+///     .with_max_pending_connections(100)
+///     .with_max_in_flight_queries(1000);
+/// // entering a maintenance window
+/// shedder.set_accepting(false);
+/// ```
+pub struct LoadShedder {
+    accepting: AtomicBool,
+    max_pending_connections: Option<usize>,
+    max_in_flight_queries: Option<usize>,
+    detector: Option<Arc<dyn OverloadDetector>>,
+    pending_connections: Arc<AtomicUsize>,
+    in_flight_queries: Arc<AtomicUsize>,
+}
+
+impl fmt::Debug for LoadShedder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LoadShedder")
+            .field("accepting", &self.accepting)
+            .field("max_pending_connections", &self.max_pending_connections)
+            .field("max_in_flight_queries", &self.max_in_flight_queries)
+            .field("load", &self.load())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for LoadShedder {
+    fn default() -> Self {
+        LoadShedder::new()
+    }
+}
+
+impl LoadShedder {
+    /// Accept all connections until limits or a detector are set
+    pub fn new() -> LoadShedder {
+        LoadShedder {
+            accepting: AtomicBool::new(true),
+            max_pending_connections: None,
+            max_in_flight_queries: None,
+            detector: None,
+            pending_connections: Arc::new(AtomicUsize::new(0)),
+            in_flight_queries: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Reject new connections when more than `max` connections are waiting
+    /// for authentication
+    pub fn with_max_pending_connections(mut self, max: usize) -> LoadShedder {
+        self.max_pending_connections = Some(max);
+        self
+    }
+
+    /// Reject new connections when `max` or more queries are executing
+    pub fn with_max_in_flight_queries(mut self, max: usize) -> LoadShedder {
+        self.max_in_flight_queries = Some(max);
+        self
+    }
+
+    /// Consult `detector` for new connections after the built-in limits
+    pub fn with_detector(mut self, detector: Arc<dyn OverloadDetector>) -> LoadShedder {
+        self.detector = Some(detector);
+        self
+    }
+
+    /// Stop or resume accepting new connections, for a maintenance window.
+    /// Returns the previous state.
+    pub fn set_accepting(&self, accepting: bool) -> bool {
+        self.accepting.swap(accepting, Ordering::AcqRel)
+    }
+
+    pub fn is_accepting(&self) -> bool {
+        self.accepting.load(Ordering::Acquire)
+    }
+
+    pub fn load(&self) -> ServerLoad {
+        ServerLoad {
+            pending_connections: self.pending_connections.load(Ordering::Acquire),
+            in_flight_queries: self.in_flight_queries.load(Ordering::Acquire),
+        }
+    }
+
+    /// Check if a new connection can be accepted, reject it with `FATAL`
+    /// `57P03` otherwise.
+    pub fn check(&self) -> PgWireResult<()> {
+        match self.rejection() {
+            Some(reason) => Err(cannot_connect_now(reason)),
+            None => Ok(()),
+        }
+    }
+
+    fn rejection(&self) -> Option<String> {
+        if !self.is_accepting() {
+            return Some("The server is in maintenance.".to_owned());
+        }
+
+        let load = self.load();
+        if let Some(max) = self.max_pending_connections {
+            if load.pending_connections > max {
+                return Some(format!(
+                    "Too many connections are starting up, the server allows at most {max}."
+                ));
+            }
+        }
+        if let Some(max) = self.max_in_flight_queries {
+            if load.in_flight_queries >= max {
+                return Some(format!(
+                    "Too many queries are executing, the server allows at most {max}."
+                ));
+            }
+        }
+        self.detector
+            .as_ref()
+            .and_then(|detector| detector.overloaded(&load))
+    }
+
+    /// Count a connection as pending until the returned guard is dropped
+    pub(crate) fn connection_pending(&self) -> LoadGuard {
+        LoadGuard::new(self.pending_connections.clone())
+    }
+
+    /// Count a query as executing until the returned guard is dropped
+    pub(crate) fn query_started(&self) -> LoadGuard {
+        LoadGuard::new(self.in_flight_queries.clone())
+    }
+}
+
+/// Error sent to rejected connections
+fn cannot_connect_now(reason: String) -> PgWireError {
+    let mut info = ErrorInfo::new(
+        "FATAL".to_owned(),
+        "57P03".to_owned(),
+        "the database system is not accepting connections".to_owned(),
+    );
+    info.detail = Some(reason);
+    PgWireError::UserError(Box::new(info))
+}
+
+/// Decrements a counter of `LoadShedder` on drop
+#[derive(Debug)]
+pub(crate) struct LoadGuard {
+    counter: Arc<AtomicUsize>,
+}
+
+impl LoadGuard {
+    fn new(counter: Arc<AtomicUsize>) -> LoadGuard {
+        counter.fetch_add(1, Ordering::AcqRel);
+        LoadGuard { counter }
+    }
+}
+
+impl Drop for LoadGuard {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::config::ServerConfig;
+    use crate::testkit::fixture::{error_code, error_field, TestHandlers};
+    use crate::testkit::MockClient;
+
+    fn rejected_code(shedder: &LoadShedder) -> Option<String> {
+        match shedder.check() {
+            Ok(()) => None,
+            Err(PgWireError::UserError(error)) => {
+                assert_eq!("FATAL", error.severity);
+                Some(error.code)
+            }
+            Err(e) => panic!("unexpected error {e}"),
+        }
+    }
+
+    #[test]
+    fn test_load_shedder_limits() {
+        let shedder = LoadShedder::new()
+            .with_max_pending_connections(1)
+            .with_max_in_flight_queries(2);
+        let first = shedder.connection_pending();
+        assert_eq!(None, rejected_code(&shedder));
+        let second = shedder.connection_pending();
+        assert_eq!(Some("57P03".to_owned()), rejected_code(&shedder));
+        drop(second);
+        drop(first);
+
+        let queries = [shedder.query_started(), shedder.query_started()];
+        assert_eq!(2, shedder.load().in_flight_queries);
+        assert_eq!(Some("57P03".to_owned()), rejected_code(&shedder));
+        drop(queries);
+        assert_eq!(ServerLoad::default(), shedder.load());
+        assert_eq!(None, rejected_code(&shedder));
+    }
+
+    #[test]
+    fn test_load_shedder_maintenance_and_detector() {
+        let shedder = LoadShedder::new().with_detector(Arc::new(|load: &ServerLoad| {
+            (load.pending_connections > 0).then(|| "busy".to_owned())
+        }));
+        assert!(shedder.set_accepting(false));
+        assert_eq!(Some("57P03".to_owned()), rejected_code(&shedder));
+        shedder.set_accepting(true);
+        assert_eq!(None, rejected_code(&shedder));
+
+        let _pending = shedder.connection_pending();
+        let Err(PgWireError::UserError(error)) = shedder.check() else {
+            panic!("connection should be rejected");
+        };
+        assert_eq!(Some("busy"), error.detail.as_deref());
+    }
+
+    #[tokio::test]
+    async fn test_load_shedding() {
+        let shedder = Arc::new(LoadShedder::new());
+        let config = Arc::new(ServerConfig {
+            load_shedder: Some(shedder.clone()),
+            ..Default::default()
+        });
+
+        // maintenance window
+        shedder.set_accepting(false);
+        let mut client = MockClient::start_with_config(TestHandlers::echo(), config.clone());
+        let messages = client.startup("tom", None).await.unwrap();
+        assert_eq!(1, messages.len());
+        assert_eq!(Some("57P03".to_owned()), error_code(&messages[0]));
+        assert_eq!(Some("FATAL".to_owned()), error_field(&messages[0], b'S'));
+        client.close().await.unwrap();
+
+        shedder.set_accepting(true);
+        let mut client = MockClient::start_with_config(TestHandlers::echo(), config);
+        client.startup("tom", None).await.unwrap();
+        // no longer pending once authenticated
+        assert_eq!(ServerLoad::default(), shedder.load());
+        client.simple_query("SELECT 1").await.unwrap();
+        assert_eq!(0, shedder.load().in_flight_queries);
+    }
+}
@@ -0,0 +1,112 @@
+//! Pool of buffers for encoding data rows.
+//!
+//! `DataRowEncoder` takes its row buffer from the pool, and the server codec
+//! gives it back after the `DataRow` is written to the connection, so
+//! streaming a large result set doesn't allocate a buffer for each row. Pools
+//! are thread-local, so no synchronization is involved.
+
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bytes::BytesMut;
+
+static MAX_BUFFERS: AtomicUsize = AtomicUsize::new(64);
+static MAX_BUFFER_CAPACITY: AtomicUsize = AtomicUsize::new(64 * 1024);
+
+thread_local! {
+    static POOL: RefCell<Vec<BytesMut>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Sizing of the buffer pool
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferPoolConfig {
+    /// Max number of idle buffers kept by each thread. `0` disables pooling.
+    pub max_buffers: usize,
+    /// Buffers grown larger than this are not returned to the pool, so one
+    /// huge row doesn't keep its memory forever.
+    pub max_buffer_capacity: usize,
+}
+
+impl Default for BufferPoolConfig {
+    fn default() -> Self {
+        BufferPoolConfig {
+            max_buffers: 64,
+            max_buffer_capacity: 64 * 1024,
+        }
+    }
+}
+
+impl BufferPoolConfig {
+    /// Config that turns off pooling, buffers are allocated for each row
+    pub fn disabled() -> Self {
+        BufferPoolConfig {
+            max_buffers: 0,
+            ..Default::default()
+        }
+    }
+}
+
+/// Set sizing of the buffer pool for all threads
+pub fn configure(config: BufferPoolConfig) {
+    MAX_BUFFERS.store(config.max_buffers, Ordering::Relaxed);
+    MAX_BUFFER_CAPACITY.store(config.max_buffer_capacity, Ordering::Relaxed);
+}
+
+/// Current sizing of the buffer pool
+pub fn config() -> BufferPoolConfig {
+    BufferPoolConfig {
+        max_buffers: MAX_BUFFERS.load(Ordering::Relaxed),
+        max_buffer_capacity: MAX_BUFFER_CAPACITY.load(Ordering::Relaxed),
+    }
+}
+
+/// Take an empty buffer with at least `capacity` bytes of capacity
+pub fn take(capacity: usize) -> BytesMut {
+    let pooled = POOL.with(|pool| pool.borrow_mut().pop());
+    match pooled {
+        Some(mut buf) => {
+            buf.reserve(capacity);
+            buf
+        }
+        None => BytesMut::with_capacity(capacity),
+    }
+}
+
+/// Return a buffer to the pool of current thread
+pub fn recycle(mut buf: BytesMut) {
+    let config = config();
+    if buf.capacity() == 0 || buf.capacity() > config.max_buffer_capacity {
+        return;
+    }
+
+    buf.clear();
+    POOL.with(|pool| {
+        let mut pool = pool.borrow_mut();
+        if pool.len() < config.max_buffers {
+            pool.push(buf);
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_buffer_pool() {
+        let mut buf = take(128);
+        buf.extend_from_slice(b"row data");
+        let ptr = buf.as_ptr();
+        recycle(buf);
+
+        let buf = take(16);
+        assert!(buf.is_empty());
+        assert!(buf.capacity() >= 128);
+        assert_eq!(ptr, buf.as_ptr());
+
+        // oversized buffers are dropped
+        recycle(BytesMut::with_capacity(config().max_buffer_capacity + 1));
+        assert!(take(0).capacity() < config().max_buffer_capacity + 1);
+    }
+}
@@ -5,11 +5,19 @@ use postgres_types::FromSqlOwned;
 
 use crate::{
     api::Type,
-    error::{PgWireError, PgWireResult},
-    messages::{data::FORMAT_CODE_BINARY, extendedquery::Bind},
+    error::{ErrorInfo, PgWireError, PgWireResult},
+    messages::{
+        data::{FORMAT_CODE_BINARY, FORMAT_CODE_TEXT},
+        extendedquery::{Bind, Parameters},
+    },
 };
 
-use super::{results::FieldFormat, stmt::StoredStatement, DEFAULT_NAME};
+use super::{
+    custom_types::TypeRegistry,
+    results::{FieldFormat, FieldInfo},
+    stmt::StoredStatement,
+    DEFAULT_NAME,
+};
 
 /// Represent a prepared sql statement and its parameters bound by a `Bind`
 /// request.
@@ -19,8 +27,11 @@ pub struct Portal<S> {
     pub name: String,
     pub statement: Arc<StoredStatement<S>>,
     pub parameter_format: Format,
-    pub parameters: Vec<Option<Bytes>>,
+    pub parameters: Parameters,
     pub result_column_format: Format,
+    /// Custom types used to decode parameters, set from
+    /// `ServerConfig::type_registry` when the portal is bound.
+    pub type_registry: Option<Arc<TypeRegistry>>,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -42,12 +53,15 @@ impl From<i16> for Format {
 }
 
 impl Format {
-    /// Get format code for given index
+    /// Get format code for given index. Columns out of range of individual
+    /// format codes, which `validate` rejects, are in text format.
     pub fn format_for(&self, idx: usize) -> FieldFormat {
         match self {
             Format::UnifiedText => FieldFormat::Text,
             Format::UnifiedBinary => FieldFormat::Binary,
-            Format::Individual(ref fv) => FieldFormat::from(fv[idx]),
+            Format::Individual(ref fv) => fv
+                .get(idx)
+                .map_or(FieldFormat::Text, |code| FieldFormat::from(*code)),
         }
     }
 
@@ -61,14 +75,102 @@ impl Format {
         self.format_for(idx) == FieldFormat::Binary
     }
 
-    fn from_codes(codes: &[i16]) -> Self {
-        if codes.is_empty() {
+    /// Format of columns defined by `fields`, unified when all columns are in
+    /// the same format
+    pub fn from_fields(fields: &[FieldInfo]) -> Format {
+        if fields.iter().all(|f| f.format() == FieldFormat::Text) {
+            Format::UnifiedText
+        } else if fields.iter().all(|f| f.format() == FieldFormat::Binary) {
+            Format::UnifiedBinary
+        } else {
+            Format::Individual(fields.iter().map(|f| f.format().value()).collect())
+        }
+    }
+
+    /// Check individual result format codes against number of columns.
+    ///
+    /// Returns error `08P01` like postgres when the number doesn't match.
+    pub fn validate(&self, columns: usize) -> PgWireResult<()> {
+        match self {
+            Format::Individual(codes) if codes.len() != columns => {
+                Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                    "ERROR".to_owned(),
+                    "08P01".to_owned(),
+                    format!(
+                        "bind message has {} result formats but query has {columns} columns",
+                        codes.len()
+                    ),
+                ))))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Formats of each of `columns` columns
+    pub fn formats(&self, columns: usize) -> PgWireResult<Vec<FieldFormat>> {
+        self.validate(columns)?;
+        Ok((0..columns).map(|idx| self.format_for(idx)).collect())
+    }
+
+    /// Set format of `fields` to the requested one, for building schema of
+    /// results and `DescribePortalResponse` from columns of a statement
+    pub fn apply_to_fields(&self, fields: Vec<FieldInfo>) -> PgWireResult<Vec<FieldInfo>> {
+        self.validate(fields.len())?;
+        Ok(fields
+            .into_iter()
+            .enumerate()
+            .map(|(idx, field)| field.with_format(self.format_for(idx)))
+            .collect())
+    }
+
+    /// Check that `fields`, the schema of encoded rows, are in the requested
+    /// format.
+    ///
+    /// Returns `ResultFormatMismatch` for the first column in other format.
+    pub fn check_fields(&self, fields: &[FieldInfo]) -> PgWireResult<()> {
+        self.validate(fields.len())?;
+        for (idx, field) in fields.iter().enumerate() {
+            let requested = self.format_for(idx);
+            if field.format() != requested {
+                return Err(PgWireError::ResultFormatMismatch(
+                    idx,
+                    field.name().to_owned(),
+                    requested,
+                    field.format(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn estimated_size(&self) -> usize {
+        match self {
+            Format::Individual(ref fv) => fv.len() * std::mem::size_of::<i16>(),
+            _ => 0,
+        }
+    }
+
+    /// Format from codes of `Bind`, rejects codes other than text and binary
+    /// with `22023` like postgres
+    fn try_from_codes(codes: &[i16]) -> PgWireResult<Self> {
+        if let Some(code) = codes
+            .iter()
+            .find(|c| **c != FORMAT_CODE_TEXT && **c != FORMAT_CODE_BINARY)
+        {
+            return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                "ERROR".to_owned(),
+                "22023".to_owned(),
+                format!("unsupported format code: {code}"),
+            ))));
+        }
+
+        Ok(if codes.is_empty() {
             Format::UnifiedText
         } else if codes.len() == 1 {
             Format::from(codes[0])
         } else {
             Format::Individual(codes.to_vec())
-        }
+        })
     }
 }
 
@@ -81,10 +183,23 @@ impl<S: Clone> Portal<S> {
             .unwrap_or_else(|| DEFAULT_NAME.to_owned());
 
         // param format
-        let param_format = Format::from_codes(&bind.parameter_format_codes);
+        let param_format = Format::try_from_codes(&bind.parameter_format_codes)?;
+        if let Format::Individual(codes) = &param_format {
+            if codes.len() != bind.parameters.len() {
+                return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                    "ERROR".to_owned(),
+                    "08P01".to_owned(),
+                    format!(
+                        "bind message has {} parameter formats but {} parameters",
+                        codes.len(),
+                        bind.parameters.len()
+                    ),
+                ))));
+            }
+        }
 
         // format
-        let result_format = Format::from_codes(&bind.result_column_format_codes);
+        let result_format = Format::try_from_codes(&bind.result_column_format_codes)?;
 
         Ok(Portal {
             name: portal_name,
@@ -92,9 +207,30 @@ impl<S: Clone> Portal<S> {
             parameter_format: param_format,
             parameters: bind.parameters.clone(),
             result_column_format: result_format,
+            type_registry: None,
         })
     }
 
+    /// Decode parameters of custom types with `registry`
+    pub fn with_type_registry(mut self, registry: Arc<TypeRegistry>) -> Self {
+        self.type_registry = Some(registry);
+        self
+    }
+
+    /// Estimated memory used by this portal, in bytes. The statement is not
+    /// counted because it's shared with `PortalStore`.
+    pub fn estimated_size(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.name.len()
+            + self
+                .parameters
+                .iter()
+                .map(|p| std::mem::size_of::<Option<Bytes>>() + p.as_ref().map_or(0, |b| b.len()))
+                .sum::<usize>()
+            + self.parameter_format.estimated_size()
+            + self.result_column_format.estimated_size()
+    }
+
     /// Get number of parameters
     pub fn parameter_len(&self) -> usize {
         self.parameters.len()
@@ -102,10 +238,19 @@ impl<S: Clone> Portal<S> {
 
     /// Attempt to get parameter at given index as type `T`.
     ///
+    /// Parameters of types registered in the `TypeRegistry` are read as text,
+    /// so `T` has to accept `Type::TEXT`. Binary parameters are converted to
+    /// text with codec of the type.
     pub fn parameter<T>(&self, idx: usize, pg_type: &Type) -> PgWireResult<Option<T>>
     where
         T: FromSqlOwned,
     {
+        if let Some(registry) = &self.type_registry {
+            if registry.get(pg_type.oid()).is_some() {
+                return self.custom_parameter(idx, pg_type, registry);
+            }
+        }
+
         if !T::accepts(pg_type) {
             return Err(PgWireError::InvalidRustTypeForParameter(
                 pg_type.name().to_owned(),
@@ -130,6 +275,48 @@ impl<S: Clone> Portal<S> {
             Ok(None)
         }
     }
+
+    fn custom_parameter<T>(
+        &self,
+        idx: usize,
+        pg_type: &Type,
+        registry: &TypeRegistry,
+    ) -> PgWireResult<Option<T>>
+    where
+        T: FromSqlOwned,
+    {
+        if !T::accepts(&Type::TEXT) {
+            return Err(PgWireError::InvalidRustTypeForParameter(
+                pg_type.name().to_owned(),
+            ));
+        }
+
+        let Some(param) = self
+            .parameters
+            .get(idx)
+            .ok_or_else(|| PgWireError::ParameterIndexOutOfBound(idx))?
+        else {
+            return Ok(None);
+        };
+
+        let text = if self.parameter_format.is_binary(idx) {
+            let codec = registry.codec(pg_type.oid()).ok_or_else(|| {
+                PgWireError::FailedToParseParameter(
+                    format!("binary format of type {} is not supported", pg_type.name()).into(),
+                )
+            })?;
+            codec
+                .binary_to_text(param)
+                .map_err(PgWireError::FailedToParseParameter)?
+        } else {
+            String::from_utf8(param.to_vec())
+                .map_err(|e| PgWireError::FailedToParseParameter(Box::new(e)))?
+        };
+
+        T::from_sql(&Type::TEXT, text.as_bytes())
+            .map(Some)
+            .map_err(PgWireError::FailedToParseParameter)
+    }
 }
 
 #[cfg(test)]
@@ -138,6 +325,101 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_result_format() {
+        let fields = vec![
+            FieldInfo::new("id".into(), None, None, Type::INT4, FieldFormat::Binary),
+            FieldInfo::new("name".into(), None, None, Type::TEXT, FieldFormat::Text),
+        ];
+        let format = Format::from_fields(&fields);
+        assert!(matches!(&format, Format::Individual(codes) if codes == &[1, 0]));
+        assert_eq!(
+            vec![FieldFormat::Binary, FieldFormat::Text],
+            format.formats(2).unwrap()
+        );
+        assert!(format.formats(3).is_err());
+        format.check_fields(&fields).unwrap();
+
+        let fields = Format::UnifiedBinary.apply_to_fields(fields).unwrap();
+        assert!(matches!(
+            Format::from_fields(&fields),
+            Format::UnifiedBinary
+        ));
+        assert!(matches!(
+            format.check_fields(&fields),
+            Err(PgWireError::ResultFormatMismatch(1, ref name, FieldFormat::Text, FieldFormat::Binary))
+                if name == "name"
+        ));
+    }
+
+    #[test]
+    fn test_bind_format_codes() {
+        let statement = Arc::new(StoredStatement::new(
+            DEFAULT_NAME.to_owned(),
+            String::new(),
+            vec![],
+        ));
+        let bind = |parameter_formats: Vec<i16>, result_formats: Vec<i16>| {
+            Bind::new(None, None, parameter_formats, vec![None], result_formats)
+        };
+        let code = |result: PgWireResult<Portal<String>>| match result {
+            Err(PgWireError::UserError(info)) => Some(info.code),
+            _ => None,
+        };
+
+        assert_eq!(
+            None,
+            code(Portal::try_new(
+                &bind(vec![1], vec![1, 0]),
+                statement.clone()
+            ))
+        );
+        assert_eq!(
+            Some("22023".to_owned()),
+            code(Portal::try_new(&bind(vec![], vec![2]), statement.clone()))
+        );
+        assert_eq!(
+            Some("08P01".to_owned()),
+            code(Portal::try_new(&bind(vec![0, 1], vec![]), statement))
+        );
+    }
+
+    #[test]
+    fn test_numeric_parameter() {
+        use rust_decimal::Decimal;
+
+        let statement = Arc::new(StoredStatement::new(
+            DEFAULT_NAME.to_owned(),
+            String::new(),
+            vec![Type::NUMERIC, Type::NUMERIC],
+        ));
+        // 12345.6789 and NaN in binary format: ndigits, weight, sign, dscale
+        // and base 10000 digits
+        let value = [0, 3, 0, 1, 0, 0, 0, 4, 0, 1, 9, 41, 26, 133];
+        let nan = [0, 0, 0, 0, 0xc0, 0, 0, 0];
+        let bind = Bind::new(
+            None,
+            None,
+            vec![1],
+            vec![
+                Some(Bytes::copy_from_slice(&value)),
+                Some(Bytes::copy_from_slice(&nan)),
+            ],
+            vec![],
+        );
+        let portal = Portal::try_new(&bind, statement).unwrap();
+
+        let decimal = portal
+            .parameter::<Decimal>(0, &Type::NUMERIC)
+            .unwrap()
+            .unwrap();
+        assert_eq!("12345.6789", decimal.to_string());
+        assert!(matches!(
+            portal.parameter::<Decimal>(1, &Type::NUMERIC),
+            Err(PgWireError::FailedToParseParameter(_))
+        ));
+    }
+
     #[test]
     fn test_from_sql() {
         assert_eq!(
@@ -0,0 +1,82 @@
+//! Forward messages between clients and an upstream postgres server.
+//!
+//! Instead of terminating the protocol with query handlers, a proxy pairs
+//! each client connection with a connection to the upstream server, opened
+//! by `ProxyHandler::connect`, and forwards messages in both directions.
+//! Each message goes through `ProxyHandler::on_frontend_message` or
+//! `ProxyHandler::on_backend_message` first, which may rewrite, drop or
+//! answer it, for example to inject credentials of the upstream server or
+//! to block some statements. Run it with `pgwire::tokio::proxy_socket` or
+//! `pgwire::tokio::proxy_stream`.
+
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::DefaultClient;
+use crate::error::PgWireResult;
+use crate::messages::startup::{CancelRequest, Startup};
+use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
+
+/// What to do with an intercepted message of type `M`, `R` is the type of
+/// messages sent back to its sender.
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum Forward<M, R> {
+    /// Forward the message, possibly rewritten
+    Message(M),
+    /// Forward nothing
+    Drop,
+    /// Forward nothing and send these messages back to the sender instead,
+    /// like answering an authentication request of upstream server
+    Reply(Vec<R>),
+}
+
+/// Decision on a message from client
+pub type FrontendForward = Forward<PgWireFrontendMessage, PgWireBackendMessage>;
+/// Decision on a message from upstream server
+pub type BackendForward = Forward<PgWireBackendMessage, PgWireFrontendMessage>;
+
+/// Connect clients to upstream servers and intercept their messages.
+///
+/// Errors returned by these methods are sent to client as `FATAL` and close
+/// both connections.
+#[async_trait]
+pub trait ProxyHandler: Send + Sync {
+    /// Connection to the upstream server, with any TLS already established
+    type Upstream: AsyncRead + AsyncWrite + Unpin + Send + Sync;
+
+    /// Connect to the upstream server for the client's startup message.
+    ///
+    /// `startup` is sent to upstream after this returns, rewrite it in place
+    /// to change the user, database or other parameters.
+    async fn connect(
+        &self,
+        client: &DefaultClient<()>,
+        startup: &mut Startup,
+    ) -> PgWireResult<Self::Upstream>;
+
+    /// Intercept a message from client before it's sent upstream
+    async fn on_frontend_message(
+        &self,
+        _client: &DefaultClient<()>,
+        message: PgWireFrontendMessage,
+    ) -> PgWireResult<FrontendForward> {
+        Ok(Forward::Message(message))
+    }
+
+    /// Intercept a message from the upstream server before it's sent to
+    /// client
+    async fn on_backend_message(
+        &self,
+        _client: &DefaultClient<()>,
+        message: PgWireBackendMessage,
+    ) -> PgWireResult<BackendForward> {
+        Ok(Forward::Message(message))
+    }
+
+    /// Called on `CancelRequest` from a client, which comes on its own
+    /// connection. The key is the one sent by upstream in `BackendKeyData`
+    /// unless it was rewritten, forward it to the upstream server that
+    /// issued it.
+    async fn on_cancel_request(&self, _cancel_request: CancelRequest) {}
+}
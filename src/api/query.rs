@@ -1,34 +1,165 @@
+use std::collections::HashMap;
 use std::fmt::Debug;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use async_trait::async_trait;
+use bytes::Buf;
 use futures::sink::{Sink, SinkExt};
-use futures::stream::StreamExt;
+use futures::stream::{BoxStream, StreamExt};
 
+use super::extensions::Extensions;
 use super::portal::Portal;
-use super::results::{into_row_description, Tag};
+use super::results::{into_row_description, RowDescriptionCache, Tag};
+use super::session::{send_session_command_response, SessionCommand};
+use super::set::REPORTED_PARAMETERS;
+use super::show::NON_PARAMETER_KEYS;
 use super::stmt::{NoopQueryParser, QueryParser, StoredStatement};
 use super::store::PortalStore;
+use super::transaction::ImplicitTransaction;
 use super::{copy, ClientInfo, ClientPortalStore, DEFAULT_NAME};
+use super::{memory, pool};
 use crate::api::results::{
-    DescribePortalResponse, DescribeResponse, DescribeStatementResponse, QueryResponse, Response,
+    DataRowWriter, DescribePortalResponse, DescribeResponse, DescribeStatementResponse, FieldInfo,
+    QueryResponse, Response,
 };
 use crate::api::PgWireConnectionState;
-use crate::error::{PgWireError, PgWireResult};
-use crate::messages::data::{NoData, ParameterDescription};
+use crate::error::{ErrorInfo, PgWireError, PgWireResult};
+use crate::messages::data::{DataRow, NoData, ParameterDescription};
 use crate::messages::extendedquery::{
     Bind, BindComplete, Close, CloseComplete, Describe, Execute, Flush, Parse, ParseComplete,
-    Sync as PgSync, TARGET_TYPE_BYTE_PORTAL, TARGET_TYPE_BYTE_STATEMENT,
+    PortalSuspended, Sync as PgSync, TARGET_TYPE_BYTE_PORTAL, TARGET_TYPE_BYTE_STATEMENT,
 };
 use crate::messages::response::{EmptyQueryResponse, ReadyForQuery, TransactionStatus};
 use crate::messages::simplequery::Query;
-use crate::messages::PgWireBackendMessage;
+use crate::messages::startup::ParameterStatus;
+use crate::messages::{check_message_length, Message, PgWireBackendMessage};
 
 fn is_empty_query(q: &str) -> bool {
     let trimmed_query = q.trim();
     trimmed_query == ";" || trimmed_query.is_empty()
 }
 
+/// Split a simple query string into individual statements.
+///
+/// Postgres allows multiple statements, separated by `;`, in a single simple
+/// query. This helper splits them while respecting single/double quoted
+/// strings, dollar-quoted strings, line comments and (nested) block comments,
+/// so `SimpleQueryHandler` implementations can execute each statement and
+/// return a `Response` for it. The returned statements are trimmed and don't
+/// include the trailing `;`. Empty statements are skipped.
+pub fn split_statements(query: &str) -> Vec<&str> {
+    let bytes = query.as_bytes();
+    let mut statements = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\'' => {
+                // E'' strings allow backslash escapes
+                let backslash_escape = i > 0
+                    && matches!(bytes[i - 1], b'e' | b'E')
+                    && (i == 1 || !is_ident_byte(bytes[i - 2]));
+                i = skip_quoted(bytes, i, b'\'', backslash_escape);
+            }
+            b'"' => {
+                i = skip_quoted(bytes, i, b'"', false);
+            }
+            b'-' if bytes.get(i + 1) == Some(&b'-') => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                let mut depth = 1;
+                i += 2;
+                while i < bytes.len() && depth > 0 {
+                    if bytes[i] == b'/' && bytes.get(i + 1) == Some(&b'*') {
+                        depth += 1;
+                        i += 2;
+                    } else if bytes[i] == b'*' && bytes.get(i + 1) == Some(&b'/') {
+                        depth -= 1;
+                        i += 2;
+                    } else {
+                        i += 1;
+                    }
+                }
+            }
+            b'$' if i == 0 || !is_ident_byte(bytes[i - 1]) => {
+                i = skip_dollar_quoted(query, i);
+            }
+            b';' => {
+                let stmt = query[start..i].trim();
+                if !stmt.is_empty() {
+                    statements.push(stmt);
+                }
+                i += 1;
+                start = i;
+            }
+            _ => {
+                i += 1;
+            }
+        }
+    }
+
+    let stmt = query[start..].trim();
+    if !stmt.is_empty() {
+        statements.push(stmt);
+    }
+
+    statements
+}
+
+fn is_ident_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || b >= 0x80
+}
+
+/// Skip a quoted string starting at `start`, returns the index after the
+/// closing quote.
+fn skip_quoted(bytes: &[u8], start: usize, quote: u8, backslash_escape: bool) -> usize {
+    let mut i = start + 1;
+    while i < bytes.len() {
+        if backslash_escape && bytes[i] == b'\\' {
+            i += 2;
+        } else if bytes[i] == quote {
+            // doubled quote is an escaped quote
+            if bytes.get(i + 1) == Some(&quote) {
+                i += 2;
+            } else {
+                return i + 1;
+            }
+        } else {
+            i += 1;
+        }
+    }
+    bytes.len()
+}
+
+/// Skip a dollar-quoted string starting at `start`. If the `$` doesn't start
+/// a dollar quote, for example a positional parameter `$1`, only the `$` is
+/// skipped.
+fn skip_dollar_quoted(query: &str, start: usize) -> usize {
+    let bytes = query.as_bytes();
+    let mut i = start + 1;
+    // tag follows identifier rules but cannot start with digit
+    if i < bytes.len() && bytes[i].is_ascii_digit() {
+        return i;
+    }
+    while i < bytes.len() && is_ident_byte(bytes[i]) {
+        i += 1;
+    }
+    if bytes.get(i) != Some(&b'$') {
+        return start + 1;
+    }
+
+    let tag = &query[start..=i];
+    if let Some(end) = query[i + 1..].find(tag) {
+        i + 1 + end + tag.len()
+    } else {
+        bytes.len()
+    }
+}
+
 /// handler for processing simple query.
 #[async_trait]
 pub trait SimpleQueryHandler: Send + Sync {
@@ -38,9 +169,17 @@ pub trait SimpleQueryHandler: Send + Sync {
     ///
     /// This handle checks empty query by default, if the query string is empty
     /// or `;`, it returns `EmptyQueryResponse` and does not call `self.do_query`.
+    /// `DISCARD ALL` and `DEALLOCATE` are also answered without `do_query`
+    /// unless `ServerConfig::handle_session_commands` is disabled.
+    ///
+    /// Responses are tracked with `ImplicitTransaction` to update the
+    /// transaction status. All responses returned by `do_query` are sent, so
+    /// `do_query` should stop executing statements after the first error, like
+    /// postgres does.
     async fn on_query<C>(&self, client: &mut C, query: Query) -> PgWireResult<()>
     where
         C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore,
         C::Error: Debug,
         PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
     {
@@ -57,38 +196,58 @@ pub trait SimpleQueryHandler: Send + Sync {
         client.set_state(super::PgWireConnectionState::QueryInProgress);
         let query_string = query.query;
 
+        let session_command = if client.server_config().handle_session_commands {
+            SessionCommand::parse(&query_string)
+        } else {
+            None
+        };
+
         if is_empty_query(&query_string) {
             client
                 .feed(PgWireBackendMessage::EmptyQueryResponse(EmptyQueryResponse))
                 .await?;
+        } else if let Some(command) = session_command {
+            send_session_command_response(client, &command).await?;
         } else {
+            check_read_only(client, &query_string)?;
             let resp = self.do_query(client, &query_string).await?;
+            let statements = resp
+                .iter()
+                .filter(|r| !matches!(r, Response::Notice(_)))
+                .count();
+            let mut transaction = ImplicitTransaction::new(transaction_status, statements);
             for r in resp {
+                transaction.on_response(&r);
                 match r {
                     Response::EmptyQuery => {
                         client
                             .feed(PgWireBackendMessage::EmptyQueryResponse(EmptyQueryResponse))
                             .await?;
                     }
-                    Response::Query(results) => {
+                    Response::Query(results) | Response::Suspended(results) => {
                         send_query_response(client, results, true).await?;
                     }
                     Response::Execution(tag) => {
                         send_execution_response(client, tag).await?;
                     }
+                    Response::ParameterStatus { name, value } => {
+                        send_parameter_status_response(client, name, value).await?;
+                    }
                     Response::TransactionStart(tag) => {
                         send_execution_response(client, tag).await?;
-                        transaction_status = transaction_status.to_in_transaction_state();
                     }
                     Response::TransactionEnd(tag) => {
                         send_execution_response(client, tag).await?;
-                        transaction_status = transaction_status.to_idle_state();
                     }
                     Response::Error(e) => {
                         client
                             .feed(PgWireBackendMessage::ErrorResponse((*e).into()))
                             .await?;
-                        transaction_status = transaction_status.to_error_state();
+                    }
+                    Response::Notice(notice) => {
+                        client
+                            .feed(PgWireBackendMessage::NoticeResponse((*notice).into()))
+                            .await?;
                     }
                     Response::CopyIn(result) => {
                         copy::send_copy_in_response(client, result).await?;
@@ -104,6 +263,7 @@ pub trait SimpleQueryHandler: Send + Sync {
                     }
                 }
             }
+            transaction_status = transaction.status();
         }
 
         if !matches!(client.state(), PgWireConnectionState::CopyInProgress(_)) {
@@ -121,6 +281,9 @@ pub trait SimpleQueryHandler: Send + Sync {
     }
 
     /// Provide your query implementation using the incoming query string.
+    ///
+    /// The query string may contain multiple statements, `split_statements`
+    /// can be used to split them and produce one `Response` for each.
     async fn do_query<'a, 'b: 'a, C>(
         &'b self,
         client: &mut C,
@@ -132,6 +295,23 @@ pub trait SimpleQueryHandler: Send + Sync {
         PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>;
 }
 
+/// Target of `ExtendedQueryHandler::do_describe`
+#[derive(Debug)]
+pub enum StatementOrPortal<'a, S> {
+    Statement(&'a StoredStatement<S>),
+    Portal(&'a Portal<S>),
+}
+
+impl<'a, S> StatementOrPortal<'a, S> {
+    /// The statement, or the statement bound by the portal
+    pub fn statement(&self) -> &'a StoredStatement<S> {
+        match self {
+            StatementOrPortal::Statement(statement) => statement,
+            StatementOrPortal::Portal(portal) => &portal.statement,
+        }
+    }
+}
+
 #[async_trait]
 pub trait ExtendedQueryHandler: Send + Sync {
     type Statement: Clone + Send + Sync;
@@ -151,9 +331,24 @@ pub trait ExtendedQueryHandler: Send + Sync {
         C::Error: Debug,
         PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
     {
+        self.check_portal_store_usage(client)?;
+
         let parser = self.query_parser();
-        let stmt = StoredStatement::parse(&message, parser).await?;
-        client.portal_store().put_statement(Arc::new(stmt));
+        let type_registry = client.server_config().type_registry.clone();
+        let stmt = StoredStatement::parse(&message, parser, &type_registry).await?;
+        // clients like ORMs parse the same query into the unnamed statement
+        // again and again, keep its describe result
+        if let Some(previous) = client.portal_store().get_statement(&stmt.id) {
+            if let Some(describe) = previous.cached_describe() {
+                if previous.query() == stmt.query()
+                    && previous.parameter_types == stmt.parameter_types
+                {
+                    stmt.cache_describe(describe.clone());
+                }
+            }
+        }
+        client.portal_store().try_put_statement(Arc::new(stmt))?;
+        retain_suspended_portals(client);
         client
             .send(PgWireBackendMessage::ParseComplete(ParseComplete::new()))
             .await?;
@@ -174,9 +369,14 @@ pub trait ExtendedQueryHandler: Send + Sync {
     {
         let statement_name = message.statement_name.as_deref().unwrap_or(DEFAULT_NAME);
 
+        self.check_portal_store_usage(client)?;
+
         if let Some(statement) = client.portal_store().get_statement(statement_name) {
-            let portal = Portal::try_new(&message, statement)?;
-            client.portal_store().put_portal(Arc::new(portal));
+            let portal = Portal::try_new(&message, statement)?
+                .with_type_registry(client.server_config().type_registry.clone());
+            remove_suspended_portal(client, &portal.name);
+            client.portal_store().try_put_portal(Arc::new(portal))?;
+            retain_suspended_portals(client);
             client
                 .send(PgWireBackendMessage::BindComplete(BindComplete::new()))
                 .await?;
@@ -186,6 +386,34 @@ pub trait ExtendedQueryHandler: Send + Sync {
         }
     }
 
+    /// Called before a new statement or portal is stored on `Parse` and
+    /// `Bind`. Return an error to reject the request.
+    ///
+    /// The default implementation rejects the request with error `53400` when
+    /// estimated memory exceeds `max_memory` of `PortalStoreLimits` in client's
+    /// `ServerConfig`. Override this to enforce your own per-session quota,
+    /// with usage from `ClientPortalStore::portal_store_stats`.
+    fn check_portal_store_usage<C>(&self, client: &C) -> PgWireResult<()>
+    where
+        C: ClientInfo + ClientPortalStore,
+        C::PortalStore: PortalStore,
+    {
+        if let Some(max_memory) = client.server_config().portal_store_limits.max_memory {
+            // stats walks all statements and portals, only when limited
+            if client.portal_store_stats().estimated_memory >= max_memory {
+                return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                    "ERROR".to_owned(),
+                    "53400".to_owned(),
+                    format!(
+                        "prepared statements and portals exceed the memory limit of {max_memory} bytes"
+                    ),
+                ))));
+            }
+        }
+
+        Ok(())
+    }
+
     /// Called when client sends `execute` command.
     ///
     /// The default implementation delegates the query to `self::do_query` and
@@ -211,49 +439,27 @@ pub trait ExtendedQueryHandler: Send + Sync {
 
         let portal_name = message.name.as_deref().unwrap_or(DEFAULT_NAME);
         if let Some(portal) = client.portal_store().get_portal(portal_name) {
-            match self
-                .do_query(client, portal.as_ref(), message.max_rows as usize)
-                .await?
-            {
-                Response::EmptyQuery => {
-                    client
-                        .feed(PgWireBackendMessage::EmptyQueryResponse(EmptyQueryResponse))
-                        .await?;
-                }
-                Response::Query(results) => {
-                    send_query_response(client, results, false).await?;
-                }
-                Response::Execution(tag) => {
-                    send_execution_response(client, tag).await?;
-                }
-                Response::TransactionStart(tag) => {
-                    send_execution_response(client, tag).await?;
-                    transaction_status = transaction_status.to_in_transaction_state();
-                }
-                Response::TransactionEnd(tag) => {
-                    send_execution_response(client, tag).await?;
-                    transaction_status = transaction_status.to_idle_state();
-                }
-
-                Response::Error(err) => {
-                    client
-                        .send(PgWireBackendMessage::ErrorResponse((*err).into()))
+            // continue a suspended portal without running the query again
+            let response = match remove_suspended_portal(client, portal_name) {
+                Some(results) => Response::Suspended(results),
+                None => {
+                    check_read_only(client, portal.statement.query())?;
+                    let response = self
+                        .do_query(client, portal.as_ref(), message.max_rows as usize)
                         .await?;
-                    transaction_status = transaction_status.to_error_state();
-                }
-                Response::CopyIn(result) => {
-                    client.set_state(PgWireConnectionState::CopyInProgress(true));
-                    copy::send_copy_in_response(client, result).await?;
+                    check_result_formats(&portal, &response)?;
+                    response
                 }
-                Response::CopyOut(result) => {
-                    client.set_state(PgWireConnectionState::CopyInProgress(true));
-                    copy::send_copy_out_response(client, result).await?;
-                }
-                Response::CopyBoth(result) => {
-                    client.set_state(PgWireConnectionState::CopyInProgress(true));
-                    copy::send_copy_both_response(client, result).await?;
-                }
-            }
+            };
+            let max_rows = message.max_rows.max(0) as usize;
+            send_execute_response(
+                client,
+                portal_name,
+                response,
+                max_rows,
+                &mut transaction_status,
+            )
+            .await?;
 
             if !matches!(client.state(), PgWireConnectionState::CopyInProgress(_)) {
                 client.set_state(super::PgWireConnectionState::ReadyForQuery);
@@ -266,9 +472,128 @@ pub trait ExtendedQueryHandler: Send + Sync {
         }
     }
 
+    /// Opt in to batched execution of pipelined `Bind`/`Execute`.
+    ///
+    /// When enabled, pipelined `Bind` and `Execute` without row limit against
+    /// the same statement are buffered until any other message, typically
+    /// `Sync`, and executed together with `on_query_batch`.
+    fn supports_query_batch(&self) -> bool {
+        false
+    }
+
+    /// Called with a run of `Bind`/`Execute` of the same statement when
+    /// `supports_query_batch` is enabled.
+    ///
+    /// The default implementation binds all portals, delegates them to
+    /// `self::do_query_batch` and sends `BindComplete` and the response of
+    /// each portal in order, exactly like `on_bind` and `on_execute` would.
+    /// An error of any item skips the rest of the batch until `Sync`.
+    async fn on_query_batch<C>(
+        &self,
+        client: &mut C,
+        batch: Vec<(Bind, Execute)>,
+    ) -> PgWireResult<()>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        if !matches!(client.state(), super::PgWireConnectionState::ReadyForQuery) {
+            return Err(PgWireError::NotReadyForQuery);
+        }
+        let Some((first, _)) = batch.first() else {
+            return Ok(());
+        };
+        let statement_name = first.statement_name.as_deref().unwrap_or(DEFAULT_NAME);
+        let statement = client
+            .portal_store()
+            .get_statement(statement_name)
+            .ok_or_else(|| PgWireError::StatementNotFound(statement_name.to_owned()))?;
+
+        self.check_portal_store_usage(client)?;
+
+        // a bind error is reported after the items before it
+        let type_registry = client.server_config().type_registry.clone();
+        let mut portals = Vec::with_capacity(batch.len());
+        let mut bind_error = None;
+        for (bind, _) in &batch {
+            match Portal::try_new(bind, statement.clone()) {
+                Ok(portal) => {
+                    portals.push(Arc::new(portal.with_type_registry(type_registry.clone())))
+                }
+                Err(e) => {
+                    bind_error = Some(e);
+                    break;
+                }
+            }
+        }
+
+        if let Err(e) = check_read_only(client, statement.query()) {
+            // fails on `Execute` of the first portal, after its `Bind`
+            let Some(portal) = portals.first() else {
+                return Err(bind_error.unwrap_or(e));
+            };
+            remove_suspended_portal(client, &portal.name);
+            client.portal_store().try_put_portal(portal.clone())?;
+            client
+                .feed(PgWireBackendMessage::BindComplete(BindComplete::new()))
+                .await?;
+            return Err(e);
+        }
+
+        let mut transaction_status = client.transaction_status();
+        client.set_state(super::PgWireConnectionState::QueryInProgress);
+
+        let responses = self.do_query_batch(client, &portals).await?;
+        if responses.len() < portals.len()
+            && !responses.iter().any(|r| matches!(r, Response::Error(_)))
+        {
+            return Err(PgWireError::ApiError(
+                format!(
+                    "do_query_batch returned {} responses for {} portals",
+                    responses.len(),
+                    portals.len()
+                )
+                .into(),
+            ));
+        }
+
+        for (portal, response) in portals.iter().zip(responses) {
+            remove_suspended_portal(client, &portal.name);
+            client.portal_store().try_put_portal(portal.clone())?;
+            client
+                .feed(PgWireBackendMessage::BindComplete(BindComplete::new()))
+                .await?;
+            if let Response::Error(e) = response {
+                return Err(PgWireError::UserError(e));
+            }
+            check_result_formats(portal, &response)?;
+            send_execute_response(client, &portal.name, response, 0, &mut transaction_status)
+                .await?;
+            if matches!(client.state(), PgWireConnectionState::CopyInProgress(_)) {
+                return Ok(());
+            }
+        }
+
+        client.set_state(super::PgWireConnectionState::ReadyForQuery);
+        client.set_transaction_status(transaction_status);
+
+        match bind_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
     /// Called when client sends `describe` command.
     ///
     /// The default implementation delegates the call to `self::do_describe`.
+    /// Results of `do_describe_statement` are cached on the statement when
+    /// `cache_describe_statement` of `ServerConfig` is enabled. Portals of a
+    /// statement with cached result are described from the cache, with the
+    /// result formats of the portal, without calling `do_describe_portal`.
+    /// The cache is kept when the same query is parsed again into the same
+    /// statement name, like the unnamed statement.
     async fn on_describe<C>(&self, client: &mut C, message: Describe) -> PgWireResult<()>
     where
         C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
@@ -280,15 +605,25 @@ pub trait ExtendedQueryHandler: Send + Sync {
         match message.target_type {
             TARGET_TYPE_BYTE_STATEMENT => {
                 if let Some(stmt) = client.portal_store().get_statement(name) {
-                    let describe_response = self.do_describe_statement(client, &stmt).await?;
-                    send_describe_response(client, &describe_response).await?;
+                    if let Some(describe_response) = stmt.cached_describe() {
+                        send_describe_response(client, describe_response).await?;
+                    } else {
+                        let describe_response = self.do_describe_statement(client, &stmt).await?;
+                        send_describe_response(client, &describe_response).await?;
+                        if client.server_config().cache_describe_statement {
+                            stmt.cache_describe(describe_response);
+                        }
+                    }
                 } else {
                     return Err(PgWireError::StatementNotFound(name.to_owned()));
                 }
             }
             TARGET_TYPE_BYTE_PORTAL => {
                 if let Some(portal) = client.portal_store().get_portal(name) {
-                    let describe_response = self.do_describe_portal(client, &portal).await?;
+                    let describe_response = match cached_portal_describe(&portal)? {
+                        Some(describe_response) => describe_response,
+                        None => self.do_describe_portal(client, &portal).await?,
+                    };
                     send_describe_response(client, &describe_response).await?;
                 } else {
                     return Err(PgWireError::PortalNotFound(name.to_owned()));
@@ -349,6 +684,7 @@ pub trait ExtendedQueryHandler: Send + Sync {
             }
             TARGET_TYPE_BYTE_PORTAL => {
                 client.portal_store().rm_portal(name);
+                remove_suspended_portal(client, name);
             }
             _ => {}
         }
@@ -358,7 +694,36 @@ pub trait ExtendedQueryHandler: Send + Sync {
         Ok(())
     }
 
+    /// Return resultset metadata of a statement or portal without actually
+    /// executing it.
+    ///
+    /// This is a single entry for both `do_describe_statement` and
+    /// `do_describe_portal`, whose default implementations delegate to it.
+    /// Parameter types are ignored for a portal, and its fields are converted
+    /// to result formats of the portal, so the format of returned fields
+    /// doesn't matter.
+    ///
+    /// The default implementation returns an error. Implement either this
+    /// method, or both `do_describe_statement` and `do_describe_portal`.
+    async fn do_describe<C>(
+        &self,
+        _client: &mut C,
+        _target: StatementOrPortal<'_, Self::Statement>,
+    ) -> PgWireResult<DescribeStatementResponse>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        Err(PgWireError::ApiError(
+            "describe is not implemented by the extended query handler".into(),
+        ))
+    }
+
     /// Return resultset metadata without actually executing statement
+    ///
+    /// The default implementation delegates to `self::do_describe`.
     async fn do_describe_statement<C>(
         &self,
         client: &mut C,
@@ -368,9 +733,16 @@ pub trait ExtendedQueryHandler: Send + Sync {
         C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
         C::PortalStore: PortalStore<Statement = Self::Statement>,
         C::Error: Debug,
-        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>;
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.do_describe(client, StatementOrPortal::Statement(target))
+            .await
+    }
 
     /// Return resultset metadata without actually executing portal
+    ///
+    /// The default implementation delegates to `self::do_describe`, and
+    /// applies result formats of the portal to the fields.
     async fn do_describe_portal<C>(
         &self,
         client: &mut C,
@@ -380,7 +752,16 @@ pub trait ExtendedQueryHandler: Send + Sync {
         C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
         C::PortalStore: PortalStore<Statement = Self::Statement>,
         C::Error: Debug,
-        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>;
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let describe = self
+            .do_describe(client, StatementOrPortal::Portal(target))
+            .await?;
+        let fields = target
+            .result_column_format
+            .apply_to_fields(describe.fields)?;
+        Ok(DescribePortalResponse::new(fields))
+    }
 
     /// This is the main implementation for query execution. Context has
     /// been provided:
@@ -399,6 +780,72 @@ pub trait ExtendedQueryHandler: Send + Sync {
         C::PortalStore: PortalStore<Statement = Self::Statement>,
         C::Error: Debug,
         PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>;
+
+    /// Execute portals of a batch, see `supports_query_batch`.
+    ///
+    /// Returns one response for each portal, in order. The default
+    /// implementation calls `self::do_query` for each portal, and stops at
+    /// the first `Response::Error`. Override this to execute the whole batch
+    /// at once, for example as a single vectorized insert.
+    async fn do_query_batch<'a, 'b: 'a, C>(
+        &'b self,
+        client: &mut C,
+        portals: &'a [Arc<Portal<Self::Statement>>],
+    ) -> PgWireResult<Vec<Response<'a>>>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let mut responses = Vec::with_capacity(portals.len());
+        for portal in portals {
+            let response = match self.do_query(client, portal.as_ref(), 0).await {
+                Ok(response) => response,
+                Err(PgWireError::UserError(e)) => Response::Error(e),
+                Err(e) => return Err(e),
+            };
+            let is_error = matches!(response, Response::Error(_));
+            responses.push(response);
+            if is_error {
+                break;
+            }
+        }
+        Ok(responses)
+    }
+}
+
+/// Describe a portal from the cached describe result of its statement
+fn cached_portal_describe<S>(portal: &Portal<S>) -> PgWireResult<Option<DescribePortalResponse>> {
+    let Some(describe) = portal.statement.cached_describe() else {
+        return Ok(None);
+    };
+    let fields = portal
+        .result_column_format
+        .apply_to_fields(describe.fields.clone())?;
+    Ok(Some(DescribePortalResponse::new(fields)))
+}
+
+/// Reject writes when `ServerConfig::read_only_mode` is on
+fn check_read_only<C: ClientInfo>(client: &C, query: &str) -> PgWireResult<()> {
+    match &client.server_config().read_only_mode {
+        Some(mode) => mode.check_query(query),
+        None => Ok(()),
+    }
+}
+
+/// Check schema of rows from `do_query` against result formats requested by
+/// `Bind` of the portal
+fn check_result_formats<S>(portal: &Portal<S>, response: &Response<'_>) -> PgWireResult<()> {
+    match response {
+        Response::Query(results) => portal
+            .result_column_format
+            .check_fields(&results.row_schema()),
+        Response::Suspended(results) => portal
+            .result_column_format
+            .check_fields(&results.row_schema()),
+        _ => Ok(()),
+    }
 }
 
 /// Helper function to send `QueryResponse` and optional `RowDescription` to client
@@ -406,6 +853,14 @@ pub trait ExtendedQueryHandler: Send + Sync {
 /// For most cases in extended query implementation, `send_describe` is set to
 /// false because not all `Execute` comes with `Describe`. The client may have
 /// decribed statement/portal before.
+///
+/// Data rows are flushed to client in batches, according to
+/// `data_row_flush_rows` and `data_row_flush_bytes` of client's `ServerConfig`.
+/// Rows of a batch are encoded straight into the write buffer of the
+/// connection when `ClientInfo::write_buffer_mut` is available, and written
+/// to the socket with a single flush.
+/// Regardless of them, it waits for the client to catch up when pending
+/// messages reach `write_buffer_high_watermark`.
 pub async fn send_query_response<C>(
     client: &mut C,
     results: QueryResponse<'_>,
@@ -423,27 +878,376 @@ where
     // Simple query has row_schema in query response. For extended query,
     // row_schema is returned as response of `Describe`.
     if send_describe {
-        let row_desc = into_row_description(&row_schema);
+        send_row_description(client, &row_schema).await?;
+    }
+
+    let (rows, _) = send_data_rows(client, &mut data_rows, 0).await?;
+
+    let tag = Tag::new(&command_tag).with_rows(rows);
+    client
+        .send(PgWireBackendMessage::CommandComplete(tag.into()))
+        .await?;
+
+    Ok(())
+}
+
+/// Send `RowDescription` of `schema`. When the write buffer of the connection
+/// is available, the description encoded for previous responses of the same
+/// schema is reused.
+async fn send_row_description<C>(client: &mut C, schema: &Arc<Vec<FieldInfo>>) -> PgWireResult<()>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    if client.write_buffer_mut().is_none() {
+        let row_desc = into_row_description(schema);
         client
             .send(PgWireBackendMessage::RowDescription(row_desc))
             .await?;
+        return Ok(());
+    }
+
+    let extensions = client.extensions_mut();
+    if extensions.get::<RowDescriptionCache>().is_none() {
+        extensions.insert(RowDescriptionCache::default());
+    }
+    let encoded = match extensions.get_mut::<RowDescriptionCache>() {
+        Some(cache) => cache.get_or_encode(schema)?,
+        None => return Ok(()),
+    };
+
+    if let Some(buf) = client.write_buffer_mut() {
+        buf.extend_from_slice(&encoded);
+        client.record_sent(b'T', encoded.len());
+    }
+    client.flush().await?;
+
+    Ok(())
+}
+
+/// Encode a `DataRow` with `encode` and feed it to the client.
+///
+/// The row is written straight into the write buffer of the connection when
+/// `ClientInfo::write_buffer_mut` is available, so there is no buffer
+/// allocated and copied for each row. The connection is flushed when the
+/// buffer reaches `data_row_flush_bytes` or `write_buffer_high_watermark` of
+/// `ServerConfig`.
+pub async fn feed_data_row<C, F>(
+    client: &mut C,
+    schema: &[FieldInfo],
+    encode: F,
+) -> PgWireResult<()>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    F: FnOnce(&mut DataRowWriter<'_>) -> PgWireResult<()>,
+{
+    let flush_bytes = client.server_config().data_row_flush_bytes;
+    let watermark = client.server_config().write_buffer_high_watermark;
+
+    if let Some(buf) = client.write_buffer_mut() {
+        let start = buf.len();
+        let mut writer = DataRowWriter::new(schema, buf);
+        // the type byte is not counted in message length
+        let result = encode(&mut writer)
+            .map(|_| writer.finish())
+            .and_then(|len| check_message_length(len - 1).map(|_| len));
+        let len = match result {
+            Ok(len) => len,
+            Err(e) => {
+                // discard the partial row
+                buf.truncate(start);
+                return Err(e);
+            }
+        };
+        let buffered = buf.len();
+        client.record_sent(b'D', len);
+
+        if (flush_bytes > 0 && buffered >= flush_bytes)
+            || buffered >= watermark
+            || exceeds_buffered_memory(client)
+        {
+            client.flush().await?;
+        }
+    } else {
+        let mut buf = pool::take(128);
+        let mut writer = DataRowWriter::new(schema, &mut buf);
+        encode(&mut writer)?;
+        writer.finish();
+
+        // strip message header for `DataRow`
+        let field_count = i16::from_be_bytes([buf[5], buf[6]]);
+        buf.advance(7);
+        client
+            .feed(PgWireBackendMessage::DataRow(DataRow::new(
+                buf,
+                field_count,
+            )))
+            .await?;
     }
 
+    Ok(())
+}
+
+/// Send data rows of the stream until it ends, or `max_rows` rows are sent
+/// when it's not 0. Returns the number and bytes of rows sent.
+async fn send_data_rows<C>(
+    client: &mut C,
+    data_rows: &mut BoxStream<'_, PgWireResult<DataRow>>,
+    max_rows: usize,
+) -> PgWireResult<(usize, usize)>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
     let mut rows = 0;
-    while let Some(row) = data_rows.next().await {
+    let mut bytes = 0;
+    // rows and bytes buffered since last flush
+    let mut batch_rows = 0;
+    let mut batch_bytes = 0;
+    while max_rows == 0 || rows < max_rows {
+        let Some(row) = data_rows.next().await else {
+            break;
+        };
         let row = row?;
         rows += 1;
-        client.feed(PgWireBackendMessage::DataRow(row)).await?;
+        batch_rows += 1;
+        // message type byte is not included in message length
+        let row_bytes = row.message_length() + 1;
+        bytes += row_bytes;
+        batch_bytes += row_bytes;
+
+        let watermark = client.server_config().write_buffer_high_watermark;
+        let buffered = if let Some(buf) = client.write_buffer_mut() {
+            // rows of a batch are appended to the write buffer, without
+            // going through `Sink` for each of them
+            row.encode(buf)?;
+            let buffered = buf.len();
+            client.record_sent(b'D', row_bytes);
+            pool::recycle(row.data);
+            buffered
+        } else {
+            client.feed(PgWireBackendMessage::DataRow(row)).await?;
+            0
+        };
+
+        if client
+            .server_config()
+            .should_flush_data_rows(batch_rows, batch_bytes)
+            || buffered >= watermark
+            || exceeds_buffered_memory(client)
+        {
+            client.flush().await?;
+            batch_rows = 0;
+            batch_bytes = 0;
+        }
     }
 
-    let tag = Tag::new(&command_tag).with_rows(rows);
+    Ok((rows, bytes))
+}
+
+/// Test if buffered memory of the client exceeds
+/// `ServerConfig::max_buffered_bytes`
+fn exceeds_buffered_memory<C: ClientInfo>(client: &C) -> bool {
     client
-        .send(PgWireBackendMessage::CommandComplete(tag.into()))
-        .await?;
+        .server_config()
+        .max_buffered_bytes
+        .is_some_and(|limit| client.buffered_memory().total() > limit)
+}
+
+/// Helper function to send rows of a portal for `Execute` with row limit.
+///
+/// At most `max_rows` rows are sent, or all of them when `max_rows` is 0.
+/// When the limit is reached, `PortalSuspended` is sent and remaining rows
+/// are kept for the next `Execute` of the portal. Otherwise the portal
+/// completes with `CommandComplete`, like `send_query_response`. The row
+/// count of the command tag only includes rows sent by this `Execute`, same
+/// as postgres.
+pub async fn send_partial_query_response<C>(
+    client: &mut C,
+    portal_name: &str,
+    results: QueryResponse<'static>,
+    max_rows: usize,
+) -> PgWireResult<()>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    let command_tag = results.command_tag().to_owned();
+    let row_schema = results.row_schema();
+    let buffered_bytes = results.buffered_bytes();
+    let mut data_rows = results.data_rows();
+
+    let (rows, bytes) = send_data_rows(client, &mut data_rows, max_rows).await?;
+
+    if max_rows > 0 && rows == max_rows {
+        // rows sent by previous `Execute` are not counted any more
+        let remaining_bytes = buffered_bytes.saturating_sub(bytes);
+        client.flush().await?;
+        memory::check_buffered_memory(client, remaining_bytes)?;
+
+        client
+            .send(PgWireBackendMessage::PortalSuspended(PortalSuspended))
+            .await?;
+
+        let mut remaining = QueryResponse::new(row_schema, data_rows);
+        remaining.set_command_tag(&command_tag);
+        remaining.set_buffered_bytes(remaining_bytes);
+        let extensions = client.extensions_mut();
+        if extensions.get::<SuspendedPortals>().is_none() {
+            extensions.insert(SuspendedPortals::default());
+        }
+        if let Some(portals) = extensions.get_mut::<SuspendedPortals>() {
+            portals
+                .0
+                .insert(portal_name.to_owned(), Mutex::new(remaining));
+        }
+    } else {
+        let tag = Tag::new(&command_tag).with_rows(rows);
+        client
+            .send(PgWireBackendMessage::CommandComplete(tag.into()))
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Send messages of the `Response` to an `Execute`, and track changes of
+/// transaction status
+async fn send_execute_response<C>(
+    client: &mut C,
+    portal_name: &str,
+    response: Response<'_>,
+    max_rows: usize,
+    transaction_status: &mut TransactionStatus,
+) -> PgWireResult<()>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    match response {
+        Response::EmptyQuery => {
+            client
+                .feed(PgWireBackendMessage::EmptyQueryResponse(EmptyQueryResponse))
+                .await?;
+        }
+        Response::Query(results) => {
+            send_query_response(client, results, false).await?;
+        }
+        Response::Suspended(results) => {
+            send_partial_query_response(client, portal_name, results, max_rows).await?;
+        }
+        Response::Execution(tag) => {
+            send_execution_response(client, tag).await?;
+        }
+        Response::ParameterStatus { name, value } => {
+            send_parameter_status_response(client, name, value).await?;
+        }
+        Response::TransactionStart(tag) => {
+            send_execution_response(client, tag).await?;
+            *transaction_status = transaction_status.to_in_transaction_state();
+        }
+        Response::TransactionEnd(tag) => {
+            send_execution_response(client, tag).await?;
+            *transaction_status = transaction_status.to_idle_state();
+        }
+        Response::Error(err) => {
+            client
+                .send(PgWireBackendMessage::ErrorResponse((*err).into()))
+                .await?;
+            *transaction_status = transaction_status.to_error_state();
+        }
+        Response::Notice(notice) => {
+            // extended query has only one response for each execute, which
+            // is finished as an empty query after the notice
+            client
+                .feed(PgWireBackendMessage::NoticeResponse((*notice).into()))
+                .await?;
+            client
+                .feed(PgWireBackendMessage::EmptyQueryResponse(EmptyQueryResponse))
+                .await?;
+        }
+        Response::CopyIn(result) => {
+            client.set_state(PgWireConnectionState::CopyInProgress(true));
+            copy::send_copy_in_response(client, result).await?;
+        }
+        Response::CopyOut(result) => {
+            client.set_state(PgWireConnectionState::CopyInProgress(true));
+            copy::send_copy_out_response(client, result).await?;
+        }
+        Response::CopyBoth(result) => {
+            client.set_state(PgWireConnectionState::CopyInProgress(true));
+            copy::send_copy_both_response(client, result).await?;
+        }
+    }
 
     Ok(())
 }
 
+/// Remaining rows of suspended portals, kept in client extensions until next
+/// `Execute` of the portal
+#[derive(Default)]
+struct SuspendedPortals(HashMap<String, Mutex<QueryResponse<'static>>>);
+
+/// Bytes of rows held by suspended portals
+pub(crate) fn suspended_portals_bytes(extensions: &Extensions) -> usize {
+    extensions.get::<SuspendedPortals>().map_or(0, |portals| {
+        portals
+            .0
+            .values()
+            .map(|results| {
+                results
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .buffered_bytes()
+            })
+            .sum()
+    })
+}
+
+/// Drop all suspended portals, when their transaction ends or the session is
+/// reset
+pub(crate) fn clear_suspended_portals(extensions: &mut Extensions) {
+    extensions.remove::<SuspendedPortals>();
+}
+
+/// Drop suspended portals no longer in the portal store, like the ones
+/// evicted by `LruPortalStore`
+fn retain_suspended_portals<C>(client: &mut C)
+where
+    C: ClientInfo + ClientPortalStore,
+    C::PortalStore: PortalStore,
+{
+    let Some(portals) = client.extensions().get::<SuspendedPortals>() else {
+        return;
+    };
+    let removed = portals
+        .0
+        .keys()
+        .filter(|name| client.portal_store().get_portal(name).is_none())
+        .cloned()
+        .collect::<Vec<_>>();
+    for name in removed {
+        remove_suspended_portal(client, &name);
+    }
+}
+
+fn remove_suspended_portal<C: ClientInfo>(
+    client: &mut C,
+    portal_name: &str,
+) -> Option<QueryResponse<'static>> {
+    client
+        .extensions_mut()
+        .get_mut::<SuspendedPortals>()
+        .and_then(|portals| portals.0.remove(portal_name))
+        .map(|results| results.into_inner().unwrap_or_else(|e| e.into_inner()))
+}
+
 /// Helper function to send a ReadyForQuery response.
 pub async fn send_ready_for_query<C>(
     client: &mut C,
@@ -476,6 +1280,51 @@ where
     Ok(())
 }
 
+/// Helper function to send response for `SET` of a reported parameter.
+///
+/// For parameters in `REPORTED_PARAMETERS`, the new value is stored in client
+/// metadata, and `ParameterStatus` is sent after the `SET` command complete
+/// like postgres does. Other parameters only get the command complete.
+/// Connection properties like `user` and `database` cannot be changed, and
+/// are rejected with `55P02`.
+pub async fn send_parameter_status_response<C>(
+    client: &mut C,
+    name: String,
+    value: String,
+) -> PgWireResult<()>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    if NON_PARAMETER_KEYS.contains(&name.as_str()) {
+        return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+            "ERROR".to_owned(),
+            "55P02".to_owned(),
+            format!("parameter \"{name}\" cannot be changed"),
+        ))));
+    }
+
+    let reported = REPORTED_PARAMETERS.contains(&name.as_str());
+    if reported {
+        client.metadata_mut().insert(name.clone(), value.clone());
+    }
+    client
+        .feed(PgWireBackendMessage::CommandComplete(
+            Tag::new("SET").into(),
+        ))
+        .await?;
+    if reported {
+        client
+            .send(PgWireBackendMessage::ParameterStatus(ParameterStatus::new(
+                name, value,
+            )))
+            .await?;
+    }
+
+    Ok(())
+}
+
 /// Helper function to send response for `Describe`.
 pub async fn send_describe_response<C, DR>(
     client: &mut C,
@@ -556,3 +1405,736 @@ impl ExtendedQueryHandler for PlaceholderExtendedQueryHandler {
         unimplemented!("Extended Query is not implemented on this server.")
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::api::compat::CompatQueryHandler;
+    use crate::api::config::ServerConfig;
+    use crate::api::connection::MessageTypeStats;
+    use crate::api::results::FieldFormat;
+    use crate::api::router::{HintClassifier, RoutingQueryHandler};
+    use crate::api::set::SetCommandHandler;
+    use crate::api::timeout::TimeoutQueryHandler;
+    use crate::api::Type;
+    use crate::error::NoticeInfo;
+    use crate::messages::response::CommandComplete;
+    use crate::messages::PgWireFrontendMessage;
+    use crate::testkit::fixture::{
+        error_code, error_field, numbers, numbers_schema, FnQueryHandler, NoopStartup, TestHandlers,
+    };
+    use crate::testkit::MockClient;
+
+    /// Feeds rows 1 to `n` directly to the client
+    struct FeedRowsHandler(i32);
+
+    #[async_trait]
+    impl SimpleQueryHandler for FeedRowsHandler {
+        async fn do_query<'a, C>(
+            &self,
+            client: &mut C,
+            _query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            let schema = numbers_schema();
+            for n in 1..=self.0 {
+                feed_data_row(client, &schema, |writer| writer.encode_field(&n)).await?;
+                assert!(
+                    client.buffered_memory().write_buffer
+                        < client.server_config().write_buffer_high_watermark
+                );
+            }
+            Ok(vec![Response::Execution(
+                Tag::new("SELECT").with_rows(self.0 as usize),
+            )])
+        }
+    }
+
+    /// Returns rows 1 to 3 of any portal, suspended when `max_rows` is set.
+    /// Portal is described with a field named differently from the
+    /// statement.
+    struct NumbersHandler;
+
+    #[async_trait]
+    impl ExtendedQueryHandler for NumbersHandler {
+        type Statement = String;
+        type QueryParser = NoopQueryParser;
+
+        fn query_parser(&self) -> Arc<Self::QueryParser> {
+            Arc::new(NoopQueryParser)
+        }
+
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &mut C,
+            _portal: &'a Portal<Self::Statement>,
+            _max_rows: usize,
+        ) -> PgWireResult<Response<'a>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            Ok(Response::Suspended(numbers(3)))
+        }
+
+        fn supports_query_batch(&self) -> bool {
+            true
+        }
+
+        /// Inserts of a batch report the size of the batch, or fail on any
+        /// parameter. Others run one by one.
+        async fn do_query_batch<'a, 'b: 'a, C>(
+            &'b self,
+            client: &mut C,
+            portals: &'a [Arc<Portal<Self::Statement>>],
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+            C::PortalStore: PortalStore<Statement = Self::Statement>,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            let mut responses = Vec::with_capacity(portals.len());
+            for portal in portals {
+                if portal.statement.statement.starts_with("INSERT") {
+                    if !portal.parameters.is_empty() {
+                        responses.push(Response::Error(Box::new(ErrorInfo::new(
+                            "ERROR".to_owned(),
+                            "23505".to_owned(),
+                            "duplicate key".to_owned(),
+                        ))));
+                        break;
+                    }
+                    responses.push(Response::Execution(
+                        Tag::new("INSERT").with_oid(0).with_rows(portals.len()),
+                    ));
+                } else {
+                    responses.push(self.do_query(client, portal.as_ref(), 0).await?);
+                }
+            }
+            Ok(responses)
+        }
+
+        async fn do_describe_statement<C>(
+            &self,
+            _client: &mut C,
+            _statement: &StoredStatement<Self::Statement>,
+        ) -> PgWireResult<DescribeStatementResponse>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            Ok(DescribeStatementResponse::new(
+                vec![],
+                (*numbers_schema()).clone(),
+            ))
+        }
+
+        async fn do_describe_portal<C>(
+            &self,
+            _client: &mut C,
+            _portal: &Portal<Self::Statement>,
+        ) -> PgWireResult<DescribePortalResponse>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            Ok(DescribePortalResponse::new(vec![FieldInfo::new(
+                "portal".to_owned(),
+                None,
+                None,
+                Type::INT4,
+                FieldFormat::Text,
+            )]))
+        }
+    }
+
+    /// Implements only `do_describe`, with an INT4 parameter for each `$`
+    struct DescribeHandler;
+
+    #[async_trait]
+    impl ExtendedQueryHandler for DescribeHandler {
+        type Statement = String;
+        type QueryParser = NoopQueryParser;
+
+        fn query_parser(&self) -> Arc<Self::QueryParser> {
+            Arc::new(NoopQueryParser)
+        }
+
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            _client: &mut C,
+            _portal: &'a Portal<Self::Statement>,
+            _max_rows: usize,
+        ) -> PgWireResult<Response<'a>>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            Ok(Response::EmptyQuery)
+        }
+
+        async fn do_describe<C>(
+            &self,
+            _client: &mut C,
+            target: StatementOrPortal<'_, Self::Statement>,
+        ) -> PgWireResult<DescribeStatementResponse>
+        where
+            C: ClientInfo + Unpin + Send + Sync,
+        {
+            let parameters = vec![Type::INT4; target.statement().statement.matches('$').count()];
+            Ok(DescribeStatementResponse::new(
+                parameters,
+                (*numbers_schema()).clone(),
+            ))
+        }
+    }
+
+    fn numbers_handlers() -> TestHandlers<NoopStartup, FnQueryHandler, NumbersHandler> {
+        TestHandlers::echo().with_extended(NumbersHandler)
+    }
+
+    #[test]
+    fn test_split_statements() {
+        assert_eq!(
+            split_statements("SELECT 1; SELECT 2;"),
+            vec!["SELECT 1", "SELECT 2"]
+        );
+        assert_eq!(split_statements("  ;; "), Vec::<&str>::new());
+        assert_eq!(
+            split_statements("SELECT 'a;b'; SELECT \"c;d\""),
+            vec!["SELECT 'a;b'", "SELECT \"c;d\""]
+        );
+        assert_eq!(
+            split_statements("SELECT 'it''s;'; SELECT E'\\';'"),
+            vec!["SELECT 'it''s;'", "SELECT E'\\';'"]
+        );
+        assert_eq!(
+            split_statements("SELECT 1 -- comment;\n; SELECT /* a /* ; */ ; */ 2"),
+            vec!["SELECT 1 -- comment;", "SELECT /* a /* ; */ ; */ 2"]
+        );
+        assert_eq!(
+            split_statements(
+                "CREATE FUNCTION f() RETURNS int AS $fn$ SELECT 1; $fn$ LANGUAGE sql; SELECT $$;$$"
+            ),
+            vec![
+                "CREATE FUNCTION f() RETURNS int AS $fn$ SELECT 1; $fn$ LANGUAGE sql",
+                "SELECT $$;$$"
+            ]
+        );
+        assert_eq!(
+            split_statements("SELECT $1; SELECT a$b; SELECT 1"),
+            vec!["SELECT $1", "SELECT a$b", "SELECT 1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_notice_response() {
+        let handlers = TestHandlers::new(FnQueryHandler::new(|_| {
+            Ok(vec![
+                Response::Notice(Box::new(NoticeInfo::new(
+                    "NOTICE".to_owned(),
+                    "00000".to_owned(),
+                    "skipping".to_owned(),
+                ))),
+                Response::Execution(Tag::new("DROP TABLE")),
+            ])
+        }));
+        let mut client = MockClient::start(handlers);
+        client.startup("tom", None).await.unwrap();
+
+        let messages = client.simple_query("DROP TABLE IF EXISTS t").await.unwrap();
+        let PgWireBackendMessage::NoticeResponse(notice) = &messages[0] else {
+            panic!("notice response expected");
+        };
+        assert!(notice.fields.contains(&(b'M', "skipping".to_owned())));
+        assert_eq!(
+            PgWireBackendMessage::CommandComplete(CommandComplete::new("DROP TABLE".to_owned())),
+            messages[1]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_notice_response_in_execute() {
+        let handlers = TestHandlers::new(FnQueryHandler::new(|_| {
+            Ok(vec![Response::Notice(Box::new(NoticeInfo::new(
+                "NOTICE".to_owned(),
+                "00000".to_owned(),
+                "skipping".to_owned(),
+            )))])
+        }));
+        let mut client = MockClient::start(handlers);
+        client.startup("tom", None).await.unwrap();
+
+        client
+            .send_all([
+                PgWireFrontendMessage::Parse(Parse::new(
+                    None,
+                    "DROP TABLE IF EXISTS t".to_owned(),
+                    vec![],
+                )),
+                PgWireFrontendMessage::Bind(Bind::new(None, None, vec![], vec![], vec![])),
+                PgWireFrontendMessage::Execute(Execute::new(None, 0)),
+                PgWireFrontendMessage::Sync(PgSync::new()),
+            ])
+            .await
+            .unwrap();
+        let messages = client.receive_until_ready().await.unwrap();
+        assert_eq!(5, messages.len());
+        let PgWireBackendMessage::NoticeResponse(notice) = &messages[2] else {
+            panic!("notice response expected");
+        };
+        assert!(notice.fields.contains(&(b'M', "skipping".to_owned())));
+        assert_eq!(
+            PgWireBackendMessage::EmptyQueryResponse(EmptyQueryResponse),
+            messages[3]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_responses_after_error() {
+        let handlers = TestHandlers::new(FnQueryHandler::new(|_| {
+            Ok(vec![
+                Response::Execution(Tag::new("INSERT").with_oid(0).with_rows(1)),
+                Response::Error(Box::new(ErrorInfo::new(
+                    "ERROR".to_owned(),
+                    "23505".to_owned(),
+                    "duplicate key value".to_owned(),
+                ))),
+                Response::Execution(Tag::new("INSERT").with_oid(0).with_rows(1)),
+            ])
+        }));
+        let mut client = MockClient::start(handlers);
+        client.startup("tom", None).await.unwrap();
+
+        // every response returned by do_query is sent
+        let messages = client
+            .simple_query(
+                "INSERT INTO t VALUES (1); INSERT INTO t VALUES (1); INSERT INTO t VALUES (2)",
+            )
+            .await
+            .unwrap();
+        assert_eq!(4, messages.len());
+        assert!(matches!(
+            messages[1],
+            PgWireBackendMessage::ErrorResponse(_)
+        ));
+        assert!(matches!(
+            messages[2],
+            PgWireBackendMessage::CommandComplete(_)
+        ));
+        // implicit transaction is rolled back
+        assert_eq!(
+            PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(TransactionStatus::Idle)),
+            messages[3]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_parameter_status_response() {
+        let handlers = TestHandlers::new(FnQueryHandler::new(|_| {
+            Ok(vec![Response::ParameterStatus {
+                name: "DateStyle".to_owned(),
+                value: "ISO, MDY".to_owned(),
+            }])
+        }));
+        let mut client = MockClient::start(handlers);
+        client.startup("tom", None).await.unwrap();
+
+        client
+            .send(PgWireFrontendMessage::Query(Query::new(
+                "SET DateStyle TO ISO, MDY".to_owned(),
+            )))
+            .await
+            .unwrap();
+        client
+            .expect(&[
+                PgWireBackendMessage::CommandComplete(CommandComplete::new("SET".to_owned())),
+                PgWireBackendMessage::ParameterStatus(ParameterStatus::new(
+                    "DateStyle".to_owned(),
+                    "ISO, MDY".to_owned(),
+                )),
+                PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(TransactionStatus::Idle)),
+            ])
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_parameter_status_reserved() {
+        let handlers = TestHandlers::new(FnQueryHandler::new(|query| {
+            let (name, value) = query.split_once('=').unwrap();
+            Ok(vec![Response::ParameterStatus {
+                name: name.to_owned(),
+                value: value.to_owned(),
+            }])
+        }));
+        let mut client = MockClient::start(handlers);
+        client.startup("tom", None).await.unwrap();
+
+        let messages = client.simple_query("user=jerry").await.unwrap();
+        assert_eq!(Some("55P02".to_owned()), error_code(&messages[0]));
+
+        // not reported to client
+        let messages = client.simple_query("search_path=s1").await.unwrap();
+        assert_eq!(
+            vec![
+                PgWireBackendMessage::CommandComplete(CommandComplete::new("SET".to_owned())),
+                PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(TransactionStatus::Idle)),
+            ],
+            messages
+        );
+    }
+
+    #[tokio::test]
+    async fn test_implicit_describe() {
+        let config = Arc::new(ServerConfig {
+            cache_describe_statement: true,
+            ..Default::default()
+        });
+        let mut client = MockClient::start_with_config(numbers_handlers(), config);
+        client.startup("tom", None).await.unwrap();
+
+        async fn describe_portal(
+            client: &mut MockClient,
+            query: &str,
+            describe_statement: bool,
+        ) -> (String, i16) {
+            let mut messages = vec![PgWireFrontendMessage::Parse(Parse::new(
+                None,
+                query.to_owned(),
+                vec![],
+            ))];
+            if describe_statement {
+                messages.push(PgWireFrontendMessage::Describe(Describe::new(
+                    TARGET_TYPE_BYTE_STATEMENT,
+                    None,
+                )));
+            }
+            messages.extend([
+                PgWireFrontendMessage::Bind(Bind::new(None, None, vec![], vec![], vec![1])),
+                PgWireFrontendMessage::Describe(Describe::new(TARGET_TYPE_BYTE_PORTAL, None)),
+                PgWireFrontendMessage::Sync(PgSync::new()),
+            ]);
+            client.send_all(messages).await.unwrap();
+            let field = client
+                .receive_until_ready()
+                .await
+                .unwrap()
+                .into_iter()
+                .filter_map(|m| match m {
+                    PgWireBackendMessage::RowDescription(mut description) => {
+                        Some(description.fields.remove(0))
+                    }
+                    _ => None,
+                })
+                .last()
+                .unwrap();
+            (field.name, field.format_code)
+        }
+
+        // portal described from the statement, in result format of portal
+        assert_eq!(
+            ("n".to_owned(), 1),
+            describe_portal(&mut client, "SELECT n", true).await
+        );
+        // the same query parsed again keeps the statement describe
+        assert_eq!(
+            ("n".to_owned(), 1),
+            describe_portal(&mut client, "SELECT n", false).await
+        );
+        assert_eq!(
+            ("portal".to_owned(), 0),
+            describe_portal(&mut client, "SELECT m", false).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_suspended_portal() {
+        let mut client = MockClient::start(numbers_handlers());
+        client.startup("tom", None).await.unwrap();
+
+        client
+            .send_all([
+                PgWireFrontendMessage::Parse(Parse::new(None, "SELECT n".to_owned(), vec![])),
+                PgWireFrontendMessage::Bind(Bind::new(None, None, vec![], vec![], vec![])),
+                PgWireFrontendMessage::Execute(Execute::new(None, 2)),
+                PgWireFrontendMessage::Execute(Execute::new(None, 2)),
+                PgWireFrontendMessage::Sync(PgSync::new()),
+            ])
+            .await
+            .unwrap();
+        let messages = client.receive_until_ready().await.unwrap();
+        let kinds = messages
+            .iter()
+            .map(|m| match m {
+                PgWireBackendMessage::DataRow(_) => "D",
+                PgWireBackendMessage::PortalSuspended(_) => "s",
+                PgWireBackendMessage::CommandComplete(_) => "C",
+                PgWireBackendMessage::ParseComplete(_) => "1",
+                PgWireBackendMessage::BindComplete(_) => "2",
+                PgWireBackendMessage::ReadyForQuery(_) => "Z",
+                _ => "?",
+            })
+            .collect::<String>();
+        assert_eq!("12DDsDCZ", kinds);
+        assert_eq!(
+            PgWireBackendMessage::PortalSuspended(PortalSuspended),
+            messages[4]
+        );
+        assert_eq!(
+            PgWireBackendMessage::CommandComplete(CommandComplete::new("SELECT 1".to_owned())),
+            messages[6]
+        );
+
+        // rebinding the portal discards suspended rows
+        client
+            .send_all([
+                PgWireFrontendMessage::Bind(Bind::new(None, None, vec![], vec![], vec![])),
+                PgWireFrontendMessage::Execute(Execute::new(None, 0)),
+                PgWireFrontendMessage::Sync(PgSync::new()),
+            ])
+            .await
+            .unwrap();
+        let messages = client.receive_until_ready().await.unwrap();
+        assert_eq!(
+            PgWireBackendMessage::BindComplete(BindComplete::new()),
+            messages[0]
+        );
+        assert_eq!(
+            PgWireBackendMessage::CommandComplete(CommandComplete::new("SELECT 3".to_owned())),
+            messages[4]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unified_describe() {
+        let mut client = MockClient::start(TestHandlers::echo().with_extended(DescribeHandler));
+        client.startup("tom", None).await.unwrap();
+
+        client
+            .send_all([
+                PgWireFrontendMessage::Parse(Parse::new(
+                    None,
+                    "SELECT n WHERE n > $1".to_owned(),
+                    vec![],
+                )),
+                PgWireFrontendMessage::Describe(Describe::new(TARGET_TYPE_BYTE_STATEMENT, None)),
+                PgWireFrontendMessage::Bind(Bind::new(None, None, vec![], vec![None], vec![1])),
+                PgWireFrontendMessage::Describe(Describe::new(TARGET_TYPE_BYTE_PORTAL, None)),
+                PgWireFrontendMessage::Sync(PgSync::new()),
+            ])
+            .await
+            .unwrap();
+        let messages = client.receive_until_ready().await.unwrap();
+        let PgWireBackendMessage::ParameterDescription(parameters) = &messages[1] else {
+            panic!("unexpected message {:?}", messages[1]);
+        };
+        assert_eq!(vec![Type::INT4.oid()], parameters.types);
+        let formats = messages
+            .iter()
+            .filter_map(|m| match m {
+                PgWireBackendMessage::RowDescription(description) => {
+                    Some(description.fields[0].format_code)
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        // the portal is described in its result format
+        assert_eq!(vec![0, 1], formats);
+    }
+
+    #[tokio::test]
+    async fn test_result_formats() {
+        let mut client = MockClient::start(numbers_handlers());
+        client.startup("tom", None).await.unwrap();
+
+        // rows of the handler are in text, binary is requested
+        client
+            .send_all([
+                PgWireFrontendMessage::Parse(Parse::new(None, "SELECT n".to_owned(), vec![])),
+                PgWireFrontendMessage::Bind(Bind::new(None, None, vec![], vec![], vec![1])),
+                PgWireFrontendMessage::Execute(Execute::new(None, 0)),
+                PgWireFrontendMessage::Sync(PgSync::new()),
+            ])
+            .await
+            .unwrap();
+        let messages = client.receive_until_ready().await.unwrap();
+        assert_eq!(4, messages.len());
+        assert!(error_field(&messages[2], b'M')
+            .unwrap()
+            .contains("Binary format is requested"));
+
+        // unknown format code is rejected on bind
+        client
+            .send_all([
+                PgWireFrontendMessage::Bind(Bind::new(None, None, vec![], vec![], vec![2])),
+                PgWireFrontendMessage::Sync(PgSync::new()),
+            ])
+            .await
+            .unwrap();
+        let messages = client.receive_until_ready().await.unwrap();
+        assert_eq!(Some("22023".to_owned()), error_code(&messages[0]));
+    }
+
+    #[tokio::test]
+    async fn test_query_batch() {
+        // wrappers keep batching of the inner handler
+        let handler = Arc::new(NumbersHandler);
+        assert!(
+            TimeoutQueryHandler::new(handler.clone(), Duration::from_secs(1))
+                .supports_query_batch()
+        );
+        assert!(CompatQueryHandler::new(handler.clone()).supports_query_batch());
+        assert!(SetCommandHandler::new(handler.clone()).supports_query_batch());
+        assert!(RoutingQueryHandler::new(handler, Arc::new(HintClassifier)).supports_query_batch());
+
+        let mut client = MockClient::start(numbers_handlers());
+        client.startup("tom", None).await.unwrap();
+
+        let mut messages = vec![PgWireFrontendMessage::Parse(Parse::new(
+            Some("s".to_owned()),
+            "INSERT n".to_owned(),
+            vec![],
+        ))];
+        for _ in 0..3 {
+            messages.push(PgWireFrontendMessage::Bind(Bind::new(
+                None,
+                Some("s".to_owned()),
+                vec![],
+                vec![],
+                vec![],
+            )));
+            messages.push(PgWireFrontendMessage::Execute(Execute::new(None, 0)));
+        }
+        messages.push(PgWireFrontendMessage::Sync(PgSync::new()));
+        client.send_all(messages).await.unwrap();
+
+        let messages = client.receive_until_ready().await.unwrap();
+        let inserted =
+            PgWireBackendMessage::CommandComplete(CommandComplete::new("INSERT 0 3".to_owned()));
+        let bound = PgWireBackendMessage::BindComplete(BindComplete::new());
+        assert_eq!(8, messages.len());
+        assert!(matches!(
+            messages[0],
+            PgWireBackendMessage::ParseComplete(_)
+        ));
+        for i in 0..3 {
+            assert_eq!(bound, messages[1 + i * 2]);
+            assert_eq!(inserted, messages[2 + i * 2]);
+        }
+        assert!(matches!(
+            messages[7],
+            PgWireBackendMessage::ReadyForQuery(_)
+        ));
+
+        // an error skips the rest of the batch until sync
+        let messages = [vec![], vec![None], vec![]]
+            .into_iter()
+            .flat_map(|parameters| {
+                [
+                    PgWireFrontendMessage::Bind(Bind::new(
+                        None,
+                        Some("s".to_owned()),
+                        vec![],
+                        parameters,
+                        vec![],
+                    )),
+                    PgWireFrontendMessage::Execute(Execute::new(None, 0)),
+                ]
+            })
+            .chain([PgWireFrontendMessage::Sync(PgSync::new())]);
+        client.send_all(messages).await.unwrap();
+        let messages = client.receive_until_ready().await.unwrap();
+        assert_eq!(5, messages.len());
+        assert_eq!(bound, messages[0]);
+        assert_eq!(bound, messages[2]);
+        assert_eq!(Some("23505".to_owned()), error_code(&messages[3]));
+        assert!(matches!(
+            messages[4],
+            PgWireBackendMessage::ReadyForQuery(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_feed_data_row() {
+        let mut client = MockClient::start(TestHandlers::echo().with_simple(FeedRowsHandler(2)));
+        client.startup("tom", None).await.unwrap();
+
+        let messages = client.simple_query("SELECT n").await.unwrap();
+        assert_eq!(4, messages.len());
+        let PgWireBackendMessage::DataRow(row) = &messages[1] else {
+            panic!("data row expected");
+        };
+        assert_eq!(1, row.field_count);
+        assert_eq!(&b"\0\0\0\x012"[..], &row.data[..]);
+        assert_eq!(
+            PgWireBackendMessage::CommandComplete(CommandComplete::new("SELECT 2".to_owned())),
+            messages[2]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_buffered_message_stats() {
+        let mut client = MockClient::start(TestHandlers::echo().with_simple(FeedRowsHandler(2)));
+        client.startup("tom", None).await.unwrap();
+        client.simple_query("SELECT n").await.unwrap();
+        let summary = client.terminate().await.unwrap();
+        assert_eq!(
+            MessageTypeStats {
+                count: 2,
+                bytes: 24
+            },
+            summary.messages.sent(b'D')
+        );
+        assert_eq!(
+            summary.bytes_sent,
+            summary.messages.sent.values().map(|s| s.bytes).sum::<u64>()
+        );
+
+        // cached row description
+        let mut client = MockClient::start(TestHandlers::new(FnQueryHandler::new(|_| {
+            Ok(vec![Response::Query(numbers(1))])
+        })));
+        client.startup("tom", None).await.unwrap();
+        client.simple_query("SELECT n").await.unwrap();
+        client.simple_query("SELECT n").await.unwrap();
+        let summary = client.terminate().await.unwrap();
+        assert_eq!(2, summary.messages.sent(b'T').count);
+        assert_eq!(
+            summary.bytes_sent,
+            summary.messages.sent.values().map(|s| s.bytes).sum::<u64>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_buffer_high_watermark() {
+        // batching by flush thresholds disabled
+        let config = Arc::new(ServerConfig {
+            data_row_flush_rows: 0,
+            data_row_flush_bytes: 0,
+            write_buffer_high_watermark: 1024,
+            ..Default::default()
+        });
+        let fed = TestHandlers::echo().with_simple(FeedRowsHandler(10000));
+        let returned = TestHandlers::new(FnQueryHandler::new(|_| {
+            Ok(vec![Response::Query(numbers(10000))])
+        }));
+        let mut clients = [
+            MockClient::start_with_config(fed, config.clone()),
+            MockClient::start_with_config(returned, config),
+        ];
+
+        for client in &mut clients {
+            client.startup("tom", None).await.unwrap();
+            let messages = client.simple_query("SELECT n").await.unwrap();
+            assert_eq!(
+                PgWireBackendMessage::CommandComplete(CommandComplete::new(
+                    "SELECT 10000".to_owned()
+                )),
+                messages[messages.len() - 2]
+            );
+        }
+    }
+}
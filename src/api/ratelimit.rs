@@ -0,0 +1,168 @@
+//! Rate limiting of incoming connections.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Number of tracked addresses, the least recently updated bucket is evicted
+/// for a new address beyond it
+const MAX_TRACKED_ADDRS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+    /// Position in `Buckets::by_update`
+    seq: u64,
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    by_addr: HashMap<IpAddr, Bucket>,
+    /// Addresses by last update, least recent first
+    by_update: BTreeMap<u64, IpAddr>,
+    seq: u64,
+}
+
+/// Address a connection is limited by. IPv6 clients usually own a whole
+/// /64, so addresses of the same /64 share one bucket.
+fn bucket_addr(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & (u128::MAX << 64))),
+        },
+        v4 => v4,
+    }
+}
+
+/// Token bucket limiting connection attempts from each source IP.
+///
+/// IPv6 addresses are limited by their /64 prefix. At most 10,000 addresses
+/// are tracked, the least recently seen ones are forgotten beyond that.
+///
+/// It's checked by `process_socket` right after the connection is accepted,
+/// before TLS handshake and startup, so excess attempts are refused by
+/// closing the socket without spending any work on them. Set it with
+/// `ServerConfig::handshake_rate_limiter`, and share the same instance for
+/// all connections of a listener.
+#[derive(Debug)]
+pub struct HandshakeRateLimiter {
+    burst: u32,
+    per_second: f64,
+    buckets: Mutex<Buckets>,
+}
+
+impl HandshakeRateLimiter {
+    /// Allow `burst` attempts at once from each address, refilled at
+    /// `per_second` attempts per second.
+    pub fn new(burst: u32, per_second: f64) -> HandshakeRateLimiter {
+        HandshakeRateLimiter {
+            burst,
+            per_second,
+            buckets: Mutex::new(Buckets::default()),
+        }
+    }
+
+    /// Take a token for a connection attempt from `addr`. Returns false if
+    /// the attempt should be refused.
+    pub fn try_acquire(&self, addr: IpAddr) -> bool {
+        self.try_acquire_at(addr, Instant::now())
+    }
+
+    fn try_acquire_at(&self, addr: IpAddr, now: Instant) -> bool {
+        let addr = bucket_addr(addr);
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let buckets = &mut *buckets;
+
+        if buckets.by_addr.len() >= MAX_TRACKED_ADDRS && !buckets.by_addr.contains_key(&addr) {
+            if let Some((_, lru)) = buckets.by_update.pop_first() {
+                buckets.by_addr.remove(&lru);
+            }
+        }
+
+        buckets.seq += 1;
+        let seq = buckets.seq;
+        let bucket = buckets.by_addr.entry(addr).or_insert(Bucket {
+            tokens: self.burst as f64,
+            updated_at: now,
+            seq,
+        });
+        buckets.by_update.remove(&bucket.seq);
+        buckets.by_update.insert(seq, addr);
+        bucket.tokens = self.refill(bucket, now);
+        bucket.updated_at = now;
+        bucket.seq = seq;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now
+            .checked_duration_since(bucket.updated_at)
+            .unwrap_or(Duration::ZERO);
+        (bucket.tokens + elapsed.as_secs_f64() * self.per_second).min(self.burst as f64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_handshake_rate_limiter() {
+        let limiter = HandshakeRateLimiter::new(2, 1.0);
+        let addr: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(limiter.try_acquire_at(addr, now));
+        assert!(limiter.try_acquire_at(addr, now));
+        assert!(!limiter.try_acquire_at(addr, now));
+        // other addresses have their own bucket
+        assert!(limiter.try_acquire_at(other, now));
+
+        assert!(!limiter.try_acquire_at(addr, now + Duration::from_millis(500)));
+        assert!(limiter.try_acquire_at(addr, now + Duration::from_millis(1500)));
+        assert!(!limiter.try_acquire_at(addr, now + Duration::from_millis(1500)));
+    }
+
+    #[test]
+    fn test_ipv6_prefix() {
+        let limiter = HandshakeRateLimiter::new(1, 1.0);
+        let now = Instant::now();
+
+        assert!(limiter.try_acquire_at("2001:db8::1".parse().unwrap(), now));
+        assert!(!limiter.try_acquire_at("2001:db8::2".parse().unwrap(), now));
+        assert!(limiter.try_acquire_at("2001:db8:0:1::1".parse().unwrap(), now));
+
+        assert!(limiter.try_acquire_at("10.0.0.1".parse().unwrap(), now));
+        assert!(!limiter.try_acquire_at("::ffff:10.0.0.1".parse().unwrap(), now));
+    }
+
+    #[test]
+    fn test_evict_least_recent() {
+        let limiter = HandshakeRateLimiter::new(1, 1.0);
+        let now = Instant::now();
+        let addr = |i: usize| IpAddr::from([10, 0, (i >> 8) as u8, i as u8]);
+
+        for i in 0..MAX_TRACKED_ADDRS {
+            assert!(limiter.try_acquire_at(addr(i), now));
+        }
+        // refresh the first address, so the second is the least recent
+        assert!(!limiter.try_acquire_at(addr(0), now));
+        assert!(limiter.try_acquire_at(addr(MAX_TRACKED_ADDRS), now));
+
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(MAX_TRACKED_ADDRS, buckets.by_addr.len());
+        assert_eq!(MAX_TRACKED_ADDRS, buckets.by_update.len());
+        assert!(buckets.by_addr.contains_key(&addr(0)));
+        assert!(!buckets.by_addr.contains_key(&addr(1)));
+    }
+}
@@ -0,0 +1,261 @@
+//! Server-wide read-only mode, like a hot standby of postgres.
+//!
+//! Set `ServerConfig::read_only_mode` and flip it with
+//! `ReadOnlyMode::set_read_only` when the node is promoted or demoted. While
+//! read-only, statements classified as writes by `WriteClassifier` are
+//! rejected with `25006` read_only_sql_transaction before reaching
+//! `do_query`. Connections are told the mode with `ParameterStatus` of
+//! `default_transaction_read_only` and `in_hot_standby` at startup and on
+//! every change, so drivers with target session attributes, like libpq's
+//! `target_session_attrs=read-write`, pick the right node.
+
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use super::handle::ClientHandle;
+use super::query::split_statements;
+use crate::error::{ErrorInfo, PgWireError, PgWireResult};
+
+/// Decide if a statement writes data, provided by the backend which knows
+/// its SQL dialect.
+pub trait WriteClassifier: Debug + Send + Sync {
+    /// Test if the single `statement` writes data
+    fn is_write(&self, statement: &str) -> bool;
+}
+
+/// Classify statements by their leading keyword.
+///
+/// DML, DDL and privilege commands are writes, as well as `COPY ... FROM`.
+/// Data-modifying statements in `WITH` are not detected, use a classifier
+/// based on a parser if the backend supports them.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KeywordWriteClassifier;
+
+const WRITE_KEYWORDS: &[&str] = &[
+    "INSERT", "UPDATE", "DELETE", "MERGE", "UPSERT", "CREATE", "ALTER", "DROP", "TRUNCATE",
+    "GRANT", "REVOKE", "COMMENT", "REINDEX", "VACUUM", "CLUSTER", "REFRESH", "IMPORT",
+];
+
+impl WriteClassifier for KeywordWriteClassifier {
+    fn is_write(&self, statement: &str) -> bool {
+        let Some(keyword) = statement.split_whitespace().next() else {
+            return false;
+        };
+        if keyword.eq_ignore_ascii_case("COPY") {
+            return is_copy_from(statement);
+        }
+        WRITE_KEYWORDS
+            .iter()
+            .any(|w| keyword.eq_ignore_ascii_case(w))
+    }
+}
+
+/// `COPY ... FROM`, ignoring the query of `COPY (SELECT ... FROM t) TO`
+fn is_copy_from(statement: &str) -> bool {
+    let mut depth = 0usize;
+    let mut outer = String::with_capacity(statement.len());
+    for c in statement.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            _ if depth == 0 => outer.push(c),
+            _ => outer.push(' '),
+        }
+    }
+    outer
+        .split_whitespace()
+        .any(|w| w.eq_ignore_ascii_case("FROM"))
+}
+
+/// Error returned for writes in read-only mode, same as postgres on a hot
+/// standby
+pub fn read_only_error(statement: &str) -> PgWireError {
+    let command = statement
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_uppercase();
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        "ERROR".to_owned(),
+        "25006".to_owned(),
+        format!("cannot execute {command} in a read-only transaction"),
+    )))
+}
+
+/// Switch of read-only mode shared by all connections.
+#[derive(Debug)]
+pub struct ReadOnlyMode {
+    read_only: AtomicBool,
+    classifier: Arc<dyn WriteClassifier>,
+    handles: Mutex<Vec<ClientHandle>>,
+}
+
+impl ReadOnlyMode {
+    /// Create the switch in given mode, with `KeywordWriteClassifier`
+    pub fn new(read_only: bool) -> ReadOnlyMode {
+        ReadOnlyMode::with_classifier(read_only, Arc::new(KeywordWriteClassifier))
+    }
+
+    /// Create the switch with the classifier of backend
+    pub fn with_classifier(read_only: bool, classifier: Arc<dyn WriteClassifier>) -> ReadOnlyMode {
+        ReadOnlyMode {
+            read_only: AtomicBool::new(read_only),
+            classifier,
+            handles: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Acquire)
+    }
+
+    /// Change the mode, for example `false` when the node is promoted.
+    ///
+    /// Connected clients receive the new `ParameterStatus` when they are
+    /// idle. Returns the previous mode.
+    pub fn set_read_only(&self, read_only: bool) -> bool {
+        let mut handles = self.handles.lock().unwrap();
+        let previous = self.read_only.swap(read_only, Ordering::AcqRel);
+        handles.retain(|handle| !handle.is_closed());
+        if previous != read_only {
+            for handle in handles.iter() {
+                for (name, value) in Self::parameters_of(read_only) {
+                    // a connection with full queue misses the change
+                    let _ = handle.send_parameter_status(name, value);
+                }
+            }
+        }
+        previous
+    }
+
+    /// `ParameterStatus` reporting current mode
+    pub fn parameters(&self) -> [(&'static str, &'static str); 2] {
+        Self::parameters_of(self.is_read_only())
+    }
+
+    fn parameters_of(read_only: bool) -> [(&'static str, &'static str); 2] {
+        let value = if read_only { "on" } else { "off" };
+        [
+            ("default_transaction_read_only", value),
+            ("in_hot_standby", value),
+        ]
+    }
+
+    /// Reject the query with `25006` if it contains a write in read-only
+    /// mode. Multiple statements of simple query are checked one by one.
+    pub fn check_query(&self, query: &str) -> PgWireResult<()> {
+        if !self.is_read_only() {
+            return Ok(());
+        }
+        match split_statements(query)
+            .into_iter()
+            .find(|statement| self.classifier.is_write(statement))
+        {
+            Some(statement) => Err(read_only_error(statement)),
+            None => Ok(()),
+        }
+    }
+
+    /// Send mode changes to the connection, and return current parameters
+    /// for its startup
+    pub(crate) fn register(&self, handle: ClientHandle) -> [(&'static str, &'static str); 2] {
+        let mut handles = self.handles.lock().unwrap();
+        handles.push(handle);
+        self.parameters()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::config::ServerConfig;
+    use crate::messages::response::CommandComplete;
+    use crate::messages::startup::ParameterStatus;
+    use crate::messages::PgWireBackendMessage;
+    use crate::testkit::fixture::{error_code, TestHandlers};
+    use crate::testkit::MockClient;
+
+    #[test]
+    fn test_keyword_classifier() {
+        let classifier = KeywordWriteClassifier;
+        assert!(classifier.is_write("insert into t values (1)"));
+        assert!(classifier.is_write("  DROP TABLE t"));
+        assert!(classifier.is_write("COPY t FROM STDIN"));
+        assert!(!classifier.is_write("COPY (SELECT * FROM t) TO STDOUT"));
+        assert!(!classifier.is_write("SELECT * FROM t"));
+        assert!(!classifier.is_write(""));
+    }
+
+    #[test]
+    fn test_read_only_mode() {
+        let mode = ReadOnlyMode::new(true);
+        assert!(mode.check_query("SELECT 1; SELECT 2").is_ok());
+        let Err(PgWireError::UserError(error)) = mode.check_query("SELECT 1; delete from t") else {
+            panic!("write should be rejected");
+        };
+        assert_eq!("25006", error.code);
+        assert_eq!(
+            "cannot execute DELETE in a read-only transaction",
+            error.message
+        );
+
+        let (handle, mut receiver) = ClientHandle::channel();
+        assert_eq!(
+            [
+                ("default_transaction_read_only", "on"),
+                ("in_hot_standby", "on")
+            ],
+            mode.register(handle)
+        );
+        assert!(mode.set_read_only(false));
+        assert!(mode.check_query("delete from t").is_ok());
+        assert_eq!(2, std::iter::from_fn(|| receiver.try_recv().ok()).count());
+
+        // no change, nothing sent
+        mode.set_read_only(false);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_only_connection() {
+        let mode = Arc::new(ReadOnlyMode::new(true));
+        let config = Arc::new(ServerConfig {
+            read_only_mode: Some(mode.clone()),
+            ..Default::default()
+        });
+        let mut client = MockClient::start_with_config(TestHandlers::echo(), config);
+        let messages = client.startup("tom", None).await.unwrap();
+        assert!(messages.contains(&PgWireBackendMessage::ParameterStatus(
+            ParameterStatus::new("in_hot_standby".to_owned(), "on".to_owned())
+        )));
+
+        let messages = client
+            .simple_query("SELECT 1; INSERT INTO t VALUES (1)")
+            .await
+            .unwrap();
+        assert_eq!(Some("25006".to_owned()), error_code(&messages[0]));
+
+        // promoted
+        assert!(mode.set_read_only(false));
+        for name in ["default_transaction_read_only", "in_hot_standby"] {
+            assert_eq!(
+                Some(PgWireBackendMessage::ParameterStatus(ParameterStatus::new(
+                    name.to_owned(),
+                    "off".to_owned()
+                ))),
+                client.receive().await.unwrap()
+            );
+        }
+        let messages = client
+            .simple_query("INSERT INTO t VALUES (1)")
+            .await
+            .unwrap();
+        assert_eq!(
+            PgWireBackendMessage::CommandComplete(CommandComplete::new(
+                "INSERT INTO t VALUES (1)".to_owned()
+            )),
+            messages[0]
+        );
+    }
+}
@@ -0,0 +1,553 @@
+//! Utilities for publishing replication streams over `CopyBoth`.
+
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Display};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use futures::{Sink, SinkExt, StreamExt};
+
+use super::copy::send_copy_out_response;
+use super::query::send_query_response;
+use super::results::{CopyResponse, DataRowEncoder, FieldFormat, FieldInfo, QueryResponse};
+use super::{ClientInfo, Type};
+use crate::error::{PgWireError, PgWireResult};
+use crate::messages::copy::{CopyData, CopyDone};
+use crate::messages::replication::{
+    to_pg_timestamp, BaseBackupMessage, PrimaryKeepalive, StandbyStatusUpdate,
+    MESSAGE_TYPE_BYTE_STANDBY_STATUS_UPDATE,
+};
+use crate::messages::PgWireBackendMessage;
+
+/// What the replication publisher should do after `KeepaliveScheduler::poll`
+#[derive(Debug, PartialEq, Eq)]
+pub enum KeepaliveEvent {
+    /// Nothing to do until `KeepaliveScheduler::next_deadline`
+    Idle,
+    /// Send the keepalive to client
+    SendKeepalive(PrimaryKeepalive),
+    /// Client hasn't sent anything within the timeout, the stream should be
+    /// terminated
+    Timeout,
+}
+
+/// Keepalive and feedback tracking for a replication stream
+///
+/// The scheduler doesn't own the connection or any timer. Call
+/// `on_client_data` for each `CopyData` received from client, and call `poll`
+/// when `next_deadline` is reached:
+///
+/// ```ignore
+/// loop {
+///     tokio::select! {
+///         _ = tokio::time::sleep_until(scheduler.next_deadline().into()) => {
+///             match scheduler.poll(Instant::now(), wal_end) {
+///                 KeepaliveEvent::SendKeepalive(k) => send_keepalive(client, k).await?,
+///                 KeepaliveEvent::Timeout => break,
+///                 KeepaliveEvent::Idle => {}
+///             }
+///         }
+///         // ... stream WAL and handle client data
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct KeepaliveScheduler {
+    interval: Duration,
+    timeout: Duration,
+    last_keepalive: Instant,
+    last_feedback: Instant,
+    last_status: Option<StandbyStatusUpdate>,
+    reply_requested: bool,
+}
+
+impl KeepaliveScheduler {
+    /// Create scheduler that sends keepalive every `interval` and times out
+    /// when client is silent for `timeout`, like postgres'
+    /// `wal_sender_timeout`.
+    pub fn new(interval: Duration, timeout: Duration) -> KeepaliveScheduler {
+        let now = Instant::now();
+        KeepaliveScheduler {
+            interval,
+            timeout,
+            last_keepalive: now,
+            last_feedback: now,
+            last_status: None,
+            reply_requested: false,
+        }
+    }
+
+    /// Record data received from client.
+    ///
+    /// Any message from client resets the timeout. Returns the decoded
+    /// `StandbyStatusUpdate` if the data is a status update.
+    pub fn on_client_data(
+        &mut self,
+        copy_data: &CopyData,
+    ) -> PgWireResult<Option<StandbyStatusUpdate>> {
+        self.last_feedback = Instant::now();
+
+        if copy_data.data.first() == Some(&MESSAGE_TYPE_BYTE_STANDBY_STATUS_UPDATE) {
+            let status = StandbyStatusUpdate::decode(copy_data.data.clone())?;
+            self.last_status = Some(status);
+            if status.reply_requested {
+                self.reply_requested = true;
+            }
+            Ok(Some(status))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Last `StandbyStatusUpdate` received from client
+    pub fn last_status(&self) -> Option<&StandbyStatusUpdate> {
+        self.last_status.as_ref()
+    }
+
+    /// Time elapsed since last message from client
+    pub fn since_last_feedback(&self) -> Duration {
+        self.last_feedback.elapsed()
+    }
+
+    /// The next time `poll` should be called
+    pub fn next_deadline(&self) -> Instant {
+        if self.reply_requested {
+            return Instant::now();
+        }
+
+        let keepalive = self.last_keepalive + self.interval;
+        let timeout = self.last_feedback + self.timeout;
+        let deadline = keepalive.min(timeout);
+        if self.ping_sent() {
+            deadline
+        } else {
+            deadline.min(self.ping_time())
+        }
+    }
+
+    fn ping_time(&self) -> Instant {
+        self.last_feedback + self.timeout / 2
+    }
+
+    /// Whether a keepalive has been sent after client became silent for half
+    /// of the timeout
+    fn ping_sent(&self) -> bool {
+        self.last_keepalive >= self.ping_time()
+    }
+
+    /// Check timers with current time and `wal_end` position of the stream.
+    ///
+    /// A keepalive is due every `interval`, or when client requested a reply.
+    /// When client has been silent for half of the timeout, the keepalive
+    /// asks client to reply.
+    pub fn poll(&mut self, now: Instant, wal_end: u64) -> KeepaliveEvent {
+        let silence = now.saturating_duration_since(self.last_feedback);
+        if silence >= self.timeout {
+            return KeepaliveEvent::Timeout;
+        }
+
+        let ping = silence >= self.timeout / 2;
+        let keepalive_due = now.saturating_duration_since(self.last_keepalive) >= self.interval;
+
+        if self.reply_requested || keepalive_due || (ping && !self.ping_sent()) {
+            self.reply_requested = false;
+            self.last_keepalive = now;
+            KeepaliveEvent::SendKeepalive(PrimaryKeepalive::new(
+                wal_end,
+                to_pg_timestamp(SystemTime::now()),
+                ping,
+            ))
+        } else {
+            KeepaliveEvent::Idle
+        }
+    }
+}
+
+/// Send keepalive to client in a `CopyData`
+pub async fn send_keepalive<C>(client: &mut C, keepalive: PrimaryKeepalive) -> PgWireResult<()>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    client
+        .send(PgWireBackendMessage::CopyData(keepalive.into()))
+        .await?;
+    Ok(())
+}
+
+/// A position in WAL, with its timeline
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, new)]
+pub struct WalPosition {
+    pub lsn: u64,
+    pub timeline: u32,
+}
+
+/// Format LSN in postgres' `X/X` form
+pub fn format_lsn(lsn: u64) -> String {
+    format!("{:X}/{:X}", lsn >> 32, lsn as u32)
+}
+
+impl Display for WalPosition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", format_lsn(self.lsn))
+    }
+}
+
+/// Options of a `BASE_BACKUP` command
+///
+/// Both the parenthesized option list of postgres 15+ and the legacy keyword
+/// syntax are accepted. Option names are normalized to uppercase.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BaseBackupOptions {
+    options: HashMap<String, Option<String>>,
+}
+
+impl BaseBackupOptions {
+    /// Parse options from a `BASE_BACKUP` command. Returns `None` if the
+    /// query is not a `BASE_BACKUP` command.
+    pub fn parse(query: &str) -> Option<BaseBackupOptions> {
+        let query = query.trim().trim_end_matches(';');
+        let rest = query
+            .get(..11)
+            .filter(|cmd| cmd.eq_ignore_ascii_case("BASE_BACKUP"))
+            .map(|_| &query[11..])?;
+        if !rest.is_empty() && !rest.starts_with(|c: char| c.is_whitespace() || c == '(') {
+            return None;
+        }
+
+        let mut options = HashMap::new();
+        let mut tokens = tokenize_options(rest).into_iter().peekable();
+        while let Some(name) = tokens.next() {
+            if name == "(" || name == ")" || name == "," {
+                continue;
+            }
+            // value is anything until next comma or closing paren, for the
+            // parenthesized syntax
+            let value = match tokens.peek().map(String::as_str) {
+                Some(",") | Some(")") | Some("(") | None => None,
+                Some(v) if rest.trim_start().starts_with('(') => {
+                    let v = v.to_owned();
+                    tokens.next();
+                    Some(v)
+                }
+                // legacy syntax: only LABEL and MAX_RATE take values
+                Some(v)
+                    if name.eq_ignore_ascii_case("LABEL")
+                        || name.eq_ignore_ascii_case("MAX_RATE") =>
+                {
+                    let v = v.to_owned();
+                    tokens.next();
+                    Some(v)
+                }
+                _ => None,
+            };
+            options.insert(name.to_uppercase(), value);
+        }
+
+        Some(BaseBackupOptions { options })
+    }
+
+    /// Get raw value of an option, `Some(None)` for options without value
+    pub fn get(&self, name: &str) -> Option<Option<&str>> {
+        self.options.get(&name.to_uppercase()).map(|v| v.as_deref())
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        match self.get(name) {
+            Some(None) => true,
+            Some(Some(v)) => !matches!(v.to_lowercase().as_str(), "false" | "off" | "no" | "0"),
+            None => false,
+        }
+    }
+
+    pub fn label(&self) -> &str {
+        self.get("LABEL").flatten().unwrap_or("base backup")
+    }
+
+    /// Whether progress messages are requested
+    pub fn progress(&self) -> bool {
+        self.flag("PROGRESS")
+    }
+
+    /// Whether WAL files required by the backup should be included
+    pub fn wal(&self) -> bool {
+        self.flag("WAL")
+    }
+
+    /// Whether a fast checkpoint is requested, by `CHECKPOINT 'fast'` or
+    /// legacy `FAST`
+    pub fn fast_checkpoint(&self) -> bool {
+        self.get("CHECKPOINT")
+            .flatten()
+            .is_some_and(|v| v.eq_ignore_ascii_case("fast"))
+            || self.flag("FAST")
+    }
+
+    /// Whether backup manifest is requested. Postgres 13+ sends manifest by
+    /// default unless `MANIFEST 'no'`.
+    pub fn manifest(&self) -> bool {
+        self.get("MANIFEST").is_none() || self.flag("MANIFEST")
+    }
+
+    /// Maximum transfer rate in kilobytes per second
+    pub fn max_rate(&self) -> Option<u64> {
+        self.get("MAX_RATE").flatten().and_then(|v| v.parse().ok())
+    }
+}
+
+fn tokenize_options(input: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '(' | ')' | ',' => tokens.push(c.to_string()),
+            '\'' => {
+                let mut value = String::new();
+                while let Some(c) = chars.next() {
+                    if c == '\'' {
+                        // escaped quote
+                        if chars.peek() == Some(&'\'') {
+                            chars.next();
+                        } else {
+                            break;
+                        }
+                    }
+                    value.push(c);
+                }
+                tokens.push(value);
+            }
+            c => {
+                let mut value = c.to_string();
+                while let Some(c) = chars.peek() {
+                    if c.is_whitespace() || matches!(c, '(' | ')' | ',' | '\'') {
+                        break;
+                    }
+                    value.push(*c);
+                    chars.next();
+                }
+                tokens.push(value);
+            }
+        }
+    }
+    tokens
+}
+
+/// A tablespace included in base backup
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq, new)]
+pub struct BackupTablespace {
+    /// Oid of the tablespace, `None` for the main data directory
+    pub oid: Option<u32>,
+    /// Location of the tablespace, `None` for the main data directory
+    pub location: Option<String>,
+    /// Estimated size in kilobytes, if progress is requested
+    pub size: Option<i64>,
+}
+
+/// Base backup started by `BaseBackupHandler`
+pub struct BaseBackup {
+    pub start: WalPosition,
+    pub tablespaces: Vec<BackupTablespace>,
+    /// Content of the backup. Each archive should start with
+    /// `BaseBackupMessage::NewArchive` followed by its tar-format data, and
+    /// the manifest, if any, starts with `BaseBackupMessage::Manifest`.
+    pub data: BoxStream<'static, PgWireResult<BaseBackupMessage>>,
+}
+
+/// handler for `BASE_BACKUP` command of replication connections
+#[async_trait]
+pub trait BaseBackupHandler: Send + Sync {
+    /// Start a backup, and return its start position and content
+    async fn start_backup<C>(
+        &self,
+        client: &mut C,
+        options: &BaseBackupOptions,
+    ) -> PgWireResult<BaseBackup>
+    where
+        C: ClientInfo + Unpin + Send + Sync;
+
+    /// Called when all content of the backup is sent, and return the end
+    /// position of the backup.
+    async fn stop_backup<C>(
+        &self,
+        client: &mut C,
+        options: &BaseBackupOptions,
+    ) -> PgWireResult<WalPosition>
+    where
+        C: ClientInfo + Unpin + Send + Sync;
+}
+
+/// Run a `BASE_BACKUP` command with the handler and send its responses.
+///
+/// This sends the start position, tablespace list, the CopyOut stream of
+/// backup content and the end position, as postgres does. Call this from
+/// `SimpleQueryHandler::do_query` and return `Tag::new("BASE_BACKUP")` as
+/// response to finish the command.
+pub async fn send_base_backup<C, H>(
+    client: &mut C,
+    handler: &H,
+    options: &BaseBackupOptions,
+) -> PgWireResult<()>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    H: BaseBackupHandler,
+{
+    let backup = handler.start_backup(client, options).await?;
+    send_wal_position(client, &backup.start).await?;
+
+    let schema = Arc::new(vec![
+        FieldInfo::new("spcoid".into(), None, None, Type::OID, FieldFormat::Text),
+        FieldInfo::new(
+            "spclocation".into(),
+            None,
+            None,
+            Type::TEXT,
+            FieldFormat::Text,
+        ),
+        FieldInfo::new("size".into(), None, None, Type::INT8, FieldFormat::Text),
+    ]);
+    let rows = backup
+        .tablespaces
+        .iter()
+        .map(|tablespace| {
+            let mut encoder = DataRowEncoder::new(schema.clone());
+            encoder.encode_field(&tablespace.oid)?;
+            encoder.encode_field(&tablespace.location)?;
+            encoder.encode_field(&tablespace.size)?;
+            encoder.finish()
+        })
+        .collect::<PgWireResult<Vec<_>>>()?;
+    send_query_response(client, QueryResponse::from_rows(schema, rows), true).await?;
+
+    send_copy_out_response(client, CopyResponse::new(0, 0, vec![])).await?;
+    let mut data = backup.data;
+    while let Some(msg) = data.next().await {
+        client
+            .send(PgWireBackendMessage::CopyData(msg?.into()))
+            .await?;
+    }
+    client
+        .send(PgWireBackendMessage::CopyDone(CopyDone::new()))
+        .await?;
+
+    let end = handler.stop_backup(client, options).await?;
+    send_wal_position(client, &end).await?;
+
+    Ok(())
+}
+
+async fn send_wal_position<C>(client: &mut C, position: &WalPosition) -> PgWireResult<()>
+where
+    C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    let schema = Arc::new(vec![
+        FieldInfo::new("recptr".into(), None, None, Type::TEXT, FieldFormat::Text),
+        FieldInfo::new("tli".into(), None, None, Type::INT8, FieldFormat::Text),
+    ]);
+    let mut encoder = DataRowEncoder::new(schema.clone());
+    encoder.encode_field(&position.to_string())?;
+    encoder.encode_field(&(position.timeline as i64))?;
+    let row = encoder.finish()?;
+
+    send_query_response(client, QueryResponse::from_rows(schema, vec![row]), true).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_base_backup_options() {
+        let options = BaseBackupOptions::parse(
+            "BASE_BACKUP ( LABEL 'pg_basebackup base backup', PROGRESS, CHECKPOINT 'fast', \
+             WAIT 0, MANIFEST 'yes', TARGET 'client')",
+        )
+        .unwrap();
+        assert_eq!("pg_basebackup base backup", options.label());
+        assert!(options.progress());
+        assert!(options.fast_checkpoint());
+        assert!(options.manifest());
+        assert!(!options.wal());
+        assert_eq!(Some(Some("client")), options.get("target"));
+        assert!(!options.flag("WAIT"));
+
+        let options =
+            BaseBackupOptions::parse("base_backup LABEL 'it''s' FAST WAL NOWAIT MAX_RATE 1024;")
+                .unwrap();
+        assert_eq!("it's", options.label());
+        assert!(options.fast_checkpoint());
+        assert!(options.wal());
+        assert!(!options.progress());
+        assert_eq!(Some(1024), options.max_rate());
+
+        let options = BaseBackupOptions::parse("BASE_BACKUP (MANIFEST 'no')").unwrap();
+        assert!(!options.manifest());
+
+        assert!(BaseBackupOptions::parse("SELECT 1").is_none());
+        assert!(BaseBackupOptions::parse("BASE_BACKUPS").is_none());
+    }
+
+    #[test]
+    fn test_format_lsn() {
+        assert_eq!("0/2000028", format_lsn(0x2000028));
+        assert_eq!(
+            "16/B374D848",
+            WalPosition::new(0x16_B374_D848, 1).to_string()
+        );
+    }
+
+    #[test]
+    fn test_keepalive_scheduler() {
+        let mut scheduler =
+            KeepaliveScheduler::new(Duration::from_secs(10), Duration::from_secs(60));
+        let start = scheduler.last_keepalive;
+
+        assert_eq!(KeepaliveEvent::Idle, scheduler.poll(start, 0));
+        assert_eq!(start + Duration::from_secs(10), scheduler.next_deadline());
+
+        match scheduler.poll(start + Duration::from_secs(10), 100) {
+            KeepaliveEvent::SendKeepalive(k) => {
+                assert_eq!(100, k.wal_end);
+                assert!(!k.reply_requested);
+            }
+            e => panic!("unexpected event {:?}", e),
+        }
+
+        // client silent for half of timeout
+        match scheduler.poll(start + Duration::from_secs(30), 100) {
+            KeepaliveEvent::SendKeepalive(k) => assert!(k.reply_requested),
+            e => panic!("unexpected event {:?}", e),
+        }
+        assert_eq!(
+            KeepaliveEvent::Idle,
+            scheduler.poll(start + Duration::from_secs(31), 100)
+        );
+        assert_eq!(start + Duration::from_secs(40), scheduler.next_deadline());
+
+        assert_eq!(
+            KeepaliveEvent::Timeout,
+            scheduler.poll(start + Duration::from_secs(60), 100)
+        );
+
+        let status = StandbyStatusUpdate::new(100, 100, 90, 0, true);
+        assert_eq!(
+            Some(status),
+            scheduler.on_client_data(&status.into()).unwrap()
+        );
+        assert_eq!(Some(&status), scheduler.last_status());
+        assert!(scheduler.next_deadline() <= Instant::now());
+        assert!(matches!(
+            scheduler.poll(Instant::now(), 100),
+            KeepaliveEvent::SendKeepalive(_)
+        ));
+    }
+}
@@ -1,18 +1,25 @@
+use std::collections::HashMap;
 use std::fmt::Debug;
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
 
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 use futures::{
-    stream::{BoxStream, StreamExt},
+    stream::{self, BoxStream, StreamExt},
     Stream,
 };
 use postgres_types::{IsNull, Oid, ToSql, Type};
 
+use super::custom_types::TypeRegistry;
 use crate::{
-    error::{ErrorInfo, PgWireResult},
+    error::{ErrorInfo, NoticeInfo, PgWireError, PgWireResult},
     messages::{
-        data::{DataRow, FieldDescription, RowDescription, FORMAT_CODE_BINARY, FORMAT_CODE_TEXT},
+        check_message_length,
+        data::{
+            DataRow, FieldDescription, RowDescription, FORMAT_CODE_BINARY, FORMAT_CODE_TEXT,
+            MESSAGE_TYPE_BYTE_DATA_ROW,
+        },
         response::CommandComplete,
+        Message,
     },
     types::ToSqlText,
 };
@@ -33,6 +40,15 @@ impl Tag {
         }
     }
 
+    /// Create a tag for `INSERT`, in the form of `INSERT oid rows`.
+    ///
+    /// `oid` is the object ID of the inserted row when exactly one row is
+    /// inserted into a table with OIDs. Use `0` for all other cases, which is
+    /// what modern postgres always sends.
+    pub fn insert(oid: Oid, rows: usize) -> Tag {
+        Tag::new("INSERT").with_oid(oid).with_rows(rows)
+    }
+
     pub fn with_rows(mut self, rows: usize) -> Tag {
         self.rows = Some(rows);
         self
@@ -57,6 +73,131 @@ impl From<Tag> for CommandComplete {
     }
 }
 
+/// Command tags of postgres built-in commands.
+///
+/// Each variant knows how its `CommandComplete` tag is formatted: `SELECT`,
+/// `INSERT`, `UPDATE`, `DELETE`, `MERGE`, `FETCH`, `MOVE` and `COPY` carry a
+/// row count, while the others are sent as the bare command name. Use
+/// `Custom` for commands not covered here.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandTag {
+    Select(usize),
+    /// `INSERT oid rows`
+    Insert(Oid, usize),
+    Update(usize),
+    Delete(usize),
+    Merge(usize),
+    Fetch(usize),
+    Move(usize),
+    Copy(usize),
+    CreateTable,
+    CreateIndex,
+    CreateView,
+    CreateSchema,
+    CreateDatabase,
+    DropTable,
+    DropIndex,
+    DropView,
+    DropSchema,
+    DropDatabase,
+    AlterTable,
+    Truncate,
+    Begin,
+    Commit,
+    Rollback,
+    Set,
+    Reset,
+    Show,
+    Prepare,
+    Deallocate,
+    DeallocateAll,
+    DiscardAll,
+    Listen,
+    Unlisten,
+    Notify,
+    Custom(String),
+}
+
+impl CommandTag {
+    /// Get the command name part of the tag
+    pub fn command(&self) -> &str {
+        match self {
+            Self::Select(_) => "SELECT",
+            Self::Insert(_, _) => "INSERT",
+            Self::Update(_) => "UPDATE",
+            Self::Delete(_) => "DELETE",
+            Self::Merge(_) => "MERGE",
+            Self::Fetch(_) => "FETCH",
+            Self::Move(_) => "MOVE",
+            Self::Copy(_) => "COPY",
+            Self::CreateTable => "CREATE TABLE",
+            Self::CreateIndex => "CREATE INDEX",
+            Self::CreateView => "CREATE VIEW",
+            Self::CreateSchema => "CREATE SCHEMA",
+            Self::CreateDatabase => "CREATE DATABASE",
+            Self::DropTable => "DROP TABLE",
+            Self::DropIndex => "DROP INDEX",
+            Self::DropView => "DROP VIEW",
+            Self::DropSchema => "DROP SCHEMA",
+            Self::DropDatabase => "DROP DATABASE",
+            Self::AlterTable => "ALTER TABLE",
+            Self::Truncate => "TRUNCATE TABLE",
+            Self::Begin => "BEGIN",
+            Self::Commit => "COMMIT",
+            Self::Rollback => "ROLLBACK",
+            Self::Set => "SET",
+            Self::Reset => "RESET",
+            Self::Show => "SHOW",
+            Self::Prepare => "PREPARE",
+            Self::Deallocate => "DEALLOCATE",
+            Self::DeallocateAll => "DEALLOCATE ALL",
+            Self::DiscardAll => "DISCARD ALL",
+            Self::Listen => "LISTEN",
+            Self::Unlisten => "UNLISTEN",
+            Self::Notify => "NOTIFY",
+            Self::Custom(command) => command,
+        }
+    }
+
+    /// Get row count of the tag, `None` for commands that don't report rows
+    pub fn rows(&self) -> Option<usize> {
+        match self {
+            Self::Select(rows)
+            | Self::Insert(_, rows)
+            | Self::Update(rows)
+            | Self::Delete(rows)
+            | Self::Merge(rows)
+            | Self::Fetch(rows)
+            | Self::Move(rows)
+            | Self::Copy(rows) => Some(*rows),
+            _ => None,
+        }
+    }
+}
+
+impl From<CommandTag> for Tag {
+    fn from(command_tag: CommandTag) -> Tag {
+        let tag = Tag::new(command_tag.command());
+        match command_tag {
+            CommandTag::Insert(oid, rows) => tag.with_oid(oid).with_rows(rows),
+            _ => {
+                if let Some(rows) = command_tag.rows() {
+                    tag.with_rows(rows)
+                } else {
+                    tag
+                }
+            }
+        }
+    }
+}
+
+impl From<CommandTag> for CommandComplete {
+    fn from(command_tag: CommandTag) -> CommandComplete {
+        Tag::from(command_tag).into()
+    }
+}
+
 /// Describe encoding of a data field.
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
 pub enum FieldFormat {
@@ -93,9 +234,19 @@ pub struct FieldInfo {
     column_id: Option<i16>,
     datatype: Type,
     format: FieldFormat,
+    #[new(value = "-1")]
+    type_modifier: i32,
 }
 
 impl FieldInfo {
+    /// Create a `FieldInfoBuilder` for a column, with text format and no
+    /// type modifier or source table by default.
+    pub fn builder(name: impl Into<String>, datatype: Type) -> FieldInfoBuilder {
+        FieldInfoBuilder {
+            field: FieldInfo::new(name.into(), None, None, datatype, FieldFormat::Text),
+        }
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
@@ -115,6 +266,72 @@ impl FieldInfo {
     pub fn format(&self) -> FieldFormat {
         self.format
     }
+
+    /// Type modifier of the column, `-1` if there is none
+    pub fn type_modifier(&self) -> i32 {
+        self.type_modifier
+    }
+
+    /// Same column in another format, like the result format of a portal
+    pub fn with_format(mut self, format: FieldFormat) -> FieldInfo {
+        self.format = format;
+        self
+    }
+}
+
+/// Builder of `FieldInfo` with full column metadata.
+///
+/// Catalog aware clients, like ORMs and GUI tools, read the source table and
+/// type modifier from `RowDescription`, for example the length of
+/// `varchar(255)`.
+///
+/// ```
+/// use pgwire::api::results::{FieldFormat, FieldInfo};
+/// use pgwire::api::Type;
+///
+/// let field = FieldInfo::builder("name", Type::VARCHAR)
+///     .table_id(16384)
+///     .column_id(2)
+///     // varchar(255), the type modifier includes 4 bytes of header
+///     .type_modifier(255 + 4)
+///     .format(FieldFormat::Binary)
+///     .build();
+/// assert_eq!(259, field.type_modifier());
+/// ```
+#[derive(Debug, Clone)]
+pub struct FieldInfoBuilder {
+    field: FieldInfo,
+}
+
+impl FieldInfoBuilder {
+    /// Set oid of the table this column comes from
+    pub fn table_id(mut self, table_id: i32) -> FieldInfoBuilder {
+        self.field.table_id = Some(table_id);
+        self
+    }
+
+    /// Set attribute number of the column in its table
+    pub fn column_id(mut self, column_id: i16) -> FieldInfoBuilder {
+        self.field.column_id = Some(column_id);
+        self
+    }
+
+    /// Set type modifier of the column, like `atttypmod` in `pg_attribute`.
+    /// Use functions of `typmod` module to encode it.
+    pub fn type_modifier(mut self, type_modifier: i32) -> FieldInfoBuilder {
+        self.field.type_modifier = type_modifier;
+        self
+    }
+
+    /// Set encoding format of the column
+    pub fn format(mut self, format: FieldFormat) -> FieldInfoBuilder {
+        self.field.format = format;
+        self
+    }
+
+    pub fn build(self) -> FieldInfo {
+        self.field
+    }
 }
 
 impl From<&FieldInfo> for FieldDescription {
@@ -124,9 +341,9 @@ impl From<&FieldInfo> for FieldDescription {
             fi.table_id.unwrap_or(0),  // table_id
             fi.column_id.unwrap_or(0), // column_id
             fi.datatype.oid(),         // type_id
-            // TODO: type size and modifier
-            0,
+            // TODO: type size
             0,
+            fi.type_modifier,
             fi.format.value(),
         )
     }
@@ -136,10 +353,48 @@ pub(crate) fn into_row_description(fields: &[FieldInfo]) -> RowDescription {
     RowDescription::new(fields.iter().map(Into::into).collect())
 }
 
+/// Max number of schemas in `RowDescriptionCache` of a connection
+const MAX_CACHED_ROW_DESCRIPTIONS: usize = 64;
+
+/// Encoded `RowDescription` of schemas sent on a connection, keyed by address
+/// of the schema `Arc`. Responses sharing the same schema, like repeated
+/// executions of a statement, reuse the encoded description.
+#[derive(Debug, Default)]
+pub(crate) struct RowDescriptionCache {
+    entries: HashMap<usize, (Weak<Vec<FieldInfo>>, Bytes)>,
+}
+
+impl RowDescriptionCache {
+    pub(crate) fn get_or_encode(&mut self, schema: &Arc<Vec<FieldInfo>>) -> PgWireResult<Bytes> {
+        // The weak reference keeps the allocation of a cached schema, so its
+        // address is never reused by another schema while the entry exists.
+        let key = Arc::as_ptr(schema) as usize;
+        if let Some((_, encoded)) = self.entries.get(&key) {
+            return Ok(encoded.clone());
+        }
+
+        if self.entries.len() >= MAX_CACHED_ROW_DESCRIPTIONS {
+            self.entries
+                .retain(|_, (schema, _)| schema.strong_count() > 0);
+            if self.entries.len() >= MAX_CACHED_ROW_DESCRIPTIONS {
+                self.entries.clear();
+            }
+        }
+
+        let mut buf = BytesMut::new();
+        into_row_description(schema).encode(&mut buf)?;
+        let encoded = buf.freeze();
+        self.entries
+            .insert(key, (Arc::downgrade(schema), encoded.clone()));
+        Ok(encoded)
+    }
+}
+
 pub struct QueryResponse<'a> {
     command_tag: String,
     row_schema: Arc<Vec<FieldInfo>>,
     data_rows: BoxStream<'a, PgWireResult<DataRow>>,
+    buffered_bytes: usize,
 }
 
 impl<'a> QueryResponse<'a> {
@@ -153,9 +408,29 @@ impl<'a> QueryResponse<'a> {
             command_tag: "SELECT".to_owned(),
             row_schema: field_defs,
             data_rows: row_stream.boxed(),
+            buffered_bytes: 0,
         }
     }
 
+    /// Create `QueryResponse` from column schemas and an iterator of data
+    /// row. Sets "SELECT" as the command tag.
+    pub fn from_iter<I>(field_defs: Arc<Vec<FieldInfo>>, rows: I) -> QueryResponse<'a>
+    where
+        I: IntoIterator<Item = PgWireResult<DataRow>>,
+        I::IntoIter: Send + 'a,
+    {
+        Self::new(field_defs, stream::iter(rows))
+    }
+
+    /// Create `QueryResponse` from column schemas and encoded data rows. Sets
+    /// "SELECT" as the command tag, and the size of rows as buffered bytes.
+    pub fn from_rows(field_defs: Arc<Vec<FieldInfo>>, rows: Vec<DataRow>) -> QueryResponse<'a> {
+        let buffered_bytes = rows.iter().map(|row| row.message_length() + 1).sum();
+        let mut response = Self::from_iter(field_defs, rows.into_iter().map(Ok));
+        response.buffered_bytes = buffered_bytes;
+        response
+    }
+
     /// Get the command tag
     pub fn command_tag(&self) -> &str {
         &self.command_tag
@@ -166,6 +441,17 @@ impl<'a> QueryResponse<'a> {
         command_tag.clone_into(&mut self.command_tag);
     }
 
+    /// Bytes of rows held in memory by this response, `0` for a lazy
+    /// stream. Counted in `BufferedMemory` while the portal is suspended.
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffered_bytes
+    }
+
+    /// Set bytes of rows held in memory, for rows collected in advance
+    pub fn set_buffered_bytes(&mut self, buffered_bytes: usize) {
+        self.buffered_bytes = buffered_bytes;
+    }
+
     /// Get schema of columns
     pub fn row_schema(&self) -> Arc<Vec<FieldInfo>> {
         self.row_schema.clone()
@@ -181,18 +467,28 @@ pub struct DataRowEncoder {
     schema: Arc<Vec<FieldInfo>>,
     row_buffer: BytesMut,
     col_index: usize,
+    type_registry: Option<Arc<TypeRegistry>>,
 }
 
 impl DataRowEncoder {
-    /// New DataRowEncoder from schema of column
+    /// New DataRowEncoder from schema of column. The row buffer is taken
+    /// from `pgwire::api::pool`.
     pub fn new(fields: Arc<Vec<FieldInfo>>) -> DataRowEncoder {
         Self {
             schema: fields,
-            row_buffer: BytesMut::with_capacity(128),
+            row_buffer: super::pool::take(128),
             col_index: 0,
+            type_registry: None,
         }
     }
 
+    /// Encode values of custom types in binary format with codecs from
+    /// `registry`
+    pub fn with_type_registry(mut self, registry: Arc<TypeRegistry>) -> DataRowEncoder {
+        self.type_registry = Some(registry);
+        self
+    }
+
     /// Encode value with custom type and format
     ///
     /// This encode function ignores data type and format information from
@@ -206,26 +502,78 @@ impl DataRowEncoder {
     where
         T: ToSql + ToSqlText + Sized,
     {
-        // remember the position of the 4-byte length field
-        let prev_index = self.row_buffer.len();
-        // write value length as -1 ahead of time
-        self.row_buffer.put_i32(-1);
+        encode_field_into(
+            &mut self.row_buffer,
+            value,
+            data_type,
+            format,
+            self.type_registry.as_deref(),
+        )?;
+        // length of the `DataRow` message
+        check_message_length(4 + 2 + self.row_buffer.len())?;
+        self.col_index += 1;
 
-        let is_null = if format == FieldFormat::Text {
-            value.to_sql_text(data_type, &mut self.row_buffer)?
-        } else {
-            value.to_sql(data_type, &mut self.row_buffer)?
-        };
+        Ok(())
+    }
 
-        if let IsNull::No = is_null {
-            let value_length = self.row_buffer.len() - prev_index - 4;
-            let mut length_bytes = &mut self.row_buffer[prev_index..(prev_index + 4)];
-            length_bytes.put_i32(value_length as i32);
-        }
+    /// Encode value with `data_type` instead of the type defined by schema,
+    /// for example a dynamically typed column. Format is still defined by
+    /// schema.
+    ///
+    /// Unlike `encode_field_with_type_and_format`, the value is checked
+    /// against `data_type` when it's encoded in binary format, and
+    /// `InvalidRustTypeForField` is returned if `T` can't be encoded as
+    /// `data_type`.
+    ///
+    /// Panic when encoding more columns than provided as schema.
+    pub fn encode_field_with_type<T>(&mut self, value: &T, data_type: &Type) -> PgWireResult<()>
+    where
+        T: ToSql + ToSqlText + Sized,
+    {
+        let format = self.schema[self.col_index].format();
+        self.encode_field_checked(value, data_type, format)
+    }
 
-        self.col_index += 1;
+    /// Encode value in `format` instead of the format defined by schema. Type
+    /// is still defined by schema.
+    ///
+    /// Returns `InvalidRustTypeForField` if `T` can't be encoded as the type in
+    /// binary format.
+    ///
+    /// Panic when encoding more columns than provided as schema.
+    pub fn encode_field_with_format<T>(
+        &mut self,
+        value: &T,
+        format: FieldFormat,
+    ) -> PgWireResult<()>
+    where
+        T: ToSql + ToSqlText + Sized,
+    {
+        let data_type = self.schema[self.col_index].datatype().clone();
+        self.encode_field_checked(value, &data_type, format)
+    }
 
-        Ok(())
+    fn encode_field_checked<T>(
+        &mut self,
+        value: &T,
+        data_type: &Type,
+        format: FieldFormat,
+    ) -> PgWireResult<()>
+    where
+        T: ToSql + ToSqlText + Sized,
+    {
+        let has_codec = self
+            .type_registry
+            .as_ref()
+            .is_some_and(|registry| registry.codec(data_type.oid()).is_some());
+        if format == FieldFormat::Binary && !has_codec && !T::accepts(data_type) {
+            return Err(PgWireError::InvalidRustTypeForField(
+                data_type.name().to_owned(),
+                std::any::type_name::<T>(),
+            ));
+        }
+
+        self.encode_field_with_type_and_format(value, data_type, format)
     }
 
     /// Encode value using type and format, defined by schema
@@ -246,6 +594,163 @@ impl DataRowEncoder {
     }
 }
 
+fn encode_field_into<T>(
+    buf: &mut BytesMut,
+    value: &T,
+    data_type: &Type,
+    format: FieldFormat,
+    type_registry: Option<&TypeRegistry>,
+) -> PgWireResult<()>
+where
+    T: ToSql + ToSqlText + Sized,
+{
+    // remember the position of the 4-byte length field
+    let prev_index = buf.len();
+    // write value length as -1 ahead of time
+    buf.put_i32(-1);
+
+    let result = encode_value_into(buf, value, data_type, format, type_registry);
+    match result {
+        Ok(IsNull::No) => {
+            let value_length = buf.len() - prev_index - 4;
+            let mut length_bytes = &mut buf[prev_index..(prev_index + 4)];
+            length_bytes.put_i32(value_length as i32);
+        }
+        Ok(IsNull::Yes) => {}
+        Err(e) => {
+            // drop the partially encoded value, so the row stays valid
+            buf.truncate(prev_index);
+            return Err(e);
+        }
+    }
+
+    Ok(())
+}
+
+fn encode_value_into<T>(
+    buf: &mut BytesMut,
+    value: &T,
+    data_type: &Type,
+    format: FieldFormat,
+    type_registry: Option<&TypeRegistry>,
+) -> PgWireResult<IsNull>
+where
+    T: ToSql + ToSqlText + Sized,
+{
+    let codec = type_registry.and_then(|registry| registry.codec(data_type.oid()));
+    let is_null = match (format, codec) {
+        (FieldFormat::Text, _) => value.to_sql_text(data_type, buf)?,
+        // custom types are encoded as text and converted by their codec
+        (FieldFormat::Binary, Some(codec)) => {
+            let mut text = BytesMut::new();
+            let is_null = value.to_sql_text(data_type, &mut text)?;
+            if let IsNull::No = is_null {
+                let text = std::str::from_utf8(&text).map_err(PgWireError::InvalidUtf8String)?;
+                codec.text_to_binary(text, buf)?;
+            }
+            is_null
+        }
+        (FieldFormat::Binary, None) => value.to_sql(data_type, buf)?,
+    };
+
+    Ok(is_null)
+}
+
+/// Header of `DataRow` message: type byte, length and field count
+const DATA_ROW_HEADER_LEN: usize = 1 + 4 + 2;
+
+/// Encode a complete `DataRow` message in place at the end of a buffer,
+/// usually the write buffer of the connection.
+///
+/// Unlike `DataRowEncoder`, no intermediate buffer is used for the row, and
+/// the row isn't copied again when it's sent. Use `feed_data_row` to write
+/// rows to a client.
+pub struct DataRowWriter<'a> {
+    schema: &'a [FieldInfo],
+    buf: &'a mut BytesMut,
+    start: usize,
+    col_index: usize,
+    type_registry: Option<&'a TypeRegistry>,
+}
+
+impl<'a> DataRowWriter<'a> {
+    /// Start a `DataRow` message at the end of `buf`
+    pub fn new(schema: &'a [FieldInfo], buf: &'a mut BytesMut) -> DataRowWriter<'a> {
+        let start = buf.len();
+        buf.put_u8(MESSAGE_TYPE_BYTE_DATA_ROW);
+        // length and field count are filled in `finish`
+        buf.put_i32(0);
+        buf.put_i16(0);
+
+        DataRowWriter {
+            schema,
+            buf,
+            start,
+            col_index: 0,
+            type_registry: None,
+        }
+    }
+
+    /// Encode values of custom types in binary format with codecs from
+    /// `registry`
+    pub fn with_type_registry(mut self, registry: &'a TypeRegistry) -> DataRowWriter<'a> {
+        self.type_registry = Some(registry);
+        self
+    }
+
+    /// Encode value with custom type and format
+    pub fn encode_field_with_type_and_format<T>(
+        &mut self,
+        value: &T,
+        data_type: &Type,
+        format: FieldFormat,
+    ) -> PgWireResult<()>
+    where
+        T: ToSql + ToSqlText + Sized,
+    {
+        encode_field_into(self.buf, value, data_type, format, self.type_registry)?;
+        self.check_length()?;
+        self.col_index += 1;
+
+        Ok(())
+    }
+
+    /// Encode value using type and format, defined by schema
+    ///
+    /// Panic when encoding more columns than provided as schema.
+    pub fn encode_field<T>(&mut self, value: &T) -> PgWireResult<()>
+    where
+        T: ToSql + ToSqlText + Sized,
+    {
+        let field = &self.schema[self.col_index];
+        encode_field_into(
+            self.buf,
+            value,
+            field.datatype(),
+            field.format(),
+            self.type_registry,
+        )?;
+        self.check_length()?;
+        self.col_index += 1;
+
+        Ok(())
+    }
+
+    fn check_length(&self) -> PgWireResult<()> {
+        // the type byte is not counted in message length
+        check_message_length(self.buf.len() - self.start - 1)
+    }
+
+    /// Complete the message, returns its total length in bytes
+    pub fn finish(self) -> usize {
+        let len = self.buf.len() - self.start;
+        let mut header = &mut self.buf[self.start + 1..self.start + DATA_ROW_HEADER_LEN];
+        header.put_i32((len - 1) as i32);
+        header.put_i16(self.col_index as i16);
+        len
+    }
+}
+
 /// Get response data for a `Describe` command
 pub trait DescribeResponse {
     fn parameters(&self) -> Option<&[Type]>;
@@ -262,7 +767,7 @@ pub trait DescribeResponse {
 
 /// Response for frontend describe statement requests.
 #[non_exhaustive]
-#[derive(Debug, new)]
+#[derive(Debug, Clone, new)]
 pub struct DescribeStatementResponse {
     pub parameters: Vec<Type>,
     pub fields: Vec<FieldInfo>,
@@ -332,9 +837,18 @@ pub struct CopyResponse {
 /// Query response types:
 ///
 /// * Query: the response contains data rows
+/// * Suspended: response of extended query when rows are more than
+///   `max_rows` of `Execute`. The portal is suspended after sending
+///   `max_rows` rows, and the remaining rows are sent on next `Execute`
 /// * Execution: response for ddl/dml execution
 /// * Error: error response
+/// * Notice: notice sent to client before responses that follow it, like
+///   `relation already exists, skipping`. As the only response of an
+///   `Execute`, it's followed by `EmptyQueryResponse`
 /// * EmptyQuery: when client sends an empty query
+/// * ParameterStatus: response for `SET` that changes a parameter in
+///   `REPORTED_PARAMETERS`, it updates client metadata and sends
+///   `ParameterStatus`. `user` and `database` cannot be changed
 /// * TransactionStart: indicate previous statement just started a transaction
 /// * TransactionEnd: indicate previous statement just ended a transaction
 /// * CopyIn: response for a copy-in request
@@ -343,10 +857,13 @@ pub struct CopyResponse {
 pub enum Response<'a> {
     EmptyQuery,
     Query(QueryResponse<'a>),
+    Suspended(QueryResponse<'static>),
     Execution(Tag),
+    ParameterStatus { name: String, value: String },
     TransactionStart(Tag),
     TransactionEnd(Tag),
     Error(Box<ErrorInfo>),
+    Notice(Box<NoticeInfo>),
     CopyIn(CopyResponse),
     CopyOut(CopyResponse),
     CopyBoth(CopyResponse),
@@ -369,6 +886,29 @@ mod test {
         let cc = CommandComplete::from(tag);
 
         assert_eq!(cc.tag, "INSERT 0 100");
+
+        let tag = Tag::insert(16384, 1);
+        let cc = CommandComplete::from(tag);
+
+        assert_eq!(cc.tag, "INSERT 16384 1");
+    }
+
+    #[test]
+    fn test_command_tag() {
+        let cases = vec![
+            (CommandTag::Select(3), "SELECT 3"),
+            (CommandTag::Insert(0, 2), "INSERT 0 2"),
+            (CommandTag::Update(0), "UPDATE 0"),
+            (CommandTag::Copy(10), "COPY 10"),
+            (CommandTag::CreateTable, "CREATE TABLE"),
+            (CommandTag::Begin, "BEGIN"),
+            (CommandTag::DiscardAll, "DISCARD ALL"),
+            (CommandTag::Custom("VACUUM".to_owned()), "VACUUM"),
+        ];
+
+        for (command_tag, expected) in cases {
+            assert_eq!(CommandComplete::from(command_tag).tag, expected);
+        }
     }
 
     #[test]
@@ -397,4 +937,158 @@ mod test {
         let _ = now.to_sql_text(&Type::TIMESTAMP, &mut expected);
         assert_eq!(row.data, expected);
     }
+
+    #[test]
+    fn test_encode_field_with_type() {
+        let schema = Arc::new(vec![
+            FieldInfo::new("value".into(), None, None, Type::TEXT, FieldFormat::Binary),
+            FieldInfo::new("id".into(), None, None, Type::INT4, FieldFormat::Binary),
+        ]);
+        let mut encoder = DataRowEncoder::new(schema);
+        encoder.encode_field_with_type(&7i64, &Type::INT8).unwrap();
+        assert!(matches!(
+            encoder.encode_field_with_type(&7i64, &Type::INT4),
+            Err(PgWireError::InvalidRustTypeForField(..))
+        ));
+        encoder
+            .encode_field_with_format(&7i32, FieldFormat::Text)
+            .unwrap();
+
+        let row = encoder.finish().unwrap();
+        assert_eq!(row.field_count, 2);
+
+        let mut expected = BytesMut::new();
+        expected.put_i32(8);
+        expected.put_i64(7);
+        expected.put_i32(1);
+        expected.put_slice(b"7");
+        assert_eq!(row.data, expected);
+    }
+
+    #[tokio::test]
+    async fn test_query_response_from_rows() {
+        let schema = Arc::new(vec![FieldInfo::new(
+            "id".into(),
+            None,
+            None,
+            Type::INT4,
+            FieldFormat::Text,
+        )]);
+        let rows = (0..3)
+            .map(|i| {
+                let mut encoder = DataRowEncoder::new(schema.clone());
+                encoder.encode_field(&i)?;
+                encoder.finish()
+            })
+            .collect::<PgWireResult<Vec<DataRow>>>()
+            .unwrap();
+
+        let response = QueryResponse::from_rows(schema.clone(), rows);
+        assert_eq!(response.command_tag(), "SELECT");
+        let rows = response.data_rows().collect::<Vec<_>>().await;
+        assert_eq!(rows.len(), 3);
+
+        let response = QueryResponse::from_iter(schema, vec![Ok(DataRow::default())]);
+        assert_eq!(response.data_rows().count().await, 1);
+    }
+
+    #[test]
+    fn test_field_info_builder() {
+        let field = FieldInfo::builder("price", Type::NUMERIC)
+            .table_id(16384)
+            .column_id(3)
+            // numeric(10, 2)
+            .type_modifier((10 << 16 | 2) + 4)
+            .build();
+        assert_eq!(Some(16384), field.table_id());
+        assert_eq!(Some(3), field.column_id());
+        assert_eq!(FieldFormat::Text, field.format());
+
+        let desc = FieldDescription::from(&field);
+        assert_eq!(16384, desc.table_id);
+        assert_eq!(3, desc.column_id);
+        assert_eq!(655366, desc.type_modifier);
+
+        let field = FieldInfo::new("id".into(), None, None, Type::INT4, FieldFormat::Text);
+        assert_eq!(-1, FieldDescription::from(&field).type_modifier);
+    }
+
+    #[test]
+    fn test_binary_numeric() {
+        use rust_decimal::Decimal;
+
+        let schema = Arc::new(vec![FieldInfo::new(
+            "amount".into(),
+            None,
+            None,
+            Type::NUMERIC,
+            FieldFormat::Binary,
+        )]);
+        let encode = |value: &str| {
+            let mut encoder = DataRowEncoder::new(schema.clone());
+            encoder
+                .encode_field(&value.parse::<Decimal>().unwrap())
+                .unwrap();
+            encoder.finish().unwrap().data[4..].to_vec()
+        };
+
+        // ndigits, weight, sign, dscale and base 10000 digits, scale of the
+        // value is kept
+        assert_eq!(vec![0, 2, 0, 0, 0, 0, 0, 2, 0, 1, 19, 136], encode("1.50"));
+        assert_eq!(
+            vec![0, 3, 0, 1, 0x40, 0, 0, 4, 0, 1, 9, 41, 26, 133],
+            encode("-12345.6789")
+        );
+        assert_eq!(vec![0, 1, 0xff, 0xff, 0, 0, 0, 4, 0, 1], encode("0.0001"));
+        assert_eq!(vec![0, 0, 0, 0, 0, 0, 0, 0], encode("0"));
+    }
+
+    #[test]
+    fn test_row_description_cache() {
+        let schema = Arc::new(vec![FieldInfo::new(
+            "id".into(),
+            None,
+            None,
+            Type::INT4,
+            FieldFormat::Text,
+        )]);
+        let mut cache = RowDescriptionCache::default();
+
+        let encoded = cache.get_or_encode(&schema).unwrap();
+        let mut expected = BytesMut::new();
+        into_row_description(&schema).encode(&mut expected).unwrap();
+        assert_eq!(&expected[..], &encoded[..]);
+        assert_eq!(
+            encoded.as_ptr(),
+            cache.get_or_encode(&schema.clone()).unwrap().as_ptr()
+        );
+
+        // equal schema in another Arc is encoded again
+        let other = Arc::new(schema.as_ref().clone());
+        assert_ne!(
+            encoded.as_ptr(),
+            cache.get_or_encode(&other).unwrap().as_ptr()
+        );
+    }
+
+    #[test]
+    fn test_data_row_writer() {
+        let schema = Arc::new(vec![
+            FieldInfo::new("id".into(), None, None, Type::INT4, FieldFormat::Binary),
+            FieldInfo::new("name".into(), None, None, Type::VARCHAR, FieldFormat::Text),
+        ]);
+
+        let mut encoder = DataRowEncoder::new(schema.clone());
+        encoder.encode_field(&42i32).unwrap();
+        encoder.encode_field(&None::<String>).unwrap();
+        let mut expected = BytesMut::new();
+        crate::messages::Message::encode(&encoder.finish().unwrap(), &mut expected).unwrap();
+
+        let mut buf = BytesMut::from(&b"prefix"[..]);
+        let mut writer = DataRowWriter::new(&schema, &mut buf);
+        writer.encode_field(&42i32).unwrap();
+        writer.encode_field(&None::<String>).unwrap();
+        assert_eq!(expected.len(), writer.finish());
+        assert_eq!(&expected[..], &buf[6..]);
+    }
 }
@@ -0,0 +1,573 @@
+//! Route queries to one of several handlers.
+//!
+//! `RoutingQueryHandler` wraps a default handler and named routes, and asks
+//! a `QueryClassifier` which route each query goes to. Read/write splitting
+//! and shard routing can be composed from existing handlers this way:
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use pgwire::api::query::PlaceholderExtendedQueryHandler;
+//! use pgwire::api::router::{
+//!     HintClassifier, QueryClassifier, ReadWriteClassifier, RoutingQueryHandler,
+//! };
+//!
+//! let primary = Arc::new(PlaceholderExtendedQueryHandler);
+//! let replica = Arc::new(PlaceholderExtendedQueryHandler);
+//! // a `/* route=primary */` hint overrides read/write splitting
+//! let classifier: Vec<Arc<dyn QueryClassifier>> = vec![
+//!     Arc::new(HintClassifier),
+//!     Arc::new(ReadWriteClassifier::new("replica")),
+//! ];
+//! let handler = RoutingQueryHandler::new(primary.clone(), Arc::new(classifier))
+//!     .with_route("primary", primary)
+//!     .with_route("replica", replica);
+//! ```
+//!
+//! A transaction can't span backends, so statements in a transaction block
+//! always go to the default route, and a query string of multiple statements
+//! goes to the default route unless all of them are classified to the same
+//! route.
+
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::Sink;
+
+use super::portal::Portal;
+use super::query::{split_statements, ExtendedQueryHandler, SimpleQueryHandler, StatementOrPortal};
+use super::readonly::{KeywordWriteClassifier, WriteClassifier};
+use super::results::{DescribePortalResponse, DescribeStatementResponse, Response};
+use super::stmt::StoredStatement;
+use super::store::PortalStore;
+use super::{ClientInfo, ClientPortalStore};
+use crate::error::{PgWireError, PgWireResult};
+use crate::messages::response::TransactionStatus;
+use crate::messages::PgWireBackendMessage;
+
+/// Choose the route of a statement.
+///
+/// It's implemented for closures taking client metadata and the statement,
+/// and for `Vec<Arc<dyn QueryClassifier>>`, which returns the first route
+/// found by its classifiers.
+pub trait QueryClassifier: Send + Sync {
+    /// Name of the route for the single `statement`, `None` for the default
+    /// route. `metadata` is the client's, with user and database of startup.
+    fn classify(&self, metadata: &HashMap<String, String>, statement: &str) -> Option<String>;
+}
+
+impl<F> QueryClassifier for F
+where
+    F: Fn(&HashMap<String, String>, &str) -> Option<String> + Send + Sync,
+{
+    fn classify(&self, metadata: &HashMap<String, String>, statement: &str) -> Option<String> {
+        self(metadata, statement)
+    }
+}
+
+impl QueryClassifier for Vec<Arc<dyn QueryClassifier>> {
+    fn classify(&self, metadata: &HashMap<String, String>, statement: &str) -> Option<String> {
+        self.iter()
+            .find_map(|classifier| classifier.classify(metadata, statement))
+    }
+}
+
+/// Leading keywords of statements that may run on a read replica
+const READ_KEYWORDS: &[&str] = &["SELECT", "WITH", "VALUES", "TABLE", "SHOW"];
+/// Row locking clauses of `SELECT`, which need the primary
+const LOCKING_CLAUSES: &[&str] = &[
+    " FOR UPDATE",
+    " FOR NO KEY UPDATE",
+    " FOR SHARE",
+    " FOR KEY SHARE",
+];
+
+/// Read/write splitting by statement type.
+///
+/// Queries like `SELECT`, `VALUES` and `SHOW` go to the read route, unless
+/// they lock rows or the `WriteClassifier` finds them writing. Everything
+/// else, including session commands like `SET`, stays on the default route.
+#[derive(Debug, Clone)]
+pub struct ReadWriteClassifier {
+    read_route: String,
+    writes: Arc<dyn WriteClassifier>,
+}
+
+impl ReadWriteClassifier {
+    /// Send reads to `read_route`, with `KeywordWriteClassifier`
+    pub fn new(read_route: impl Into<String>) -> ReadWriteClassifier {
+        ReadWriteClassifier {
+            read_route: read_route.into(),
+            writes: Arc::new(KeywordWriteClassifier),
+        }
+    }
+
+    /// Use the classifier of backend to find writes, like data-modifying
+    /// statements in `WITH`
+    pub fn with_write_classifier(
+        mut self,
+        writes: Arc<dyn WriteClassifier>,
+    ) -> ReadWriteClassifier {
+        self.writes = writes;
+        self
+    }
+
+    fn is_read(&self, statement: &str) -> bool {
+        let statement = statement
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_ascii_uppercase();
+        let keyword = statement
+            .trim_start_matches('(')
+            .split([' ', '('])
+            .next()
+            .unwrap_or_default();
+        READ_KEYWORDS.contains(&keyword)
+            && !LOCKING_CLAUSES.iter().any(|c| statement.contains(c))
+            && !self.writes.is_write(&statement)
+    }
+}
+
+impl QueryClassifier for ReadWriteClassifier {
+    fn classify(&self, _metadata: &HashMap<String, String>, statement: &str) -> Option<String> {
+        self.is_read(statement).then(|| self.read_route.clone())
+    }
+}
+
+/// Keywords followed by a table name
+const TABLE_KEYWORDS: &[&str] = &["FROM", "JOIN", "INTO", "UPDATE", "TABLE"];
+
+/// Route by prefix of the table names, for example `archive_` tables on a
+/// separate backend.
+///
+/// Tables are found after `FROM`, `JOIN`, `INTO`, `UPDATE` and `TABLE`, with
+/// schema and quotes removed. The route of the first table matching a prefix
+/// is used.
+#[derive(Debug, Clone, Default)]
+pub struct TablePrefixClassifier {
+    prefixes: Vec<(String, String)>,
+}
+
+impl TablePrefixClassifier {
+    pub fn new() -> TablePrefixClassifier {
+        TablePrefixClassifier::default()
+    }
+
+    /// Send statements on tables starting with `prefix` to `route`. Prefixes
+    /// are matched in the order they are added.
+    pub fn with_prefix(
+        mut self,
+        prefix: impl Into<String>,
+        route: impl Into<String>,
+    ) -> TablePrefixClassifier {
+        self.prefixes.push((prefix.into(), route.into()));
+        self
+    }
+}
+
+/// Names of tables referenced by the statement
+fn table_names(statement: &str) -> Vec<String> {
+    let tokens = statement
+        .split(|c: char| c.is_whitespace() || matches!(c, ',' | '(' | ')' | ';'))
+        .filter(|t| !t.is_empty())
+        .collect::<Vec<_>>();
+    tokens
+        .windows(2)
+        .filter(|pair| {
+            TABLE_KEYWORDS
+                .iter()
+                .any(|k| pair[0].eq_ignore_ascii_case(k))
+        })
+        .map(|pair| {
+            let name = pair[1].rsplit('.').next().unwrap_or_default();
+            name.trim_matches('"').to_owned()
+        })
+        .collect()
+}
+
+impl QueryClassifier for TablePrefixClassifier {
+    fn classify(&self, _metadata: &HashMap<String, String>, statement: &str) -> Option<String> {
+        table_names(statement).iter().find_map(|table| {
+            self.prefixes
+                .iter()
+                .find(|(prefix, _)| table.starts_with(prefix.as_str()))
+                .map(|(_, route)| route.clone())
+        })
+    }
+}
+
+/// Route by a hint in the leading comment of the statement, like
+/// `/* route=shard1 */ SELECT ...` or `-- route=shard1`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HintClassifier;
+
+impl HintClassifier {
+    fn hint(comment: &str) -> Option<String> {
+        comment.split_whitespace().find_map(|token| {
+            token
+                .strip_prefix("route=")
+                .filter(|route| !route.is_empty())
+                .map(str::to_owned)
+        })
+    }
+}
+
+impl QueryClassifier for HintClassifier {
+    fn classify(&self, _metadata: &HashMap<String, String>, statement: &str) -> Option<String> {
+        let statement = statement.trim_start();
+        if let Some(rest) = statement.strip_prefix("/*") {
+            let (comment, _) = rest.split_once("*/")?;
+            return Self::hint(comment);
+        }
+        if let Some(rest) = statement.strip_prefix("--") {
+            return Self::hint(rest.lines().next().unwrap_or_default());
+        }
+        None
+    }
+}
+
+/// Wrapper of query handlers that dispatches each query to a route chosen
+/// by `QueryClassifier`.
+///
+/// All routes are the same handler type `H`, use an enum to combine
+/// different backends. In extended query, statements are parsed by the
+/// query parser of the default route and routed by the query text of
+/// `Parse`. `Describe` and `Execute` of the same statement go to the same
+/// route.
+///
+/// Only `do_*` methods, `query_parser` and `check_portal_store_usage` are
+/// delegated to the inner handlers, messages are processed by the default
+/// `on_*` implementations.
+pub struct RoutingQueryHandler<H> {
+    default: Arc<H>,
+    routes: HashMap<String, Arc<H>>,
+    classifier: Arc<dyn QueryClassifier>,
+}
+
+impl<H> Debug for RoutingQueryHandler<H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RoutingQueryHandler")
+            .field("routes", &self.routes.keys().collect::<Vec<_>>())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<H> Clone for RoutingQueryHandler<H> {
+    fn clone(&self) -> Self {
+        RoutingQueryHandler {
+            default: self.default.clone(),
+            routes: self.routes.clone(),
+            classifier: self.classifier.clone(),
+        }
+    }
+}
+
+impl<H> RoutingQueryHandler<H> {
+    pub fn new(default: Arc<H>, classifier: Arc<dyn QueryClassifier>) -> RoutingQueryHandler<H> {
+        RoutingQueryHandler {
+            default,
+            routes: HashMap::new(),
+            classifier,
+        }
+    }
+
+    /// Add a named route
+    pub fn with_route(
+        mut self,
+        name: impl Into<String>,
+        handler: Arc<H>,
+    ) -> RoutingQueryHandler<H> {
+        self.routes.insert(name.into(), handler);
+        self
+    }
+
+    /// Get the handler of default route
+    pub fn default_route(&self) -> &Arc<H> {
+        &self.default
+    }
+
+    /// Get the handler of a named route
+    pub fn route(&self, name: &str) -> Option<&Arc<H>> {
+        self.routes.get(name)
+    }
+
+    /// Handler of the route for `statements`. Fails if the classifier
+    /// returns a route that doesn't exist.
+    fn select<'s, C, I>(&self, client: &C, statements: I) -> PgWireResult<&Arc<H>>
+    where
+        C: ClientInfo,
+        I: IntoIterator<Item = &'s str>,
+    {
+        if client.transaction_status() != TransactionStatus::Idle {
+            return Ok(&self.default);
+        }
+
+        let mut routes = statements
+            .into_iter()
+            .map(|statement| self.classifier.classify(client.metadata(), statement));
+        let Some(Some(name)) = routes.next() else {
+            return Ok(&self.default);
+        };
+        if !routes.all(|route| route.as_ref() == Some(&name)) {
+            return Ok(&self.default);
+        }
+        self.routes
+            .get(&name)
+            .ok_or_else(|| PgWireError::ApiError(format!("no route named {name}").into()))
+    }
+
+    fn select_statement<C: ClientInfo, S>(
+        &self,
+        client: &C,
+        statement: &StoredStatement<S>,
+    ) -> PgWireResult<&Arc<H>> {
+        if statement.query().is_empty() {
+            return Ok(&self.default);
+        }
+        self.select(client, split_statements(statement.query()))
+    }
+}
+
+#[async_trait]
+impl<H: SimpleQueryHandler> SimpleQueryHandler for RoutingQueryHandler<H> {
+    async fn do_query<'a, 'b: 'a, C>(
+        &'b self,
+        client: &mut C,
+        query: &'a str,
+    ) -> PgWireResult<Vec<Response<'a>>>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let handler = self.select(client, split_statements(query))?;
+        handler.do_query(client, query).await
+    }
+}
+
+#[async_trait]
+impl<H: ExtendedQueryHandler> ExtendedQueryHandler for RoutingQueryHandler<H> {
+    type Statement = H::Statement;
+    type QueryParser = H::QueryParser;
+
+    fn query_parser(&self) -> Arc<Self::QueryParser> {
+        self.default.query_parser()
+    }
+
+    fn check_portal_store_usage<C>(&self, client: &C) -> PgWireResult<()>
+    where
+        C: ClientInfo + ClientPortalStore,
+        C::PortalStore: PortalStore,
+    {
+        self.default.check_portal_store_usage(client)
+    }
+
+    async fn do_describe<C>(
+        &self,
+        client: &mut C,
+        target: StatementOrPortal<'_, Self::Statement>,
+    ) -> PgWireResult<DescribeStatementResponse>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let statement = match &target {
+            StatementOrPortal::Statement(statement) => *statement,
+            StatementOrPortal::Portal(portal) => portal.statement.as_ref(),
+        };
+        let handler = self.select_statement(client, statement)?;
+        handler.do_describe(client, target).await
+    }
+
+    async fn do_describe_statement<C>(
+        &self,
+        client: &mut C,
+        target: &StoredStatement<Self::Statement>,
+    ) -> PgWireResult<DescribeStatementResponse>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let handler = self.select_statement(client, target)?;
+        handler.do_describe_statement(client, target).await
+    }
+
+    async fn do_describe_portal<C>(
+        &self,
+        client: &mut C,
+        target: &Portal<Self::Statement>,
+    ) -> PgWireResult<DescribePortalResponse>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let handler = self.select_statement(client, &target.statement)?;
+        handler.do_describe_portal(client, target).await
+    }
+
+    async fn do_query<'a, 'b: 'a, C>(
+        &'b self,
+        client: &mut C,
+        portal: &'a Portal<Self::Statement>,
+        max_rows: usize,
+    ) -> PgWireResult<Response<'a>>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let handler = self.select_statement(client, &portal.statement)?;
+        handler.do_query(client, portal, max_rows).await
+    }
+
+    /// Enabled if any route supports it, the default `do_query_batch` of
+    /// other routes runs the batch one by one
+    fn supports_query_batch(&self) -> bool {
+        self.routes
+            .values()
+            .chain(Some(&self.default))
+            .any(|handler| handler.supports_query_batch())
+    }
+
+    async fn do_query_batch<'a, 'b: 'a, C>(
+        &'b self,
+        client: &mut C,
+        portals: &'a [Arc<Portal<Self::Statement>>],
+    ) -> PgWireResult<Vec<Response<'a>>>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        // portals of a batch share the same statement
+        let handler = match portals.first() {
+            Some(portal) => self.select_statement(client, &portal.statement)?,
+            None => &self.default,
+        };
+        handler.do_query_batch(client, portals).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testkit::fixture::{command_tag, error_code, FnQueryHandler, TestHandlers};
+    use crate::testkit::MockClient;
+
+    fn classify<Q: QueryClassifier>(classifier: &Q, statement: &str) -> Option<String> {
+        classifier.classify(&HashMap::new(), statement)
+    }
+
+    #[test]
+    fn test_read_write_classifier() {
+        let classifier = ReadWriteClassifier::new("replica");
+        for read in [
+            "SELECT 1",
+            "select *\nfrom t",
+            "SHOW TimeZone",
+            "(SELECT 1)",
+        ] {
+            assert_eq!(
+                Some("replica".to_owned()),
+                classify(&classifier, read),
+                "{read}"
+            );
+        }
+        for write in [
+            "INSERT INTO t VALUES (1)",
+            "SELECT * FROM t FOR UPDATE",
+            "select * from t for  share",
+            "SET search_path TO s",
+            "BEGIN",
+        ] {
+            assert_eq!(None, classify(&classifier, write), "{write}");
+        }
+    }
+
+    #[test]
+    fn test_table_prefix_and_hint_classifier() {
+        let classifier = TablePrefixClassifier::new()
+            .with_prefix("archive_", "archive")
+            .with_prefix("log_", "logs");
+        assert_eq!(
+            Some("archive".to_owned()),
+            classify(
+                &classifier,
+                "SELECT * FROM users JOIN public.\"archive_orders\" o ON true"
+            )
+        );
+        assert_eq!(
+            Some("logs".to_owned()),
+            classify(&classifier, "insert into log_2024(id) values (1)")
+        );
+        assert_eq!(None, classify(&classifier, "SELECT 'FROM archive_x'"));
+
+        assert_eq!(
+            Some("shard1".to_owned()),
+            classify(&HintClassifier, " /* route=shard1 */ SELECT 1")
+        );
+        assert_eq!(
+            Some("shard2".to_owned()),
+            classify(&HintClassifier, "-- app=x route=shard2\nSELECT 1")
+        );
+        assert_eq!(
+            None,
+            classify(&HintClassifier, "SELECT 1 /* route=shard1 */")
+        );
+
+        let chain: Vec<Arc<dyn QueryClassifier>> =
+            vec![Arc::new(HintClassifier), Arc::new(classifier)];
+        assert_eq!(
+            Some("shard1".to_owned()),
+            classify(&chain, "/* route=shard1 */ SELECT * FROM archive_orders")
+        );
+        assert_eq!(
+            Some("archive".to_owned()),
+            classify(&chain, "SELECT * FROM archive_orders")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_routing_query_handler() {
+        let classifier: Vec<Arc<dyn QueryClassifier>> = vec![
+            Arc::new(HintClassifier),
+            Arc::new(ReadWriteClassifier::new("replica")),
+        ];
+        let router = RoutingQueryHandler::new(
+            Arc::new(FnQueryHandler::tag("PRIMARY")),
+            Arc::new(classifier),
+        )
+        .with_route("replica", Arc::new(FnQueryHandler::tag("REPLICA")));
+        let mut client = MockClient::start(TestHandlers::new(router));
+        client.startup("tom", None).await.unwrap();
+
+        let messages = client.simple_query("SELECT 1; SELECT 2").await.unwrap();
+        assert_eq!(Some("REPLICA"), command_tag(&messages));
+        let messages = client.simple_query("DELETE FROM t").await.unwrap();
+        assert_eq!(Some("PRIMARY"), command_tag(&messages));
+        // statements of different routes stay together
+        let messages = client
+            .simple_query("SELECT 1; DELETE FROM t")
+            .await
+            .unwrap();
+        assert_eq!(Some("PRIMARY"), command_tag(&messages));
+
+        // hint to an unknown route
+        let messages = client
+            .simple_query("/* route=shard9 */ SELECT 1")
+            .await
+            .unwrap();
+        assert_eq!(Some("XX000".to_owned()), error_code(&messages[0]));
+    }
+}
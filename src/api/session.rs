@@ -0,0 +1,342 @@
+//! `DISCARD` and `DEALLOCATE` commands used by connection poolers.
+//!
+//! Poolers like PgBouncer in session mode send `DISCARD ALL` before handing a
+//! server connection to another client. When
+//! `ServerConfig::handle_session_commands` is enabled, which is the default,
+//! `SimpleQueryHandler::on_query` answers these commands with
+//! `send_session_command_response` without calling `do_query`.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Debug;
+
+use futures::sink::{Sink, SinkExt};
+
+use super::query::clear_suspended_portals;
+use super::results::Tag;
+use super::set::REPORTED_PARAMETERS;
+use super::show::NON_PARAMETER_KEYS;
+use super::store::PortalStore;
+use super::{ClientInfo, ClientPortalStore};
+use crate::error::{ErrorInfo, PgWireError, PgWireResult};
+use crate::messages::response::TransactionStatus;
+use crate::messages::startup::ParameterStatus;
+use crate::messages::PgWireBackendMessage;
+
+/// A session management command handled by the framework
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionCommand {
+    /// `DISCARD ALL`: close all portals, deallocate all statements and reset
+    /// session parameters
+    DiscardAll,
+    /// `DISCARD PLANS`: nothing is cached by the framework, only the tag is
+    /// sent
+    DiscardPlans,
+    /// `DEALLOCATE name`
+    Deallocate(String),
+    /// `DEALLOCATE ALL`
+    DeallocateAll,
+}
+
+impl SessionCommand {
+    /// Parse a single statement query into `SessionCommand`, returns `None`
+    /// for other queries.
+    ///
+    /// Other forms of `DISCARD`, like `DISCARD TEMP`, depend on the backend
+    /// and are not recognized.
+    pub fn parse(query: &str) -> Option<SessionCommand> {
+        let query = query.trim().trim_end_matches(';').trim_end();
+        let mut words = query.split_whitespace();
+        let command = words.next()?;
+
+        let result = if command.eq_ignore_ascii_case("DISCARD") {
+            match words.next()? {
+                w if w.eq_ignore_ascii_case("ALL") => SessionCommand::DiscardAll,
+                w if w.eq_ignore_ascii_case("PLANS") => SessionCommand::DiscardPlans,
+                _ => return None,
+            }
+        } else if command.eq_ignore_ascii_case("DEALLOCATE") {
+            let mut name = words.next()?;
+            if name.eq_ignore_ascii_case("PREPARE") {
+                name = words.next()?;
+            }
+            if name.eq_ignore_ascii_case("ALL") {
+                SessionCommand::DeallocateAll
+            } else {
+                SessionCommand::Deallocate(identifier(name)?)
+            }
+        } else {
+            return None;
+        };
+
+        // trailing words, like a second statement
+        if words.next().is_some() {
+            return None;
+        }
+        Some(result)
+    }
+
+    /// Command tag sent in `CommandComplete`
+    pub fn tag(&self) -> Tag {
+        match self {
+            SessionCommand::DiscardAll => Tag::new("DISCARD ALL"),
+            SessionCommand::DiscardPlans => Tag::new("DISCARD PLANS"),
+            SessionCommand::Deallocate(_) => Tag::new("DEALLOCATE"),
+            SessionCommand::DeallocateAll => Tag::new("DEALLOCATE ALL"),
+        }
+    }
+}
+
+/// Quoted identifiers keep their case, others are folded to lower case like
+/// postgres does.
+fn identifier(name: &str) -> Option<String> {
+    if let Some(quoted) = name.strip_prefix('"') {
+        let quoted = quoted.strip_suffix('"')?;
+        Some(quoted.replace("\"\"", "\""))
+    } else {
+        Some(name.to_lowercase())
+    }
+}
+
+/// Session parameters when the client finished authentication, which are
+/// restored by `DISCARD ALL`. It's stored in client extensions.
+#[derive(Debug, Clone, Default)]
+pub struct SessionDefaults(pub HashMap<String, String>);
+
+/// Remember current client metadata as `SessionDefaults`
+pub(crate) fn save_session_defaults<C: ClientInfo>(client: &mut C) {
+    let defaults = SessionDefaults(client.metadata().clone());
+    client.extensions_mut().insert(defaults);
+}
+
+/// Restore session parameters in `metadata` to `defaults`, parameters set
+/// after authentication are removed. Connection properties like `user` and
+/// `database` are kept as is.
+///
+/// Returns the changed parameters in `REPORTED_PARAMETERS` with their new
+/// value, which is empty for a removed one like `RESET` does.
+fn reset_parameters(
+    metadata: &mut HashMap<String, String>,
+    defaults: &HashMap<String, String>,
+) -> Vec<(String, String)> {
+    let names = metadata
+        .keys()
+        .chain(defaults.keys())
+        .filter(|name| !NON_PARAMETER_KEYS.contains(&name.as_str()))
+        .cloned()
+        .collect::<BTreeSet<_>>();
+
+    let mut changed = Vec::new();
+    for name in names {
+        let default = defaults.get(&name);
+        if metadata.get(&name) == default {
+            continue;
+        }
+        match default {
+            Some(value) => metadata.insert(name.clone(), value.clone()),
+            None => metadata.remove(&name),
+        };
+        if REPORTED_PARAMETERS.contains(&name.as_str()) {
+            changed.push((name, default.cloned().unwrap_or_default()));
+        }
+    }
+    changed
+}
+
+/// Execute the command on client state and send `CommandComplete`.
+///
+/// Reported parameters restored by `DISCARD ALL` are reported to client with
+/// `ParameterStatus`, after the command complete like `SET` does.
+pub async fn send_session_command_response<C>(
+    client: &mut C,
+    command: &SessionCommand,
+) -> PgWireResult<()>
+where
+    C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send,
+    C::PortalStore: PortalStore,
+    C::Error: Debug,
+    PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+{
+    let mut changed = Vec::new();
+    match command {
+        SessionCommand::DiscardAll => {
+            if client.transaction_status() != TransactionStatus::Idle {
+                return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                    "ERROR".to_owned(),
+                    "25001".to_owned(),
+                    "DISCARD ALL cannot run inside a transaction block".to_owned(),
+                ))));
+            }
+            client.portal_store().rm_all_portals();
+            client.portal_store().rm_all_statements();
+            clear_suspended_portals(client.extensions_mut());
+
+            if let Some(SessionDefaults(defaults)) = client.extensions().get::<SessionDefaults>() {
+                let defaults = defaults.clone();
+                changed = reset_parameters(client.metadata_mut(), &defaults);
+            }
+        }
+        SessionCommand::DiscardPlans => {}
+        SessionCommand::Deallocate(name) => {
+            if client.portal_store().get_statement(name).is_none() {
+                return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                    "ERROR".to_owned(),
+                    "26000".to_owned(),
+                    format!("prepared statement \"{name}\" does not exist"),
+                ))));
+            }
+            client.portal_store().rm_statement(name);
+        }
+        SessionCommand::DeallocateAll => {
+            client.portal_store().rm_all_statements();
+        }
+    }
+
+    client
+        .feed(PgWireBackendMessage::CommandComplete(command.tag().into()))
+        .await?;
+    for (name, value) in changed {
+        client
+            .feed(PgWireBackendMessage::ParameterStatus(ParameterStatus::new(
+                name, value,
+            )))
+            .await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::results::Response;
+    use crate::messages::extendedquery::{Parse, Sync as PgSync};
+    use crate::messages::response::{CommandComplete, ReadyForQuery};
+    use crate::messages::PgWireFrontendMessage;
+    use crate::testkit::fixture::{error_code, FnQueryHandler, TestHandlers};
+    use crate::testkit::MockClient;
+
+    #[test]
+    fn test_parse_session_command() {
+        assert_eq!(
+            Some(SessionCommand::DiscardAll),
+            SessionCommand::parse("discard all;")
+        );
+        assert_eq!(
+            Some(SessionCommand::DiscardPlans),
+            SessionCommand::parse(" DISCARD PLANS ")
+        );
+        assert_eq!(
+            Some(SessionCommand::Deallocate("s1".to_owned())),
+            SessionCommand::parse("DEALLOCATE PREPARE S1")
+        );
+        assert_eq!(
+            Some(SessionCommand::Deallocate("S1".to_owned())),
+            SessionCommand::parse("deallocate \"S1\"")
+        );
+        assert_eq!(
+            Some(SessionCommand::DeallocateAll),
+            SessionCommand::parse("DEALLOCATE ALL")
+        );
+        assert_eq!(None, SessionCommand::parse("DISCARD TEMP"));
+        assert_eq!(None, SessionCommand::parse("DISCARD ALL; SELECT 1"));
+        assert_eq!(None, SessionCommand::parse("SELECT 1"));
+        assert_eq!(None, SessionCommand::parse("DEALLOCATE"));
+    }
+
+    #[test]
+    fn test_reset_parameters() {
+        let defaults = HashMap::from([
+            ("user".to_owned(), "tom".to_owned()),
+            ("DateStyle".to_owned(), "ISO, MDY".to_owned()),
+        ]);
+        let mut metadata = HashMap::from([
+            ("user".to_owned(), "jerry".to_owned()),
+            ("options".to_owned(), "-c geqo=off".to_owned()),
+            ("DateStyle".to_owned(), "German".to_owned()),
+            ("search_path".to_owned(), "s1".to_owned()),
+        ]);
+
+        let changed = reset_parameters(&mut metadata, &defaults);
+        assert_eq!(
+            vec![("DateStyle".to_owned(), "ISO, MDY".to_owned())],
+            changed
+        );
+        assert_eq!(
+            HashMap::from([
+                ("user".to_owned(), "jerry".to_owned()),
+                ("options".to_owned(), "-c geqo=off".to_owned()),
+                ("DateStyle".to_owned(), "ISO, MDY".to_owned()),
+            ]),
+            metadata
+        );
+    }
+
+    #[tokio::test]
+    async fn test_session_commands() {
+        // answers `SET name TO value` with parameter status
+        let handler = FnQueryHandler::new(|query| {
+            Ok(vec![match query
+                .strip_prefix("SET ")
+                .and_then(|s| s.split_once(" TO "))
+            {
+                Some((name, value)) => Response::ParameterStatus {
+                    name: name.to_owned(),
+                    value: value.to_owned(),
+                },
+                None => Response::Execution(Tag::new(query)),
+            }])
+        });
+        let mut client = MockClient::start(TestHandlers::new(handler));
+        client.startup("tom", None).await.unwrap();
+
+        client
+            .send_all([
+                PgWireFrontendMessage::Parse(Parse::new(
+                    Some("s1".to_owned()),
+                    "SELECT n".to_owned(),
+                    vec![],
+                )),
+                PgWireFrontendMessage::Sync(PgSync::new()),
+            ])
+            .await
+            .unwrap();
+        client.receive_until_ready().await.unwrap();
+
+        let messages = client.simple_query("DEALLOCATE s1").await.unwrap();
+        assert_eq!(
+            PgWireBackendMessage::CommandComplete(CommandComplete::new("DEALLOCATE".to_owned())),
+            messages[0]
+        );
+        let messages = client.simple_query("DEALLOCATE s1").await.unwrap();
+        assert_eq!(Some("26000".to_owned()), error_code(&messages[0]));
+
+        client
+            .simple_query("SET application_name TO psql")
+            .await
+            .unwrap();
+        client.simple_query("SET search_path TO s1").await.unwrap();
+        let messages = client.simple_query("DISCARD ALL").await.unwrap();
+        // only reported parameters are sent
+        assert_eq!(
+            vec![
+                PgWireBackendMessage::CommandComplete(CommandComplete::new(
+                    "DISCARD ALL".to_owned()
+                )),
+                PgWireBackendMessage::ParameterStatus(ParameterStatus::new(
+                    "application_name".to_owned(),
+                    String::new()
+                )),
+                PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(TransactionStatus::Idle)),
+            ],
+            messages
+        );
+
+        // other DISCARD forms go to do_query
+        let messages = client.simple_query("DISCARD TEMP").await.unwrap();
+        assert_eq!(
+            PgWireBackendMessage::CommandComplete(CommandComplete::new("DISCARD TEMP".to_owned())),
+            messages[0]
+        );
+    }
+}
@@ -0,0 +1,615 @@
+//! `SET` commands sent by drivers.
+//!
+//! Drivers set session parameters like `extra_float_digits` and
+//! `application_name` right after connecting, and fail to connect if the
+//! server returns an error for them. `SetCommandHandler` answers these `SET`
+//! statements in the framework, so backends that cannot parse them still
+//! work with mainstream drivers.
+
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::Sink;
+
+use super::guc::{parse_timeout, EXECUTION_PARAMETERS};
+use super::portal::Portal;
+use super::query::{split_statements, ExtendedQueryHandler, SimpleQueryHandler, StatementOrPortal};
+use super::results::{DescribePortalResponse, DescribeStatementResponse, Response, Tag};
+use super::session::SessionDefaults;
+use super::stmt::StoredStatement;
+use super::store::PortalStore;
+use super::{ClientInfo, ClientPortalStore};
+use crate::error::{ErrorInfo, PgWireError, PgWireResult};
+use crate::messages::PgWireBackendMessage;
+
+/// Parameters handled by `SetCommandHandler::new`
+pub const DRIVER_PARAMETERS: &[&str] = &[
+    "extra_float_digits",
+    "client_encoding",
+    "application_name",
+    "statement_timeout",
+    "search_path",
+];
+
+/// Parameters reported to client with `ParameterStatus` when changed, like
+/// `GUC_REPORT` parameters of postgres
+pub const REPORTED_PARAMETERS: &[&str] = &[
+    "application_name",
+    "client_encoding",
+    "DateStyle",
+    "IntervalStyle",
+    "TimeZone",
+    "standard_conforming_strings",
+    "integer_datetimes",
+    "default_transaction_read_only",
+];
+
+/// A parsed `SET name { TO | = } value` statement
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SetStatement {
+    pub name: String,
+    /// Values separated by `, ` with quotes removed, `None` for `DEFAULT`
+    pub value: Option<String>,
+    /// `SET LOCAL`, which only lasts until the end of transaction
+    pub local: bool,
+}
+
+impl SetStatement {
+    /// Parse a single `SET` statement, returns `None` for other statements
+    /// and special forms like `SET TIME ZONE` or `SET TRANSACTION`. `RESET
+    /// name` is parsed as `SET name TO DEFAULT`.
+    pub fn parse(statement: &str) -> Option<SetStatement> {
+        let mut tokens = Tokens(statement.trim().trim_end_matches(';').trim_end());
+        let command = tokens.word()?;
+        if command.eq_ignore_ascii_case("RESET") {
+            let name = identifier(tokens.word()?);
+            if !tokens.0.is_empty() || name == "all" {
+                return None;
+            }
+            return Some(SetStatement {
+                name,
+                value: None,
+                local: false,
+            });
+        }
+        if !command.eq_ignore_ascii_case("SET") {
+            return None;
+        }
+
+        let mut local = false;
+        let mut name = tokens.word()?;
+        if name.eq_ignore_ascii_case("SESSION") || name.eq_ignore_ascii_case("LOCAL") {
+            local = name.eq_ignore_ascii_case("LOCAL");
+            name = tokens.word()?;
+        }
+        let name = identifier(name);
+
+        if !tokens.eat("=") && !tokens.word()?.eq_ignore_ascii_case("TO") {
+            return None;
+        }
+
+        let mut values = Vec::new();
+        loop {
+            values.push(tokens.value()?);
+            if !tokens.eat(",") {
+                break;
+            }
+        }
+        if !tokens.0.is_empty() {
+            return None;
+        }
+
+        let value = match values.as_slice() {
+            [(value, false)] if value.eq_ignore_ascii_case("DEFAULT") => None,
+            _ => Some(
+                values
+                    .into_iter()
+                    .map(|(value, _)| value)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ),
+        };
+
+        Some(SetStatement { name, value, local })
+    }
+}
+
+fn identifier(name: &str) -> String {
+    match name.strip_prefix('"').and_then(|n| n.strip_suffix('"')) {
+        Some(quoted) => quoted.replace("\"\"", "\""),
+        None => name.to_lowercase(),
+    }
+}
+
+/// Minimal tokenizer of `SET` statement
+struct Tokens<'a>(&'a str);
+
+impl<'a> Tokens<'a> {
+    fn eat(&mut self, token: &str) -> bool {
+        let rest = self.0.trim_start();
+        match rest.strip_prefix(token) {
+            Some(rest) => {
+                self.0 = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Identifier, keyword or number, including quoted identifier
+    fn word(&mut self) -> Option<&'a str> {
+        let rest = self.0.trim_start();
+        let end = if rest.starts_with('"') {
+            quoted_end(rest, '"')?
+        } else {
+            rest.find(|c: char| !(c.is_alphanumeric() || "_.$-+".contains(c)))
+                .unwrap_or(rest.len())
+        };
+        if end == 0 {
+            return None;
+        }
+        self.0 = &rest[end..];
+        Some(&rest[..end])
+    }
+
+    /// Value, and if it was a quoted string
+    fn value(&mut self) -> Option<(String, bool)> {
+        let rest = self.0.trim_start();
+        if rest.starts_with('\'') {
+            let end = quoted_end(rest, '\'')?;
+            self.0 = &rest[end..];
+            Some((rest[1..end - 1].replace("''", "'"), true))
+        } else {
+            let word = self.word()?;
+            let value = if word.starts_with('"') {
+                identifier(word)
+            } else {
+                word.to_owned()
+            };
+            Some((value, false))
+        }
+    }
+}
+
+/// End of a quoted token starting at the beginning of `s`, after the closing
+/// quote. Doubled quotes are escapes.
+fn quoted_end(s: &str, quote: char) -> Option<usize> {
+    let mut chars = s.char_indices().skip(1).peekable();
+    while let Some((i, c)) = chars.next() {
+        if c == quote {
+            if chars.peek().map(|(_, c)| *c) == Some(quote) {
+                chars.next();
+            } else {
+                return Some(i + 1);
+            }
+        }
+    }
+    None
+}
+
+/// Wrapper of query handlers that answers `SET` of the given parameters in
+/// simple query, without calling `do_query` of the inner handler.
+///
+/// The value is stored in client metadata, and reported to client with
+/// `ParameterStatus` if the parameter is in `REPORTED_PARAMETERS`, so
+/// monitoring tools see changes of `application_name` mid-session. `SET ...
+/// TO DEFAULT` and `RESET` restore the value when the session started. Only
+/// queries consisting entirely of handled `SET` statements are answered,
+/// others are passed to the inner handler as is. In extended query, a
+/// statement of a single handled `SET` is answered with the query text from
+/// `Parse`.
+///
+/// `client_encoding` only accepts `UTF8`, other encodings are rejected with
+/// `22023`.
+#[derive(Debug, Clone)]
+pub struct SetCommandHandler<H> {
+    inner: Arc<H>,
+    parameters: Vec<String>,
+}
+
+impl<H> SetCommandHandler<H> {
+    /// Handle `SET` of `DRIVER_PARAMETERS`
+    pub fn new(inner: Arc<H>) -> SetCommandHandler<H> {
+        SetCommandHandler {
+            inner,
+            parameters: DRIVER_PARAMETERS.iter().map(|p| (*p).to_owned()).collect(),
+        }
+    }
+
+    /// Replace the handled parameters
+    pub fn with_parameters(mut self, parameters: Vec<String>) -> SetCommandHandler<H> {
+        self.parameters = parameters;
+        self
+    }
+
+    /// Get the inner handler
+    pub fn inner(&self) -> &Arc<H> {
+        &self.inner
+    }
+
+    /// Name of handled parameter in the case it's configured, `None` if the
+    /// parameter is not handled.
+    fn handled_name(&self, name: &str) -> Option<&str> {
+        self.parameters
+            .iter()
+            .find(|p| p.eq_ignore_ascii_case(name))
+            .map(String::as_str)
+    }
+
+    /// Name and value of a handled `SET` statement
+    fn handled_set(&self, statement: &str) -> Option<(String, Option<String>)> {
+        let set = SetStatement::parse(statement)?;
+        let name = self.handled_name(&set.name)?.to_owned();
+        Some((name, set.value))
+    }
+}
+
+fn apply_set<'a, C: ClientInfo>(
+    client: &mut C,
+    name: &str,
+    value: Option<String>,
+) -> PgWireResult<Response<'a>> {
+    let value = match value {
+        Some(value) => value,
+        None => {
+            let default = client
+                .extensions()
+                .get::<SessionDefaults>()
+                .and_then(|defaults| defaults.0.get(name).cloned());
+            match default {
+                Some(default) => default,
+                // reported parameters without a default are reset to empty
+                None if REPORTED_PARAMETERS.contains(&name) => String::new(),
+                None => {
+                    client.metadata_mut().remove(name);
+                    return Ok(Response::Execution(Tag::new("SET")));
+                }
+            }
+        }
+    };
+
+    if name == "client_encoding" && !is_utf8(&value) {
+        return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+            "ERROR".to_owned(),
+            "22023".to_owned(),
+            format!("invalid value for parameter \"client_encoding\": \"{value}\""),
+        ))));
+    }
+
+    if EXECUTION_PARAMETERS.contains(&name) && name.ends_with("_timeout") {
+        parse_timeout(name, &value)?;
+    }
+
+    if REPORTED_PARAMETERS.contains(&name) {
+        // stored in metadata when the response is sent
+        Ok(Response::ParameterStatus {
+            name: name.to_owned(),
+            value,
+        })
+    } else {
+        client.metadata_mut().insert(name.to_owned(), value);
+        Ok(Response::Execution(Tag::new("SET")))
+    }
+}
+
+fn is_utf8(encoding: &str) -> bool {
+    encoding.eq_ignore_ascii_case("UTF8")
+        || encoding.eq_ignore_ascii_case("UTF-8")
+        || encoding.eq_ignore_ascii_case("UNICODE")
+}
+
+#[async_trait]
+impl<H: SimpleQueryHandler> SimpleQueryHandler for SetCommandHandler<H> {
+    async fn do_query<'a, 'b: 'a, C>(
+        &'b self,
+        client: &mut C,
+        query: &'a str,
+    ) -> PgWireResult<Vec<Response<'a>>>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let statements = split_statements(query)
+            .into_iter()
+            .map(|s| self.handled_set(s))
+            .collect::<Option<Vec<_>>>();
+
+        match statements {
+            Some(statements) if !statements.is_empty() => {
+                let mut responses = Vec::with_capacity(statements.len());
+                for (name, value) in statements {
+                    match apply_set(client, &name, value) {
+                        Ok(response) => responses.push(response),
+                        Err(PgWireError::UserError(error)) => {
+                            responses.push(Response::Error(error));
+                            break;
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(responses)
+            }
+            _ => self.inner.do_query(client, query).await,
+        }
+    }
+}
+
+#[async_trait]
+impl<H: ExtendedQueryHandler> ExtendedQueryHandler for SetCommandHandler<H> {
+    type Statement = H::Statement;
+    type QueryParser = H::QueryParser;
+
+    fn query_parser(&self) -> Arc<Self::QueryParser> {
+        self.inner.query_parser()
+    }
+
+    fn check_portal_store_usage<C>(&self, client: &C) -> PgWireResult<()>
+    where
+        C: ClientInfo + ClientPortalStore,
+        C::PortalStore: PortalStore,
+    {
+        self.inner.check_portal_store_usage(client)
+    }
+
+    async fn do_describe<C>(
+        &self,
+        client: &mut C,
+        target: StatementOrPortal<'_, Self::Statement>,
+    ) -> PgWireResult<DescribeStatementResponse>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.inner.do_describe(client, target).await
+    }
+
+    async fn do_describe_statement<C>(
+        &self,
+        client: &mut C,
+        target: &StoredStatement<Self::Statement>,
+    ) -> PgWireResult<DescribeStatementResponse>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        if self.handled_set(target.query()).is_some() {
+            return Ok(DescribeStatementResponse::new(
+                target.parameter_types.clone(),
+                vec![],
+            ));
+        }
+        self.inner.do_describe_statement(client, target).await
+    }
+
+    async fn do_describe_portal<C>(
+        &self,
+        client: &mut C,
+        target: &Portal<Self::Statement>,
+    ) -> PgWireResult<DescribePortalResponse>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        if self.handled_set(target.statement.query()).is_some() {
+            return Ok(DescribePortalResponse::new(vec![]));
+        }
+        self.inner.do_describe_portal(client, target).await
+    }
+
+    async fn do_query<'a, 'b: 'a, C>(
+        &'b self,
+        client: &mut C,
+        portal: &'a Portal<Self::Statement>,
+        max_rows: usize,
+    ) -> PgWireResult<Response<'a>>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        if let Some((name, value)) = self.handled_set(portal.statement.query()) {
+            return match apply_set(client, &name, value) {
+                Err(PgWireError::UserError(error)) => Ok(Response::Error(error)),
+                result => result,
+            };
+        }
+        self.inner.do_query(client, portal, max_rows).await
+    }
+
+    fn supports_query_batch(&self) -> bool {
+        self.inner.supports_query_batch()
+    }
+
+    async fn do_query_batch<'a, 'b: 'a, C>(
+        &'b self,
+        client: &mut C,
+        portals: &'a [Arc<Portal<Self::Statement>>],
+    ) -> PgWireResult<Vec<Response<'a>>>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        // portals of a batch share the same statement
+        let is_set = portals
+            .first()
+            .is_some_and(|portal| self.handled_set(portal.statement.query()).is_some());
+        if !is_set {
+            return self.inner.do_query_batch(client, portals).await;
+        }
+
+        let mut responses = Vec::with_capacity(portals.len());
+        for portal in portals {
+            let response = self.do_query(client, portal.as_ref(), 0).await?;
+            let is_error = matches!(response, Response::Error(_));
+            responses.push(response);
+            if is_error {
+                break;
+            }
+        }
+        Ok(responses)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::messages::data::NoData;
+    use crate::messages::extendedquery::{
+        Bind, BindComplete, Describe, Execute, Parse, ParseComplete, Sync as PgSync,
+        TARGET_TYPE_BYTE_PORTAL,
+    };
+    use crate::messages::response::{CommandComplete, ReadyForQuery, TransactionStatus};
+    use crate::messages::startup::ParameterStatus;
+    use crate::messages::PgWireFrontendMessage;
+    use crate::testkit::fixture::{error_code, FnQueryHandler, NoopStartup, TestHandlers};
+    use crate::testkit::MockClient;
+
+    fn set_handlers() -> TestHandlers<NoopStartup, SetCommandHandler<FnQueryHandler>> {
+        TestHandlers::new(SetCommandHandler::new(Arc::new(FnQueryHandler::echo())))
+    }
+
+    fn set(name: &str, value: Option<&str>) -> Option<SetStatement> {
+        Some(SetStatement {
+            name: name.to_owned(),
+            value: value.map(str::to_owned),
+            local: false,
+        })
+    }
+
+    #[test]
+    fn test_parse_set() {
+        assert_eq!(
+            set("extra_float_digits", Some("3")),
+            SetStatement::parse("SET extra_float_digits = 3")
+        );
+        assert_eq!(
+            set("application_name", Some("it's me")),
+            SetStatement::parse("set application_name to 'it''s me';")
+        );
+        assert_eq!(
+            set("search_path", Some("$user, public")),
+            SetStatement::parse("SET SESSION search_path TO \"$user\", public")
+        );
+        assert_eq!(
+            set("statement_timeout", None),
+            SetStatement::parse("SET statement_timeout TO DEFAULT")
+        );
+        assert_eq!(
+            set("statement_timeout", Some("-1")),
+            SetStatement::parse("SET statement_timeout = -1")
+        );
+        assert!(
+            SetStatement::parse("SET LOCAL search_path = x")
+                .unwrap()
+                .local
+        );
+        assert_eq!(None, SetStatement::parse("SET TIME ZONE 'UTC'"));
+        assert_eq!(
+            None,
+            SetStatement::parse("SET TRANSACTION ISOLATION LEVEL SERIALIZABLE")
+        );
+        assert_eq!(
+            set("application_name", None),
+            SetStatement::parse("RESET application_name")
+        );
+        assert_eq!(None, SetStatement::parse("RESET ALL"));
+        assert_eq!(None, SetStatement::parse("SET a = 1 2"));
+        assert_eq!(None, SetStatement::parse("SELECT 1"));
+    }
+
+    #[tokio::test]
+    async fn test_set_commands() {
+        let mut client = MockClient::start(set_handlers());
+        client.startup("tom", None).await.unwrap();
+
+        let messages = client
+            .simple_query("SET extra_float_digits = 3; SET application_name = 'psql'")
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![
+                PgWireBackendMessage::CommandComplete(CommandComplete::new("SET".to_owned())),
+                PgWireBackendMessage::CommandComplete(CommandComplete::new("SET".to_owned())),
+                PgWireBackendMessage::ParameterStatus(ParameterStatus::new(
+                    "application_name".to_owned(),
+                    "psql".to_owned()
+                )),
+                PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(TransactionStatus::Idle)),
+            ],
+            messages
+        );
+
+        let messages = client
+            .simple_query("SET client_encoding TO 'LATIN1'")
+            .await
+            .unwrap();
+        assert_eq!(Some("22023".to_owned()), error_code(&messages[0]));
+
+        // unhandled parameters go to do_query
+        let messages = client.simple_query("SET work_mem = '1MB'").await.unwrap();
+        assert_eq!(
+            PgWireBackendMessage::CommandComplete(CommandComplete::new(
+                "SET work_mem = '1MB'".to_owned()
+            )),
+            messages[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_set_application_name() {
+        let mut client = MockClient::start(set_handlers());
+        client.startup("tom", None).await.unwrap();
+
+        client
+            .send_all([
+                PgWireFrontendMessage::Parse(Parse::new(
+                    None,
+                    "SET application_name = 'pgbench'".to_owned(),
+                    vec![],
+                )),
+                PgWireFrontendMessage::Bind(Bind::new(None, None, vec![], vec![], vec![])),
+                PgWireFrontendMessage::Describe(Describe::new(TARGET_TYPE_BYTE_PORTAL, None)),
+                PgWireFrontendMessage::Execute(Execute::new(None, 0)),
+                PgWireFrontendMessage::Sync(PgSync::new()),
+            ])
+            .await
+            .unwrap();
+        let messages = client.receive_until_ready().await.unwrap();
+        assert_eq!(
+            vec![
+                PgWireBackendMessage::ParseComplete(ParseComplete::new()),
+                PgWireBackendMessage::BindComplete(BindComplete::new()),
+                PgWireBackendMessage::NoData(NoData::new()),
+                PgWireBackendMessage::CommandComplete(CommandComplete::new("SET".to_owned())),
+                PgWireBackendMessage::ParameterStatus(ParameterStatus::new(
+                    "application_name".to_owned(),
+                    "pgbench".to_owned()
+                )),
+                PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(TransactionStatus::Idle)),
+            ],
+            messages
+        );
+
+        // no application_name at startup, reset to empty
+        let messages = client.simple_query("RESET application_name").await.unwrap();
+        assert_eq!(
+            PgWireBackendMessage::ParameterStatus(ParameterStatus::new(
+                "application_name".to_owned(),
+                String::new()
+            )),
+            messages[1]
+        );
+    }
+}
@@ -0,0 +1,246 @@
+//! `SHOW` commands served from session parameters.
+//!
+//! Session parameters are stored in client metadata, set by startup
+//! parameters and `SET`, and the server parameters come from
+//! `ServerParameterProvider`. `show_response` answers `SHOW name` and
+//! `SHOW ALL` from both, with the same result set as postgres.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use super::auth::ServerParameterProvider;
+use super::results::{DataRowEncoder, FieldInfo, QueryResponse, Response};
+use super::{ClientInfo, Type, METADATA_DATABASE, METADATA_USER};
+use crate::error::{ErrorInfo, PgWireError, PgWireResult};
+
+/// Metadata entries that are not parameters
+pub(crate) const NON_PARAMETER_KEYS: &[&str] =
+    &[METADATA_USER, METADATA_DATABASE, "options", "replication"];
+
+/// A parsed `SHOW` statement
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShowStatement {
+    /// `SHOW name`, `name` is in lower case unless quoted
+    Parameter(String),
+    /// `SHOW ALL`
+    All,
+}
+
+impl ShowStatement {
+    /// Parse a single `SHOW` statement, returns `None` for other statements.
+    ///
+    /// `SHOW TIME ZONE` and `SHOW TRANSACTION ISOLATION LEVEL` are parsed as
+    /// `timezone` and `transaction_isolation` like postgres does.
+    pub fn parse(statement: &str) -> Option<ShowStatement> {
+        let statement = statement.trim().trim_end_matches(';').trim_end();
+        let mut words = statement.split_whitespace();
+        if !words.next()?.eq_ignore_ascii_case("SHOW") {
+            return None;
+        }
+
+        let words = words.collect::<Vec<_>>();
+        let lower = words
+            .iter()
+            .map(|w| w.to_lowercase())
+            .collect::<Vec<_>>()
+            .join(" ");
+        match lower.as_str() {
+            "all" => Some(ShowStatement::All),
+            "time zone" => Some(ShowStatement::Parameter("timezone".to_owned())),
+            "transaction isolation level" => {
+                Some(ShowStatement::Parameter("transaction_isolation".to_owned()))
+            }
+            _ if words.len() == 1 => {
+                let name = words[0];
+                let name = match name.strip_prefix('"') {
+                    Some(quoted) => quoted.strip_suffix('"')?.replace("\"\"", "\""),
+                    None => name.to_lowercase(),
+                };
+                Some(ShowStatement::Parameter(name))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Current parameters of the session, server parameters overridden by
+/// client metadata. Keys keep their original spelling, like `DateStyle`.
+pub fn session_parameters<C, P>(client: &C, provider: &P) -> BTreeMap<String, String>
+where
+    C: ClientInfo,
+    P: ServerParameterProvider,
+{
+    let mut parameters = provider
+        .server_parameters(client)
+        .unwrap_or_default()
+        .into_iter()
+        .collect::<BTreeMap<_, _>>();
+    for (name, value) in client.metadata() {
+        if NON_PARAMETER_KEYS.contains(&name.as_str()) {
+            continue;
+        }
+        // a parameter set by client replaces the server one of other case
+        parameters.retain(|k, _| !k.eq_ignore_ascii_case(name) || k == name);
+        parameters.insert(name.clone(), value.clone());
+    }
+    parameters
+}
+
+/// Build the response of `SHOW` from session parameters.
+///
+/// `SHOW name` returns a single column named after the parameter, and `SHOW
+/// ALL` returns `name` and `setting` of all parameters. Unknown parameters
+/// are rejected with `42704` like postgres.
+pub fn show_response<'a, C, P>(
+    client: &C,
+    provider: &P,
+    statement: &ShowStatement,
+) -> PgWireResult<Response<'a>>
+where
+    C: ClientInfo,
+    P: ServerParameterProvider,
+{
+    let parameters = session_parameters(client, provider);
+    match statement {
+        ShowStatement::Parameter(name) => {
+            let (name, value) = parameters
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                .ok_or_else(|| {
+                    PgWireError::UserError(Box::new(ErrorInfo::new(
+                        "ERROR".to_owned(),
+                        "42704".to_owned(),
+                        format!("unrecognized configuration parameter \"{name}\""),
+                    )))
+                })?;
+
+            let fields = Arc::new(vec![FieldInfo::builder(name, Type::TEXT).build()]);
+            let mut encoder = DataRowEncoder::new(fields.clone());
+            encoder.encode_field(value)?;
+            Ok(Response::Query(QueryResponse::from_rows(
+                fields,
+                vec![encoder.finish()?],
+            )))
+        }
+        ShowStatement::All => {
+            let fields = Arc::new(vec![
+                FieldInfo::builder("name", Type::TEXT).build(),
+                FieldInfo::builder("setting", Type::TEXT).build(),
+            ]);
+            let mut parameters = parameters.into_iter().collect::<Vec<_>>();
+            parameters.sort_by_key(|(k, _)| k.to_lowercase());
+            let rows = parameters
+                .iter()
+                .map(|(name, value)| {
+                    let mut encoder = DataRowEncoder::new(fields.clone());
+                    encoder.encode_field(name)?;
+                    encoder.encode_field(value)?;
+                    encoder.finish()
+                })
+                .collect::<PgWireResult<Vec<_>>>()?;
+            Ok(Response::Query(QueryResponse::from_rows(fields, rows)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fmt::Debug;
+
+    use async_trait::async_trait;
+    use futures::Sink;
+
+    use super::*;
+    use crate::api::auth::DefaultServerParameterProvider;
+    use crate::api::query::SimpleQueryHandler;
+    use crate::api::results::{Response, Tag};
+    use crate::api::set::SetCommandHandler;
+    use crate::api::ClientInfo;
+    use crate::messages::PgWireBackendMessage;
+    use crate::testkit::fixture::{error_code, single_value, TestHandlers};
+    use crate::testkit::MockClient;
+
+    /// Answers `SHOW` from session parameters
+    struct ShowHandler;
+
+    #[async_trait]
+    impl SimpleQueryHandler for ShowHandler {
+        async fn do_query<'a, C>(
+            &self,
+            client: &mut C,
+            query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            match ShowStatement::parse(query) {
+                Some(show) => {
+                    let provider = DefaultServerParameterProvider::default();
+                    Ok(vec![show_response(client, &provider, &show)?])
+                }
+                None => Ok(vec![Response::Execution(Tag::new(query))]),
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_show() {
+        assert_eq!(
+            Some(ShowStatement::Parameter("datestyle".to_owned())),
+            ShowStatement::parse("SHOW DateStyle;")
+        );
+        assert_eq!(
+            Some(ShowStatement::Parameter("DateStyle".to_owned())),
+            ShowStatement::parse("show \"DateStyle\"")
+        );
+        assert_eq!(Some(ShowStatement::All), ShowStatement::parse("SHOW ALL"));
+        assert_eq!(
+            Some(ShowStatement::Parameter("transaction_isolation".to_owned())),
+            ShowStatement::parse("SHOW TRANSACTION ISOLATION LEVEL")
+        );
+        assert_eq!(None, ShowStatement::parse("SHOW"));
+        assert_eq!(None, ShowStatement::parse("SHOW a b"));
+        assert_eq!(None, ShowStatement::parse("SELECT 1"));
+    }
+
+    #[tokio::test]
+    async fn test_show() {
+        let handler = SetCommandHandler::new(Arc::new(ShowHandler))
+            .with_parameters(vec!["DateStyle".to_owned()]);
+        let mut client = MockClient::start(TestHandlers::echo().with_simple(handler));
+        client.startup("tom", None).await.unwrap();
+        client
+            .simple_query("SET DateStyle TO ISO, MDY")
+            .await
+            .unwrap();
+
+        let messages = client.simple_query("SHOW datestyle").await.unwrap();
+        let PgWireBackendMessage::RowDescription(description) = &messages[0] else {
+            panic!("unexpected message {:?}", messages[0]);
+        };
+        assert_eq!("DateStyle", description.fields[0].name);
+        assert_eq!(
+            b"ISO, MDY".to_vec(),
+            single_value(&mut client, "SHOW DateStyle").await
+        );
+
+        assert_eq!(
+            b"UTF8".to_vec(),
+            single_value(&mut client, "SHOW server_encoding").await
+        );
+
+        let messages = client.simple_query("SHOW nothing").await.unwrap();
+        assert_eq!(Some("42704".to_owned()), error_code(&messages[0]));
+
+        let messages = client.simple_query("SHOW ALL").await.unwrap();
+        let rows = messages
+            .iter()
+            .filter(|m| matches!(m, PgWireBackendMessage::DataRow(_)))
+            .count();
+        // 5 default server parameters, user is not a parameter
+        assert_eq!(5, rows);
+    }
+}
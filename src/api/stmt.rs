@@ -1,4 +1,5 @@
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
 
 use async_trait::async_trait;
 use postgres_types::Type;
@@ -6,6 +7,8 @@ use postgres_types::Type;
 use crate::error::PgWireResult;
 use crate::messages::extendedquery::Parse;
 
+use super::custom_types::TypeRegistry;
+use super::results::DescribeStatementResponse;
 use super::DEFAULT_NAME;
 
 #[non_exhaustive]
@@ -18,19 +21,64 @@ pub struct StoredStatement<S> {
     /// type ids of query parameters, can be empty if frontend asks backend for
     /// type inference
     pub parameter_types: Vec<Type>,
+    /// cached result of `do_describe_statement`
+    #[new(default)]
+    describe_cache: OnceLock<DescribeStatementResponse>,
+    /// query text from `Parse`, empty if the statement is not created from
+    /// `Parse`
+    #[new(default)]
+    query: String,
 }
 
 impl<S> StoredStatement<S> {
-    pub(crate) async fn parse<Q>(parse: &Parse, parser: Q) -> PgWireResult<StoredStatement<S>>
+    /// Estimated memory used by this statement, in bytes.
+    ///
+    /// Heap allocations owned by the parsed statement `S` are not counted.
+    pub fn estimated_size(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self.id.len()
+            + self.query.len()
+            + self.parameter_types.len() * std::mem::size_of::<Type>()
+    }
+
+    /// Query text of this statement
+    pub fn query(&self) -> &str {
+        &self.query
+    }
+
+    /// Get cached describe response of this statement
+    pub fn cached_describe(&self) -> Option<&DescribeStatementResponse> {
+        self.describe_cache.get()
+    }
+
+    /// Cache describe response of this statement. The cache can only be set
+    /// once, later calls are ignored.
+    pub fn cache_describe(&self, describe: DescribeStatementResponse) {
+        let _ = self.describe_cache.set(describe);
+    }
+
+    pub(crate) async fn parse<Q>(
+        parse: &Parse,
+        parser: Q,
+        type_registry: &TypeRegistry,
+    ) -> PgWireResult<StoredStatement<S>>
     where
         Q: QueryParser<Statement = S>,
     {
         let types = parse
             .type_oids
             .iter()
-            .map(|oid| Type::from_oid(*oid).unwrap_or(Type::UNKNOWN))
+            .map(|oid| type_registry.type_from_oid(*oid))
             .collect::<Vec<Type>>();
         let statement = parser.parse_sql(&parse.query, &types).await?;
+        let types = if types.is_empty() || types.contains(&Type::UNKNOWN) {
+            match parser.infer_parameter_types(&statement, &types)? {
+                Some(inferred) => merge_parameter_types(&types, inferred),
+                None => types,
+            }
+        } else {
+            types
+        };
         Ok(StoredStatement {
             id: parse
                 .name
@@ -38,10 +86,25 @@ impl<S> StoredStatement<S> {
                 .unwrap_or_else(|| DEFAULT_NAME.to_owned()),
             statement,
             parameter_types: types,
+            describe_cache: OnceLock::new(),
+            query: parse.query.clone(),
         })
     }
 }
 
+/// Declared parameter types override inferred ones
+fn merge_parameter_types(declared: &[Type], mut inferred: Vec<Type>) -> Vec<Type> {
+    if inferred.len() < declared.len() {
+        inferred.resize(declared.len(), Type::UNKNOWN);
+    }
+    for (inferred, declared) in inferred.iter_mut().zip(declared) {
+        if *declared != Type::UNKNOWN {
+            *inferred = declared.clone();
+        }
+    }
+    inferred
+}
+
 /// Trait for sql parser. The parser transforms string query into its statement
 /// type.
 #[async_trait]
@@ -49,6 +112,21 @@ pub trait QueryParser {
     type Statement;
 
     async fn parse_sql(&self, sql: &str, types: &[Type]) -> PgWireResult<Self::Statement>;
+
+    /// Infer types of parameters not specified by client.
+    ///
+    /// It's called at `Parse` time when client declared no parameter types, or
+    /// some of them are `UNKNOWN` (oid 0). Return types of all parameters of
+    /// the statement, they are recorded in `StoredStatement::parameter_types`
+    /// for `ParameterDescription` and decoding parameters. Types declared by
+    /// client are always kept. The default implementation infers nothing.
+    fn infer_parameter_types(
+        &self,
+        _statement: &Self::Statement,
+        _types: &[Type],
+    ) -> PgWireResult<Option<Vec<Type>>> {
+        Ok(None)
+    }
 }
 
 #[async_trait]
@@ -61,6 +139,269 @@ where
     async fn parse_sql(&self, sql: &str, types: &[Type]) -> PgWireResult<Self::Statement> {
         (**self).parse_sql(sql, types).await
     }
+
+    fn infer_parameter_types(
+        &self,
+        statement: &Self::Statement,
+        types: &[Type],
+    ) -> PgWireResult<Option<Vec<Type>>> {
+        (**self).infer_parameter_types(statement, types)
+    }
+}
+
+/// `QueryParser` wrapper that caches parsed statements by query text and
+/// parameter types, so the same query prepared again and again by ORMs is
+/// only parsed once.
+///
+/// At most `capacity` statements are cached, the least recently used one is
+/// evicted when it's full. Parse errors are not cached. Share the parser
+/// between connections by returning the same `Arc` from
+/// `ExtendedQueryHandler::query_parser`.
+#[derive(Debug)]
+pub struct CachedQueryParser<P: QueryParser> {
+    inner: P,
+    capacity: usize,
+    cache: Mutex<ParserCache<P::Statement>>,
+}
+
+type ParserCacheKey = (String, Vec<Type>);
+
+#[derive(Debug)]
+struct ParserCache<S> {
+    entries: HashMap<ParserCacheKey, (S, u64)>,
+    clock: u64,
+}
+
+impl<P: QueryParser> CachedQueryParser<P> {
+    pub fn new(inner: P, capacity: usize) -> CachedQueryParser<P> {
+        CachedQueryParser {
+            inner,
+            capacity,
+            cache: Mutex::new(ParserCache {
+                entries: HashMap::new(),
+                clock: 0,
+            }),
+        }
+    }
+
+    /// Get the inner parser
+    pub fn inner(&self) -> &P {
+        &self.inner
+    }
+
+    /// Number of cached statements
+    pub fn len(&self) -> usize {
+        self.cache.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove all cached statements, for example after schema changes
+    pub fn clear(&self) {
+        self.cache.lock().unwrap().entries.clear();
+    }
+}
+
+#[async_trait]
+impl<P> QueryParser for CachedQueryParser<P>
+where
+    P: QueryParser + Send + Sync,
+    P::Statement: Clone + Send + Sync,
+{
+    type Statement = P::Statement;
+
+    async fn parse_sql(&self, sql: &str, types: &[Type]) -> PgWireResult<Self::Statement> {
+        let key = (sql.to_owned(), types.to_vec());
+        {
+            let mut cache = self.cache.lock().unwrap();
+            cache.clock += 1;
+            let tick = cache.clock;
+            if let Some((statement, last_used)) = cache.entries.get_mut(&key) {
+                *last_used = tick;
+                return Ok(statement.clone());
+            }
+        }
+
+        // parse without holding the lock
+        let statement = self.inner.parse_sql(sql, types).await?;
+
+        if self.capacity > 0 {
+            let mut cache = self.cache.lock().unwrap();
+            if cache.entries.len() >= self.capacity && !cache.entries.contains_key(&key) {
+                let lru = cache
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, (_, last_used))| *last_used)
+                    .map(|(key, _)| key.clone());
+                if let Some(lru) = lru {
+                    cache.entries.remove(&lru);
+                }
+            }
+            let tick = cache.clock;
+            cache.entries.insert(key, (statement.clone(), tick));
+        }
+        Ok(statement)
+    }
+
+    fn infer_parameter_types(
+        &self,
+        statement: &Self::Statement,
+        types: &[Type],
+    ) -> PgWireResult<Option<Vec<Type>>> {
+        self.inner.infer_parameter_types(statement, types)
+    }
+}
+
+/// Query with `?` placeholders rewritten to postgres style `$n`, by
+/// `rewrite_placeholders`.
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RewrittenQuery {
+    /// The rewritten query
+    pub query: String,
+    /// Byte offset of each `?` placeholder in the original query, the
+    /// placeholder at index `i` is rewritten to `$(i + 1)`
+    pub placeholders: Vec<usize>,
+}
+
+impl RewrittenQuery {
+    /// Number of parameters of the rewritten query
+    pub fn parameter_count(&self) -> usize {
+        self.placeholders.len()
+    }
+}
+
+/// Rewrite JDBC/ODBC style `?` placeholders to `$1..$n`.
+///
+/// `?` in string literals, quoted identifiers, dollar quoted strings and
+/// comments are kept as is. `??` is the escape of a literal `?`, like the
+/// postgres JDBC driver, so jsonb operators like `??|` can be written.
+/// Existing `$n` placeholders are not renumbered, so queries should not mix
+/// both styles.
+///
+/// ```
+/// use pgwire::api::stmt::rewrite_placeholders;
+///
+/// let rewritten = rewrite_placeholders("SELECT * FROM t WHERE a = ? AND b = '?' AND c = ?");
+/// assert_eq!(
+///     "SELECT * FROM t WHERE a = $1 AND b = '?' AND c = $2",
+///     rewritten.query
+/// );
+/// assert_eq!(vec![26, 48], rewritten.placeholders);
+/// ```
+pub fn rewrite_placeholders(query: &str) -> RewrittenQuery {
+    // all delimiters are ascii, so byte offsets of them are char boundaries
+    let bytes = query.as_bytes();
+    let mut rewritten = String::with_capacity(query.len() + 8);
+    let mut placeholders = Vec::new();
+    let mut copied = 0;
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let next = bytes.get(i + 1).copied();
+        i = match (bytes[i], next) {
+            (b'\'', _) => {
+                let escape = i > 0
+                    && bytes[i - 1].eq_ignore_ascii_case(&b'e')
+                    && (i == 1 || !is_identifier_byte(bytes[i - 2]));
+                skip_quoted(bytes, i, b'\'', escape)
+            }
+            (b'"', _) => skip_quoted(bytes, i, b'"', false),
+            (b'-', Some(b'-')) => bytes[i..]
+                .iter()
+                .position(|b| *b == b'\n')
+                .map_or(bytes.len(), |end| i + end + 1),
+            (b'/', Some(b'*')) => skip_block_comment(bytes, i),
+            (b'$', _) => skip_dollar_quoted(query, i).unwrap_or(i + 1),
+            (b'?', Some(b'?')) => {
+                rewritten.push_str(&query[copied..=i]);
+                copied = i + 2;
+                i + 2
+            }
+            (b'?', _) => {
+                rewritten.push_str(&query[copied..i]);
+                placeholders.push(i);
+                rewritten.push('$');
+                rewritten.push_str(&placeholders.len().to_string());
+                copied = i + 1;
+                i + 1
+            }
+            _ => i + 1,
+        };
+    }
+    rewritten.push_str(&query[copied..]);
+
+    RewrittenQuery {
+        query: rewritten,
+        placeholders,
+    }
+}
+
+fn is_identifier_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || b >= 0x80
+}
+
+/// Skip quoted string or identifier starting at `start`, return offset after
+/// the closing quote
+fn skip_quoted(bytes: &[u8], start: usize, quote: u8, backslash_escape: bool) -> usize {
+    let mut i = start + 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' if backslash_escape => i += 2,
+            // doubled quote is an escaped quote
+            b if b == quote && bytes.get(i + 1) == Some(&quote) => i += 2,
+            b if b == quote => return i + 1,
+            _ => i += 1,
+        }
+    }
+    bytes.len()
+}
+
+/// Skip block comment starting at `start`, which can be nested in postgres
+fn skip_block_comment(bytes: &[u8], start: usize) -> usize {
+    let mut depth = 0;
+    let mut i = start;
+    while i < bytes.len() {
+        match (bytes[i], bytes.get(i + 1)) {
+            (b'/', Some(b'*')) => {
+                depth += 1;
+                i += 2;
+            }
+            (b'*', Some(b'/')) => {
+                depth -= 1;
+                i += 2;
+                if depth == 0 {
+                    return i;
+                }
+            }
+            _ => i += 1,
+        }
+    }
+    bytes.len()
+}
+
+/// Skip dollar quoted string like `$tag$...$tag$` starting at `start`, `None`
+/// if the `$` doesn't start a dollar quote, for example `$1`
+fn skip_dollar_quoted(query: &str, start: usize) -> Option<usize> {
+    let bytes = query.as_bytes();
+    if start > 0 && is_identifier_byte(bytes[start - 1]) {
+        return None;
+    }
+    let tag_len = bytes[start + 1..].iter().position(|b| *b == b'$')?;
+    let tag = &bytes[start + 1..start + 1 + tag_len];
+    if tag.first().is_some_and(u8::is_ascii_digit) || !tag.iter().all(|b| is_identifier_byte(*b)) {
+        return None;
+    }
+
+    let delimiter = &query[start..start + tag_len + 2];
+    let body = start + delimiter.len();
+    Some(
+        query[body..]
+            .find(delimiter)
+            .map_or(query.len(), |end| body + end + delimiter.len()),
+    )
 }
 
 /// A demo parser implementation. Never use it in serious application.
@@ -75,3 +416,244 @@ impl QueryParser for NoopQueryParser {
         Ok(sql.to_owned())
     }
 }
+
+/// A `QueryParser` implementation backed by
+/// [sqlparser-rs](https://crates.io/crates/sqlparser).
+///
+/// The parser uses `PostgreSqlDialect` by default, use `with_dialect` to
+/// switch to other dialects. Syntax errors are reported to client as error
+/// `42601` at `Parse` time.
+#[cfg(feature = "sqlparser")]
+#[derive(Debug)]
+pub struct SqlQueryParser {
+    dialect: Box<dyn sqlparser::dialect::Dialect + Send + Sync>,
+}
+
+#[cfg(feature = "sqlparser")]
+impl Default for SqlQueryParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "sqlparser")]
+impl SqlQueryParser {
+    /// Create parser with postgresql dialect
+    pub fn new() -> Self {
+        Self::with_dialect(sqlparser::dialect::PostgreSqlDialect {})
+    }
+
+    /// Create parser with given dialect
+    pub fn with_dialect<D>(dialect: D) -> Self
+    where
+        D: sqlparser::dialect::Dialect + Send + Sync,
+    {
+        SqlQueryParser {
+            dialect: Box::new(dialect),
+        }
+    }
+
+    /// Parse the query into exactly one statement
+    pub fn parse(&self, sql: &str) -> PgWireResult<sqlparser::ast::Statement> {
+        let mut statements = sqlparser::parser::Parser::parse_sql(self.dialect.as_ref(), sql)
+            .map_err(|e| syntax_error(e.to_string()))?;
+
+        match statements.len() {
+            1 => Ok(statements.remove(0)),
+            0 => Err(syntax_error("empty query".to_owned())),
+            _ => Err(syntax_error(
+                "cannot insert multiple commands into a prepared statement".to_owned(),
+            )),
+        }
+    }
+}
+
+#[cfg(feature = "sqlparser")]
+fn syntax_error(message: String) -> crate::error::PgWireError {
+    crate::error::PgWireError::UserError(Box::new(crate::error::ErrorInfo::new(
+        "ERROR".to_owned(),
+        "42601".to_owned(),
+        message,
+    )))
+}
+
+#[cfg(feature = "sqlparser")]
+#[async_trait]
+impl QueryParser for SqlQueryParser {
+    type Statement = sqlparser::ast::Statement;
+
+    async fn parse_sql(&self, sql: &str, _types: &[Type]) -> PgWireResult<Self::Statement> {
+        self.parse(sql)
+    }
+}
+
+/// Count parameters of a parsed statement.
+///
+/// For postgres style `$n` placeholders, the largest `n` is returned. Each `?`
+/// placeholder is counted as one parameter.
+#[cfg(feature = "sqlparser")]
+pub fn parameter_count(statement: &sqlparser::ast::Statement) -> usize {
+    use std::ops::ControlFlow;
+
+    use sqlparser::ast::{visit_expressions, Expr, Value};
+
+    let mut max_index = 0;
+    let mut anonymous = 0;
+    let _ = visit_expressions(statement, |expr| {
+        if let Expr::Value(Value::Placeholder(p)) = expr {
+            if let Some(index) = p.strip_prefix('$').and_then(|i| i.parse::<usize>().ok()) {
+                max_index = max_index.max(index);
+            } else {
+                anonymous += 1;
+            }
+        }
+        ControlFlow::<()>::Continue(())
+    });
+
+    max_index + anonymous
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(feature = "sqlparser")]
+    #[tokio::test]
+    async fn test_sql_query_parser() {
+        let parser = SqlQueryParser::new();
+        let stmt = parser
+            .parse_sql("SELECT * FROM users WHERE id = $1 AND name = $2", &[])
+            .await
+            .unwrap();
+        assert_eq!(parameter_count(&stmt), 2);
+
+        assert!(parser.parse_sql("SELEC 1", &[]).await.is_err());
+        assert!(parser.parse_sql("SELECT 1; SELECT 2", &[]).await.is_err());
+    }
+
+    #[test]
+    fn test_describe_cache() {
+        let stmt = StoredStatement::new("s1".to_owned(), "SELECT 1".to_owned(), vec![]);
+        assert!(stmt.cached_describe().is_none());
+
+        stmt.cache_describe(DescribeStatementResponse::new(vec![Type::INT4], vec![]));
+        stmt.cache_describe(DescribeStatementResponse::new(vec![], vec![]));
+        assert_eq!(stmt.cached_describe().unwrap().parameters, vec![Type::INT4]);
+    }
+
+    /// Every parameter of `$n` placeholders is inferred as INT8
+    struct Int8Parser;
+
+    #[async_trait]
+    impl QueryParser for Int8Parser {
+        type Statement = String;
+
+        async fn parse_sql(&self, sql: &str, _types: &[Type]) -> PgWireResult<Self::Statement> {
+            Ok(sql.to_owned())
+        }
+
+        fn infer_parameter_types(
+            &self,
+            statement: &Self::Statement,
+            _types: &[Type],
+        ) -> PgWireResult<Option<Vec<Type>>> {
+            Ok(Some(vec![Type::INT8; statement.matches('$').count()]))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_infer_parameter_types() {
+        let registry = TypeRegistry::new();
+        let parse = Parse::new(
+            Some("s1".to_owned()),
+            "SELECT $1, $2".to_owned(),
+            vec![0, Type::TEXT.oid()],
+        );
+        let stmt = StoredStatement::parse(&parse, Int8Parser, &registry)
+            .await
+            .unwrap();
+        assert_eq!(vec![Type::INT8, Type::TEXT], stmt.parameter_types);
+
+        let parse = Parse::new(None, "SELECT $1".to_owned(), vec![Type::INT4.oid()]);
+        let stmt = StoredStatement::parse(&parse, Int8Parser, &registry)
+            .await
+            .unwrap();
+        assert_eq!(vec![Type::INT4], stmt.parameter_types);
+    }
+}
+
+#[cfg(test)]
+mod cache_tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct CountingParser(AtomicUsize);
+
+    #[async_trait]
+    impl QueryParser for CountingParser {
+        type Statement = String;
+
+        async fn parse_sql(&self, sql: &str, _types: &[Type]) -> PgWireResult<Self::Statement> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(sql.to_owned())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cached_query_parser() {
+        let parser = CachedQueryParser::new(CountingParser::default(), 2);
+        parser.parse_sql("SELECT 1", &[]).await.unwrap();
+        parser.parse_sql("SELECT 1", &[]).await.unwrap();
+        assert_eq!(1, parser.inner().0.load(Ordering::SeqCst));
+
+        // parameter types are part of the key
+        parser.parse_sql("SELECT 1", &[Type::INT4]).await.unwrap();
+        assert_eq!(2, parser.inner().0.load(Ordering::SeqCst));
+        assert_eq!(2, parser.len());
+
+        // "SELECT 1" with INT4 is the least recently used
+        parser.parse_sql("SELECT 1", &[]).await.unwrap();
+        parser.parse_sql("SELECT 2", &[]).await.unwrap();
+        assert_eq!(2, parser.len());
+        parser.parse_sql("SELECT 1", &[]).await.unwrap();
+        assert_eq!(3, parser.inner().0.load(Ordering::SeqCst));
+        parser.parse_sql("SELECT 1", &[Type::INT4]).await.unwrap();
+        assert_eq!(4, parser.inner().0.load(Ordering::SeqCst));
+
+        parser.clear();
+        assert!(parser.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod placeholder_tests {
+    use super::*;
+
+    #[test]
+    fn test_rewrite_placeholders() {
+        let rewritten = rewrite_placeholders("INSERT INTO t VALUES (?, ?, ?)");
+        assert_eq!("INSERT INTO t VALUES ($1, $2, $3)", rewritten.query);
+        assert_eq!(3, rewritten.parameter_count());
+
+        for query in [
+            "SELECT 'it''s ?', \"col?\" FROM t",
+            "SELECT E'\\' ?' FROM t",
+            "SELECT $$ ? $$, $tag$ $$ ? $tag$",
+            "SELECT 1 -- why?\n",
+            "SELECT /* a /* nested ? */ ? */ 1",
+        ] {
+            let rewritten = rewrite_placeholders(query);
+            assert_eq!(query, rewritten.query);
+            assert_eq!(0, rewritten.parameter_count());
+        }
+
+        let rewritten = rewrite_placeholders("SELECT data ?? 'k', data ??| ? FROM t");
+        assert_eq!("SELECT data ? 'k', data ?| $1 FROM t", rewritten.query);
+        assert_eq!(vec![29], rewritten.placeholders);
+
+        let rewritten = rewrite_placeholders("SELECT '你好', ? -- ?");
+        assert_eq!("SELECT '你好', $1 -- ?", rewritten.query);
+    }
+}
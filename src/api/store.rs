@@ -1,63 +1,657 @@
 use std::collections::BTreeMap;
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
 use super::portal::Portal;
 use super::stmt::StoredStatement;
+use super::DEFAULT_NAME;
+use crate::error::{ErrorInfo, PgWireError, PgWireResult};
 
 pub trait PortalStore: Send + Sync {
     type Statement;
 
     fn put_statement(&self, statement: Arc<StoredStatement<Self::Statement>>);
 
+    /// Store a statement for `Parse`, failing if limits of the store are
+    /// reached. Calls `put_statement` by default.
+    fn try_put_statement(
+        &self,
+        statement: Arc<StoredStatement<Self::Statement>>,
+    ) -> PgWireResult<()> {
+        self.put_statement(statement);
+        Ok(())
+    }
+
     fn rm_statement(&self, name: &str);
 
     fn get_statement(&self, name: &str) -> Option<Arc<StoredStatement<Self::Statement>>>;
 
     fn put_portal(&self, portal: Arc<Portal<Self::Statement>>);
 
+    /// Store a portal for `Bind`, failing if limits of the store are reached.
+    /// Calls `put_portal` by default.
+    fn try_put_portal(&self, portal: Arc<Portal<Self::Statement>>) -> PgWireResult<()> {
+        self.put_portal(portal);
+        Ok(())
+    }
+
     fn rm_portal(&self, name: &str);
 
     fn get_portal(&self, name: &str) -> Option<Arc<Portal<Self::Statement>>>;
+
+    /// Remove all statements, for `DEALLOCATE ALL`
+    fn rm_all_statements(&self);
+
+    /// Remove all portals, for `CLOSE ALL`
+    fn rm_all_portals(&self);
+
+    /// Get current usage of this store
+    fn stats(&self) -> PortalStoreStats {
+        PortalStoreStats::default()
+    }
+}
+
+/// Usage of a `PortalStore`
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PortalStoreStats {
+    /// number of stored statements
+    pub statements: usize,
+    /// number of stored portals
+    pub portals: usize,
+    /// estimated memory used by statements and portals, in bytes
+    pub estimated_memory: usize,
+}
+
+/// Behaviour of `MemPortalStore` when its limits are reached.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LimitPolicy {
+    /// Evict the least recently used statement or portal
+    #[default]
+    EvictLeastRecentlyUsed,
+    /// Reject the new statement or portal with error `53400`
+    Reject,
+}
+
+/// Limits of statements and portals stored for each connection.
+///
+/// The unnamed statement and portal don't count towards the limits and are
+/// never evicted.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PortalStoreLimits {
+    /// max number of prepared statements, `None` for unlimited
+    pub max_statements: Option<usize>,
+    /// max number of portals, `None` for unlimited
+    pub max_portals: Option<usize>,
+    /// what to do when the limit is reached
+    pub policy: LimitPolicy,
+    /// max estimated memory of statements and portals in bytes, `None` for
+    /// unlimited. New `Parse` and `Bind` are rejected with error `53400` once
+    /// the budget is exceeded.
+    pub max_memory: Option<usize>,
+}
+
+#[derive(Debug)]
+struct LruEntry<V> {
+    value: Arc<V>,
+    last_used: u64,
+    weight: usize,
+}
+
+/// Entries indexed by name and by time of last use, so the least recently
+/// used one is found without scanning all entries
+#[derive(Debug)]
+struct LruMap<V> {
+    entries: BTreeMap<String, LruEntry<V>>,
+    order: BTreeMap<u64, String>,
+}
+
+impl<V> Default for LruMap<V> {
+    fn default() -> Self {
+        LruMap {
+            entries: BTreeMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+}
+
+impl<V> LruMap<V> {
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        self.entries.contains_key(name)
+    }
+
+    fn get(&mut self, name: &str, tick: u64) -> Option<Arc<V>> {
+        let entry = self.entries.get_mut(name)?;
+        if let Some(name) = self.order.remove(&entry.last_used) {
+            self.order.insert(tick, name);
+        }
+        entry.last_used = tick;
+        Some(entry.value.clone())
+    }
+
+    /// Insert an entry, returning the one it replaces
+    fn insert(
+        &mut self,
+        name: String,
+        value: Arc<V>,
+        weight: usize,
+        tick: u64,
+    ) -> Option<LruEntry<V>> {
+        let old = self.remove(&name);
+        self.order.insert(tick, name.clone());
+        self.entries.insert(
+            name,
+            LruEntry {
+                value,
+                last_used: tick,
+                weight,
+            },
+        );
+        old
+    }
+
+    fn remove(&mut self, name: &str) -> Option<LruEntry<V>> {
+        let entry = self.entries.remove(name)?;
+        self.order.remove(&entry.last_used);
+        Some(entry)
+    }
+
+    /// Remove all entries, returning their total weight
+    fn clear(&mut self) -> usize {
+        self.order.clear();
+        std::mem::take(&mut self.entries)
+            .into_values()
+            .map(|e| e.weight)
+            .sum()
+    }
+
+    /// Time of last use and name of the least recently used entry, except the
+    /// unnamed one and `keep`
+    fn lru(&self, keep: Option<&str>) -> Option<(u64, &str)> {
+        self.order
+            .iter()
+            .map(|(last_used, name)| (*last_used, name.as_str()))
+            .find(|(_, name)| *name != DEFAULT_NAME && Some(*name) != keep)
+    }
+
+    fn values(&self) -> impl Iterator<Item = &V> {
+        self.entries.values().map(|e| e.value.as_ref())
+    }
 }
 
 #[derive(Debug, Default, new)]
 pub struct MemPortalStore<S> {
     #[new(default)]
-    statements: RwLock<BTreeMap<String, Arc<StoredStatement<S>>>>,
+    statements: Mutex<LruMap<StoredStatement<S>>>,
+    #[new(default)]
+    portals: Mutex<LruMap<Portal<S>>>,
     #[new(default)]
-    portals: RwLock<BTreeMap<String, Arc<Portal<S>>>>,
+    limits: PortalStoreLimits,
+    #[new(default)]
+    clock: AtomicU64,
+}
+
+impl<S> MemPortalStore<S> {
+    /// Create a store with limits on number of statements and portals
+    pub fn with_limits(limits: PortalStoreLimits) -> Self {
+        MemPortalStore {
+            statements: Mutex::default(),
+            portals: Mutex::default(),
+            limits,
+            clock: AtomicU64::default(),
+        }
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
 }
 
+fn put_entry<V>(
+    map: &Mutex<LruMap<V>>,
+    name: String,
+    value: Arc<V>,
+    max: Option<usize>,
+    policy: LimitPolicy,
+    tick: u64,
+    kind: &str,
+) -> PgWireResult<()> {
+    let mut guard = map.lock().unwrap();
+    if let Some(max) = max {
+        let named = guard.len() - usize::from(guard.contains(DEFAULT_NAME));
+        if name != DEFAULT_NAME && !guard.contains(&name) && named >= max {
+            let lru = guard.lru(None).map(|(_, name)| name.to_owned());
+
+            match (policy, lru) {
+                (LimitPolicy::EvictLeastRecentlyUsed, Some(lru)) => {
+                    guard.remove(&lru);
+                }
+                _ => {
+                    return Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                        "ERROR".to_owned(),
+                        "53400".to_owned(),
+                        format!("too many {kind}s, the limit is {max}"),
+                    ))));
+                }
+            }
+        }
+    }
+
+    guard.insert(name, value, 0, tick);
+    Ok(())
+}
+
+/// `put_statement` and `put_portal` of `MemPortalStore` can't fail, they
+/// evict the least recently used entry when the limit is reached, regardless
+/// of `LimitPolicy`. `Parse` and `Bind` go through `try_put_statement` and
+/// `try_put_portal`, which follow the policy.
 impl<S: Clone + Send + Sync> PortalStore for MemPortalStore<S> {
     type Statement = S;
 
     fn put_statement(&self, statement: Arc<StoredStatement<Self::Statement>>) {
-        let mut guard = self.statements.write().unwrap();
-        guard.insert(statement.id.to_owned(), statement);
+        // a limit of 0 leaves nothing to evict
+        let _ = put_entry(
+            &self.statements,
+            statement.id.clone(),
+            statement,
+            self.limits.max_statements,
+            LimitPolicy::EvictLeastRecentlyUsed,
+            self.tick(),
+            "prepared statement",
+        );
+    }
+
+    fn try_put_statement(
+        &self,
+        statement: Arc<StoredStatement<Self::Statement>>,
+    ) -> PgWireResult<()> {
+        put_entry(
+            &self.statements,
+            statement.id.clone(),
+            statement,
+            self.limits.max_statements,
+            self.limits.policy,
+            self.tick(),
+            "prepared statement",
+        )
+    }
+
+    fn rm_statement(&self, name: &str) {
+        self.statements.lock().unwrap().remove(name);
+    }
+
+    fn get_statement(&self, name: &str) -> Option<Arc<StoredStatement<Self::Statement>>> {
+        let tick = self.tick();
+        self.statements.lock().unwrap().get(name, tick)
+    }
+
+    fn put_portal(&self, portal: Arc<Portal<Self::Statement>>) {
+        // a limit of 0 leaves nothing to evict
+        let _ = put_entry(
+            &self.portals,
+            portal.name.clone(),
+            portal,
+            self.limits.max_portals,
+            LimitPolicy::EvictLeastRecentlyUsed,
+            self.tick(),
+            "portal",
+        );
+    }
+
+    fn try_put_portal(&self, portal: Arc<Portal<Self::Statement>>) -> PgWireResult<()> {
+        put_entry(
+            &self.portals,
+            portal.name.clone(),
+            portal,
+            self.limits.max_portals,
+            self.limits.policy,
+            self.tick(),
+            "portal",
+        )
+    }
+
+    fn rm_portal(&self, name: &str) {
+        self.portals.lock().unwrap().remove(name);
+    }
+
+    fn get_portal(&self, name: &str) -> Option<Arc<Portal<Self::Statement>>> {
+        let tick = self.tick();
+        self.portals.lock().unwrap().get(name, tick)
+    }
+
+    fn rm_all_statements(&self) {
+        self.statements.lock().unwrap().clear();
+    }
+
+    fn rm_all_portals(&self) {
+        self.portals.lock().unwrap().clear();
+    }
+
+    fn stats(&self) -> PortalStoreStats {
+        let statements = self.statements.lock().unwrap();
+        let portals = self.portals.lock().unwrap();
+
+        PortalStoreStats {
+            statements: statements.len(),
+            portals: portals.len(),
+            estimated_memory: statements
+                .values()
+                .map(|s| s.estimated_size())
+                .sum::<usize>()
+                + portals.values().map(|p| p.estimated_size()).sum::<usize>(),
+        }
+    }
+}
+
+/// Weight of each entry in `LruPortalStore`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryWeight {
+    /// Every statement and portal weighs 1, the capacity is number of entries
+    Count,
+    /// Statements and portals weigh their estimated memory, the capacity is
+    /// in bytes
+    EstimatedSize,
+}
+
+#[derive(Debug)]
+struct LruState<S> {
+    statements: LruMap<StoredStatement<S>>,
+    portals: LruMap<Portal<S>>,
+    total_weight: usize,
+    clock: u64,
+}
+
+impl<S> LruState<S> {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// Evict least recently used entries, except the one just inserted and
+    /// the unnamed statement and portal, until total weight is within
+    /// capacity
+    fn evict(&mut self, capacity: usize, keep_statement: Option<&str>, keep_portal: Option<&str>) {
+        while self.total_weight > capacity {
+            let lru_statement = self
+                .statements
+                .lru(keep_statement)
+                .map(|(last_used, name)| (last_used, name.to_owned()));
+            let lru_portal = self
+                .portals
+                .lru(keep_portal)
+                .map(|(last_used, name)| (last_used, name.to_owned()));
+
+            let removed = match (lru_statement, lru_portal) {
+                (Some((s, name)), Some((p, _))) if s <= p => {
+                    self.statements.remove(&name).map(|e| e.weight)
+                }
+                (_, Some((_, name))) => self.portals.remove(&name).map(|e| e.weight),
+                (Some((_, name)), None) => self.statements.remove(&name).map(|e| e.weight),
+                (None, None) => None,
+            };
+            match removed {
+                Some(weight) => self.total_weight -= weight,
+                // only the new entry and unnamed ones are left
+                None => break,
+            }
+        }
+    }
+}
+
+/// `PortalStore` bounded by total weight of its statements and portals.
+///
+/// Unlike `MemPortalStore`, statements and portals share one capacity, and
+/// the least recently used ones are evicted to make room for new entries, so
+/// memory of each connection is bounded without failing client requests.
+/// An entry heavier than the capacity is still stored, after evicting all
+/// other entries. The unnamed statement and portal count towards the
+/// capacity but are never evicted.
+///
+/// Clients reusing an evicted statement fail with
+/// `PgWireError::StatementNotFound`, so the capacity should be larger than
+/// the number of statements clients keep prepared.
+#[derive(Debug)]
+pub struct LruPortalStore<S> {
+    state: Mutex<LruState<S>>,
+    capacity: usize,
+    weight: EntryWeight,
+}
+
+impl<S> LruPortalStore<S> {
+    /// Create store holding at most `capacity` statements and portals
+    pub fn new(capacity: usize) -> LruPortalStore<S> {
+        Self::with_weight(capacity, EntryWeight::Count)
+    }
+
+    /// Create store whose `capacity` is measured by `weight`
+    pub fn with_weight(capacity: usize, weight: EntryWeight) -> LruPortalStore<S> {
+        LruPortalStore {
+            state: Mutex::new(LruState {
+                statements: LruMap::default(),
+                portals: LruMap::default(),
+                total_weight: 0,
+                clock: 0,
+            }),
+            capacity,
+            weight,
+        }
+    }
+
+    fn weigh(&self, estimated_size: impl FnOnce() -> usize) -> usize {
+        match self.weight {
+            EntryWeight::Count => 1,
+            EntryWeight::EstimatedSize => estimated_size(),
+        }
+    }
+}
+
+impl<S: Clone + Send + Sync> PortalStore for LruPortalStore<S> {
+    type Statement = S;
+
+    fn put_statement(&self, statement: Arc<StoredStatement<Self::Statement>>) {
+        let weight = self.weigh(|| statement.estimated_size());
+        let mut state = self.state.lock().unwrap();
+        let last_used = state.tick();
+        let name = statement.id.clone();
+        if let Some(old) = state
+            .statements
+            .insert(name.clone(), statement, weight, last_used)
+        {
+            state.total_weight -= old.weight;
+        }
+        state.total_weight += weight;
+        state.evict(self.capacity, Some(&name), None);
     }
 
     fn rm_statement(&self, name: &str) {
-        let mut guard = self.statements.write().unwrap();
-        guard.remove(name);
+        let mut state = self.state.lock().unwrap();
+        if let Some(old) = state.statements.remove(name) {
+            state.total_weight -= old.weight;
+        }
     }
 
     fn get_statement(&self, name: &str) -> Option<Arc<StoredStatement<Self::Statement>>> {
-        let guard = self.statements.read().unwrap();
-        guard.get(name).cloned()
+        let mut state = self.state.lock().unwrap();
+        let tick = state.tick();
+        state.statements.get(name, tick)
     }
 
     fn put_portal(&self, portal: Arc<Portal<Self::Statement>>) {
-        let mut guard = self.portals.write().unwrap();
-        guard.insert(portal.name.to_owned(), portal);
+        let weight = self.weigh(|| portal.estimated_size());
+        let mut state = self.state.lock().unwrap();
+        let last_used = state.tick();
+        let name = portal.name.clone();
+        if let Some(old) = state
+            .portals
+            .insert(name.clone(), portal, weight, last_used)
+        {
+            state.total_weight -= old.weight;
+        }
+        state.total_weight += weight;
+        state.evict(self.capacity, None, Some(&name));
     }
 
     fn rm_portal(&self, name: &str) {
-        let mut guard = self.portals.write().unwrap();
-        guard.remove(name);
+        let mut state = self.state.lock().unwrap();
+        if let Some(old) = state.portals.remove(name) {
+            state.total_weight -= old.weight;
+        }
     }
 
     fn get_portal(&self, name: &str) -> Option<Arc<Portal<Self::Statement>>> {
-        let guard = self.portals.read().unwrap();
-        guard.get(name).cloned()
+        let mut state = self.state.lock().unwrap();
+        let tick = state.tick();
+        state.portals.get(name, tick)
+    }
+
+    fn rm_all_statements(&self) {
+        let mut state = self.state.lock().unwrap();
+        let removed = state.statements.clear();
+        state.total_weight -= removed;
+    }
+
+    fn rm_all_portals(&self) {
+        let mut state = self.state.lock().unwrap();
+        let removed = state.portals.clear();
+        state.total_weight -= removed;
+    }
+
+    fn stats(&self) -> PortalStoreStats {
+        let state = self.state.lock().unwrap();
+        PortalStoreStats {
+            statements: state.statements.len(),
+            portals: state.portals.len(),
+            estimated_memory: state
+                .statements
+                .values()
+                .map(|s| s.estimated_size())
+                .sum::<usize>()
+                + state
+                    .portals
+                    .values()
+                    .map(|p| p.estimated_size())
+                    .sum::<usize>(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn statement(name: &str) -> Arc<StoredStatement<String>> {
+        Arc::new(StoredStatement::new(name.to_owned(), "".to_owned(), vec![]))
+    }
+
+    #[test]
+    fn test_statement_limits() {
+        let store = MemPortalStore::with_limits(PortalStoreLimits {
+            max_statements: Some(2),
+            max_portals: None,
+            policy: LimitPolicy::EvictLeastRecentlyUsed,
+            max_memory: None,
+        });
+        store.put_statement(statement("s1"));
+        store.put_statement(statement("s2"));
+        // s1 is now most recently used
+        assert!(store.get_statement("s1").is_some());
+        store.put_statement(statement("s3"));
+
+        assert!(store.get_statement("s1").is_some());
+        assert!(store.get_statement("s2").is_none());
+        assert!(store.get_statement("s3").is_some());
+
+        let store = MemPortalStore::with_limits(PortalStoreLimits {
+            max_statements: Some(1),
+            max_portals: None,
+            policy: LimitPolicy::Reject,
+            max_memory: None,
+        });
+        store.try_put_statement(statement("s1")).unwrap();
+        // replacing an existing statement is allowed
+        store.try_put_statement(statement("s1")).unwrap();
+        let err = store.try_put_statement(statement("s2")).unwrap_err();
+        assert!(matches!(err, PgWireError::UserError(info) if info.code == "53400"));
+        // the unnamed statement isn't limited
+        store.try_put_statement(statement(DEFAULT_NAME)).unwrap();
+        // put_statement evicts regardless of the policy
+        store.put_statement(statement("s2"));
+        assert!(store.get_statement("s1").is_none());
+        assert!(store.get_statement(DEFAULT_NAME).is_some());
+        store.rm_statement(DEFAULT_NAME);
+
+        let stats = store.stats();
+        assert_eq!(stats.statements, 1);
+        assert_eq!(stats.portals, 0);
+        assert!(stats.estimated_memory > 0);
+    }
+
+    #[test]
+    fn test_lru_portal_store() {
+        let store = LruPortalStore::new(2);
+        store.put_statement(statement("s1"));
+        store.put_statement(statement("s2"));
+        assert!(store.get_statement("s1").is_some());
+        // statements and portals share the capacity
+        let portal = Portal::try_new(
+            &crate::messages::extendedquery::Bind::new(
+                Some("p1".to_owned()),
+                Some("s1".to_owned()),
+                vec![],
+                vec![],
+                vec![],
+            ),
+            store.get_statement("s1").unwrap(),
+        )
+        .unwrap();
+        store.put_portal(Arc::new(portal));
+
+        assert!(store.get_statement("s1").is_some());
+        assert!(store.get_statement("s2").is_none());
+        assert!(store.get_portal("p1").is_some());
+        assert_eq!(2, store.stats().statements + store.stats().portals);
+
+        let size = statement("s1").estimated_size();
+        let store = LruPortalStore::with_weight(size * 2, EntryWeight::EstimatedSize);
+        store.put_statement(statement("s1"));
+        store.put_statement(statement("s2"));
+        store.put_statement(statement("s3"));
+        assert!(store.get_statement("s1").is_none());
+        assert_eq!(2, store.stats().statements);
+
+        store.rm_statement("s2");
+        store.rm_statement("s3");
+        assert_eq!(0, store.stats().statements);
+    }
+
+    #[test]
+    fn test_unnamed_not_evicted() {
+        let store = MemPortalStore::with_limits(PortalStoreLimits {
+            max_statements: Some(1),
+            max_portals: None,
+            policy: LimitPolicy::EvictLeastRecentlyUsed,
+            max_memory: None,
+        });
+        store.put_statement(statement(DEFAULT_NAME));
+        store.put_statement(statement("s1"));
+        store.put_statement(statement("s2"));
+        assert!(store.get_statement(DEFAULT_NAME).is_some());
+        assert!(store.get_statement("s1").is_none());
+        assert!(store.get_statement("s2").is_some());
+
+        let store = LruPortalStore::new(2);
+        store.put_statement(statement(DEFAULT_NAME));
+        store.put_statement(statement("s1"));
+        store.put_statement(statement("s2"));
+        store.put_statement(statement("s3"));
+        assert!(store.get_statement(DEFAULT_NAME).is_some());
+        assert!(store.get_statement("s2").is_none());
+        assert!(store.get_statement("s3").is_some());
+        assert_eq!(2, store.stats().statements);
     }
 }
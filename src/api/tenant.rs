@@ -0,0 +1,607 @@
+//! Multi-tenant servers with query handlers of each database.
+//!
+//! `DatabaseRouter` maps the `database` startup parameter, which defaults to
+//! the user name like postgres, to its own simple query, extended query and
+//! copy handlers, taken from a `PgWireServerHandlers` of the database.
+//! Databases not in the map use the catch-all handlers if set, otherwise the
+//! client is rejected at startup with `FATAL` `3D000` invalid_catalog_name.
+//!
+//! Wrap the startup, error, cancel and connection handlers shared by all
+//! databases together with the router in `DatabaseHandlers`, and serve it
+//! like any `PgWireServerHandlers`:
+//!
+//! ```no_run
+//! # use pgwire::api::PgWireServerHandlers;
+//! use pgwire::api::tenant::{DatabaseHandlers, DatabaseRouter};
+//!
+//! # fn serve<B: PgWireServerHandlers, H: PgWireServerHandlers>(base: B, sales: H, hr: H, sandbox: H) {
+//! let router = DatabaseRouter::new("sales", sales)
+//!     .with_database("hr", hr)
+//!     .with_catch_all(sandbox);
+//! let handlers = DatabaseHandlers::new(base, router);
+//! // pgwire::tokio::process_socket(socket, None, handlers)
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::Sink;
+
+use super::auth::StartupHandler;
+use super::cancel::CancelHandler;
+use super::connection::ConnectionHandler;
+use super::copy::{CopyHandler, CopyRow};
+use super::portal::Portal;
+use super::query::{ExtendedQueryHandler, SimpleQueryHandler, StatementOrPortal};
+use super::results::{DescribePortalResponse, DescribeStatementResponse, Response};
+use super::stmt::StoredStatement;
+use super::store::PortalStore;
+use super::{
+    ClientInfo, ClientPortalStore, PgWireServerHandlers, METADATA_DATABASE, METADATA_USER,
+};
+use crate::error::{ErrorInfo, PgWireError, PgWireResult};
+use crate::messages::copy::{CopyData, CopyDone, CopyFail};
+use crate::messages::extendedquery::{
+    Bind, Close, Describe, Execute, Flush, Parse, Sync as PgSync,
+};
+use crate::messages::simplequery::Query;
+use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
+
+/// Error for a database not served, same as postgres
+pub fn database_not_found_error(severity: &str, database: &str) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        severity.to_owned(),
+        "3D000".to_owned(),
+        format!("database \"{database}\" does not exist"),
+    )))
+}
+
+/// Database of the client, the user name if not specified
+fn database_of<C: ClientInfo>(client: &C) -> Option<&str> {
+    let metadata = client.metadata();
+    metadata
+        .get(METADATA_DATABASE)
+        .or_else(|| metadata.get(METADATA_USER))
+        .map(String::as_str)
+}
+
+/// Query handlers of a database
+struct QueryHandlers<H: PgWireServerHandlers> {
+    simple: Arc<H::SimpleQueryHandler>,
+    extended: Arc<H::ExtendedQueryHandler>,
+    copy: Arc<H::CopyHandler>,
+}
+
+impl<H: PgWireServerHandlers> QueryHandlers<H> {
+    fn new(handlers: &H) -> QueryHandlers<H> {
+        QueryHandlers {
+            simple: handlers.simple_query_handler(),
+            extended: handlers.extended_query_handler(),
+            copy: handlers.copy_handler(),
+        }
+    }
+}
+
+/// Query and copy handlers dispatching to the handlers of client's database.
+///
+/// Handlers of each database are created once, when the database is added,
+/// and shared by its connections. All databases use the same handler types
+/// `H`, use an enum to combine different backends. Every `on_*` and `do_*`
+/// method is delegated, so each database parses statements with its own
+/// query parser. `query_parser` returns the parser of the first database.
+pub struct DatabaseRouter<H: PgWireServerHandlers> {
+    first: String,
+    databases: HashMap<String, QueryHandlers<H>>,
+    catch_all: Option<QueryHandlers<H>>,
+}
+
+impl<H: PgWireServerHandlers> Debug for DatabaseRouter<H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DatabaseRouter")
+            .field("databases", &self.databases.keys().collect::<Vec<_>>())
+            .field("catch_all", &self.catch_all.is_some())
+            .finish()
+    }
+}
+
+impl<H: PgWireServerHandlers> DatabaseRouter<H> {
+    /// Create the router with handlers of the first database
+    pub fn new(database: impl Into<String>, handlers: H) -> DatabaseRouter<H> {
+        let database = database.into();
+        let mut databases = HashMap::new();
+        databases.insert(database.clone(), QueryHandlers::new(&handlers));
+        DatabaseRouter {
+            first: database,
+            databases,
+            catch_all: None,
+        }
+    }
+
+    /// Add handlers of a database
+    pub fn with_database(mut self, database: impl Into<String>, handlers: H) -> DatabaseRouter<H> {
+        self.databases
+            .insert(database.into(), QueryHandlers::new(&handlers));
+        self
+    }
+
+    /// Serve databases not added with these handlers, instead of rejecting
+    /// them
+    pub fn with_catch_all(mut self, handlers: H) -> DatabaseRouter<H> {
+        self.catch_all = Some(QueryHandlers::new(&handlers));
+        self
+    }
+
+    /// Test if connections to `database` are accepted
+    pub fn serves(&self, database: &str) -> bool {
+        self.handlers_of(database).is_some()
+    }
+
+    fn handlers_of(&self, database: &str) -> Option<&QueryHandlers<H>> {
+        self.databases.get(database).or(self.catch_all.as_ref())
+    }
+
+    fn resolve<C: ClientInfo>(&self, client: &C) -> PgWireResult<&QueryHandlers<H>> {
+        let database = database_of(client).unwrap_or_default();
+        self.handlers_of(database)
+            .ok_or_else(|| database_not_found_error("ERROR", database))
+    }
+
+    fn first(&self) -> &QueryHandlers<H> {
+        // the first database is never removed
+        &self.databases[&self.first]
+    }
+}
+
+#[async_trait]
+impl<H> SimpleQueryHandler for DatabaseRouter<H>
+where
+    H: PgWireServerHandlers + Send + Sync,
+{
+    async fn on_query<C>(&self, client: &mut C, query: Query) -> PgWireResult<()>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.resolve(client)?.simple.on_query(client, query).await
+    }
+
+    async fn do_query<'a, 'b: 'a, C>(
+        &'b self,
+        client: &mut C,
+        query: &'a str,
+    ) -> PgWireResult<Vec<Response<'a>>>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.resolve(client)?.simple.do_query(client, query).await
+    }
+}
+
+#[async_trait]
+impl<H> ExtendedQueryHandler for DatabaseRouter<H>
+where
+    H: PgWireServerHandlers + Send + Sync,
+{
+    type Statement = <H::ExtendedQueryHandler as ExtendedQueryHandler>::Statement;
+    type QueryParser = <H::ExtendedQueryHandler as ExtendedQueryHandler>::QueryParser;
+
+    fn query_parser(&self) -> Arc<Self::QueryParser> {
+        self.first().extended.query_parser()
+    }
+
+    async fn on_parse<C>(&self, client: &mut C, message: Parse) -> PgWireResult<()>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.resolve(client)?
+            .extended
+            .on_parse(client, message)
+            .await
+    }
+
+    async fn on_bind<C>(&self, client: &mut C, message: Bind) -> PgWireResult<()>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.resolve(client)?
+            .extended
+            .on_bind(client, message)
+            .await
+    }
+
+    fn check_portal_store_usage<C>(&self, client: &C) -> PgWireResult<()>
+    where
+        C: ClientInfo + ClientPortalStore,
+        C::PortalStore: PortalStore,
+    {
+        self.resolve(client)?
+            .extended
+            .check_portal_store_usage(client)
+    }
+
+    async fn on_execute<C>(&self, client: &mut C, message: Execute) -> PgWireResult<()>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.resolve(client)?
+            .extended
+            .on_execute(client, message)
+            .await
+    }
+
+    /// Enabled if any database supports it, the default `on_query_batch` of
+    /// other databases runs the batch one by one
+    fn supports_query_batch(&self) -> bool {
+        self.databases
+            .values()
+            .chain(self.catch_all.as_ref())
+            .any(|handlers| handlers.extended.supports_query_batch())
+    }
+
+    async fn on_query_batch<C>(
+        &self,
+        client: &mut C,
+        batch: Vec<(Bind, Execute)>,
+    ) -> PgWireResult<()>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.resolve(client)?
+            .extended
+            .on_query_batch(client, batch)
+            .await
+    }
+
+    async fn on_describe<C>(&self, client: &mut C, message: Describe) -> PgWireResult<()>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.resolve(client)?
+            .extended
+            .on_describe(client, message)
+            .await
+    }
+
+    async fn on_flush<C>(&self, client: &mut C, message: Flush) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.resolve(client)?
+            .extended
+            .on_flush(client, message)
+            .await
+    }
+
+    async fn on_sync<C>(&self, client: &mut C, message: PgSync) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.resolve(client)?
+            .extended
+            .on_sync(client, message)
+            .await
+    }
+
+    async fn on_close<C>(&self, client: &mut C, message: Close) -> PgWireResult<()>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.resolve(client)?
+            .extended
+            .on_close(client, message)
+            .await
+    }
+
+    async fn do_describe<C>(
+        &self,
+        client: &mut C,
+        target: StatementOrPortal<'_, Self::Statement>,
+    ) -> PgWireResult<DescribeStatementResponse>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.resolve(client)?
+            .extended
+            .do_describe(client, target)
+            .await
+    }
+
+    async fn do_describe_statement<C>(
+        &self,
+        client: &mut C,
+        target: &StoredStatement<Self::Statement>,
+    ) -> PgWireResult<DescribeStatementResponse>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.resolve(client)?
+            .extended
+            .do_describe_statement(client, target)
+            .await
+    }
+
+    async fn do_describe_portal<C>(
+        &self,
+        client: &mut C,
+        target: &Portal<Self::Statement>,
+    ) -> PgWireResult<DescribePortalResponse>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.resolve(client)?
+            .extended
+            .do_describe_portal(client, target)
+            .await
+    }
+
+    async fn do_query<'a, 'b: 'a, C>(
+        &'b self,
+        client: &mut C,
+        portal: &'a Portal<Self::Statement>,
+        max_rows: usize,
+    ) -> PgWireResult<Response<'a>>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.resolve(client)?
+            .extended
+            .do_query(client, portal, max_rows)
+            .await
+    }
+
+    async fn do_query_batch<'a, 'b: 'a, C>(
+        &'b self,
+        client: &mut C,
+        portals: &'a [Arc<Portal<Self::Statement>>],
+    ) -> PgWireResult<Vec<Response<'a>>>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.resolve(client)?
+            .extended
+            .do_query_batch(client, portals)
+            .await
+    }
+}
+
+#[async_trait]
+impl<H> CopyHandler for DatabaseRouter<H>
+where
+    H: PgWireServerHandlers + Send + Sync,
+{
+    async fn on_copy_data<C>(&self, client: &mut C, copy_data: CopyData) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.resolve(client)?
+            .copy
+            .on_copy_data(client, copy_data)
+            .await
+    }
+
+    async fn on_copy_rows<C>(&self, client: &mut C, rows: Vec<CopyRow>) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.resolve(client)?.copy.on_copy_rows(client, rows).await
+    }
+
+    async fn on_copy_done<C>(&self, client: &mut C, done: CopyDone) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.resolve(client)?.copy.on_copy_done(client, done).await
+    }
+
+    async fn on_copy_fail<C>(&self, client: &mut C, fail: CopyFail) -> PgWireError
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        match self.resolve(client) {
+            Ok(handlers) => handlers.copy.on_copy_fail(client, fail).await,
+            Err(e) => e,
+        }
+    }
+}
+
+/// Wrapper of startup handler that rejects databases not served by the
+/// router with `FATAL` `3D000`, before the inner handler authenticates the
+/// client.
+#[derive(Debug)]
+pub struct DatabaseStartupHandler<S, H: PgWireServerHandlers> {
+    inner: Arc<S>,
+    router: Arc<DatabaseRouter<H>>,
+}
+
+impl<S, H: PgWireServerHandlers> DatabaseStartupHandler<S, H> {
+    pub fn new(inner: Arc<S>, router: Arc<DatabaseRouter<H>>) -> DatabaseStartupHandler<S, H> {
+        DatabaseStartupHandler { inner, router }
+    }
+}
+
+#[async_trait]
+impl<S, H> StartupHandler for DatabaseStartupHandler<S, H>
+where
+    S: StartupHandler,
+    H: PgWireServerHandlers + Send + Sync,
+{
+    async fn on_startup<C>(
+        &self,
+        client: &mut C,
+        message: PgWireFrontendMessage,
+    ) -> PgWireResult<()>
+    where
+        C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        if let PgWireFrontendMessage::Startup(startup) = &message {
+            let database = startup
+                .parameters
+                .get(METADATA_DATABASE)
+                .or_else(|| startup.parameters.get(METADATA_USER))
+                .map(String::as_str)
+                .unwrap_or_default();
+            if !self.router.serves(database) {
+                return Err(database_not_found_error("FATAL", database));
+            }
+        }
+        self.inner.on_startup(client, message).await
+    }
+}
+
+/// Handlers of a multi-tenant server: query and copy handlers of the
+/// client's database from `DatabaseRouter`, and the others shared by all
+/// databases from `base`.
+#[derive(Debug)]
+pub struct DatabaseHandlers<B, H: PgWireServerHandlers> {
+    base: B,
+    router: Arc<DatabaseRouter<H>>,
+}
+
+impl<B, H: PgWireServerHandlers> DatabaseHandlers<B, H> {
+    pub fn new(base: B, router: DatabaseRouter<H>) -> DatabaseHandlers<B, H> {
+        DatabaseHandlers {
+            base,
+            router: Arc::new(router),
+        }
+    }
+
+    /// Get the router
+    pub fn router(&self) -> &Arc<DatabaseRouter<H>> {
+        &self.router
+    }
+}
+
+impl<B, H> PgWireServerHandlers for DatabaseHandlers<B, H>
+where
+    B: PgWireServerHandlers,
+    H: PgWireServerHandlers + Send + Sync,
+{
+    type StartupHandler = DatabaseStartupHandler<B::StartupHandler, H>;
+    type SimpleQueryHandler = DatabaseRouter<H>;
+    type ExtendedQueryHandler = DatabaseRouter<H>;
+    type CopyHandler = DatabaseRouter<H>;
+    type ErrorHandler = B::ErrorHandler;
+
+    fn simple_query_handler(&self) -> Arc<Self::SimpleQueryHandler> {
+        self.router.clone()
+    }
+
+    fn extended_query_handler(&self) -> Arc<Self::ExtendedQueryHandler> {
+        self.router.clone()
+    }
+
+    fn startup_handler(&self) -> Arc<Self::StartupHandler> {
+        Arc::new(DatabaseStartupHandler::new(
+            self.base.startup_handler(),
+            self.router.clone(),
+        ))
+    }
+
+    fn copy_handler(&self) -> Arc<Self::CopyHandler> {
+        self.router.clone()
+    }
+
+    fn error_handler(&self) -> Arc<Self::ErrorHandler> {
+        self.base.error_handler()
+    }
+
+    fn cancel_handler(&self) -> Arc<impl CancelHandler> {
+        self.base.cancel_handler()
+    }
+
+    fn connection_handler(&self) -> Arc<impl ConnectionHandler> {
+        self.base.connection_handler()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testkit::fixture::{command_tag, error_code, FnQueryHandler, TestHandlers};
+    use crate::testkit::MockClient;
+
+    #[tokio::test]
+    async fn test_database_router() {
+        let tenant = |name| TestHandlers::new(FnQueryHandler::tag(name));
+        let router =
+            || DatabaseRouter::new("sales", tenant("SALES")).with_database("hr", tenant("HR"));
+
+        let mut client = MockClient::start(DatabaseHandlers::new(TestHandlers::echo(), router()));
+        client.startup("tom", Some("sales")).await.unwrap();
+        let messages = client.simple_query("SELECT 1").await.unwrap();
+        assert_eq!(Some("SALES"), command_tag(&messages));
+
+        // database defaults to user name
+        let mut client = MockClient::start(DatabaseHandlers::new(TestHandlers::echo(), router()));
+        client.startup("hr", None).await.unwrap();
+        let messages = client.simple_query("SELECT 1").await.unwrap();
+        assert_eq!(Some("HR"), command_tag(&messages));
+
+        let mut client = MockClient::start(DatabaseHandlers::new(TestHandlers::echo(), router()));
+        let messages = client.startup("tom", Some("finance")).await.unwrap();
+        assert_eq!(Some("3D000".to_owned()), error_code(&messages[0]));
+        assert!(client.receive().await.unwrap().is_none());
+
+        let mut client = MockClient::start(DatabaseHandlers::new(
+            TestHandlers::echo(),
+            router().with_catch_all(tenant("SANDBOX")),
+        ));
+        client.startup("tom", Some("finance")).await.unwrap();
+        let messages = client.simple_query("SELECT 1").await.unwrap();
+        assert_eq!(Some("SANDBOX"), command_tag(&messages));
+    }
+}
@@ -0,0 +1,375 @@
+//! Time limit of query execution.
+
+use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::stream::{BoxStream, Stream, StreamExt};
+use futures::Sink;
+use tokio::time::{Instant, Sleep};
+use tokio_util::sync::CancellationToken;
+
+use super::guc::execution_settings;
+use super::portal::Portal;
+use super::query::{ExtendedQueryHandler, SimpleQueryHandler, StatementOrPortal};
+use super::results::{DescribePortalResponse, DescribeStatementResponse, QueryResponse, Response};
+use super::stmt::StoredStatement;
+use super::store::PortalStore;
+use super::{ClientInfo, ClientPortalStore};
+use crate::error::{ErrorInfo, PgWireError, PgWireResult};
+use crate::messages::data::DataRow;
+use crate::messages::PgWireBackendMessage;
+
+/// Error returned to client when its query exceeds the time limit, same as
+/// `statement_timeout` of postgres.
+pub fn statement_timeout_error() -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        "ERROR".to_owned(),
+        "57014".to_owned(),
+        "canceling statement due to statement timeout".to_owned(),
+    )))
+}
+
+/// Wrapper of query handlers that limits the time of `do_query`.
+///
+/// When `do_query` of the inner handler, and the stream of rows of its
+/// `Response::Query`, don't finish in `timeout`, the query fails with `57014`
+/// query_canceled. The `ClientInfo::cancellation_token` of the query is
+/// cancelled too, so work spawned by the backend can stop. Rows of a
+/// `Response::Suspended` are fetched by later `Execute` and not limited.
+///
+/// `statement_timeout` of the session, set at startup or by `SET`, takes
+/// precedence over `timeout`, and `0` disables the limit like postgres.
+///
+/// Only `do_*` methods, `query_parser` and `check_portal_store_usage` are
+/// delegated to the inner handler, messages are processed by the default
+/// `on_*` implementations.
+#[derive(Debug, Clone)]
+pub struct TimeoutQueryHandler<H> {
+    inner: Arc<H>,
+    timeout: Duration,
+}
+
+impl<H> TimeoutQueryHandler<H> {
+    pub fn new(inner: Arc<H>, timeout: Duration) -> TimeoutQueryHandler<H> {
+        TimeoutQueryHandler { inner, timeout }
+    }
+
+    /// Get the inner handler
+    pub fn inner(&self) -> &Arc<H> {
+        &self.inner
+    }
+
+    /// Time limit of each query
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn expired<C: ClientInfo>(&self, client: &C) -> PgWireError {
+        if let Some(token) = client.cancellation_token() {
+            token.cancel();
+        }
+        statement_timeout_error()
+    }
+
+    /// Deadline of a query starting now, `None` if `statement_timeout` of
+    /// the session disables it
+    fn deadline<C: ClientInfo>(&self, client: &mut C) -> PgWireResult<Option<Instant>> {
+        let timeout = if client.metadata().contains_key("statement_timeout") {
+            execution_settings(client)?.statement_timeout
+        } else {
+            Some(self.timeout)
+        };
+        Ok(timeout.map(|timeout| Instant::now() + timeout))
+    }
+
+    /// Limit rows of a query response to the deadline
+    fn limit<'a, C: ClientInfo>(
+        &self,
+        client: &C,
+        response: Response<'a>,
+        deadline: Instant,
+    ) -> Response<'a> {
+        let Response::Query(results) = response else {
+            return response;
+        };
+        let command_tag = results.command_tag().to_owned();
+        let buffered_bytes = results.buffered_bytes();
+        let schema = results.row_schema();
+        let rows = DeadlineRows {
+            rows: results.data_rows(),
+            sleep: Box::pin(tokio::time::sleep_until(deadline)),
+            token: client.cancellation_token(),
+            expired: false,
+        };
+
+        let mut results = QueryResponse::new(schema, rows);
+        results.set_command_tag(&command_tag);
+        results.set_buffered_bytes(buffered_bytes);
+        Response::Query(results)
+    }
+}
+
+/// Rows of a query that fail with `57014` when the deadline passes before the
+/// stream ends
+struct DeadlineRows<'a> {
+    rows: BoxStream<'a, PgWireResult<DataRow>>,
+    sleep: Pin<Box<Sleep>>,
+    token: Option<CancellationToken>,
+    expired: bool,
+}
+
+impl Stream for DeadlineRows<'_> {
+    type Item = PgWireResult<DataRow>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.expired {
+            return Poll::Ready(None);
+        }
+        if let Poll::Ready(row) = self.rows.poll_next_unpin(cx) {
+            return Poll::Ready(row);
+        }
+        if self.sleep.as_mut().poll(cx).is_ready() {
+            self.expired = true;
+            if let Some(token) = &self.token {
+                token.cancel();
+            }
+            return Poll::Ready(Some(Err(statement_timeout_error())));
+        }
+        Poll::Pending
+    }
+}
+
+#[async_trait]
+impl<H: SimpleQueryHandler> SimpleQueryHandler for TimeoutQueryHandler<H> {
+    async fn do_query<'a, 'b: 'a, C>(
+        &'b self,
+        client: &mut C,
+        query: &'a str,
+    ) -> PgWireResult<Vec<Response<'a>>>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let Some(deadline) = self.deadline(client)? else {
+            return self.inner.do_query(client, query).await;
+        };
+        match tokio::time::timeout_at(deadline, self.inner.do_query(client, query)).await {
+            Ok(result) => Ok(result?
+                .into_iter()
+                .map(|response| self.limit(client, response, deadline))
+                .collect()),
+            Err(_) => Err(self.expired(client)),
+        }
+    }
+}
+
+#[async_trait]
+impl<H: ExtendedQueryHandler> ExtendedQueryHandler for TimeoutQueryHandler<H> {
+    type Statement = H::Statement;
+    type QueryParser = H::QueryParser;
+
+    fn query_parser(&self) -> Arc<Self::QueryParser> {
+        self.inner.query_parser()
+    }
+
+    fn check_portal_store_usage<C>(&self, client: &C) -> PgWireResult<()>
+    where
+        C: ClientInfo + ClientPortalStore,
+        C::PortalStore: PortalStore,
+    {
+        self.inner.check_portal_store_usage(client)
+    }
+
+    async fn do_describe<C>(
+        &self,
+        client: &mut C,
+        target: StatementOrPortal<'_, Self::Statement>,
+    ) -> PgWireResult<DescribeStatementResponse>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.inner.do_describe(client, target).await
+    }
+
+    async fn do_describe_statement<C>(
+        &self,
+        client: &mut C,
+        target: &StoredStatement<Self::Statement>,
+    ) -> PgWireResult<DescribeStatementResponse>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.inner.do_describe_statement(client, target).await
+    }
+
+    async fn do_describe_portal<C>(
+        &self,
+        client: &mut C,
+        target: &Portal<Self::Statement>,
+    ) -> PgWireResult<DescribePortalResponse>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        self.inner.do_describe_portal(client, target).await
+    }
+
+    async fn do_query<'a, 'b: 'a, C>(
+        &'b self,
+        client: &mut C,
+        portal: &'a Portal<Self::Statement>,
+        max_rows: usize,
+    ) -> PgWireResult<Response<'a>>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let Some(deadline) = self.deadline(client)? else {
+            return self.inner.do_query(client, portal, max_rows).await;
+        };
+        match tokio::time::timeout_at(deadline, self.inner.do_query(client, portal, max_rows)).await
+        {
+            Ok(result) => Ok(self.limit(client, result?, deadline)),
+            Err(_) => Err(self.expired(client)),
+        }
+    }
+
+    fn supports_query_batch(&self) -> bool {
+        self.inner.supports_query_batch()
+    }
+
+    async fn do_query_batch<'a, 'b: 'a, C>(
+        &'b self,
+        client: &mut C,
+        portals: &'a [Arc<Portal<Self::Statement>>],
+    ) -> PgWireResult<Vec<Response<'a>>>
+    where
+        C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+        C::PortalStore: PortalStore<Statement = Self::Statement>,
+        C::Error: Debug,
+        PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+    {
+        let Some(deadline) = self.deadline(client)? else {
+            return self.inner.do_query_batch(client, portals).await;
+        };
+        match tokio::time::timeout_at(deadline, self.inner.do_query_batch(client, portals)).await {
+            Ok(result) => Ok(result?
+                .into_iter()
+                .map(|response| self.limit(client, response, deadline))
+                .collect()),
+            Err(_) => Err(self.expired(client)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::stream;
+
+    use super::*;
+    use crate::api::results::Tag;
+    use crate::api::set::SetCommandHandler;
+    use crate::messages::response::CommandComplete;
+    use crate::testkit::fixture::{error_code, numbers, numbers_schema, TestHandlers};
+    use crate::testkit::MockClient;
+
+    /// Sleeps for the seconds of `SELECT pg_sleep(n)`, returns one row and
+    /// never ends the stream for `SELECT stream`, answers other queries
+    /// right away
+    struct SleepHandler;
+
+    #[async_trait]
+    impl SimpleQueryHandler for SleepHandler {
+        async fn do_query<'a, C>(
+            &self,
+            _client: &mut C,
+            query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            let seconds = query
+                .strip_prefix("SELECT pg_sleep(")
+                .and_then(|s| s.strip_suffix(')'))
+                .and_then(|s| s.parse().ok());
+            if let Some(seconds) = seconds {
+                tokio::time::sleep(Duration::from_secs_f64(seconds)).await;
+            }
+            if query == "SELECT stream" {
+                let rows = numbers(1).data_rows().chain(stream::pending());
+                return Ok(vec![Response::Query(QueryResponse::new(
+                    numbers_schema(),
+                    rows,
+                ))]);
+            }
+            Ok(vec![Response::Execution(Tag::new("SELECT").with_rows(1))])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_timeout() {
+        let handler = TimeoutQueryHandler::new(Arc::new(SleepHandler), Duration::from_millis(100));
+        let mut client = MockClient::start(TestHandlers::echo().with_simple(handler));
+        client.startup("tom", None).await.unwrap();
+
+        let messages = client.simple_query("SELECT pg_sleep(10)").await.unwrap();
+        assert_eq!(Some("57014".to_owned()), error_code(&messages[0]));
+
+        // rows are sent in time too
+        let messages = client.simple_query("SELECT stream").await.unwrap();
+        assert!(matches!(messages[1], PgWireBackendMessage::DataRow(_)));
+        assert_eq!(Some("57014".to_owned()), error_code(&messages[2]));
+
+        // queries in time are not affected
+        let messages = client.simple_query("SELECT 1").await.unwrap();
+        assert_eq!(
+            PgWireBackendMessage::CommandComplete(CommandComplete::new("SELECT 1".to_owned())),
+            messages[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_session_statement_timeout() {
+        let handler = TimeoutQueryHandler::new(Arc::new(SleepHandler), Duration::from_secs(60));
+        let handler = SetCommandHandler::new(Arc::new(handler))
+            .with_parameters(vec!["statement_timeout".to_owned()]);
+        let mut client = MockClient::start(TestHandlers::echo().with_simple(handler));
+        client.startup("tom", None).await.unwrap();
+
+        client
+            .simple_query("SET statement_timeout TO 100")
+            .await
+            .unwrap();
+        let messages = client.simple_query("SELECT pg_sleep(10)").await.unwrap();
+        assert_eq!(Some("57014".to_owned()), error_code(&messages[0]));
+
+        // disabled by 0
+        client
+            .simple_query("SET statement_timeout TO 0")
+            .await
+            .unwrap();
+        let messages = client.simple_query("SELECT pg_sleep(0.2)").await.unwrap();
+        assert_eq!(
+            PgWireBackendMessage::CommandComplete(CommandComplete::new("SELECT 1".to_owned())),
+            messages[0]
+        );
+    }
+}
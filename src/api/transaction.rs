@@ -1,3 +1,4 @@
+use super::results::Response;
 use crate::messages::response::TransactionStatus;
 
 impl TransactionStatus {
@@ -20,3 +21,107 @@ impl TransactionStatus {
         }
     }
 }
+
+/// Transaction tracking for statements of a single simple query.
+///
+/// Postgres executes multiple statements of a simple query string in an
+/// implicit transaction, unless they are already in a transaction block. Once
+/// one of the statements fails, the remaining statements are skipped and the
+/// implicit transaction is rolled back.
+///
+/// `SimpleQueryHandler::on_query` uses this to update `TransactionStatus`
+/// from the responses of `do_query`, which are all sent to the client.
+/// `do_query` implementations can create one with
+/// `split_statements(query).len()` to find out if they should wrap the
+/// statements in a backend transaction, and feed it each response to stop
+/// executing statements once `is_failed` returns true.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImplicitTransaction {
+    status: TransactionStatus,
+    implicit: bool,
+    failed: bool,
+}
+
+impl ImplicitTransaction {
+    /// Create tracking for a query of `statements` statements, starting from
+    /// current transaction status of the client.
+    pub fn new(status: TransactionStatus, statements: usize) -> Self {
+        ImplicitTransaction {
+            status,
+            implicit: statements > 1 && status == TransactionStatus::Idle,
+            failed: false,
+        }
+    }
+
+    /// Return true if statements run in an implicit transaction
+    pub fn is_implicit(&self) -> bool {
+        self.implicit
+    }
+
+    /// Return true if one of the statements failed and the remaining
+    /// statements should be skipped
+    pub fn is_failed(&self) -> bool {
+        self.failed
+    }
+
+    /// Transaction status after the statements processed so far
+    pub fn status(&self) -> TransactionStatus {
+        self.status
+    }
+
+    /// Update tracking with response of next statement.
+    pub fn on_response(&mut self, response: &Response<'_>) {
+        match response {
+            Response::TransactionStart(_) => {
+                // explicit transaction block takes over the implicit one
+                self.implicit = false;
+                self.status = self.status.to_in_transaction_state();
+            }
+            Response::TransactionEnd(_) => {
+                self.status = self.status.to_idle_state();
+            }
+            Response::Error(_) => {
+                self.failed = true;
+                self.status = self.status.to_error_state();
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::results::Tag;
+    use crate::error::ErrorInfo;
+
+    fn error() -> Response<'static> {
+        Response::Error(Box::new(ErrorInfo::new(
+            "ERROR".to_owned(),
+            "42P01".to_owned(),
+            "relation does not exist".to_owned(),
+        )))
+    }
+
+    #[test]
+    fn test_implicit_transaction() {
+        let mut txn = ImplicitTransaction::new(TransactionStatus::Idle, 3);
+        assert!(txn.is_implicit());
+        txn.on_response(&Response::Execution(Tag::new("INSERT")));
+        txn.on_response(&error());
+        assert!(txn.is_failed());
+        // implicit transaction is rolled back
+        assert_eq!(txn.status(), TransactionStatus::Idle);
+
+        let mut txn = ImplicitTransaction::new(TransactionStatus::Idle, 3);
+        txn.on_response(&Response::TransactionStart(Tag::new("BEGIN")));
+        assert!(!txn.is_implicit());
+        txn.on_response(&error());
+        assert_eq!(txn.status(), TransactionStatus::Error);
+
+        let txn = ImplicitTransaction::new(TransactionStatus::Transaction, 3);
+        assert!(!txn.is_implicit());
+        let txn = ImplicitTransaction::new(TransactionStatus::Idle, 1);
+        assert!(!txn.is_implicit());
+    }
+}
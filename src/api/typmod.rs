@@ -0,0 +1,142 @@
+//! Type modifiers of column types, like `atttypmod` in `pg_attribute`.
+//!
+//! Catalog aware clients, like ORMs and GUI tools, decode the type modifier
+//! from `RowDescription` to find out the length of `varchar(n)` or the
+//! precision of `numeric(p, s)`. These functions encode them the same way as
+//! postgres, for `FieldInfoBuilder::type_modifier` and
+//! `ColumnDef::type_modifier`:
+//!
+//! ```
+//! use pgwire::api::results::FieldInfo;
+//! use pgwire::api::{typmod, Type};
+//!
+//! # fn main() -> pgwire::error::PgWireResult<()> {
+//! let field = FieldInfo::builder("amount", Type::NUMERIC)
+//!     .type_modifier(typmod::numeric(10, 2)?)
+//!     .build();
+//! assert_eq!(Some((10, 2)), typmod::numeric_precision_scale(field.type_modifier()));
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::error::{ErrorInfo, PgWireError, PgWireResult};
+
+/// Type modifier of types without modifier
+pub const NO_TYPMOD: i32 = -1;
+
+/// Header size of variable length values, included in type modifiers of
+/// `varchar`, `bpchar` and `numeric`
+const VARHDRSZ: i32 = 4;
+
+/// Max length of `varchar(n)` and `char(n)`
+const MAX_CHAR_LENGTH: i32 = 10 * 1024 * 1024;
+/// Max precision of `numeric(p, s)`
+const MAX_NUMERIC_PRECISION: i32 = 1000;
+/// Scale of `numeric(p, s)` ranges from `-1000` to `1000`
+const MAX_NUMERIC_SCALE: i32 = 1000;
+/// Max fractional digits of seconds in time types
+const MAX_TIME_PRECISION: i32 = 6;
+/// Range bits of `interval` allowing all fields
+const INTERVAL_FULL_RANGE: i32 = 0x7fff;
+
+fn invalid_typmod(message: String) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        "ERROR".to_owned(),
+        "22023".to_owned(),
+        message,
+    )))
+}
+
+/// `varchar(length)` and `char(length)`, the length is in characters
+pub fn char_length(length: i32) -> PgWireResult<i32> {
+    if !(1..=MAX_CHAR_LENGTH).contains(&length) {
+        return Err(invalid_typmod(format!(
+            "length for type varchar must be between 1 and {MAX_CHAR_LENGTH}"
+        )));
+    }
+    Ok(length + VARHDRSZ)
+}
+
+/// `numeric(precision, scale)`. Negative scale, supported since postgres 15,
+/// rounds to the left of the decimal point.
+pub fn numeric(precision: i32, scale: i32) -> PgWireResult<i32> {
+    if !(1..=MAX_NUMERIC_PRECISION).contains(&precision) {
+        return Err(invalid_typmod(format!(
+            "NUMERIC precision {precision} must be between 1 and {MAX_NUMERIC_PRECISION}"
+        )));
+    }
+    if !(-MAX_NUMERIC_SCALE..=MAX_NUMERIC_SCALE).contains(&scale) {
+        return Err(invalid_typmod(format!(
+            "NUMERIC scale {scale} must be between -{MAX_NUMERIC_SCALE} and {MAX_NUMERIC_SCALE}"
+        )));
+    }
+    Ok(((precision << 16) | (scale & 0x7ff)) + VARHDRSZ)
+}
+
+/// `time(precision)`, `timetz(precision)`, `timestamp(precision)` and
+/// `timestamptz(precision)`, the precision is fractional digits of seconds
+pub fn time_precision(precision: i32) -> PgWireResult<i32> {
+    if !(0..=MAX_TIME_PRECISION).contains(&precision) {
+        return Err(invalid_typmod(format!(
+            "time precision {precision} must be between 0 and {MAX_TIME_PRECISION}"
+        )));
+    }
+    Ok(precision)
+}
+
+/// `interval(precision)` without field restriction
+pub fn interval_precision(precision: i32) -> PgWireResult<i32> {
+    time_precision(precision).map(|precision| (INTERVAL_FULL_RANGE << 16) | precision)
+}
+
+/// Length of `varchar(n)` or `char(n)`, `None` if unlimited
+pub fn char_length_of(typmod: i32) -> Option<i32> {
+    (typmod >= VARHDRSZ).then(|| typmod - VARHDRSZ)
+}
+
+/// Precision and scale of `numeric(p, s)`, `None` if unconstrained
+pub fn numeric_precision_scale(typmod: i32) -> Option<(i32, i32)> {
+    if typmod < VARHDRSZ {
+        return None;
+    }
+    let typmod = typmod - VARHDRSZ;
+    // scale is an 11 bits signed integer
+    let scale = ((typmod & 0x7ff) ^ 1024) - 1024;
+    Some(((typmod >> 16) & 0xffff, scale))
+}
+
+/// Precision of time types and `interval`, `None` if not specified
+pub fn time_precision_of(typmod: i32) -> Option<i32> {
+    (typmod >= 0).then_some(typmod & 0xffff)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_typmod() {
+        assert_eq!(259, char_length(255).unwrap());
+        assert_eq!(Some(255), char_length_of(259));
+        assert_eq!(None, char_length_of(NO_TYPMOD));
+        assert!(char_length(0).is_err());
+
+        // same as atttypmod of numeric(10, 2) in postgres
+        assert_eq!(655366, numeric(10, 2).unwrap());
+        assert_eq!(Some((10, 2)), numeric_precision_scale(655366));
+        assert_eq!(
+            Some((5, -3)),
+            numeric_precision_scale(numeric(5, -3).unwrap())
+        );
+        assert_eq!(None, numeric_precision_scale(NO_TYPMOD));
+        assert!(numeric(0, 0).is_err());
+        assert!(numeric(10, 1001).is_err());
+
+        assert_eq!(3, time_precision(3).unwrap());
+        assert_eq!(Some(3), time_precision_of(3));
+        assert_eq!(None, time_precision_of(NO_TYPMOD));
+        assert!(time_precision(7).is_err());
+        assert_eq!(2147418115, interval_precision(3).unwrap());
+        assert_eq!(Some(3), time_precision_of(2147418115));
+    }
+}
@@ -14,8 +14,18 @@ pub enum PgWireError {
     InvalidTargetType(u8),
     #[error("Invalid transaction status, received {0}")]
     InvalidTransactionStatus(u8),
+    #[error("Invalid message length: {0}")]
+    InvalidMessageLength(i32),
+    #[error("Message length {0} exceeds the limit of 1GB")]
+    MessageTooLarge(usize),
+    #[error("Invalid UTF-8 string: {0}")]
+    InvalidUtf8String(#[source] std::str::Utf8Error),
+    #[error("Incomplete message: {0}")]
+    IncompleteMessage(#[from] bytes::TryGetError),
     #[error("Invalid startup message")]
     InvalidStartupMessage,
+    #[error("Invalid replication message")]
+    InvalidReplicationMessage,
     #[error("Invalid authentication message code: {0}")]
     InvalidAuthenticationMessageCode(i32),
     #[error(transparent)]
@@ -28,16 +38,36 @@ pub enum PgWireError {
     ParameterIndexOutOfBound(usize),
     #[error("Cannot convert postgre type {0} to given rust type")]
     InvalidRustTypeForParameter(String),
+    #[error("Cannot encode rust value of {1} as postgres type {0}")]
+    InvalidRustTypeForField(String, &'static str),
     #[error("Failed to parse parameter: {0}")]
-    FailedToParseParameter(Box<dyn std::error::Error + Send + Sync>),
+    FailedToParseParameter(#[source] Box<dyn std::error::Error + Send + Sync>),
     #[error("Failed to parse scram message: {0}")]
     InvalidScramMessage(String),
     #[error("Certificate algorithm is not supported")]
     UnsupportedCertificateSignatureAlgorithm,
+    #[error("Invalid CIDR: {0}")]
+    InvalidCidr(String),
     #[error("Username is required")]
     UserNameRequired,
     #[error("Connection is not ready for query")]
     NotReadyForQuery,
+    #[error("Connection is closed")]
+    ConnectionClosed,
+    #[error("Too many out of band messages queued for the connection")]
+    OutOfBandQueueFull,
+    #[error("Invalid copy response: {0}")]
+    InvalidCopyResponse(String),
+    #[error("Handler panicked: {0}")]
+    HandlerPanicked(String),
+    #[cfg(feature = "server-api")]
+    #[error("Column {0} ({1}) is encoded in {3:?} format, but {2:?} format is requested")]
+    ResultFormatMismatch(
+        usize,
+        String,
+        crate::api::results::FieldFormat,
+        crate::api::results::FieldFormat,
+    ),
     #[cfg(feature = "client-api")]
     #[error("Failed to parse connection config, invalid value for: {0}")]
     InvalidConfig(String),
@@ -45,6 +75,12 @@ pub enum PgWireError {
     #[error("Failed to parse connection config, unknown config: {0}")]
     UnknownConfig(String),
     #[cfg(feature = "client-api")]
+    #[error("Failed to parse server version: {0}")]
+    InvalidServerVersion(String),
+    #[cfg(feature = "client-api")]
+    #[error("Query returned {0} rows, but {1} row is expected")]
+    UnexpectedRowCount(usize, &'static str),
+    #[cfg(feature = "client-api")]
     #[error("Failed to parse utf8 value")]
     InvalidUtf8ConfigValue(#[source] Utf8std::str::Utf8Error),
 
@@ -52,7 +88,75 @@ pub enum PgWireError {
     ApiError(#[from] Box<dyn std::error::Error + 'static + Send + Sync>),
 
     #[error("User provided error: {0}")]
-    UserError(Box<ErrorInfo>),
+    UserError(#[source] Box<ErrorInfo>),
+
+    /// Error marked as fatal by `PgWireError::fatal`
+    #[error(transparent)]
+    Fatal(Box<PgWireError>),
+    /// Error marked as non-fatal by `PgWireError::non_fatal`
+    #[error(transparent)]
+    NonFatal(Box<PgWireError>),
+}
+
+impl PgWireError {
+    /// Return true if the connection is closed after this error is sent to
+    /// client.
+    ///
+    /// `UserError` is fatal when its severity is `FATAL` or `PANIC`, and
+    /// `ApiError`, `MessageTooLarge`, `InvalidRustTypeForField` and
+    /// `ResultFormatMismatch` are not fatal, while other errors are fatal
+    /// because the connection may be in an unknown state. Use `fatal`,
+    /// `non_fatal` or `set_fatal` to override this for an error.
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            PgWireError::Fatal(_) => true,
+            PgWireError::NonFatal(_) => false,
+            PgWireError::UserError(info) => info.is_fatal(),
+            PgWireError::ApiError(_) => false,
+            // nothing is written for the message, so the stream is intact
+            PgWireError::MessageTooLarge(_) => false,
+            PgWireError::InvalidRustTypeForField(..) => false,
+            #[cfg(feature = "server-api")]
+            PgWireError::ResultFormatMismatch(..) => false,
+            _ => true,
+        }
+    }
+
+    /// Mark this error as fatal
+    pub fn fatal(self) -> PgWireError {
+        PgWireError::Fatal(Box::new(self.into_inner()))
+    }
+
+    /// Mark this error as non-fatal
+    pub fn non_fatal(self) -> PgWireError {
+        PgWireError::NonFatal(Box::new(self.into_inner()))
+    }
+
+    /// Mark this error as fatal or non-fatal in place, for example in
+    /// `ErrorHandler::on_error`
+    pub fn set_fatal(&mut self, fatal: bool) {
+        let error = std::mem::replace(self, PgWireError::NotReadyForQuery);
+        *self = if fatal {
+            error.fatal()
+        } else {
+            error.non_fatal()
+        };
+    }
+
+    /// Get the error without mark of `fatal` or `non_fatal`
+    pub fn into_inner(self) -> PgWireError {
+        match self {
+            PgWireError::Fatal(e) | PgWireError::NonFatal(e) => e.into_inner(),
+            e => e,
+        }
+    }
+
+    /// Iterate this error and its sources, from this error to the root cause
+    pub fn chain(&self) -> impl Iterator<Item = &(dyn std::error::Error + 'static)> {
+        std::iter::successors(Some(self as &(dyn std::error::Error + 'static)), |e| {
+            e.source()
+        })
+    }
 }
 
 impl From<PgWireError> for IOError {
@@ -110,8 +214,16 @@ pub struct ErrorInfo {
     // Routine: the name of the source-code routine reporting the error.
     #[new(default)]
     pub routine: Option<String>,
+    // Underlying cause of the error, for logging only and never sent to
+    // client
+    #[new(default)]
+    pub source: Option<Box<dyn std::error::Error + Send + Sync>>,
 }
 
+/// Fields of a notice message, which are the same as error message. Use a
+/// notice severity like `NOTICE` or `WARNING` for it.
+pub type NoticeInfo = ErrorInfo;
+
 impl Display for ErrorInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -122,7 +234,30 @@ impl Display for ErrorInfo {
     }
 }
 
+impl std::error::Error for ErrorInfo {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source
+            .as_deref()
+            .map(|e| e as &(dyn std::error::Error + 'static))
+    }
+}
+
 impl ErrorInfo {
+    /// Return true if the severity is `FATAL` or `PANIC`, which ends the
+    /// session in postgres
+    pub fn is_fatal(&self) -> bool {
+        matches!(self.severity.as_str(), "FATAL" | "PANIC")
+    }
+
+    /// Attach underlying cause of the error
+    pub fn with_source<E>(mut self, source: E) -> ErrorInfo
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        self.source = Some(Box::new(source));
+        self
+    }
+
     fn into_fields(self) -> Vec<(u8, String)> {
         let mut fields = Vec::with_capacity(11);
 
@@ -167,12 +302,257 @@ impl From<ErrorInfo> for ErrorResponse {
     }
 }
 
+/// Read fields of an error received from server, unknown fields are ignored
+impl From<ErrorResponse> for ErrorInfo {
+    fn from(response: ErrorResponse) -> ErrorInfo {
+        let mut info = ErrorInfo::new(String::new(), String::new(), String::new());
+        for (field, value) in response.fields {
+            match field {
+                b'S' => info.severity = value,
+                b'C' => info.code = value,
+                b'M' => info.message = value,
+                b'D' => info.detail = Some(value),
+                b'H' => info.hint = Some(value),
+                b'P' => info.position = Some(value),
+                b'p' => info.internal_position = Some(value),
+                b'q' => info.internal_query = Some(value),
+                b'W' => info.where_context = Some(value),
+                b'F' => info.file_name = Some(value),
+                b'L' => info.line = value.parse().ok(),
+                b'R' => info.routine = Some(value),
+                _ => {}
+            }
+        }
+        info
+    }
+}
+
 impl From<ErrorInfo> for NoticeResponse {
     fn from(ei: ErrorInfo) -> NoticeResponse {
         NoticeResponse::new(ei.into_fields())
     }
 }
 
+/// Guess SQLSTATE from error message of embedded databases, which don't
+/// report one.
+#[cfg(any(feature = "rusqlite", feature = "duckdb", feature = "sqlx"))]
+fn sqlstate_from_message(message: &str) -> &'static str {
+    let message = message.to_lowercase();
+    if message.contains("syntax error") || message.starts_with("parser error") {
+        "42601"
+    } else if message.contains("unique constraint") || message.contains("duplicate key") {
+        "23505"
+    } else if message.contains("not null constraint") {
+        "23502"
+    } else if message.contains("foreign key") {
+        "23503"
+    } else if message.contains("check constraint") {
+        "23514"
+    } else if message.contains("constraint") {
+        "23000"
+    } else if message.contains("no such table")
+        || (message.contains("table") && message.contains("does not exist"))
+    {
+        "42P01"
+    } else if message.contains("no such column")
+        || (message.contains("column") && message.contains("not found"))
+    {
+        "42703"
+    } else if message.contains("no such function")
+        || (message.contains("function") && message.contains("does not exist"))
+    {
+        "42883"
+    } else if message.contains("already exists") {
+        "42P07"
+    } else if message.contains("division by zero") || message.contains("divide by zero") {
+        "22012"
+    } else if message.starts_with("conversion error") {
+        "22P02"
+    } else if message.starts_with("out of range error") {
+        "22003"
+    } else if message.starts_with("transaction") {
+        "25000"
+    } else if message.starts_with("permission error") {
+        "42501"
+    } else if message.starts_with("not implemented error") {
+        "0A000"
+    } else {
+        "XX000"
+    }
+}
+
+#[cfg(feature = "rusqlite")]
+mod rusqlite_adapter {
+    use rusqlite::ffi;
+    use rusqlite::{Error, ErrorCode};
+
+    use super::{sqlstate_from_message, ErrorInfo, PgWireError};
+
+    fn sqlstate(e: &Error) -> &'static str {
+        match e {
+            Error::SqliteFailure(err, msg) => match err.code {
+                ErrorCode::ConstraintViolation => match err.extended_code {
+                    ffi::SQLITE_CONSTRAINT_UNIQUE | ffi::SQLITE_CONSTRAINT_PRIMARYKEY => "23505",
+                    ffi::SQLITE_CONSTRAINT_NOTNULL => "23502",
+                    ffi::SQLITE_CONSTRAINT_FOREIGNKEY => "23503",
+                    ffi::SQLITE_CONSTRAINT_CHECK => "23514",
+                    _ => "23000",
+                },
+                ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked => "55P03",
+                ErrorCode::OutOfMemory => "53200",
+                ErrorCode::DiskFull => "53100",
+                ErrorCode::ReadOnly => "25006",
+                ErrorCode::PermissionDenied => "42501",
+                ErrorCode::OperationInterrupted => "57014",
+                ErrorCode::TooBig => "54000",
+                ErrorCode::TypeMismatch => "42804",
+                ErrorCode::DatabaseCorrupt => "XX001",
+                ErrorCode::SystemIoFailure | ErrorCode::CannotOpen => "58030",
+                _ => msg.as_deref().map_or("XX000", sqlstate_from_message),
+            },
+            Error::QueryReturnedNoRows => "P0002",
+            Error::InvalidColumnName(_) | Error::InvalidColumnIndex(_) => "42703",
+            Error::InvalidColumnType(..) => "42804",
+            Error::InvalidParameterCount(..) => "08P01",
+            Error::InvalidQuery | Error::MultipleStatement => "42601",
+            Error::IntegralValueOutOfRange(..) => "22003",
+            Error::FromSqlConversionFailure(..)
+            | Error::ToSqlConversionFailure(_)
+            | Error::Utf8Error(_)
+            | Error::NulError(_) => "22000",
+            _ => "XX000",
+        }
+    }
+
+    impl From<Error> for ErrorInfo {
+        fn from(e: Error) -> ErrorInfo {
+            ErrorInfo::new("ERROR".to_owned(), sqlstate(&e).to_owned(), e.to_string())
+                .with_source(e)
+        }
+    }
+
+    impl From<Error> for PgWireError {
+        fn from(e: Error) -> PgWireError {
+            PgWireError::UserError(Box::new(e.into()))
+        }
+    }
+}
+
+#[cfg(feature = "duckdb")]
+mod duckdb_adapter {
+    use duckdb::ffi::ErrorCode;
+    use duckdb::Error;
+
+    use super::{sqlstate_from_message, ErrorInfo, PgWireError};
+
+    fn sqlstate(e: &Error) -> &'static str {
+        match e {
+            Error::DuckDBFailure(err, msg) => match err.code {
+                ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked => "55P03",
+                ErrorCode::OutOfMemory => "53200",
+                ErrorCode::DiskFull => "53100",
+                ErrorCode::ReadOnly => "25006",
+                ErrorCode::PermissionDenied => "42501",
+                ErrorCode::OperationInterrupted => "57014",
+                ErrorCode::TooBig => "54000",
+                ErrorCode::TypeMismatch => "42804",
+                ErrorCode::DatabaseCorrupt => "XX001",
+                ErrorCode::SystemIoFailure | ErrorCode::CannotOpen => "58030",
+                // duckdb reports most errors as unknown, with the error
+                // class in message like `Catalog Error: ...`
+                _ => msg.as_deref().map_or("XX000", sqlstate_from_message),
+            },
+            Error::QueryReturnedNoRows => "P0002",
+            Error::QueryReturnedMoreThanOneRow => "21000",
+            Error::InvalidColumnName(_) | Error::InvalidColumnIndex(_) => "42703",
+            Error::InvalidColumnType(..) => "42804",
+            Error::InvalidParameterCount(..) | Error::InvalidParameterIndex(_) => "08P01",
+            Error::InvalidQuery | Error::MultipleStatement => "42601",
+            Error::IntegralValueOutOfRange(..) | Error::UnsignedIntegralValueOutOfRange(..) => {
+                "22003"
+            }
+            Error::FromSqlConversionFailure(..)
+            | Error::ToSqlConversionFailure(_)
+            | Error::Utf8Error(_)
+            | Error::NulError(_) => "22000",
+            _ => "XX000",
+        }
+    }
+
+    impl From<Error> for ErrorInfo {
+        fn from(e: Error) -> ErrorInfo {
+            ErrorInfo::new("ERROR".to_owned(), sqlstate(&e).to_owned(), e.to_string())
+                .with_source(e)
+        }
+    }
+
+    impl From<Error> for PgWireError {
+        fn from(e: Error) -> PgWireError {
+            PgWireError::UserError(Box::new(e.into()))
+        }
+    }
+}
+
+#[cfg(feature = "sqlx")]
+mod sqlx_adapter {
+    use sqlx::error::{DatabaseError, ErrorKind};
+    use sqlx::Error;
+
+    use super::{sqlstate_from_message, ErrorInfo, PgWireError};
+
+    /// SQLSTATE of a database error. Postgres and MySQL drivers report one
+    /// in `code`, while SQLite reports its numeric result code there.
+    fn database_sqlstate(e: &dyn DatabaseError) -> String {
+        match e.code() {
+            Some(code)
+                if code.len() == 5
+                    && code
+                        .bytes()
+                        .all(|b| b.is_ascii_digit() || b.is_ascii_uppercase()) =>
+            {
+                code.into_owned()
+            }
+            _ => match e.kind() {
+                ErrorKind::UniqueViolation => "23505",
+                ErrorKind::ForeignKeyViolation => "23503",
+                ErrorKind::NotNullViolation => "23502",
+                ErrorKind::CheckViolation => "23514",
+                _ => sqlstate_from_message(e.message()),
+            }
+            .to_owned(),
+        }
+    }
+
+    fn sqlstate(e: &Error) -> String {
+        match e {
+            Error::Database(err) => return database_sqlstate(err.as_ref()),
+            Error::RowNotFound => "P0002",
+            Error::ColumnNotFound(_) | Error::ColumnIndexOutOfBounds { .. } => "42703",
+            Error::TypeNotFound { .. } => "42704",
+            Error::InvalidArgument(_) => "22023",
+            Error::ColumnDecode { .. } | Error::Decode(_) | Error::Encode(_) => "22000",
+            Error::Io(_) => "08006",
+            Error::Tls(_) | Error::PoolTimedOut => "08001",
+            Error::PoolClosed => "08003",
+            Error::Protocol(_) => "08P01",
+            _ => "XX000",
+        }
+        .to_owned()
+    }
+
+    impl From<Error> for ErrorInfo {
+        fn from(e: Error) -> ErrorInfo {
+            ErrorInfo::new("ERROR".to_owned(), sqlstate(&e), e.to_string()).with_source(e)
+        }
+    }
+
+    impl From<Error> for PgWireError {
+        fn from(e: Error) -> PgWireError {
+            PgWireError::UserError(Box::new(e.into()))
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -189,4 +569,127 @@ mod test {
         assert_eq!("Password authentication failed", error_info.message);
         assert!(error_info.file_name.is_none());
     }
+
+    #[test]
+    fn test_error_chain_and_fatality() {
+        let io_error = std::io::Error::other("disk failure");
+        let error = PgWireError::UserError(Box::new(
+            ErrorInfo::new(
+                "ERROR".to_owned(),
+                "58030".to_owned(),
+                "io error".to_owned(),
+            )
+            .with_source(io_error),
+        ));
+        assert!(!error.is_fatal());
+        assert_eq!(3, error.chain().count());
+        assert_eq!("disk failure", error.chain().last().unwrap().to_string());
+
+        let mut error = error.fatal();
+        assert!(error.is_fatal());
+        assert_eq!(3, error.chain().count());
+        error.set_fatal(false);
+        assert!(!error.is_fatal());
+        assert!(matches!(error.into_inner(), PgWireError::UserError(_)));
+
+        assert!(PgWireError::InvalidStartupMessage.is_fatal());
+        assert!(!PgWireError::InvalidStartupMessage.non_fatal().is_fatal());
+    }
+
+    #[cfg(feature = "rusqlite")]
+    #[test]
+    fn test_rusqlite_error_info() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE t (id INTEGER PRIMARY KEY, name TEXT NOT NULL)")
+            .unwrap();
+        conn.execute("INSERT INTO t VALUES (1, 'a')", []).unwrap();
+
+        let code = |sql: &str| ErrorInfo::from(conn.execute(sql, []).unwrap_err()).code;
+        assert_eq!("23505", code("INSERT INTO t VALUES (1, 'b')"));
+        assert_eq!("23502", code("INSERT INTO t VALUES (2, NULL)"));
+        assert_eq!("42601", code("SELEC 1"));
+        assert_eq!("42P01", code("SELECT * FROM missing"));
+    }
+
+    #[cfg(feature = "sqlx")]
+    #[test]
+    fn test_sqlx_error_info() {
+        use std::borrow::Cow;
+
+        use sqlx::error::{DatabaseError, ErrorKind};
+
+        #[derive(Debug)]
+        struct TestDatabaseError(Option<&'static str>, ErrorKind);
+
+        impl std::fmt::Display for TestDatabaseError {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str(self.message())
+            }
+        }
+
+        impl std::error::Error for TestDatabaseError {}
+
+        impl DatabaseError for TestDatabaseError {
+            fn message(&self) -> &str {
+                "syntax error at or near \"SELEC\""
+            }
+
+            fn code(&self) -> Option<Cow<'_, str>> {
+                self.0.map(Cow::Borrowed)
+            }
+
+            fn as_error(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+                self
+            }
+
+            fn as_error_mut(&mut self) -> &mut (dyn std::error::Error + Send + Sync + 'static) {
+                self
+            }
+
+            fn into_error(self: Box<Self>) -> Box<dyn std::error::Error + Send + Sync + 'static> {
+                self
+            }
+
+            fn kind(&self) -> ErrorKind {
+                match self.1 {
+                    ErrorKind::UniqueViolation => ErrorKind::UniqueViolation,
+                    _ => ErrorKind::Other,
+                }
+            }
+        }
+
+        let code = |e: TestDatabaseError| ErrorInfo::from(sqlx::Error::Database(Box::new(e))).code;
+        // SQLSTATE reported by postgres and mysql
+        assert_eq!(
+            "42P01",
+            code(TestDatabaseError(Some("42P01"), ErrorKind::Other))
+        );
+        // sqlite result code
+        assert_eq!(
+            "23505",
+            code(TestDatabaseError(Some("2067"), ErrorKind::UniqueViolation))
+        );
+        assert_eq!("42601", code(TestDatabaseError(None, ErrorKind::Other)));
+        assert_eq!("P0002", ErrorInfo::from(sqlx::Error::RowNotFound).code);
+    }
+
+    #[cfg(any(feature = "rusqlite", feature = "duckdb", feature = "sqlx"))]
+    #[test]
+    fn test_sqlstate_from_message() {
+        assert_eq!(
+            "42P01",
+            sqlstate_from_message("Catalog Error: Table with name foo does not exist!")
+        );
+        assert_eq!(
+            "23505",
+            sqlstate_from_message(
+                "Constraint Error: Duplicate key \"id: 1\" violates primary key constraint"
+            )
+        );
+        assert_eq!(
+            "42601",
+            sqlstate_from_message("Parser Error: syntax error at end of input")
+        );
+        assert_eq!("XX000", sqlstate_from_message("something went wrong"));
+    }
 }
@@ -54,6 +54,14 @@
 //! - `server-api-ring` is almost same to `server-api-aws-lc-rs` except for it's
 //!   using `ring` as crypto backend.
 //! - `scram` for the SASL/SCRAM authenticator.
+//! - `client-cert` for identity extraction from TLS client certificates.
+//! - `sqlparser` for a `QueryParser` implementation backed by `sqlparser-rs`.
+//! - `serde` for `Serialize` and `Deserialize` implementations of messages.
+//! - `capture` for writing and reading captured wire messages as JSON lines.
+//! - `testing` for `arbitrary::Arbitrary` implementations of messages and
+//!   fuzz targets of the codec.
+//! - `rusqlite`, `duckdb` and `sqlx` for converting errors of these database
+//!   crates into `ErrorInfo` with postgres SQLSTATE codes.
 //! - Turn off default features if you just use our Protocol layer.
 //!
 //! ## Examples
@@ -72,6 +80,9 @@ pub mod api;
 pub mod error;
 /// the protocol layer.
 pub mod messages;
+/// mock frontend for testing handlers.
+#[cfg(feature = "server-api")]
+pub mod testkit;
 /// server entry-point for tokio based application.
 #[cfg(feature = "server-api")]
 pub mod tokio;
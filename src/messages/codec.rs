@@ -1,8 +1,9 @@
 use std::str;
 
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{Buf, BufMut, BytesMut, TryGetError};
 
-use crate::error::PgWireResult;
+use super::ByteStr;
+use crate::error::{PgWireError, PgWireResult};
 
 /// Get null-terminated string, returns None when empty cstring read.
 ///
@@ -10,6 +11,29 @@ use crate::error::PgWireResult;
 /// empty cstring. This behaviour works for how postgres wire protocol handling
 /// key-value pairs, which is ended by a single `\0`
 pub(crate) fn get_cstring(buf: &mut BytesMut) -> Option<String> {
+    split_cstring(buf).map(|s| String::from_utf8_lossy(&s).into_owned())
+}
+
+/// Get null-terminated string, and return error if it's not valid UTF-8.
+/// Returns `None` when empty cstring read, like `get_cstring`.
+pub(crate) fn get_utf8_cstring(buf: &mut BytesMut) -> PgWireResult<Option<String>> {
+    split_cstring(buf)
+        .map(|s| {
+            str::from_utf8(&s)
+                .map(|s| s.to_owned())
+                .map_err(PgWireError::InvalidUtf8String)
+        })
+        .transpose()
+}
+
+/// Like `get_utf8_cstring`, but slices the string from buf without copying.
+pub(crate) fn get_utf8_cstring_bytes(buf: &mut BytesMut) -> PgWireResult<Option<ByteStr>> {
+    split_cstring(buf)
+        .map(|s| ByteStr::from_utf8(s.freeze()).map_err(PgWireError::InvalidUtf8String))
+        .transpose()
+}
+
+fn split_cstring(buf: &mut BytesMut) -> Option<BytesMut> {
     let mut i = 0;
 
     // with bound check to prevent invalid format
@@ -18,14 +42,29 @@ pub(crate) fn get_cstring(buf: &mut BytesMut) -> Option<String> {
     }
 
     // i+1: include the '\0'
-    // move cursor to the end of cstring
-    let string_buf = buf.split_to(i + 1);
+    // move cursor to the end of cstring, the terminator can be missing in
+    // malformed message
+    let mut string_buf = buf.split_to((i + 1).min(buf.remaining()));
 
     if i == 0 {
         None
     } else {
-        Some(String::from_utf8_lossy(&string_buf[..i]).into_owned())
+        string_buf.truncate(i);
+        Some(string_buf)
+    }
+}
+
+/// Split `len` bytes from buf, or return error if there is no enough bytes
+pub(crate) fn try_split_to(buf: &mut BytesMut, len: usize) -> PgWireResult<BytesMut> {
+    if buf.remaining() < len {
+        return Err(TryGetError {
+            requested: len,
+            available: buf.remaining(),
+        }
+        .into());
     }
+
+    Ok(buf.split_to(len))
 }
 
 /// Put null-termianted string
@@ -55,6 +94,9 @@ pub(crate) fn get_length(buf: &BytesMut, offset: usize) -> Option<usize> {
 
 /// Check if message_length matches and move the cursor to right position then
 /// call the `decode_fn` for the body
+///
+/// The body given to `decode_fn` is limited to the message length, so a
+/// malformed message never reads into the next one.
 pub(crate) fn decode_packet<T, F>(
     buf: &mut BytesMut,
     offset: usize,
@@ -64,9 +106,14 @@ where
     F: Fn(&mut BytesMut, usize) -> PgWireResult<T>,
 {
     if let Some(msg_len) = get_length(buf, offset) {
+        if !(4..=i32::MAX as usize).contains(&msg_len) {
+            return Err(PgWireError::InvalidMessageLength(msg_len as i32));
+        }
+
         if buf.remaining() >= msg_len + offset {
             buf.advance(offset + 4);
-            return decode_fn(buf, msg_len).map(|r| Some(r));
+            let mut body = buf.split_to(msg_len - 4);
+            return decode_fn(&mut body, msg_len).map(|r| Some(r));
         }
     }
 
@@ -8,7 +8,10 @@ pub const MESSAGE_TYPE_BYTE_COPY_DATA: u8 = b'd';
 
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, Default, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CopyData {
+    #[cfg_attr(feature = "testing", arbitrary(with = super::testing::arbitrary_bytes))]
     pub data: Bytes,
 }
 
@@ -37,6 +40,8 @@ pub const MESSAGE_TYPE_BYTE_COPY_DONE: u8 = b'c';
 
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, Default, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CopyDone;
 
 impl Message for CopyDone {
@@ -62,6 +67,8 @@ pub const MESSAGE_TYPE_BYTE_COPY_FAIL: u8 = b'f';
 
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, Default, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CopyFail {
     pub message: String,
 }
@@ -91,6 +98,8 @@ pub const MESSAGE_TYPE_BYTE_COPY_IN_RESPONSE: u8 = b'G';
 
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, Default, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CopyInResponse {
     pub format: i8,
     pub columns: i16,
@@ -117,11 +126,11 @@ impl Message for CopyInResponse {
     }
 
     fn decode_body(buf: &mut BytesMut, _len: usize) -> PgWireResult<Self> {
-        let format = buf.get_i8();
-        let columns = buf.get_i16();
-        let mut column_formats = Vec::with_capacity(columns as usize);
+        let format = buf.try_get_i8()?;
+        let columns = buf.try_get_i16()?;
+        let mut column_formats = Vec::with_capacity(columns.max(0) as usize);
         for _ in 0..columns {
-            column_formats.push(buf.get_i16());
+            column_formats.push(buf.try_get_i16()?);
         }
 
         Ok(Self::new(format, columns, column_formats))
@@ -132,6 +141,8 @@ pub const MESSAGE_TYPE_BYTE_COPY_OUT_RESPONSE: u8 = b'H';
 
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, Default, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CopyOutResponse {
     pub format: i8,
     pub columns: i16,
@@ -158,11 +169,11 @@ impl Message for CopyOutResponse {
     }
 
     fn decode_body(buf: &mut BytesMut, _len: usize) -> PgWireResult<Self> {
-        let format = buf.get_i8();
-        let columns = buf.get_i16();
-        let mut column_formats = Vec::with_capacity(columns as usize);
+        let format = buf.try_get_i8()?;
+        let columns = buf.try_get_i16()?;
+        let mut column_formats = Vec::with_capacity(columns.max(0) as usize);
         for _ in 0..columns {
-            column_formats.push(buf.get_i16());
+            column_formats.push(buf.try_get_i16()?);
         }
 
         Ok(Self::new(format, columns, column_formats))
@@ -173,6 +184,8 @@ pub const MESSAGE_TYPE_BYTE_COPY_BOTH_RESPONSE: u8 = b'W';
 
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, Default, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CopyBothResponse {
     pub format: i8,
     pub columns: i16,
@@ -199,11 +212,11 @@ impl Message for CopyBothResponse {
     }
 
     fn decode_body(buf: &mut BytesMut, _len: usize) -> PgWireResult<Self> {
-        let format = buf.get_i8();
-        let columns = buf.get_i16();
-        let mut column_formats = Vec::with_capacity(columns as usize);
+        let format = buf.try_get_i8()?;
+        let columns = buf.try_get_i16()?;
+        let mut column_formats = Vec::with_capacity(columns.max(0) as usize);
         for _ in 0..columns {
-            column_formats.push(buf.get_i16());
+            column_formats.push(buf.try_get_i16()?);
         }
 
         Ok(Self::new(format, columns, column_formats))
@@ -9,6 +9,8 @@ pub const FORMAT_CODE_BINARY: i16 = 1;
 
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, Default, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FieldDescription {
     // the field name
     pub name: String,
@@ -28,6 +30,8 @@ pub struct FieldDescription {
 
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, Default, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RowDescription {
     pub fields: Vec<FieldDescription>,
 }
@@ -65,18 +69,18 @@ impl Message for RowDescription {
     }
 
     fn decode_body(buf: &mut BytesMut, _: usize) -> PgWireResult<Self> {
-        let fields_len = buf.get_i16();
-        let mut fields = Vec::with_capacity(fields_len as usize);
+        let fields_len = buf.try_get_i16()?;
+        let mut fields = Vec::with_capacity(fields_len.max(0) as usize);
 
         for _ in 0..fields_len {
             let field = FieldDescription {
                 name: codec::get_cstring(buf).unwrap_or_else(|| "".to_owned()),
-                table_id: buf.get_i32(),
-                column_id: buf.get_i16(),
-                type_id: buf.get_u32(),
-                type_size: buf.get_i16(),
-                type_modifier: buf.get_i32(),
-                format_code: buf.get_i16(),
+                table_id: buf.try_get_i32()?,
+                column_id: buf.try_get_i16()?,
+                type_id: buf.try_get_u32()?,
+                type_size: buf.try_get_i16()?,
+                type_modifier: buf.try_get_i32()?,
+                format_code: buf.try_get_i16()?,
             };
 
             fields.push(field);
@@ -89,6 +93,8 @@ impl Message for RowDescription {
 /// Data structure returned when frontend describes a statement
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, Default, new, Clone)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ParameterDescription {
     /// parameter types
     pub types: Vec<u32>,
@@ -116,11 +122,11 @@ impl Message for ParameterDescription {
     }
 
     fn decode_body(buf: &mut BytesMut, _: usize) -> PgWireResult<Self> {
-        let types_len = buf.get_u16();
+        let types_len = buf.try_get_u16()?;
         let mut types = Vec::with_capacity(types_len as usize);
 
         for _ in 0..types_len {
-            types.push(buf.get_i32() as u32);
+            types.push(buf.try_get_i32()? as u32);
         }
 
         Ok(ParameterDescription { types })
@@ -133,7 +139,10 @@ impl Message for ParameterDescription {
 /// codes from previous `RowDescription` message.
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, Default, new, Clone)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DataRow {
+    #[cfg_attr(feature = "testing", arbitrary(with = super::testing::arbitrary_bytes_mut))]
     pub data: BytesMut,
     pub field_count: i16,
 }
@@ -161,7 +170,7 @@ impl Message for DataRow {
     }
 
     fn decode_body(buf: &mut BytesMut, msg_len: usize) -> PgWireResult<Self> {
-        let field_count = buf.get_i16();
+        let field_count = buf.try_get_i16()?;
         // get body size from packet
         let data = buf.split_to(msg_len - 4 - 2);
 
@@ -173,6 +182,8 @@ impl Message for DataRow {
 /// in extended query
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, Default, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NoData;
 
 pub const MESSAGE_TYPE_BYTE_NO_DATA: u8 = b'n';
@@ -1,15 +1,26 @@
 use bytes::{Buf, BufMut, Bytes};
+use smallvec::SmallVec;
 
 use super::{codec, Message};
 use crate::error::PgWireResult;
 
+/// Parameter type oids of `Parse`, stored inline for up to 8 parameters
+pub type TypeOids = SmallVec<[u32; 8]>;
+/// Format codes of `Bind`, stored inline for up to 8 codes
+pub type FormatCodes = SmallVec<[i16; 8]>;
+/// Parameter values of `Bind`, stored inline for up to 8 parameters
+pub type Parameters = SmallVec<[Option<Bytes>; 8]>;
+
 /// Request from frontend to parse a prepared query string
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Parse {
     pub name: Option<String>,
     pub query: String,
-    pub type_oids: Vec<u32>,
+    #[new(into)]
+    pub type_oids: TypeOids,
 }
 
 pub const MESSAGE_TYPE_BYTE_PARSE: u8 = b'P';
@@ -40,12 +51,12 @@ impl Message for Parse {
 
     fn decode_body(buf: &mut bytes::BytesMut, _: usize) -> PgWireResult<Self> {
         let name = codec::get_cstring(buf);
-        let query = codec::get_cstring(buf).unwrap_or_else(|| "".to_owned());
-        let type_oid_count = buf.get_u16();
+        let query = codec::get_utf8_cstring(buf)?.unwrap_or_default();
+        let type_oid_count = buf.try_get_u16()?;
 
-        let mut type_oids = Vec::with_capacity(type_oid_count as usize);
+        let mut type_oids = TypeOids::with_capacity(type_oid_count as usize);
         for _ in 0..type_oid_count {
-            type_oids.push(buf.get_u32());
+            type_oids.push(buf.try_get_u32()?);
         }
 
         Ok(Parse {
@@ -59,6 +70,8 @@ impl Message for Parse {
 /// Response for Parse command, sent from backend to frontend
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ParseComplete;
 
 pub const MESSAGE_TYPE_BYTE_PARSE_COMPLETE: u8 = b'1';
@@ -88,6 +101,8 @@ impl Message for ParseComplete {
 /// Closing the prepared statement or portal
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Close {
     pub target_type: u8,
     pub name: Option<String>,
@@ -115,7 +130,7 @@ impl Message for Close {
     }
 
     fn decode_body(buf: &mut bytes::BytesMut, _: usize) -> PgWireResult<Self> {
-        let target_type = buf.get_u8();
+        let target_type = buf.try_get_u8()?;
         let name = codec::get_cstring(buf);
 
         Ok(Close { target_type, name })
@@ -125,6 +140,8 @@ impl Message for Close {
 /// Response for Close command, sent from backend to frontend
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CloseComplete;
 
 pub const MESSAGE_TYPE_BYTE_CLOSE_COMPLETE: u8 = b'3';
@@ -154,15 +171,21 @@ impl Message for CloseComplete {
 /// Bind command, for executing prepared statement
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Bind {
     pub portal_name: Option<String>,
     pub statement_name: Option<String>,
-    pub parameter_format_codes: Vec<i16>,
+    #[new(into)]
+    pub parameter_format_codes: FormatCodes,
     // None for Null data, TODO: consider wrapping this together with DataRow in
     // data.rs
-    pub parameters: Vec<Option<Bytes>>,
+    #[new(into)]
+    #[cfg_attr(feature = "testing", arbitrary(with = super::testing::arbitrary_parameters))]
+    pub parameters: Parameters,
 
-    pub result_column_format_codes: Vec<i16>,
+    #[new(into)]
+    pub result_column_format_codes: FormatCodes,
 }
 
 pub const MESSAGE_TYPE_BYTE_BIND: u8 = b'B';
@@ -214,30 +237,31 @@ impl Message for Bind {
         let portal_name = codec::get_cstring(buf);
         let statement_name = codec::get_cstring(buf);
 
-        let parameter_format_code_len = buf.get_u16();
-        let mut parameter_format_codes = Vec::with_capacity(parameter_format_code_len as usize);
+        let parameter_format_code_len = buf.try_get_u16()?;
+        let mut parameter_format_codes =
+            FormatCodes::with_capacity(parameter_format_code_len as usize);
 
         for _ in 0..parameter_format_code_len {
-            parameter_format_codes.push(buf.get_i16());
+            parameter_format_codes.push(buf.try_get_i16()?);
         }
 
-        let parameter_len = buf.get_u16();
-        let mut parameters = Vec::with_capacity(parameter_len as usize);
+        let parameter_len = buf.try_get_u16()?;
+        let mut parameters = Parameters::with_capacity(parameter_len as usize);
         for _ in 0..parameter_len {
-            let data_len = buf.get_i32();
+            let data_len = buf.try_get_i32()?;
 
             if data_len >= 0 {
-                parameters.push(Some(buf.split_to(data_len as usize).freeze()));
+                parameters.push(Some(codec::try_split_to(buf, data_len as usize)?.freeze()));
             } else {
                 parameters.push(None);
             }
         }
 
-        let result_column_format_code_len = buf.get_i16();
+        let result_column_format_code_len = buf.try_get_i16()?;
         let mut result_column_format_codes =
-            Vec::with_capacity(result_column_format_code_len as usize);
+            FormatCodes::with_capacity(result_column_format_code_len.max(0) as usize);
         for _ in 0..result_column_format_code_len {
-            result_column_format_codes.push(buf.get_i16());
+            result_column_format_codes.push(buf.try_get_i16()?);
         }
 
         Ok(Bind {
@@ -255,6 +279,8 @@ impl Message for Bind {
 /// Success response for `Bind`
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct BindComplete;
 
 pub const MESSAGE_TYPE_BYTE_BIND_COMPLETE: u8 = b'2';
@@ -285,6 +311,8 @@ impl Message for BindComplete {
 /// particular portal or statement
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Describe {
     pub target_type: u8,
     pub name: Option<String>,
@@ -309,7 +337,7 @@ impl Message for Describe {
     }
 
     fn decode_body(buf: &mut bytes::BytesMut, _: usize) -> PgWireResult<Self> {
-        let target_type = buf.get_u8();
+        let target_type = buf.try_get_u8()?;
         let name = codec::get_cstring(buf);
 
         Ok(Describe { target_type, name })
@@ -319,6 +347,8 @@ impl Message for Describe {
 /// Execute portal by its name
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Execute {
     pub name: Option<String>,
     pub max_rows: i32,
@@ -344,7 +374,7 @@ impl Message for Execute {
 
     fn decode_body(buf: &mut bytes::BytesMut, _: usize) -> PgWireResult<Self> {
         let name = codec::get_cstring(buf);
-        let max_rows = buf.get_i32();
+        let max_rows = buf.try_get_i32()?;
 
         Ok(Execute { name, max_rows })
     }
@@ -352,6 +382,8 @@ impl Message for Execute {
 
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Flush;
 
 pub const MESSAGE_TYPE_BYTE_FLUSH: u8 = b'H';
@@ -379,6 +411,8 @@ impl Message for Flush {
 /// Execute portal by its name
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Sync;
 
 pub const MESSAGE_TYPE_BYTE_SYNC: u8 = b'S';
@@ -405,6 +439,8 @@ impl Message for Sync {
 
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PortalSuspended;
 
 pub const MESSAGE_TYPE_BYTE_PORTAL_SUSPENDED: u8 = b's';
@@ -5,7 +5,7 @@
 //! types of supported messages. `Message` trait allows you to encode/decode
 //! them on a `BytesMut` buffer.
 
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 
 use crate::error::{PgWireError, PgWireResult};
 
@@ -31,12 +31,18 @@ pub trait Message: Sized {
     ///
     /// Message type and length are encoded in this implementation and it calls
     /// `encode_body` for remaining parts.
+    ///
+    /// Returns `MessageTooLarge` without writing anything if the message is
+    /// longer than `MAX_MESSAGE_LENGTH`.
     fn encode(&self, buf: &mut BytesMut) -> PgWireResult<()> {
+        let message_length = self.message_length();
+        check_message_length(message_length)?;
+
         if let Some(mt) = Self::message_type() {
             buf.put_u8(mt);
         }
 
-        buf.put_i32(self.message_length() as i32);
+        buf.put_i32(message_length as i32);
         self.encode_body(buf)
     }
 
@@ -54,6 +60,19 @@ pub trait Message: Sized {
     }
 }
 
+/// Max length of a message, 1GB like the max allocation size of postgres.
+pub const MAX_MESSAGE_LENGTH: usize = 0x3fff_ffff;
+
+/// Return `MessageTooLarge` error if `message_length` exceeds
+/// `MAX_MESSAGE_LENGTH`
+pub(crate) fn check_message_length(message_length: usize) -> PgWireResult<()> {
+    if message_length > MAX_MESSAGE_LENGTH {
+        Err(PgWireError::MessageTooLarge(message_length))
+    } else {
+        Ok(())
+    }
+}
+
 mod codec;
 /// Copy messages
 pub mod copy;
@@ -61,22 +80,38 @@ pub mod copy;
 pub mod data;
 /// Extended query messages, including request/response for parse, bind and etc.
 pub mod extendedquery;
+/// Human readable summary of messages
+pub mod pretty;
+/// Replication stream messages carried in `CopyData`
+pub mod replication;
 /// General response messages
 pub mod response;
 /// Simple query messages, including descriptions
 pub mod simplequery;
 /// Startup messages
 pub mod startup;
+/// Write large messages directly to `AsyncWrite`
+#[cfg(any(feature = "server-api", feature = "client-api"))]
+pub mod stream;
 /// Termination messages
 pub mod terminate;
+/// Arbitrary message generation and fuzz targets
+#[cfg(feature = "testing")]
+pub mod testing;
 
 /// Messages sent from Frontend
+// `Bind` stores its parameters inline to save allocations, which is the
+// purpose of the size difference
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PgWireFrontendMessage {
     Startup(startup::Startup),
     // when client has no ssl configured, it skip this message.
     // our decoder will return a `SslRequest(None)` for this case.
     SslRequest(Option<startup::SslRequest>),
+    CancelRequest(startup::CancelRequest),
     PasswordMessageFamily(startup::PasswordMessageFamily),
 
     Query(simplequery::Query),
@@ -94,6 +129,15 @@ pub enum PgWireFrontendMessage {
     CopyData(copy::CopyData),
     CopyFail(copy::CopyFail),
     CopyDone(copy::CopyDone),
+
+    /// Message with a type unknown to pgwire. `PgWireFrontendMessage::decode`
+    /// returns `InvalidMessageType` error for such messages, it's produced by
+    /// the server codec for vendor specific extensions.
+    Unknown(UnknownMessage),
+    /// Message that failed to decode and is skipped. It's produced by the
+    /// server codec in query phase, so the session can report the error and
+    /// continue with next message.
+    Invalid(InvalidMessage),
 }
 
 impl PgWireFrontendMessage {
@@ -120,6 +164,7 @@ impl PgWireFrontendMessage {
                     Ok(())
                 }
             }
+            Self::CancelRequest(msg) => msg.encode(buf),
             Self::PasswordMessageFamily(msg) => msg.encode(buf),
 
             Self::Query(msg) => msg.encode(buf),
@@ -137,6 +182,10 @@ impl PgWireFrontendMessage {
             Self::CopyData(msg) => msg.encode(buf),
             Self::CopyFail(msg) => msg.encode(buf),
             Self::CopyDone(msg) => msg.encode(buf),
+
+            Self::Unknown(msg) => msg.encode(buf),
+            // the raw message is discarded
+            Self::Invalid(_) => Ok(()),
         }
     }
 
@@ -199,8 +248,176 @@ impl PgWireFrontendMessage {
     }
 }
 
+/// UTF-8 string backed by `Bytes`.
+///
+/// Decoders slice it from the incoming buffer instead of copying into an
+/// owned `String`. It derefs to `str`, and converts into `String` when an
+/// owned value is required.
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ByteStr(Bytes);
+
+impl ByteStr {
+    /// Create from bytes, returns error if they are not valid UTF-8
+    pub fn from_utf8(bytes: Bytes) -> Result<ByteStr, std::str::Utf8Error> {
+        std::str::from_utf8(&bytes)?;
+        Ok(ByteStr(bytes))
+    }
+
+    pub const fn from_static(s: &'static str) -> ByteStr {
+        ByteStr(Bytes::from_static(s.as_bytes()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        // SAFETY: content is validated as UTF-8 on construction
+        unsafe { std::str::from_utf8_unchecked(&self.0) }
+    }
+
+    pub fn as_bytes(&self) -> &Bytes {
+        &self.0
+    }
+
+    pub fn into_bytes(self) -> Bytes {
+        self.0
+    }
+}
+
+impl std::ops::Deref for ByteStr {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl AsRef<str> for ByteStr {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl std::borrow::Borrow<str> for ByteStr {
+    fn borrow(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl std::fmt::Debug for ByteStr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl std::fmt::Display for ByteStr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(self.as_str(), f)
+    }
+}
+
+impl From<String> for ByteStr {
+    fn from(s: String) -> ByteStr {
+        ByteStr(Bytes::from(s))
+    }
+}
+
+impl From<&str> for ByteStr {
+    fn from(s: &str) -> ByteStr {
+        ByteStr(Bytes::copy_from_slice(s.as_bytes()))
+    }
+}
+
+impl From<ByteStr> for String {
+    fn from(s: ByteStr) -> String {
+        s.as_str().to_owned()
+    }
+}
+
+impl PartialEq<str> for ByteStr {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for ByteStr {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl PartialEq<String> for ByteStr {
+    fn eq(&self, other: &String) -> bool {
+        self.as_str() == other
+    }
+}
+
+#[cfg(feature = "testing")]
+impl<'a> arbitrary::Arbitrary<'a> for ByteStr {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        String::arbitrary(u).map(ByteStr::from)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for ByteStr {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for ByteStr {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(ByteStr::from)
+    }
+}
+
+/// A message of unknown type, with its raw body
+#[non_exhaustive]
+#[derive(PartialEq, Eq, Debug, Clone, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UnknownMessage {
+    pub message_type: u8,
+    #[cfg_attr(feature = "testing", arbitrary(with = testing::arbitrary_bytes))]
+    pub body: Bytes,
+}
+
+impl UnknownMessage {
+    pub fn encode(&self, buf: &mut BytesMut) -> PgWireResult<()> {
+        check_message_length(self.body.len() + 4)?;
+        buf.put_u8(self.message_type);
+        buf.put_i32((self.body.len() + 4) as i32);
+        buf.put_slice(&self.body);
+        Ok(())
+    }
+
+    /// Decode any typed message as `UnknownMessage`
+    pub fn decode(buf: &mut BytesMut) -> PgWireResult<Option<Self>> {
+        if buf.remaining() > 1 {
+            let message_type = buf[0];
+            codec::decode_packet(buf, 1, |body, _| {
+                Ok(UnknownMessage::new(message_type, body.split().freeze()))
+            })
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// A message skipped because it failed to decode
+#[non_exhaustive]
+#[derive(PartialEq, Eq, Debug, Clone, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct InvalidMessage {
+    pub message_type: u8,
+    /// Why the message failed to decode
+    pub reason: String,
+}
+
 /// Messages sent from Backend
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PgWireBackendMessage {
     // startup
     Authentication(startup::Authentication),
@@ -369,7 +586,8 @@ mod test {
     use super::simplequery::*;
     use super::startup::*;
     use super::terminate::*;
-    use super::Message;
+    use super::{check_message_length, Message, MAX_MESSAGE_LENGTH};
+    use crate::error::PgWireError;
     use bytes::{Buf, BufMut, Bytes, BytesMut};
 
     macro_rules! roundtrip {
@@ -429,6 +647,17 @@ mod test {
     fn test_query() {
         let query = Query::new("SELECT 1".to_owned());
         roundtrip!(query, Query);
+
+        // query text is sliced from the received buffer
+        let mut buffer = BytesMut::new();
+        query.encode(&mut buffer).unwrap();
+        let start = buffer.as_ptr() as usize;
+        let end = start + buffer.len();
+        let decoded = Query::decode(&mut buffer).unwrap().unwrap();
+        let ptr = decoded.query.as_ptr() as usize;
+        assert!(ptr >= start && ptr < end);
+        assert_eq!(decoded.query, "SELECT 1");
+        assert_eq!("SELECT 1", String::from(decoded.query));
     }
 
     #[test]
@@ -555,6 +784,14 @@ mod test {
             vec![0],
         );
         roundtrip!(bind, Bind);
+
+        // few parameters are decoded without heap allocation of vectors
+        let mut buffer = BytesMut::new();
+        bind.encode(&mut buffer).unwrap();
+        let decoded = Bind::decode(&mut buffer).unwrap().unwrap();
+        assert!(!decoded.parameter_format_codes.spilled());
+        assert!(!decoded.parameters.spilled());
+        assert!(!decoded.result_column_format_codes.spilled());
     }
 
     #[test]
@@ -581,6 +818,12 @@ mod test {
         roundtrip!(sslreq, SslRequest);
     }
 
+    #[test]
+    fn test_cancel_request() {
+        let cancel = CancelRequest::new(1234, 5678);
+        roundtrip!(cancel, CancelRequest);
+    }
+
     #[test]
     fn test_sslresponse() {
         let sslaccept = SslResponse::Accept;
@@ -640,6 +883,20 @@ mod test {
         roundtrip!(copydata, CopyData);
     }
 
+    #[test]
+    fn test_message_too_large() {
+        // the zeroed allocation is never touched
+        let copydata = CopyData::new(Bytes::from(vec![0u8; MAX_MESSAGE_LENGTH]));
+        let mut buffer = BytesMut::new();
+        assert!(matches!(
+            copydata.encode(&mut buffer),
+            Err(PgWireError::MessageTooLarge(len)) if len == MAX_MESSAGE_LENGTH + 4
+        ));
+        assert!(buffer.is_empty());
+
+        assert!(check_message_length(MAX_MESSAGE_LENGTH).is_ok());
+    }
+
     #[test]
     fn test_copy_done() {
         let copydone = CopyDone::new();
@@ -670,4 +927,28 @@ mod test {
             NotificationResponse::new(10087, "channel".to_owned(), "payload".to_owned());
         roundtrip!(notification_response, NotificationResponse);
     }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        use super::PgWireBackendMessage;
+
+        let messages =
+            vec![
+                PgWireBackendMessage::RowDescription(RowDescription::new(vec![
+                    FieldDescription::new("id".into(), 123, 1, 23, 4, -1, 0),
+                ])),
+                PgWireBackendMessage::DataRow(DataRow::new(BytesMut::from(&b"\0\0\0\x011"[..]), 1)),
+                PgWireBackendMessage::CommandComplete(CommandComplete::new("SELECT 1".to_owned())),
+                PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(TransactionStatus::Idle)),
+            ];
+        let json = serde_json::to_string(&messages).unwrap();
+        let decoded: Vec<PgWireBackendMessage> = serde_json::from_str(&json).unwrap();
+        assert_eq!(messages, decoded);
+
+        let query = Query::new("SELECT 1".to_owned());
+        let json = serde_json::to_string(&query).unwrap();
+        assert_eq!(r#"{"query":"SELECT 1"}"#, json);
+        assert_eq!(query, serde_json::from_str(&json).unwrap());
+    }
 }
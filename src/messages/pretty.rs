@@ -0,0 +1,293 @@
+//! Human readable summary of messages for logging.
+//!
+//! `Display` of `PgWireFrontendMessage` and `PgWireBackendMessage` prints
+//! message name and a short summary of its content. Queries are truncated,
+//! values and payloads are printed as sizes, and passwords, SASL data and
+//! cancel keys are never printed.
+
+use std::fmt::{self, Display, Formatter};
+
+use super::extendedquery::TARGET_TYPE_BYTE_STATEMENT;
+use super::startup::{Authentication, PasswordMessageFamily};
+use super::{PgWireBackendMessage, PgWireFrontendMessage};
+
+/// Max characters of query text printed
+pub const MAX_DISPLAY_QUERY_LENGTH: usize = 100;
+
+/// Query text truncated to `MAX_DISPLAY_QUERY_LENGTH` characters
+struct DisplayQuery<'a>(&'a str);
+
+impl Display for DisplayQuery<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.0.char_indices().nth(MAX_DISPLAY_QUERY_LENGTH) {
+            Some((end, _)) => write!(f, "{:?}...", &self.0[..end]),
+            None => write!(f, "{:?}", self.0),
+        }
+    }
+}
+
+fn name(name: &Option<String>) -> &str {
+    name.as_deref().unwrap_or_default()
+}
+
+fn target(target_type: u8) -> &'static str {
+    if target_type == TARGET_TYPE_BYTE_STATEMENT {
+        "statement"
+    } else {
+        "portal"
+    }
+}
+
+fn error_fields(f: &mut Formatter<'_>, kind: &str, fields: &[(u8, String)]) -> fmt::Result {
+    let field = |code: u8| {
+        fields
+            .iter()
+            .find(|(k, _)| *k == code)
+            .map(|(_, v)| v.as_str())
+            .unwrap_or_default()
+    };
+    write!(
+        f,
+        "{kind}({} {}: {})",
+        field(b'S'),
+        field(b'C'),
+        field(b'M')
+    )
+}
+
+impl Display for PgWireFrontendMessage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PgWireFrontendMessage::Startup(startup) => {
+                write!(
+                    f,
+                    "Startup({}.{}",
+                    startup.protocol_number_major, startup.protocol_number_minor
+                )?;
+                for (key, value) in &startup.parameters {
+                    write!(f, ", {key}={value}")?;
+                }
+                write!(f, ")")
+            }
+            PgWireFrontendMessage::SslRequest(Some(_)) => write!(f, "SslRequest"),
+            PgWireFrontendMessage::SslRequest(None) => write!(f, "SslRequest(skipped)"),
+            PgWireFrontendMessage::CancelRequest(cancel) => {
+                write!(f, "CancelRequest(pid={})", cancel.pid)
+            }
+            PgWireFrontendMessage::PasswordMessageFamily(pwd) => match pwd {
+                PasswordMessageFamily::Raw(data) => {
+                    write!(f, "PasswordMessage({} bytes)", data.len())
+                }
+                PasswordMessageFamily::Password(_) => write!(f, "Password(<redacted>)"),
+                PasswordMessageFamily::SASLInitialResponse(resp) => write!(
+                    f,
+                    "SASLInitialResponse({}, {} bytes)",
+                    resp.auth_method,
+                    resp.data.as_ref().map(|d| d.len()).unwrap_or_default()
+                ),
+                PasswordMessageFamily::SASLResponse(resp) => {
+                    write!(f, "SASLResponse({} bytes)", resp.data.len())
+                }
+            },
+            PgWireFrontendMessage::Query(query) => {
+                write!(f, "Query({})", DisplayQuery(&query.query))
+            }
+            PgWireFrontendMessage::Parse(parse) => write!(
+                f,
+                "Parse({:?}, {}, {} parameter types)",
+                name(&parse.name),
+                DisplayQuery(&parse.query),
+                parse.type_oids.len()
+            ),
+            PgWireFrontendMessage::Close(close) => write!(
+                f,
+                "Close({} {:?})",
+                target(close.target_type),
+                name(&close.name)
+            ),
+            PgWireFrontendMessage::Bind(bind) => write!(
+                f,
+                "Bind(portal {:?}, statement {:?}, {} parameters)",
+                name(&bind.portal_name),
+                name(&bind.statement_name),
+                bind.parameters.len()
+            ),
+            PgWireFrontendMessage::Describe(describe) => write!(
+                f,
+                "Describe({} {:?})",
+                target(describe.target_type),
+                name(&describe.name)
+            ),
+            PgWireFrontendMessage::Execute(execute) => write!(
+                f,
+                "Execute(portal {:?}, max_rows={})",
+                name(&execute.name),
+                execute.max_rows
+            ),
+            PgWireFrontendMessage::Flush(_) => write!(f, "Flush"),
+            PgWireFrontendMessage::Sync(_) => write!(f, "Sync"),
+            PgWireFrontendMessage::Terminate(_) => write!(f, "Terminate"),
+            PgWireFrontendMessage::CopyData(data) => {
+                write!(f, "CopyData({} bytes)", data.data.len())
+            }
+            PgWireFrontendMessage::CopyFail(fail) => write!(f, "CopyFail({:?})", fail.message),
+            PgWireFrontendMessage::CopyDone(_) => write!(f, "CopyDone"),
+            PgWireFrontendMessage::Unknown(unknown) => write!(
+                f,
+                "Unknown({:?}, {} bytes)",
+                unknown.message_type as char,
+                unknown.body.len()
+            ),
+            PgWireFrontendMessage::Invalid(invalid) => write!(
+                f,
+                "Invalid({:?}, {})",
+                invalid.message_type as char, invalid.reason
+            ),
+        }
+    }
+}
+
+impl Display for PgWireBackendMessage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PgWireBackendMessage::Authentication(auth) => match auth {
+                Authentication::Ok => write!(f, "Authentication(Ok)"),
+                Authentication::CleartextPassword => {
+                    write!(f, "Authentication(CleartextPassword)")
+                }
+                Authentication::KerberosV5 => write!(f, "Authentication(KerberosV5)"),
+                Authentication::MD5Password(_) => write!(f, "Authentication(MD5Password)"),
+                Authentication::SASL(mechanisms) => {
+                    write!(f, "Authentication(SASL {})", mechanisms.join(", "))
+                }
+                Authentication::SASLContinue(data) => {
+                    write!(f, "Authentication(SASLContinue, {} bytes)", data.len())
+                }
+                Authentication::SASLFinal(data) => {
+                    write!(f, "Authentication(SASLFinal, {} bytes)", data.len())
+                }
+            },
+            PgWireBackendMessage::ParameterStatus(status) => {
+                write!(f, "ParameterStatus({}={})", status.name, status.value)
+            }
+            PgWireBackendMessage::BackendKeyData(key) => {
+                write!(f, "BackendKeyData(pid={})", key.pid)
+            }
+            PgWireBackendMessage::ParseComplete(_) => write!(f, "ParseComplete"),
+            PgWireBackendMessage::CloseComplete(_) => write!(f, "CloseComplete"),
+            PgWireBackendMessage::BindComplete(_) => write!(f, "BindComplete"),
+            PgWireBackendMessage::PortalSuspended(_) => write!(f, "PortalSuspended"),
+            PgWireBackendMessage::CommandComplete(complete) => {
+                write!(f, "CommandComplete({})", complete.tag)
+            }
+            PgWireBackendMessage::EmptyQueryResponse(_) => write!(f, "EmptyQueryResponse"),
+            PgWireBackendMessage::ReadyForQuery(ready) => {
+                write!(f, "ReadyForQuery({:?})", ready.status)
+            }
+            PgWireBackendMessage::ErrorResponse(error) => {
+                error_fields(f, "ErrorResponse", &error.fields)
+            }
+            PgWireBackendMessage::NoticeResponse(notice) => {
+                error_fields(f, "NoticeResponse", &notice.fields)
+            }
+            PgWireBackendMessage::SslResponse(resp) => write!(f, "SslResponse({resp:?})"),
+            PgWireBackendMessage::NotificationResponse(notification) => write!(
+                f,
+                "NotificationResponse(pid={}, channel {:?}, {} bytes)",
+                notification.pid,
+                notification.channel,
+                notification.payload.len()
+            ),
+            PgWireBackendMessage::ParameterDescription(desc) => {
+                write!(f, "ParameterDescription({} types)", desc.types.len())
+            }
+            PgWireBackendMessage::RowDescription(desc) => {
+                write!(f, "RowDescription(")?;
+                for (i, field) in desc.fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", field.name)?;
+                }
+                write!(f, ")")
+            }
+            PgWireBackendMessage::DataRow(row) => write!(
+                f,
+                "DataRow({} fields, {} bytes)",
+                row.field_count,
+                row.data.len()
+            ),
+            PgWireBackendMessage::NoData(_) => write!(f, "NoData"),
+            PgWireBackendMessage::CopyData(data) => {
+                write!(f, "CopyData({} bytes)", data.data.len())
+            }
+            PgWireBackendMessage::CopyFail(fail) => write!(f, "CopyFail({:?})", fail.message),
+            PgWireBackendMessage::CopyDone(_) => write!(f, "CopyDone"),
+            PgWireBackendMessage::CopyInResponse(resp) => {
+                write!(f, "CopyInResponse({} columns)", resp.columns)
+            }
+            PgWireBackendMessage::CopyOutResponse(resp) => {
+                write!(f, "CopyOutResponse({} columns)", resp.columns)
+            }
+            PgWireBackendMessage::CopyBothResponse(resp) => {
+                write!(f, "CopyBothResponse({} columns)", resp.columns)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::messages::extendedquery::{
+        Bind, Describe, FormatCodes, Parameters, TARGET_TYPE_BYTE_PORTAL,
+    };
+    use crate::messages::response::ErrorResponse;
+    use crate::messages::simplequery::Query;
+    use crate::messages::startup::Password;
+
+    #[test]
+    fn test_display_messages() {
+        let query = PgWireFrontendMessage::Query(Query::new("SELECT 1".to_owned()));
+        assert_eq!("Query(\"SELECT 1\")", query.to_string());
+
+        let long_query = format!("SELECT '{}'", "x".repeat(200));
+        let query = PgWireFrontendMessage::Query(Query::new(long_query));
+        let display = query.to_string();
+        assert!(display.ends_with("...)"));
+        assert!(display.len() < 120);
+
+        let password = PgWireFrontendMessage::PasswordMessageFamily(
+            PasswordMessageFamily::Password(Password::new("secret".to_owned())),
+        );
+        assert!(!password.to_string().contains("secret"));
+
+        let bind = PgWireFrontendMessage::Bind(Bind::new(
+            None,
+            Some("s1".to_owned()),
+            FormatCodes::new(),
+            [Some(bytes::Bytes::from_static(b"1")), None]
+                .into_iter()
+                .collect::<Parameters>(),
+            FormatCodes::new(),
+        ));
+        assert_eq!(
+            "Bind(portal \"\", statement \"s1\", 2 parameters)",
+            bind.to_string()
+        );
+
+        let describe =
+            PgWireFrontendMessage::Describe(Describe::new(TARGET_TYPE_BYTE_PORTAL, None));
+        assert_eq!("Describe(portal \"\")", describe.to_string());
+
+        let error = PgWireBackendMessage::ErrorResponse(ErrorResponse::new(vec![
+            (b'S', "ERROR".to_owned()),
+            (b'C', "42P01".to_owned()),
+            (b'M', "relation \"t\" does not exist".to_owned()),
+        ]));
+        assert_eq!(
+            "ErrorResponse(ERROR 42P01: relation \"t\" does not exist)",
+            error.to_string()
+        );
+    }
+}
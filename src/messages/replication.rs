@@ -0,0 +1,304 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use super::codec;
+use super::copy::CopyData;
+use crate::error::{PgWireError, PgWireResult};
+
+/// Seconds between unix epoch and postgres epoch (2000-01-01)
+const PG_EPOCH_OFFSET_SECS: u64 = 946_684_800;
+
+/// Convert system time to postgres replication timestamp, which is
+/// microseconds since 2000-01-01.
+pub fn to_pg_timestamp(time: SystemTime) -> i64 {
+    let since_pg_epoch = time
+        .duration_since(UNIX_EPOCH + Duration::from_secs(PG_EPOCH_OFFSET_SECS))
+        .unwrap_or_default();
+    since_pg_epoch.as_micros() as i64
+}
+
+pub const MESSAGE_TYPE_BYTE_XLOG_DATA: u8 = b'w';
+
+/// WAL data sent from server, wrapped in `CopyData`.
+#[non_exhaustive]
+#[derive(PartialEq, Eq, Debug, Clone, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct XLogData {
+    pub wal_start: u64,
+    pub wal_end: u64,
+    pub send_time: i64,
+    #[cfg_attr(feature = "testing", arbitrary(with = super::testing::arbitrary_bytes))]
+    pub data: Bytes,
+}
+
+impl XLogData {
+    pub fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u8(MESSAGE_TYPE_BYTE_XLOG_DATA);
+        buf.put_u64(self.wal_start);
+        buf.put_u64(self.wal_end);
+        buf.put_i64(self.send_time);
+        buf.put_slice(&self.data);
+    }
+
+    pub fn decode(mut buf: Bytes) -> PgWireResult<Self> {
+        check_message(&buf, MESSAGE_TYPE_BYTE_XLOG_DATA, 25)?;
+        buf.advance(1);
+        let wal_start = buf.get_u64();
+        let wal_end = buf.get_u64();
+        let send_time = buf.get_i64();
+
+        Ok(XLogData {
+            wal_start,
+            wal_end,
+            send_time,
+            data: buf,
+        })
+    }
+}
+
+impl From<XLogData> for CopyData {
+    fn from(msg: XLogData) -> CopyData {
+        let mut buf = BytesMut::with_capacity(25 + msg.data.len());
+        msg.encode(&mut buf);
+        CopyData::new(buf.freeze())
+    }
+}
+
+pub const MESSAGE_TYPE_BYTE_PRIMARY_KEEPALIVE: u8 = b'k';
+
+/// Keepalive sent from server, wrapped in `CopyData`.
+#[non_exhaustive]
+#[derive(PartialEq, Eq, Debug, Clone, Copy, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PrimaryKeepalive {
+    pub wal_end: u64,
+    pub send_time: i64,
+    /// Ask client to reply with `StandbyStatusUpdate` as soon as possible
+    pub reply_requested: bool,
+}
+
+impl PrimaryKeepalive {
+    pub fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u8(MESSAGE_TYPE_BYTE_PRIMARY_KEEPALIVE);
+        buf.put_u64(self.wal_end);
+        buf.put_i64(self.send_time);
+        buf.put_u8(self.reply_requested as u8);
+    }
+
+    pub fn decode(mut buf: Bytes) -> PgWireResult<Self> {
+        check_message(&buf, MESSAGE_TYPE_BYTE_PRIMARY_KEEPALIVE, 18)?;
+        buf.advance(1);
+
+        Ok(PrimaryKeepalive {
+            wal_end: buf.get_u64(),
+            send_time: buf.get_i64(),
+            reply_requested: buf.get_u8() == 1,
+        })
+    }
+}
+
+impl From<PrimaryKeepalive> for CopyData {
+    fn from(msg: PrimaryKeepalive) -> CopyData {
+        let mut buf = BytesMut::with_capacity(18);
+        msg.encode(&mut buf);
+        CopyData::new(buf.freeze())
+    }
+}
+
+pub const MESSAGE_TYPE_BYTE_STANDBY_STATUS_UPDATE: u8 = b'r';
+
+/// Status update sent from client, wrapped in `CopyData`.
+#[non_exhaustive]
+#[derive(PartialEq, Eq, Debug, Clone, Copy, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StandbyStatusUpdate {
+    pub written: u64,
+    pub flushed: u64,
+    pub applied: u64,
+    pub client_time: i64,
+    /// Ask server to reply with a keepalive immediately
+    pub reply_requested: bool,
+}
+
+impl StandbyStatusUpdate {
+    pub fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u8(MESSAGE_TYPE_BYTE_STANDBY_STATUS_UPDATE);
+        buf.put_u64(self.written);
+        buf.put_u64(self.flushed);
+        buf.put_u64(self.applied);
+        buf.put_i64(self.client_time);
+        buf.put_u8(self.reply_requested as u8);
+    }
+
+    pub fn decode(mut buf: Bytes) -> PgWireResult<Self> {
+        check_message(&buf, MESSAGE_TYPE_BYTE_STANDBY_STATUS_UPDATE, 34)?;
+        buf.advance(1);
+
+        Ok(StandbyStatusUpdate {
+            written: buf.get_u64(),
+            flushed: buf.get_u64(),
+            applied: buf.get_u64(),
+            client_time: buf.get_i64(),
+            reply_requested: buf.get_u8() == 1,
+        })
+    }
+}
+
+impl From<StandbyStatusUpdate> for CopyData {
+    fn from(msg: StandbyStatusUpdate) -> CopyData {
+        let mut buf = BytesMut::with_capacity(34);
+        msg.encode(&mut buf);
+        CopyData::new(buf.freeze())
+    }
+}
+
+pub const MESSAGE_TYPE_BYTE_BACKUP_NEW_ARCHIVE: u8 = b'n';
+pub const MESSAGE_TYPE_BYTE_BACKUP_MANIFEST: u8 = b'm';
+pub const MESSAGE_TYPE_BYTE_BACKUP_DATA: u8 = b'd';
+pub const MESSAGE_TYPE_BYTE_BACKUP_PROGRESS: u8 = b'p';
+
+/// Messages of `BASE_BACKUP` CopyOut stream, wrapped in `CopyData`.
+#[non_exhaustive]
+#[derive(PartialEq, Eq, Debug, Clone)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BaseBackupMessage {
+    /// Start of a new archive, with archive name and tablespace location.
+    /// The location is empty for the main data directory.
+    NewArchive { name: String, location: String },
+    /// Start of the backup manifest
+    Manifest,
+    /// Content of current archive or manifest
+    Data(#[cfg_attr(feature = "testing", arbitrary(with = super::testing::arbitrary_bytes))] Bytes),
+    /// Total bytes of archives sent so far
+    Progress(i64),
+}
+
+impl BaseBackupMessage {
+    pub fn encode(&self, buf: &mut BytesMut) {
+        match self {
+            Self::NewArchive { name, location } => {
+                buf.put_u8(MESSAGE_TYPE_BYTE_BACKUP_NEW_ARCHIVE);
+                codec::put_cstring(buf, name);
+                codec::put_cstring(buf, location);
+            }
+            Self::Manifest => buf.put_u8(MESSAGE_TYPE_BYTE_BACKUP_MANIFEST),
+            Self::Data(data) => {
+                buf.put_u8(MESSAGE_TYPE_BYTE_BACKUP_DATA);
+                buf.put_slice(data);
+            }
+            Self::Progress(bytes) => {
+                buf.put_u8(MESSAGE_TYPE_BYTE_BACKUP_PROGRESS);
+                buf.put_i64(*bytes);
+            }
+        }
+    }
+
+    pub fn decode(mut buf: Bytes) -> PgWireResult<Self> {
+        if buf.is_empty() {
+            return Err(PgWireError::InvalidReplicationMessage);
+        }
+
+        match buf.get_u8() {
+            MESSAGE_TYPE_BYTE_BACKUP_NEW_ARCHIVE => {
+                // both strings are null-terminated, and location can be empty
+                let mut strings = buf.split(|b| *b == b'\0');
+                let mut next_string = || {
+                    strings
+                        .next()
+                        .map(|s| String::from_utf8_lossy(s).into_owned())
+                        .ok_or(PgWireError::InvalidReplicationMessage)
+                };
+                let name = next_string()?;
+                let location = next_string()?;
+                if !buf.ends_with(b"\0") {
+                    return Err(PgWireError::InvalidReplicationMessage);
+                }
+                Ok(Self::NewArchive { name, location })
+            }
+            MESSAGE_TYPE_BYTE_BACKUP_MANIFEST => Ok(Self::Manifest),
+            MESSAGE_TYPE_BYTE_BACKUP_DATA => Ok(Self::Data(buf)),
+            MESSAGE_TYPE_BYTE_BACKUP_PROGRESS if buf.remaining() >= 8 => {
+                Ok(Self::Progress(buf.get_i64()))
+            }
+            MESSAGE_TYPE_BYTE_BACKUP_PROGRESS => Err(PgWireError::InvalidReplicationMessage),
+            t => Err(PgWireError::InvalidMessageType(t)),
+        }
+    }
+}
+
+impl From<BaseBackupMessage> for CopyData {
+    fn from(msg: BaseBackupMessage) -> CopyData {
+        let mut buf = BytesMut::new();
+        msg.encode(&mut buf);
+        CopyData::new(buf.freeze())
+    }
+}
+
+fn check_message(buf: &Bytes, message_type: u8, min_len: usize) -> PgWireResult<()> {
+    match buf.first() {
+        Some(t) if *t != message_type => Err(PgWireError::InvalidMessageType(*t)),
+        Some(_) if buf.len() >= min_len => Ok(()),
+        _ => Err(PgWireError::InvalidReplicationMessage),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_replication_messages() {
+        let keepalive = PrimaryKeepalive::new(100, 42, true);
+        let data = CopyData::from(keepalive);
+        assert_eq!(keepalive, PrimaryKeepalive::decode(data.data).unwrap());
+
+        let status = StandbyStatusUpdate::new(3, 2, 1, 42, false);
+        let data = CopyData::from(status);
+        assert_eq!(
+            status,
+            StandbyStatusUpdate::decode(data.data.clone()).unwrap()
+        );
+        assert!(PrimaryKeepalive::decode(data.data).is_err());
+
+        let xlog = XLogData::new(1, 2, 42, Bytes::from_static(b"wal"));
+        let data = CopyData::from(xlog.clone());
+        assert_eq!(xlog, XLogData::decode(data.data).unwrap());
+
+        assert!(StandbyStatusUpdate::decode(Bytes::from_static(b"r")).is_err());
+    }
+
+    #[test]
+    fn test_base_backup_messages() {
+        let messages = vec![
+            BaseBackupMessage::NewArchive {
+                name: "base.tar".to_owned(),
+                location: "".to_owned(),
+            },
+            BaseBackupMessage::Data(Bytes::from_static(b"tar")),
+            BaseBackupMessage::Progress(1024),
+            BaseBackupMessage::Manifest,
+        ];
+
+        for msg in messages {
+            let data = CopyData::from(msg.clone());
+            assert_eq!(msg, BaseBackupMessage::decode(data.data).unwrap());
+        }
+
+        assert!(BaseBackupMessage::decode(Bytes::from_static(b"p1")).is_err());
+    }
+
+    #[test]
+    fn test_pg_timestamp() {
+        let pg_epoch = UNIX_EPOCH + Duration::from_secs(PG_EPOCH_OFFSET_SECS);
+        assert_eq!(0, to_pg_timestamp(pg_epoch));
+        assert_eq!(
+            1_000_001,
+            to_pg_timestamp(pg_epoch + Duration::from_micros(1_000_001))
+        );
+    }
+}
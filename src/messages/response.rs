@@ -6,6 +6,8 @@ use crate::error::{PgWireError, PgWireResult};
 
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CommandComplete {
     pub tag: String,
 }
@@ -37,6 +39,8 @@ impl Message for CommandComplete {
 
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct EmptyQueryResponse;
 
 pub const MESSAGE_TYPE_BYTE_EMPTY_QUERY_RESPONSE: u8 = b'I';
@@ -61,11 +65,15 @@ impl Message for EmptyQueryResponse {
 
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ReadyForQuery {
     pub status: TransactionStatus,
 }
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
 pub enum TransactionStatus {
     Idle = READY_STATUS_IDLE,
@@ -97,7 +105,7 @@ impl Message for ReadyForQuery {
     }
 
     fn decode_body(buf: &mut BytesMut, _: usize) -> PgWireResult<Self> {
-        let status = TransactionStatus::try_from(buf.get_u8())?;
+        let status = TransactionStatus::try_from(buf.try_get_u8()?)?;
         Ok(ReadyForQuery::new(status))
     }
 }
@@ -117,6 +125,8 @@ impl TryFrom<u8> for TransactionStatus {
 /// postgres error response, sent from backend to frontend
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, Default, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ErrorResponse {
     pub fields: Vec<(u8, String)>,
 }
@@ -147,7 +157,7 @@ impl Message for ErrorResponse {
     fn decode_body(buf: &mut BytesMut, _: usize) -> PgWireResult<Self> {
         let mut fields = Vec::new();
         loop {
-            let code = buf.get_u8();
+            let code = buf.try_get_u8()?;
 
             if code == b'\0' {
                 return Ok(ErrorResponse { fields });
@@ -162,6 +172,8 @@ impl Message for ErrorResponse {
 /// postgres error response, sent from backend to frontend
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, Default, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NoticeResponse {
     pub fields: Vec<(u8, String)>,
 }
@@ -192,7 +204,7 @@ impl Message for NoticeResponse {
     fn decode_body(buf: &mut BytesMut, _: usize) -> PgWireResult<Self> {
         let mut fields = Vec::new();
         loop {
-            let code = buf.get_u8();
+            let code = buf.try_get_u8()?;
 
             if code == b'\0' {
                 return Ok(NoticeResponse { fields });
@@ -204,6 +216,24 @@ impl Message for NoticeResponse {
     }
 }
 
+/// Error response of protocol 2.0, which is a single null-terminated message.
+///
+/// pgwire doesn't support protocol 2.0, this is only used to reject clients
+/// of the old protocol with an error they can read.
+#[non_exhaustive]
+#[derive(PartialEq, Eq, Debug, new)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ErrorResponseV2 {
+    pub message: String,
+}
+
+impl ErrorResponseV2 {
+    pub fn encode(&self, buf: &mut BytesMut) {
+        buf.put_u8(MESSAGE_TYPE_BYTE_ERROR_RESPONSE);
+        codec::put_cstring(buf, &self.message);
+    }
+}
+
 /// Response to SSLRequest.
 ///
 /// To initiate an SSL-encrypted connection, the frontend initially sends an
@@ -212,6 +242,8 @@ impl Message for NoticeResponse {
 /// unwilling to perform SSL, respectively.
 #[non_exhaustive]
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum SslResponse {
     Accept,
     Refuse,
@@ -267,6 +299,8 @@ impl Message for SslResponse {
 /// NotificationResponse
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, Default, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct NotificationResponse {
     pub pid: i32,
     pub channel: String,
@@ -294,7 +328,7 @@ impl Message for NotificationResponse {
     }
 
     fn decode_body(buf: &mut BytesMut, _: usize) -> PgWireResult<Self> {
-        let pid = buf.get_i32();
+        let pid = buf.try_get_i32()?;
         let channel = codec::get_cstring(buf).unwrap_or_else(|| "".to_owned());
         let payload = codec::get_cstring(buf).unwrap_or_else(|| "".to_owned());
 
@@ -1,14 +1,18 @@
 use bytes::BytesMut;
 
 use super::codec;
-use super::Message;
+use super::{ByteStr, Message};
 use crate::error::PgWireResult;
 
 /// A sql query sent from frontend to backend.
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Query {
-    pub query: String,
+    /// Query text, sliced from the received message without copying
+    #[new(into)]
+    pub query: ByteStr,
 }
 
 pub const MESSAGE_TYPE_BYTE_QUERY: u8 = b'Q';
@@ -30,7 +34,7 @@ impl Message for Query {
     }
 
     fn decode_body(buf: &mut BytesMut, _: usize) -> PgWireResult<Self> {
-        let query = codec::get_cstring(buf).unwrap_or_else(|| "".to_owned());
+        let query = codec::get_utf8_cstring_bytes(buf)?.unwrap_or_default();
 
         Ok(Query::new(query))
     }
@@ -9,6 +9,8 @@ use crate::error::{PgWireError, PgWireResult};
 /// Postgresql wire protocol startup message.
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Startup {
     #[new(value = "3")]
     pub protocol_number_major: u16,
@@ -82,8 +84,8 @@ impl Message for Startup {
         }
 
         // parse
-        let protocol_number_major = buf.get_u16();
-        let protocol_number_minor = buf.get_u16();
+        let protocol_number_major = buf.try_get_u16()?;
+        let protocol_number_minor = buf.try_get_u16()?;
 
         // end by reading the last \0
         let mut parameters = BTreeMap::new();
@@ -103,22 +105,29 @@ impl Message for Startup {
 /// authentication response family, sent by backend
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Authentication {
     Ok,                   // code 0
     CleartextPassword,    // code 3
     KerberosV5,           // code 2
     MD5Password(Vec<u8>), // code 5, with 4 bytes of md5 salt
 
-    SASL(Vec<String>),   // code 10, with server supported sasl mechanisms
-    SASLContinue(Bytes), // code 11, with authentication data
-    SASLFinal(Bytes),    // code 12, with additional authentication data
-
-                         // TODO: more types
-                         // AuthenticationSCMCredential
-                         //
-                         // AuthenticationGSS
-                         // AuthenticationGSSContinue
-                         // AuthenticationSSPI
+    SASL(Vec<String>), // code 10, with server supported sasl mechanisms
+    // code 11, with authentication data
+    SASLContinue(
+        #[cfg_attr(feature = "testing", arbitrary(with = super::testing::arbitrary_bytes))] Bytes,
+    ),
+    // code 12, with additional authentication data
+    SASLFinal(
+        #[cfg_attr(feature = "testing", arbitrary(with = super::testing::arbitrary_bytes))] Bytes,
+    ),
+    // TODO: more types
+    // AuthenticationSCMCredential
+    //
+    // AuthenticationGSS
+    // AuthenticationGSSContinue
+    // AuthenticationSSPI
 }
 
 pub const MESSAGE_TYPE_BYTE_AUTHENTICATION: u8 = b'R';
@@ -173,7 +182,7 @@ impl Message for Authentication {
     }
 
     fn decode_body(buf: &mut BytesMut, msg_len: usize) -> PgWireResult<Self> {
-        let code = buf.get_i32();
+        let code = buf.try_get_i32()?;
         let msg = match code {
             0 => Authentication::Ok,
             2 => Authentication::KerberosV5,
@@ -219,9 +228,14 @@ pub const MESSAGE_TYPE_BYTE_PASWORD_MESSAGE_FAMILY: u8 = b'p';
 /// `into_password`/`into_sasl_initial_response`/... methods to them
 #[non_exhaustive]
 #[derive(Debug)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PasswordMessageFamily {
     /// The type of message is unknown.
-    Raw(BytesMut),
+    Raw(
+        #[cfg_attr(feature = "testing", arbitrary(with = super::testing::arbitrary_bytes_mut))]
+        BytesMut,
+    ),
     /// Password message
     Password(Password),
     /// SASLInitialResponse
@@ -318,6 +332,8 @@ impl PasswordMessageFamily {
 /// password packet sent from frontend
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Password {
     pub password: String,
 }
@@ -348,6 +364,8 @@ impl Message for Password {
 /// parameter ack sent from backend after authentication success
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ParameterStatus {
     pub name: String,
     pub value: String,
@@ -384,6 +402,8 @@ impl Message for ParameterStatus {
 /// `CancelRequestMessage`
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct BackendKeyData {
     pub pid: i32,
     pub secret_key: i32,
@@ -410,8 +430,8 @@ impl Message for BackendKeyData {
     }
 
     fn decode_body(buf: &mut BytesMut, _: usize) -> PgWireResult<Self> {
-        let pid = buf.get_i32();
-        let secret_key = buf.get_i32();
+        let pid = buf.try_get_i32()?;
+        let secret_key = buf.try_get_i32()?;
 
         Ok(BackendKeyData { pid, secret_key })
     }
@@ -426,11 +446,18 @@ impl Message for BackendKeyData {
 /// connection.
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SslRequest;
 
 impl SslRequest {
     pub const BODY_MAGIC_NUMBER: i32 = 80877103;
     pub const BODY_SIZE: usize = 8;
+
+    /// Check if the packet in buffer is a `SslRequest`.
+    pub fn is_ssl_request_packet(buf: &[u8]) -> bool {
+        buf.len() >= 8 && (&buf[4..8]).get_i32() == Self::BODY_MAGIC_NUMBER
+    }
 }
 
 impl Message for SslRequest {
@@ -464,10 +491,70 @@ impl Message for SslRequest {
     }
 }
 
+/// `CancelRequest` message, sent from frontend on a new connection to cancel
+/// query running on another connection, identified by its `BackendKeyData`.
+#[non_exhaustive]
+#[derive(PartialEq, Eq, Debug, Clone, Copy, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CancelRequest {
+    pub pid: i32,
+    pub secret_key: i32,
+}
+
+impl CancelRequest {
+    pub const BODY_MAGIC_NUMBER: i32 = 80877102;
+    pub const BODY_SIZE: usize = 16;
+
+    /// Check if the packet in buffer is a `CancelRequest`.
+    pub fn is_cancel_request_packet(buf: &[u8]) -> bool {
+        buf.len() >= 8 && (&buf[4..8]).get_i32() == Self::BODY_MAGIC_NUMBER
+    }
+}
+
+impl Message for CancelRequest {
+    #[inline]
+    fn message_type() -> Option<u8> {
+        None
+    }
+
+    #[inline]
+    fn message_length(&self) -> usize {
+        Self::BODY_SIZE
+    }
+
+    fn encode_body(&self, buf: &mut BytesMut) -> PgWireResult<()> {
+        buf.put_i32(Self::BODY_MAGIC_NUMBER);
+        buf.put_i32(self.pid);
+        buf.put_i32(self.secret_key);
+        Ok(())
+    }
+
+    fn decode(buf: &mut BytesMut) -> PgWireResult<Option<Self>> {
+        codec::decode_packet(buf, 0, Self::decode_body)
+    }
+
+    fn decode_body(buf: &mut BytesMut, msg_len: usize) -> PgWireResult<Self> {
+        if msg_len != Self::BODY_SIZE {
+            return Err(PgWireError::InvalidStartupMessage);
+        }
+
+        // magic number
+        buf.try_get_i32()?;
+        let pid = buf.try_get_i32()?;
+        let secret_key = buf.try_get_i32()?;
+
+        Ok(CancelRequest { pid, secret_key })
+    }
+}
+
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SASLInitialResponse {
     pub auth_method: String,
+    #[cfg_attr(feature = "testing", arbitrary(with = super::testing::arbitrary_option_bytes))]
     pub data: Option<Bytes>,
 }
 
@@ -495,11 +582,11 @@ impl Message for SASLInitialResponse {
 
     fn decode_body(buf: &mut BytesMut, _full_len: usize) -> PgWireResult<Self> {
         let auth_method = codec::get_cstring(buf).unwrap_or_else(|| "".to_owned());
-        let data_len = buf.get_i32();
+        let data_len = buf.try_get_i32()?;
         let data = if data_len == -1 {
             None
         } else {
-            Some(buf.split_to(data_len as usize).freeze())
+            Some(codec::try_split_to(buf, data_len as usize)?.freeze())
         };
 
         Ok(SASLInitialResponse { auth_method, data })
@@ -508,7 +595,10 @@ impl Message for SASLInitialResponse {
 
 #[non_exhaustive]
 #[derive(PartialEq, Eq, Debug, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SASLResponse {
+    #[cfg_attr(feature = "testing", arbitrary(with = super::testing::arbitrary_bytes))]
     pub data: Bytes,
 }
 
@@ -0,0 +1,133 @@
+//! Write messages directly to an `AsyncWrite`.
+//!
+//! `Message::encode` copies the whole message into a `BytesMut` before it's
+//! flushed. For `CopyData` and `DataRow` carrying multi-megabyte values this
+//! doubles the memory usage, so these functions write the header and then the
+//! payload from its own buffer, or from an `AsyncRead` when the payload is not
+//! in memory at all.
+//!
+//! When used on a connection wrapped in `Framed`, flush the sink first and
+//! write to `Framed::get_mut`, so messages are not reordered.
+
+use bytes::{BufMut, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::copy::MESSAGE_TYPE_BYTE_COPY_DATA;
+use super::data::MESSAGE_TYPE_BYTE_DATA_ROW;
+use super::{check_message_length, Message, PgWireBackendMessage};
+use crate::error::{PgWireError, PgWireResult};
+
+impl PgWireBackendMessage {
+    /// Write this message to `writer`.
+    ///
+    /// Payload of `CopyData` and `DataRow` is written from the message buffer
+    /// without copy, other messages are encoded as usual.
+    pub async fn write_to<W>(&self, writer: &mut W) -> PgWireResult<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        match self {
+            PgWireBackendMessage::CopyData(copy_data) => {
+                let mut header = BytesMut::with_capacity(5);
+                put_header(
+                    &mut header,
+                    MESSAGE_TYPE_BYTE_COPY_DATA,
+                    copy_data.message_length(),
+                )?;
+                writer.write_all(&header).await?;
+                writer.write_all(&copy_data.data).await?;
+            }
+            PgWireBackendMessage::DataRow(row) => {
+                let mut header = BytesMut::with_capacity(7);
+                put_header(
+                    &mut header,
+                    MESSAGE_TYPE_BYTE_DATA_ROW,
+                    row.message_length(),
+                )?;
+                header.put_i16(row.field_count);
+                writer.write_all(&header).await?;
+                writer.write_all(&row.data).await?;
+            }
+            message => {
+                let mut buf = BytesMut::new();
+                message.encode(&mut buf)?;
+                writer.write_all(&buf).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Write a `CopyData` message with `len` bytes of payload read from `body`.
+///
+/// The payload is streamed through a small buffer, so it's never held in
+/// memory entirely. Returns `IoError` of `UnexpectedEof` if `body` ends
+/// before `len` bytes, the connection is broken in that case because the
+/// message header is already written.
+pub async fn write_copy_data_from<W, R>(
+    writer: &mut W,
+    len: usize,
+    body: &mut R,
+) -> PgWireResult<()>
+where
+    W: AsyncWrite + Unpin,
+    R: AsyncRead + Unpin,
+{
+    let mut header = BytesMut::with_capacity(5);
+    put_header(&mut header, MESSAGE_TYPE_BYTE_COPY_DATA, 4 + len)?;
+    writer.write_all(&header).await?;
+
+    let copied = tokio::io::copy(&mut body.take(len as u64), writer).await?;
+    if copied < len as u64 {
+        return Err(PgWireError::IoError(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!("CopyData body ended after {copied} of {len} bytes"),
+        )));
+    }
+
+    Ok(())
+}
+
+fn put_header(buf: &mut BytesMut, message_type: u8, message_length: usize) -> PgWireResult<()> {
+    check_message_length(message_length)?;
+    buf.put_u8(message_type);
+    buf.put_i32(message_length as i32);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::messages::copy::CopyData;
+    use crate::messages::data::DataRow;
+    use crate::messages::response::CommandComplete;
+
+    #[tokio::test]
+    async fn test_write_to() {
+        let messages = vec![
+            PgWireBackendMessage::DataRow(DataRow::new(BytesMut::from(&[0u8; 1000][..]), 1)),
+            PgWireBackendMessage::CopyData(CopyData::new(bytes::Bytes::from(vec![1u8; 1000]))),
+            PgWireBackendMessage::CommandComplete(CommandComplete::new("COPY 1".to_owned())),
+        ];
+
+        let mut written = Vec::new();
+        let mut encoded = BytesMut::new();
+        for message in &messages {
+            message.write_to(&mut written).await.unwrap();
+            message.encode(&mut encoded).unwrap();
+        }
+        assert_eq!(&encoded[..], &written[..]);
+
+        let mut written = Vec::new();
+        write_copy_data_from(&mut written, 1000, &mut &[1u8; 2000][..])
+            .await
+            .unwrap();
+        let mut encoded = BytesMut::new();
+        messages[1].encode(&mut encoded).unwrap();
+        assert_eq!(&encoded[..], &written[..]);
+
+        let result = write_copy_data_from(&mut Vec::new(), 1000, &mut &[1u8; 10][..]).await;
+        assert!(matches!(result, Err(PgWireError::IoError(_))));
+    }
+}
@@ -3,6 +3,8 @@ use crate::error::PgWireResult;
 
 #[non_exhaustive]
 #[derive(Default, PartialEq, Eq, Debug, new)]
+#[cfg_attr(feature = "testing", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Terminate;
 
 pub const MESSAGE_TYPE_BYTE_TERMINATE: u8 = b'X';
@@ -0,0 +1,143 @@
+//! Property testing and fuzzing support, enabled by `testing` feature.
+//!
+//! All message types implement `arbitrary::Arbitrary` with this feature, and
+//! functions in this module can be used as fuzz targets, for example with
+//! `cargo fuzz`:
+//!
+//! ```ignore
+//! fuzz_target!(|data: &[u8]| {
+//!     pgwire::messages::testing::fuzz_decode(data);
+//! });
+//! ```
+
+use arbitrary::{Arbitrary, Result, Unstructured};
+use bytes::{Bytes, BytesMut};
+
+use super::replication::{BaseBackupMessage, PrimaryKeepalive, StandbyStatusUpdate, XLogData};
+use super::startup::{CancelRequest, PasswordMessageFamily, SslRequest, Startup};
+use super::{Message, PgWireBackendMessage, PgWireFrontendMessage};
+
+pub(crate) fn arbitrary_bytes(u: &mut Unstructured<'_>) -> Result<Bytes> {
+    Vec::<u8>::arbitrary(u).map(Bytes::from)
+}
+
+pub(crate) fn arbitrary_bytes_mut(u: &mut Unstructured<'_>) -> Result<BytesMut> {
+    <&[u8]>::arbitrary(u).map(BytesMut::from)
+}
+
+pub(crate) fn arbitrary_option_bytes(u: &mut Unstructured<'_>) -> Result<Option<Bytes>> {
+    Option::<Vec<u8>>::arbitrary(u).map(|v| v.map(Bytes::from))
+}
+
+pub(crate) fn arbitrary_parameters(
+    u: &mut Unstructured<'_>,
+) -> Result<super::extendedquery::Parameters> {
+    u.arbitrary_iter::<Option<Vec<u8>>>()?
+        .map(|v| v.map(|v| v.map(Bytes::from)))
+        .collect()
+}
+
+/// Decode `data` with all decoders of this crate, as frontend messages,
+/// backend messages and payloads of replication streams.
+///
+/// Decoding errors are expected for malformed input, only panics or hangs are
+/// bugs.
+pub fn fuzz_decode(data: &[u8]) {
+    // startup packets have no message type
+    let _ = Startup::decode(&mut BytesMut::from(data));
+    let _ = SslRequest::decode(&mut BytesMut::from(data));
+    let _ = CancelRequest::decode(&mut BytesMut::from(data));
+
+    decode_all(data, PgWireFrontendMessage::decode);
+    decode_all(data, PgWireBackendMessage::decode);
+
+    // password message family is decoded in context of authentication
+    if let Ok(Some(PgWireFrontendMessage::PasswordMessageFamily(PasswordMessageFamily::Raw(
+        body,
+    )))) = PgWireFrontendMessage::decode(&mut BytesMut::from(data))
+    {
+        let _ = PasswordMessageFamily::Raw(body.clone()).into_password();
+        let _ = PasswordMessageFamily::Raw(body.clone()).into_sasl_initial_response();
+        let _ = PasswordMessageFamily::Raw(body).into_sasl_response();
+    }
+
+    let payload = Bytes::copy_from_slice(data);
+    let _ = XLogData::decode(payload.clone());
+    let _ = PrimaryKeepalive::decode(payload.clone());
+    let _ = StandbyStatusUpdate::decode(payload.clone());
+    let _ = BaseBackupMessage::decode(payload);
+}
+
+/// Encode an arbitrary frontend message and decode it back.
+///
+/// Note that arbitrary messages may not be valid in protocol, so the decoded
+/// message may differ from the original one.
+pub fn fuzz_roundtrip_frontend(message: &PgWireFrontendMessage) {
+    let mut buf = BytesMut::new();
+    if message.encode(&mut buf).is_ok() {
+        decode_all(&buf, PgWireFrontendMessage::decode);
+    }
+}
+
+/// Encode an arbitrary backend message and decode it back.
+///
+/// Note that arbitrary messages may not be valid in protocol, so the decoded
+/// message may differ from the original one.
+pub fn fuzz_roundtrip_backend(message: &PgWireBackendMessage) {
+    let mut buf = BytesMut::new();
+    if message.encode(&mut buf).is_ok() {
+        decode_all(&buf, PgWireBackendMessage::decode);
+    }
+}
+
+fn decode_all<T, F>(data: &[u8], decode: F)
+where
+    F: Fn(&mut BytesMut) -> crate::error::PgWireResult<Option<T>>,
+{
+    let mut buf = BytesMut::from(data);
+    while let Ok(Some(_)) = decode(&mut buf) {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::messages::copy::CopyData;
+
+    #[test]
+    fn test_fuzz_entry_points() {
+        // a deterministic set of pseudo random inputs
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        let mut input = vec![0u8; 512];
+        for _ in 0..256 {
+            for b in input.iter_mut() {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                *b = seed as u8;
+            }
+
+            let mut u = Unstructured::new(&input);
+            if let Ok(msg) = PgWireFrontendMessage::arbitrary(&mut u) {
+                fuzz_roundtrip_frontend(&msg);
+            }
+            let mut u = Unstructured::new(&input);
+            if let Ok(msg) = PgWireBackendMessage::arbitrary(&mut u) {
+                fuzz_roundtrip_backend(&msg);
+            }
+            let _ = CopyData::arbitrary(&mut Unstructured::new(&input));
+            fuzz_decode(&input);
+            // random body with valid message type and length
+            for t in b"RSKZEDTtnpCIcfdGHWN1234sAkrwQPBXHFv" {
+                let mut framed = input[..(seed as usize % 64 + 5)].to_vec();
+                framed.insert(0, *t);
+                let len = (framed.len() as i32 - 1).to_be_bytes();
+                framed[1..5].copy_from_slice(&len);
+                fuzz_decode(&framed);
+            }
+        }
+
+        fuzz_decode(b"");
+        fuzz_decode(b"p\x00\x00\x00\x08abc");
+        fuzz_decode(b"n\x00\x00\x00\x04");
+    }
+}
@@ -0,0 +1,697 @@
+//! A scripted mock frontend for testing handler implementations.
+//!
+//! `MockClient` runs your `PgWireServerHandlers` on an in-memory duplex
+//! stream, so you can send any sequence of frontend messages and assert on
+//! the exact backend messages, without a real postgres client or network.
+//!
+//! ```no_run
+//! # use pgwire::api::PgWireServerHandlers;
+//! # use pgwire::testkit::MockClient;
+//! # async fn test<H: PgWireServerHandlers + Send + Sync + 'static>(handlers: H) {
+//! let mut client = MockClient::start(handlers);
+//! client.startup("postgres", None).await.unwrap();
+//! let messages = client.simple_query("SELECT 1").await.unwrap();
+//! client.terminate().await.unwrap();
+//! # }
+//! ```
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncWriteExt, DuplexStream};
+use tokio::task::JoinHandle;
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+use crate::api::capture::{CaptureDirection, CaptureRecord};
+use crate::api::config::ServerConfig;
+use crate::api::connection::ConnectionSummary;
+use crate::api::PgWireServerHandlers;
+use crate::error::{PgWireError, PgWireResult};
+use crate::messages::simplequery::Query;
+use crate::messages::startup::{CancelRequest, SslRequest, Startup};
+use crate::messages::terminate::Terminate;
+use crate::messages::{Message, PgWireBackendMessage, PgWireFrontendMessage};
+use crate::tokio::process_stream;
+
+const MOCK_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Codec of the frontend side
+#[derive(Debug, Default)]
+pub(crate) struct MockClientCodec;
+
+impl Decoder for MockClientCodec {
+    type Item = PgWireBackendMessage;
+    type Error = PgWireError;
+
+    fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        PgWireBackendMessage::decode(src)
+    }
+}
+
+impl Encoder<PgWireFrontendMessage> for MockClientCodec {
+    type Error = PgWireError;
+
+    fn encode(
+        &mut self,
+        item: PgWireFrontendMessage,
+        dst: &mut bytes::BytesMut,
+    ) -> Result<(), Self::Error> {
+        item.encode(dst)
+    }
+}
+
+/// A mock frontend connected to handlers running on an in-memory duplex.
+pub struct MockClient {
+    socket: Framed<DuplexStream, MockClientCodec>,
+    server: JoinHandle<Result<ConnectionSummary, io::Error>>,
+}
+
+impl MockClient {
+    /// Address reported to handlers as the client address
+    pub const PEER_ADDR: SocketAddr =
+        SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 54321);
+
+    /// Start handlers with default `ServerConfig` and connect to it.
+    pub fn start<H>(handlers: H) -> MockClient
+    where
+        H: PgWireServerHandlers + Send + Sync + 'static,
+    {
+        Self::start_with_config(handlers, Arc::new(ServerConfig::default()))
+    }
+
+    /// Start handlers with custom `ServerConfig` and connect to it.
+    pub fn start_with_config<H>(handlers: H, config: Arc<ServerConfig>) -> MockClient
+    where
+        H: PgWireServerHandlers + Send + Sync + 'static,
+    {
+        Self::spawn(|stream| process_stream(stream, Self::PEER_ADDR, handlers, config))
+    }
+
+    /// Spawn `server` on one end of an in-memory stream and connect to the
+    /// other end
+    pub(crate) fn spawn<F, Fut>(server: F) -> MockClient
+    where
+        F: FnOnce(DuplexStream) -> Fut,
+        Fut: Future<Output = Result<ConnectionSummary, io::Error>> + Send + 'static,
+    {
+        let (client_stream, server_stream) = tokio::io::duplex(MOCK_BUFFER_SIZE);
+        MockClient {
+            socket: Framed::new(client_stream, MockClientCodec),
+            server: tokio::spawn(server(server_stream)),
+        }
+    }
+
+    /// Send a single frontend message
+    pub async fn send(&mut self, message: PgWireFrontendMessage) -> PgWireResult<()> {
+        self.socket.send(message).await
+    }
+
+    /// Send a sequence of frontend messages, and flush them together
+    pub async fn send_all<I>(&mut self, messages: I) -> PgWireResult<()>
+    where
+        I: IntoIterator<Item = PgWireFrontendMessage>,
+    {
+        for message in messages {
+            self.socket.feed(message).await?;
+        }
+        self.socket.flush().await
+    }
+
+    /// Send raw bytes, for testing malformed messages
+    pub async fn send_raw(&mut self, data: &[u8]) -> PgWireResult<()> {
+        self.socket.flush().await?;
+        self.socket.get_mut().write_all(data).await?;
+        Ok(())
+    }
+
+    /// Receive next backend message, returns `None` if server closed the
+    /// connection.
+    pub async fn receive(&mut self) -> PgWireResult<Option<PgWireBackendMessage>> {
+        self.socket.next().await.transpose()
+    }
+
+    /// Receive backend messages until `ReadyForQuery` (included) or the
+    /// connection is closed.
+    pub async fn receive_until_ready(&mut self) -> PgWireResult<Vec<PgWireBackendMessage>> {
+        let mut messages = Vec::new();
+        while let Some(message) = self.receive().await? {
+            let is_ready = matches!(message, PgWireBackendMessage::ReadyForQuery(_));
+            messages.push(message);
+            if is_ready {
+                break;
+            }
+        }
+
+        Ok(messages)
+    }
+
+    /// Receive exactly `expected.len()` messages and assert they are equal to
+    /// `expected`.
+    ///
+    /// # Panics
+    ///
+    /// Panics when received messages are different, or the connection is
+    /// closed before enough messages received.
+    pub async fn expect(&mut self, expected: &[PgWireBackendMessage]) {
+        let mut received = Vec::with_capacity(expected.len());
+        for _ in 0..expected.len() {
+            match self.receive().await {
+                Ok(Some(message)) => received.push(message),
+                Ok(None) => break,
+                Err(e) => panic!("failed to decode backend message: {e}"),
+            }
+        }
+
+        assert_eq!(expected, received.as_slice());
+    }
+
+    /// Send startup message with given user and database, and receive
+    /// messages until the server is ready for query.
+    ///
+    /// Authentication challenges are returned as is, so use `send` and
+    /// `receive_until_ready` to complete authentication with password.
+    pub async fn startup(
+        &mut self,
+        user: &str,
+        database: Option<&str>,
+    ) -> PgWireResult<Vec<PgWireBackendMessage>> {
+        let mut parameters = BTreeMap::new();
+        parameters.insert("user".to_owned(), user.to_owned());
+        if let Some(database) = database {
+            parameters.insert("database".to_owned(), database.to_owned());
+        }
+        let mut startup = Startup::new();
+        startup.parameters = parameters;
+
+        self.send(PgWireFrontendMessage::Startup(startup)).await?;
+        self.receive_until_ready().await
+    }
+
+    /// Run a simple query and receive responses until `ReadyForQuery`
+    pub async fn simple_query(&mut self, query: &str) -> PgWireResult<Vec<PgWireBackendMessage>> {
+        self.send(PgWireFrontendMessage::Query(Query::new(query.to_owned())))
+            .await?;
+        self.receive_until_ready().await
+    }
+
+    /// Send `Terminate` and wait for the server to finish processing this
+    /// connection.
+    pub async fn terminate(mut self) -> Result<ConnectionSummary, io::Error> {
+        self.send(PgWireFrontendMessage::Terminate(Terminate::new()))
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e))?;
+        self.close().await
+    }
+
+    /// Close the connection without `Terminate`, and wait for the server to
+    /// finish processing this connection.
+    pub async fn close(self) -> Result<ConnectionSummary, io::Error> {
+        drop(self.socket);
+        self.server.await.map_err(io::Error::other)?
+    }
+}
+
+/// Replay frontend messages of a captured session against handlers, and
+/// return all backend messages sent by handlers.
+///
+/// Records from other sessions should be filtered out before calling this.
+/// `SSLRequest` and `CancelRequest` packets are skipped because they cannot
+/// be replayed on an in-memory stream. A `Terminate` is sent if the capture
+/// doesn't end with one.
+pub async fn replay<'a, H, I>(handlers: H, records: I) -> PgWireResult<Vec<PgWireBackendMessage>>
+where
+    H: PgWireServerHandlers + Send + Sync + 'static,
+    I: IntoIterator<Item = &'a CaptureRecord>,
+{
+    let (client_stream, server_stream) = tokio::io::duplex(MOCK_BUFFER_SIZE);
+    let server = tokio::spawn(process_stream(
+        server_stream,
+        MockClient::PEER_ADDR,
+        handlers,
+        Arc::new(ServerConfig::default()),
+    ));
+
+    let frontend_data = records
+        .into_iter()
+        .filter(|r| r.direction == CaptureDirection::Frontend)
+        .filter(|r| {
+            !SslRequest::is_ssl_request_packet(&r.data)
+                && !CancelRequest::is_cancel_request_packet(&r.data)
+        })
+        .map(|r| r.data.clone())
+        .collect::<Vec<_>>();
+    let terminated = frontend_data
+        .last()
+        .is_some_and(|data| data.first() == Some(&b'X'));
+
+    let (reader, mut writer) = tokio::io::split(client_stream);
+    // write and read concurrently, so the duplex buffer is never full
+    let write = async move {
+        for data in frontend_data {
+            writer.write_all(&data).await?;
+        }
+        if !terminated {
+            let mut buf = bytes::BytesMut::new();
+            Terminate::new().encode(&mut buf)?;
+            writer.write_all(&buf).await?;
+        }
+        // keep the write half open until the server closes the connection
+        Ok::<_, PgWireError>(writer)
+    };
+    let read = async move {
+        let mut reader = tokio_util::codec::FramedRead::new(reader, MockClientCodec);
+        let mut messages = Vec::new();
+        while let Some(msg) = reader.next().await {
+            messages.push(msg?);
+        }
+        Ok::<_, PgWireError>(messages)
+    };
+
+    let (writer, messages) = futures::join!(write, read);
+    writer?;
+    server.await.map_err(io::Error::other)??;
+
+    messages
+}
+
+/// Handlers and helpers shared by tests of other modules
+#[cfg(test)]
+pub(crate) mod fixture {
+    use std::collections::HashMap;
+    use std::fmt::Debug;
+    use std::sync::Arc;
+
+    use async_trait::async_trait;
+    use futures::Sink;
+
+    use super::MockClient;
+    use crate::api::auth::noop::NoopAuthStartupHandler;
+    use crate::api::auth::{
+        AuthSource, DefaultServerParameterProvider, LoginInfo, Password, StartupHandler,
+    };
+    use crate::api::copy::NoopCopyHandler;
+    use crate::api::portal::Portal;
+    use crate::api::query::{ExtendedQueryHandler, SimpleQueryHandler, StatementOrPortal};
+    use crate::api::results::{
+        DataRowEncoder, DescribeStatementResponse, FieldFormat, FieldInfo, QueryResponse, Response,
+        Tag,
+    };
+    use crate::api::stmt::NoopQueryParser;
+    use crate::api::store::PortalStore;
+    use crate::api::{ClientInfo, ClientPortalStore, NoopErrorHandler, PgWireServerHandlers, Type};
+    use crate::error::{PgWireError, PgWireResult};
+    use crate::messages::startup::{self, PasswordMessageFamily, Startup};
+    use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
+
+    pub(crate) type NoopStartup = NoopAuthStartupHandler<DefaultServerParameterProvider>;
+
+    /// Handlers of a test, with noop copy, error, cancel and connection
+    /// handlers
+    pub(crate) struct TestHandlers<S, Q, E = Q> {
+        startup: Arc<S>,
+        simple: Arc<Q>,
+        extended: Arc<E>,
+    }
+
+    impl<Q> TestHandlers<NoopStartup, Q, Q> {
+        /// `handler` for both simple and extended query, without
+        /// authentication
+        pub(crate) fn new(handler: Q) -> Self {
+            let handler = Arc::new(handler);
+            TestHandlers {
+                startup: Arc::new(NoopAuthStartupHandler::new(
+                    DefaultServerParameterProvider::default(),
+                )),
+                simple: handler.clone(),
+                extended: handler,
+            }
+        }
+    }
+
+    impl TestHandlers<NoopStartup, FnQueryHandler> {
+        /// Answer every query with the query itself as command tag
+        pub(crate) fn echo() -> Self {
+            TestHandlers::new(FnQueryHandler::echo())
+        }
+    }
+
+    impl<S, Q, E> TestHandlers<S, Q, E> {
+        pub(crate) fn with_startup<T>(self, startup: T) -> TestHandlers<T, Q, E> {
+            TestHandlers {
+                startup: Arc::new(startup),
+                simple: self.simple,
+                extended: self.extended,
+            }
+        }
+
+        pub(crate) fn with_simple<T>(self, simple: T) -> TestHandlers<S, T, E> {
+            TestHandlers {
+                startup: self.startup,
+                simple: Arc::new(simple),
+                extended: self.extended,
+            }
+        }
+
+        pub(crate) fn with_extended<T>(self, extended: T) -> TestHandlers<S, Q, T> {
+            TestHandlers {
+                startup: self.startup,
+                simple: self.simple,
+                extended: Arc::new(extended),
+            }
+        }
+    }
+
+    impl<S, Q, E> PgWireServerHandlers for TestHandlers<S, Q, E>
+    where
+        S: StartupHandler,
+        Q: SimpleQueryHandler,
+        E: ExtendedQueryHandler,
+    {
+        type StartupHandler = S;
+        type SimpleQueryHandler = Q;
+        type ExtendedQueryHandler = E;
+        type CopyHandler = NoopCopyHandler;
+        type ErrorHandler = NoopErrorHandler;
+
+        fn simple_query_handler(&self) -> Arc<Self::SimpleQueryHandler> {
+            self.simple.clone()
+        }
+
+        fn extended_query_handler(&self) -> Arc<Self::ExtendedQueryHandler> {
+            self.extended.clone()
+        }
+
+        fn startup_handler(&self) -> Arc<Self::StartupHandler> {
+            self.startup.clone()
+        }
+
+        fn copy_handler(&self) -> Arc<Self::CopyHandler> {
+            Arc::new(NoopCopyHandler)
+        }
+
+        fn error_handler(&self) -> Arc<Self::ErrorHandler> {
+            Arc::new(NoopErrorHandler)
+        }
+    }
+
+    type QueryFn = dyn Fn(&HashMap<String, String>, &str) -> PgWireResult<Vec<Response<'static>>>
+        + Send
+        + Sync;
+
+    /// Simple and extended query handler answering each query with a
+    /// function of client metadata and the query. Extended query returns the
+    /// first response, and describes no fields.
+    pub(crate) struct FnQueryHandler(Box<QueryFn>);
+
+    impl FnQueryHandler {
+        pub(crate) fn new<F>(f: F) -> FnQueryHandler
+        where
+            F: Fn(&str) -> PgWireResult<Vec<Response<'static>>> + Send + Sync + 'static,
+        {
+            FnQueryHandler(Box::new(move |_, query| f(query)))
+        }
+
+        pub(crate) fn with_metadata<F>(f: F) -> FnQueryHandler
+        where
+            F: Fn(&HashMap<String, String>, &str) -> PgWireResult<Vec<Response<'static>>>
+                + Send
+                + Sync
+                + 'static,
+        {
+            FnQueryHandler(Box::new(f))
+        }
+
+        /// Answer every query with the query itself as command tag
+        pub(crate) fn echo() -> FnQueryHandler {
+            FnQueryHandler::new(|query| Ok(vec![Response::Execution(Tag::new(query))]))
+        }
+
+        /// Answer every query with `tag`
+        pub(crate) fn tag(tag: &str) -> FnQueryHandler {
+            let tag = tag.to_owned();
+            FnQueryHandler::new(move |_| Ok(vec![Response::Execution(Tag::new(&tag))]))
+        }
+    }
+
+    #[async_trait]
+    impl SimpleQueryHandler for FnQueryHandler {
+        async fn do_query<'a, C>(
+            &self,
+            client: &mut C,
+            query: &'a str,
+        ) -> PgWireResult<Vec<Response<'a>>>
+        where
+            C: ClientInfo + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            (self.0)(client.metadata(), query)
+        }
+    }
+
+    #[async_trait]
+    impl ExtendedQueryHandler for FnQueryHandler {
+        type Statement = String;
+        type QueryParser = NoopQueryParser;
+
+        fn query_parser(&self) -> Arc<Self::QueryParser> {
+            Arc::new(NoopQueryParser)
+        }
+
+        async fn do_query<'a, 'b: 'a, C>(
+            &'b self,
+            client: &mut C,
+            portal: &'a Portal<Self::Statement>,
+            _max_rows: usize,
+        ) -> PgWireResult<Response<'a>>
+        where
+            C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+            C::PortalStore: PortalStore<Statement = Self::Statement>,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            let responses = (self.0)(client.metadata(), &portal.statement.statement)?;
+            Ok(responses.into_iter().next().unwrap_or(Response::EmptyQuery))
+        }
+
+        async fn do_describe<C>(
+            &self,
+            _client: &mut C,
+            target: StatementOrPortal<'_, Self::Statement>,
+        ) -> PgWireResult<DescribeStatementResponse>
+        where
+            C: ClientInfo + ClientPortalStore + Sink<PgWireBackendMessage> + Unpin + Send + Sync,
+            C::PortalStore: PortalStore<Statement = Self::Statement>,
+            C::Error: Debug,
+            PgWireError: From<<C as Sink<PgWireBackendMessage>>::Error>,
+        {
+            Ok(DescribeStatementResponse::new(
+                target.statement().parameter_types.clone(),
+                vec![],
+            ))
+        }
+    }
+
+    /// Auth source with the same cleartext password for every user
+    pub(crate) struct StaticPassword(pub(crate) &'static str);
+
+    #[async_trait]
+    impl AuthSource for StaticPassword {
+        async fn get_password(&self, _login: &LoginInfo) -> PgWireResult<Password> {
+            Ok(Password::new(None, self.0.as_bytes().to_vec()))
+        }
+    }
+
+    /// Schema of a single `n` INT4 column
+    pub(crate) fn numbers_schema() -> Arc<Vec<FieldInfo>> {
+        Arc::new(vec![FieldInfo::new(
+            "n".to_owned(),
+            None,
+            None,
+            Type::INT4,
+            FieldFormat::Text,
+        )])
+    }
+
+    /// Rows of `n` from 1 to `count`, encoded ahead
+    pub(crate) fn numbers(count: i32) -> QueryResponse<'static> {
+        let schema = numbers_schema();
+        let rows = (1..=count)
+            .map(|n| {
+                let mut encoder = DataRowEncoder::new(schema.clone());
+                encoder.encode_field(&n)?;
+                encoder.finish()
+            })
+            .collect::<PgWireResult<Vec<_>>>()
+            .expect("numbers are encoded");
+        QueryResponse::from_rows(schema, rows)
+    }
+
+    /// A single row of a single text column
+    pub(crate) fn single_value_response(name: &str, value: &str) -> Response<'static> {
+        let schema = Arc::new(vec![FieldInfo::new(
+            name.to_owned(),
+            None,
+            None,
+            Type::TEXT,
+            FieldFormat::Text,
+        )]);
+        let mut encoder = DataRowEncoder::new(schema.clone());
+        let row = encoder.encode_field(&value).and_then(|_| encoder.finish());
+        Response::Query(QueryResponse::from_iter(schema, [row]))
+    }
+
+    /// Value of the only column of the only row returned by `query`
+    pub(crate) async fn single_value(client: &mut MockClient, query: &str) -> Vec<u8> {
+        let rows = client
+            .simple_query(query)
+            .await
+            .unwrap()
+            .into_iter()
+            .filter_map(|message| match message {
+                PgWireBackendMessage::DataRow(row) => Some(row),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(1, rows.len(), "rows of {query}");
+        rows[0].data[4..].to_vec()
+    }
+
+    /// SQLSTATE of an error response
+    pub(crate) fn error_code(message: &PgWireBackendMessage) -> Option<String> {
+        error_field(message, b'C')
+    }
+
+    /// A field of an error response, like `b'S'` for severity
+    pub(crate) fn error_field(message: &PgWireBackendMessage, field: u8) -> Option<String> {
+        let PgWireBackendMessage::ErrorResponse(error) = message else {
+            return None;
+        };
+        error
+            .fields
+            .iter()
+            .find(|(k, _)| *k == field)
+            .map(|(_, v)| v.clone())
+    }
+
+    /// Tag of the first `CommandComplete`
+    pub(crate) fn command_tag(messages: &[PgWireBackendMessage]) -> Option<&str> {
+        messages.iter().find_map(|message| match message {
+            PgWireBackendMessage::CommandComplete(complete) => Some(complete.tag.as_str()),
+            _ => None,
+        })
+    }
+
+    /// Send startup message and receive the first response
+    pub(crate) async fn start_login(client: &mut MockClient, user: &str) -> PgWireBackendMessage {
+        let mut startup = Startup::new();
+        startup
+            .parameters
+            .insert("user".to_owned(), user.to_owned());
+        client
+            .send(PgWireFrontendMessage::Startup(startup))
+            .await
+            .unwrap();
+        client.receive().await.unwrap().unwrap()
+    }
+
+    /// Send a password and receive messages until ready
+    pub(crate) async fn send_password(
+        client: &mut MockClient,
+        password: String,
+    ) -> Vec<PgWireBackendMessage> {
+        client
+            .send(PgWireFrontendMessage::PasswordMessageFamily(
+                PasswordMessageFamily::Password(startup::Password::new(password)),
+            ))
+            .await
+            .unwrap();
+        client.receive_until_ready().await.unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::fixture::TestHandlers;
+    use super::*;
+    use crate::api::connection::DisconnectReason;
+    use crate::messages::response::{CommandComplete, ReadyForQuery, TransactionStatus};
+    use crate::messages::startup::{Authentication, ParameterStatus};
+
+    #[tokio::test]
+    async fn test_mock_client() {
+        let mut client = MockClient::start(TestHandlers::echo());
+
+        let messages = client.startup("tom", Some("db")).await.unwrap();
+        assert_eq!(
+            Some(&PgWireBackendMessage::Authentication(Authentication::Ok)),
+            messages.first()
+        );
+        assert!(messages.contains(&PgWireBackendMessage::ParameterStatus(
+            ParameterStatus::new("client_encoding".to_owned(), "UTF8".to_owned())
+        )));
+        assert_eq!(
+            Some(&PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
+                TransactionStatus::Idle
+            ))),
+            messages.last()
+        );
+
+        client
+            .send(PgWireFrontendMessage::Query(Query::new(
+                "VACUUM".to_owned(),
+            )))
+            .await
+            .unwrap();
+        client
+            .expect(&[
+                PgWireBackendMessage::CommandComplete(CommandComplete::new("VACUUM".to_owned())),
+                PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(TransactionStatus::Idle)),
+            ])
+            .await;
+
+        let summary = client.terminate().await.unwrap();
+        assert_eq!(MockClient::PEER_ADDR, summary.addr);
+        assert_eq!(1, summary.queries);
+        assert!(summary.bytes_received > 0);
+        assert!(summary.bytes_sent > summary.bytes_received);
+        assert_eq!(Some(DisconnectReason::Terminate), summary.reason);
+        assert_eq!(1, summary.messages.received(b'Q').count);
+        assert_eq!(1, summary.messages.received(b'X').count);
+        assert_eq!(2, summary.messages.sent(b'Z').count);
+        assert_eq!(12, summary.messages.sent(b'Z').bytes);
+        assert_eq!(
+            summary.bytes_sent,
+            summary.messages.sent.values().map(|s| s.bytes).sum::<u64>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let mut records = Vec::new();
+        for msg in [
+            PgWireFrontendMessage::Startup(Startup::new()),
+            PgWireFrontendMessage::Query(Query::new("BEGIN".to_owned())),
+        ] {
+            let mut buf = bytes::BytesMut::new();
+            msg.encode(&mut buf).unwrap();
+            records.push(CaptureRecord::now(
+                MockClient::PEER_ADDR,
+                CaptureDirection::Frontend,
+                buf.freeze(),
+            ));
+        }
+
+        let messages = replay(TestHandlers::echo(), &records).await.unwrap();
+        assert_eq!(
+            Some(&PgWireBackendMessage::CommandComplete(
+                CommandComplete::new("BEGIN".to_owned())
+            )),
+            messages.get(messages.len() - 2)
+        );
+    }
+}
@@ -0,0 +1,292 @@
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::net::{Ipv4Addr, SocketAddrV4};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::pin::pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::{self, Either};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+#[cfg(unix)]
+use super::server::process_stream;
+use super::server::{process_socket_with_config, spawn_supervised};
+use crate::api::config::ServerConfig;
+use crate::api::PgWireServerHandlers;
+
+/// Pause before accepting again after an accept error, like running out of
+/// file descriptors
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Address to listen on
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    /// Unix domain socket, postgres clients look for
+    /// `<dir>/.s.PGSQL.<port>`
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{addr}"),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+impl From<SocketAddr> for ListenAddr {
+    fn from(addr: SocketAddr) -> ListenAddr {
+        ListenAddr::Tcp(addr)
+    }
+}
+
+enum BoundListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+enum Accepted {
+    Tcp(tokio::net::TcpStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+impl BoundListener {
+    async fn accept(&self) -> io::Result<Accepted> {
+        match self {
+            BoundListener::Tcp(listener) => listener.accept().await.map(|(s, _)| Accepted::Tcp(s)),
+            #[cfg(unix)]
+            BoundListener::Unix(listener, _) => {
+                listener.accept().await.map(|(s, _)| Accepted::Unix(s))
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<ListenAddr> {
+        match self {
+            BoundListener::Tcp(listener) => listener.local_addr().map(ListenAddr::Tcp),
+            #[cfg(unix)]
+            BoundListener::Unix(_, path) => Ok(ListenAddr::Unix(path.clone())),
+        }
+    }
+}
+
+impl Drop for BoundListener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let BoundListener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Accept connections on multiple addresses, like IPv4 and IPv6 TCP and a
+/// unix domain socket, and process them with the same handlers.
+///
+/// ```no_run
+/// # use std::sync::Arc;
+/// # use pgwire::api::PgWireServerHandlers;
+/// # use pgwire::tokio::{ListenAddr, PgWireListener};
+/// # async fn run<H: PgWireServerHandlers + Send + Sync + 'static>(handlers: Arc<H>) -> std::io::Result<()> {
+/// let listener = PgWireListener::bind([
+///     ListenAddr::Tcp("0.0.0.0:5432".parse().unwrap()),
+///     ListenAddr::Tcp("[::]:5432".parse().unwrap()),
+/// ])
+/// .await?;
+/// listener.serve(handlers, std::future::pending()).await;
+/// # Ok(())
+/// # }
+/// ```
+pub struct PgWireListener {
+    listeners: Vec<BoundListener>,
+    tls_acceptor: Option<crate::tokio::TlsAcceptor>,
+    config: Arc<ServerConfig>,
+}
+
+impl PgWireListener {
+    /// Bind all addresses, fails if any of them can't be bound
+    pub async fn bind<I>(addrs: I) -> io::Result<PgWireListener>
+    where
+        I: IntoIterator<Item = ListenAddr>,
+    {
+        let mut listeners = Vec::new();
+        for addr in addrs {
+            let listener = match addr {
+                ListenAddr::Tcp(addr) => BoundListener::Tcp(TcpListener::bind(addr).await?),
+                #[cfg(unix)]
+                ListenAddr::Unix(path) => BoundListener::Unix(UnixListener::bind(&path)?, path),
+            };
+            listeners.push(listener);
+        }
+        if listeners.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no address to listen on",
+            ));
+        }
+
+        Ok(PgWireListener {
+            listeners,
+            tls_acceptor: None,
+            config: Arc::new(ServerConfig::default()),
+        })
+    }
+
+    /// Accept `SSLRequest` of TCP connections with this acceptor. Unix domain
+    /// socket connections are always without TLS.
+    pub fn with_tls_acceptor(mut self, tls_acceptor: crate::tokio::TlsAcceptor) -> Self {
+        self.tls_acceptor = Some(tls_acceptor);
+        self
+    }
+
+    /// `ServerConfig` of all accepted connections
+    pub fn with_config(mut self, config: Arc<ServerConfig>) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Bound addresses, with actual ports of addresses bound with port `0`
+    pub fn local_addrs(&self) -> io::Result<Vec<ListenAddr>> {
+        self.listeners
+            .iter()
+            .map(BoundListener::local_addr)
+            .collect()
+    }
+
+    /// Accept connections on all addresses until `shutdown` resolves, each
+    /// connection is processed in its own task started by
+    /// `spawn_supervised`.
+    ///
+    /// All listeners are closed together on shutdown, and unix domain socket
+    /// files are removed. Connections accepted before shutdown are not
+    /// interrupted. Accept errors, like running out of file descriptors, are
+    /// retried after a short pause.
+    pub async fn serve<H, F>(self, handlers: Arc<H>, shutdown: F)
+    where
+        H: PgWireServerHandlers + Send + Sync + 'static,
+        F: Future<Output = ()>,
+    {
+        let mut shutdown = pin!(shutdown);
+        loop {
+            let accept = future::select_all(self.listeners.iter().map(|l| Box::pin(l.accept())));
+            let socket = match future::select(shutdown.as_mut(), accept).await {
+                Either::Left(_) => return,
+                Either::Right(((Ok(socket), _, _), _)) => socket,
+                Either::Right(((Err(_), _, _), _)) => {
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
+
+            let handlers = handlers.clone();
+            let config = self.config.clone();
+            match socket {
+                Accepted::Tcp(socket) => {
+                    let tls_acceptor = self.tls_acceptor.clone();
+                    spawn_supervised(process_socket_with_config(
+                        socket,
+                        tls_acceptor,
+                        handlers,
+                        config,
+                    ));
+                }
+                #[cfg(unix)]
+                Accepted::Unix(socket) => {
+                    let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+                    spawn_supervised(process_stream(socket, addr, handlers, config));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{AsyncRead, AsyncWrite};
+    use tokio::net::{TcpStream, UnixStream};
+    use tokio_util::codec::Framed;
+
+    use super::*;
+    use crate::messages::response::{ReadyForQuery, TransactionStatus};
+    use crate::messages::startup::Startup;
+    use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
+    use crate::testkit::fixture::TestHandlers;
+    use crate::testkit::MockClientCodec;
+
+    /// Log in as `tom`, and return the last message of the response
+    async fn login<S>(stream: S) -> Option<PgWireBackendMessage>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut socket = Framed::new(stream, MockClientCodec);
+        let mut startup = Startup::new();
+        startup
+            .parameters
+            .insert("user".to_owned(), "tom".to_owned());
+        socket
+            .send(PgWireFrontendMessage::Startup(startup))
+            .await
+            .unwrap();
+        let mut last = None;
+        while let Some(message) = socket.next().await {
+            let message = message.unwrap();
+            let ready = matches!(message, PgWireBackendMessage::ReadyForQuery(_));
+            last = Some(message);
+            if ready {
+                break;
+            }
+        }
+        last
+    }
+
+    #[tokio::test]
+    async fn test_listener() {
+        let socket_path =
+            std::env::temp_dir().join(format!(".s.PGSQL.pgwire-test-{}", std::process::id()));
+        let listener = PgWireListener::bind([
+            ListenAddr::Tcp("127.0.0.1:0".parse().unwrap()),
+            ListenAddr::Unix(socket_path.clone()),
+        ])
+        .await
+        .unwrap();
+        let ListenAddr::Tcp(tcp_addr) = listener.local_addrs().unwrap()[0] else {
+            panic!("first address is tcp");
+        };
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(listener.serve(Arc::new(TestHandlers::echo()), async {
+            let _ = stopped.await;
+        }));
+
+        let ready = Some(PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
+            TransactionStatus::Idle,
+        )));
+        assert_eq!(
+            ready,
+            login(TcpStream::connect(tcp_addr).await.unwrap()).await
+        );
+        assert_eq!(
+            ready,
+            login(UnixStream::connect(&socket_path).await.unwrap()).await
+        );
+
+        // all listeners are closed on shutdown
+        stop.send(()).unwrap();
+        server.await.unwrap();
+        assert!(TcpStream::connect(tcp_addr).await.is_err());
+        assert!(!socket_path.exists());
+    }
+}
@@ -1,11 +1,24 @@
 #[cfg(feature = "client-api")]
 pub mod client;
 
+#[cfg(feature = "server-api")]
+mod listener;
+#[cfg(feature = "server-api")]
+mod proxy;
 #[cfg(feature = "server-api")]
 mod server;
+#[cfg(feature = "server-api")]
+mod write_timeout;
 
 #[cfg(feature = "server-api")]
-pub use server::process_socket;
+pub use listener::{ListenAddr, PgWireListener};
+#[cfg(feature = "server-api")]
+pub use proxy::{proxy_socket, proxy_stream};
+#[cfg(feature = "server-api")]
+pub use server::{
+    process_socket, process_socket_with_config, process_socket_with_portal_store, process_stream,
+    spawn_supervised,
+};
 
 #[cfg(any(feature = "_ring", feature = "_aws-lc-rs"))]
 pub use tokio_rustls;
@@ -15,6 +28,7 @@ pub type TlsAcceptor = tokio_rustls::TlsAcceptor;
 pub type TlsConnector = tokio_rustls::TlsConnector;
 
 #[cfg(not(any(feature = "_ring", feature = "_aws-lc-rs")))]
+#[derive(Debug, Clone)]
 pub enum TlsAcceptor {}
 #[cfg(not(any(feature = "_ring", feature = "_aws-lc-rs")))]
 pub enum TlsConnector {}
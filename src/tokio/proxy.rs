@@ -0,0 +1,451 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures::future::{self, Either};
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+use super::server::{
+    apply_socket_options, peek_for_sslrequest, reject_protocol_version, PgWireMessageServerCodec,
+    SslNegotiationType,
+};
+use super::write_timeout::WriteTimeout;
+use crate::api::config::ServerConfig;
+use crate::api::connection::{ConnectionSummary, DisconnectReason};
+use crate::api::proxy::{Forward, ProxyHandler};
+use crate::api::{ClientInfo, DefaultClient, PgWireConnectionState};
+use crate::error::{ErrorInfo, PgWireError, PgWireResult};
+use crate::messages::response::SslResponse;
+use crate::messages::{PgWireBackendMessage, PgWireFrontendMessage};
+
+type FrontendSocket<S> = Framed<S, PgWireMessageServerCodec<()>>;
+
+/// Codec of the connection to upstream server, which is the frontend side of
+/// the protocol
+#[derive(Debug)]
+struct UpstreamCodec;
+
+impl Decoder for UpstreamCodec {
+    type Item = PgWireBackendMessage;
+    type Error = PgWireError;
+
+    fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        PgWireBackendMessage::decode(src)
+    }
+}
+
+impl Encoder<PgWireFrontendMessage> for UpstreamCodec {
+    type Error = PgWireError;
+
+    fn encode(
+        &mut self,
+        item: PgWireFrontendMessage,
+        dst: &mut bytes::BytesMut,
+    ) -> Result<(), Self::Error> {
+        item.encode(dst)
+    }
+}
+
+/// Proxy a TCP connection to the upstream server opened by `handler`,
+/// resolves to its `ConnectionSummary` when either side is closed.
+///
+/// TLS of client is terminated with `tls_acceptor`, the connection to
+/// upstream server is encrypted only if `ProxyHandler::connect` does it.
+pub async fn proxy_socket<H>(
+    tcp_socket: TcpStream,
+    tls_acceptor: Option<crate::tokio::TlsAcceptor>,
+    handler: Arc<H>,
+    config: Arc<ServerConfig>,
+) -> Result<ConnectionSummary, io::Error>
+where
+    H: ProxyHandler,
+{
+    let addr = tcp_socket.peer_addr()?;
+    apply_socket_options(&tcp_socket, &config.socket_options)?;
+    let tcp_socket = WriteTimeout::new(tcp_socket, config.write_timeout);
+
+    let client_info = DefaultClient::with_config(addr, false, config.clone());
+    let mut socket = Framed::new(tcp_socket, PgWireMessageServerCodec::new(client_info));
+    socket.set_backpressure_boundary(config.write_buffer_high_watermark);
+
+    let ssl = peek_for_sslrequest(&mut socket, tls_acceptor.is_some()).await?;
+    if ssl == SslNegotiationType::None {
+        return run_proxy(&mut socket, handler).await;
+    }
+
+    #[cfg(any(feature = "_ring", feature = "_aws-lc-rs"))]
+    {
+        let parts = socket.into_parts();
+        // safe to unwrap tls_acceptor here
+        let ssl_socket = tls_acceptor.unwrap().accept(parts.io).await?;
+        if ssl == SslNegotiationType::Direct {
+            super::server::check_alpn_for_direct_ssl(&ssl_socket)?;
+        }
+
+        let mut client_info = DefaultClient::with_config(addr, true, config.clone());
+        client_info.client_certificates = ssl_socket
+            .get_ref()
+            .1
+            .peer_certificates()
+            .map(|certs| certs.to_vec());
+        let mut socket = Framed::new(ssl_socket, PgWireMessageServerCodec::new(client_info));
+        socket.set_backpressure_boundary(config.write_buffer_high_watermark);
+        run_proxy(&mut socket, handler).await
+    }
+
+    #[cfg(not(any(feature = "_ring", feature = "_aws-lc-rs")))]
+    Ok(socket.codec().summary(None))
+}
+
+/// Proxy a connection over any byte stream, like `process_stream`.
+/// `SSLRequest` from client is always refused.
+pub async fn proxy_stream<S, H>(
+    stream: S,
+    addr: SocketAddr,
+    handler: Arc<H>,
+    config: Arc<ServerConfig>,
+) -> Result<ConnectionSummary, io::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    H: ProxyHandler,
+{
+    let client_info = DefaultClient::with_config(addr, false, config.clone());
+    let stream = WriteTimeout::new(stream, config.write_timeout);
+    let mut socket = Framed::new(stream, PgWireMessageServerCodec::new(client_info));
+    socket.set_backpressure_boundary(config.write_buffer_high_watermark);
+
+    if let Some(Ok(PgWireFrontendMessage::SslRequest(Some(_)))) = socket.next().await {
+        socket
+            .send(PgWireBackendMessage::SslResponse(SslResponse::Refuse))
+            .await?;
+    }
+
+    run_proxy(&mut socket, handler).await
+}
+
+async fn run_proxy<S, H>(
+    socket: &mut FrontendSocket<S>,
+    handler: Arc<H>,
+) -> Result<ConnectionSummary, io::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    H: ProxyHandler,
+{
+    let mut startup = loop {
+        match socket.next().await {
+            Some(Ok(PgWireFrontendMessage::Startup(startup))) => break startup,
+            // not a real message, the client didn't request TLS
+            Some(Ok(PgWireFrontendMessage::SslRequest(None))) => continue,
+            Some(Ok(PgWireFrontendMessage::CancelRequest(cancel_request))) => {
+                handler.on_cancel_request(cancel_request).await;
+                return Ok(socket.codec().summary(None));
+            }
+            Some(Err(PgWireError::InvalidProtocolVersion(version))) => {
+                reject_protocol_version(socket, version).await?;
+                return Ok(socket.codec().summary(Some(DisconnectReason::Error)));
+            }
+            Some(_) => {
+                send_fatal(socket, protocol_violation("invalid startup packet")).await?;
+                return Ok(socket.codec().summary(Some(DisconnectReason::Error)));
+            }
+            None => {
+                return Ok(socket
+                    .codec()
+                    .summary(Some(DisconnectReason::ConnectionClosed)))
+            }
+        }
+    };
+
+    socket.set_state(PgWireConnectionState::AuthenticationInProgress);
+    for (name, value) in &startup.parameters {
+        socket.metadata_mut().insert(name.clone(), value.clone());
+    }
+
+    let upstream = match handler
+        .connect(&socket.codec().client_info, &mut startup)
+        .await
+    {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            send_fatal(socket, e).await?;
+            return Ok(socket.codec().summary(Some(DisconnectReason::Error)));
+        }
+    };
+    let mut upstream = Framed::new(upstream, UpstreamCodec);
+    let reason = match upstream.send(PgWireFrontendMessage::Startup(startup)).await {
+        Ok(()) => forward(socket, &mut upstream, handler.as_ref()).await?,
+        Err(_) => {
+            send_fatal(socket, upstream_lost()).await?;
+            DisconnectReason::Error
+        }
+    };
+
+    Ok(socket.codec().summary(Some(reason)))
+}
+
+/// Forward messages in both directions until either side is closed
+async fn forward<S, U, H>(
+    socket: &mut FrontendSocket<S>,
+    upstream: &mut Framed<U, UpstreamCodec>,
+    handler: &H,
+) -> Result<DisconnectReason, io::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    U: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    H: ProxyHandler,
+{
+    loop {
+        let next = match future::select(socket.next(), upstream.next()).await {
+            Either::Left((message, _)) => Either::Left(message),
+            Either::Right((message, _)) => Either::Right(message),
+        };
+
+        match next {
+            Either::Left(Some(Ok(message)))
+                if !matches!(message, PgWireFrontendMessage::Invalid(_)) =>
+            {
+                let terminate = matches!(message, PgWireFrontendMessage::Terminate(_));
+                let decision = handler
+                    .on_frontend_message(&socket.codec().client_info, message)
+                    .await;
+                match decision {
+                    Ok(Forward::Message(message)) => {
+                        // messages of the same batch are flushed together
+                        let sent = if socket.read_buffer().is_empty() || terminate {
+                            upstream.send(message).await
+                        } else {
+                            upstream.feed(message).await
+                        };
+                        if sent.is_err() {
+                            send_fatal(socket, upstream_lost()).await?;
+                            return Ok(DisconnectReason::Error);
+                        }
+                    }
+                    Ok(Forward::Drop) => {}
+                    Ok(Forward::Reply(messages)) => {
+                        for message in messages {
+                            socket.feed(message).await?;
+                        }
+                        socket.flush().await?;
+                    }
+                    Err(e) => {
+                        send_fatal(socket, e).await?;
+                        let _ = upstream.close().await;
+                        return Ok(DisconnectReason::Error);
+                    }
+                }
+                if terminate {
+                    let _ = upstream.close().await;
+                    return Ok(DisconnectReason::Terminate);
+                }
+            }
+            Either::Left(Some(_)) => {
+                // the message is consumed and can't be forwarded
+                send_fatal(socket, protocol_violation("invalid frontend message")).await?;
+                let _ = upstream.close().await;
+                return Ok(DisconnectReason::Error);
+            }
+            Either::Left(None) => {
+                let _ = upstream.close().await;
+                return Ok(DisconnectReason::ConnectionClosed);
+            }
+            Either::Right(Some(Ok(message))) => {
+                if matches!(message, PgWireBackendMessage::ReadyForQuery(_)) {
+                    socket.set_state(PgWireConnectionState::ReadyForQuery);
+                }
+                let decision = handler
+                    .on_backend_message(&socket.codec().client_info, message)
+                    .await;
+                match decision {
+                    Ok(Forward::Message(message)) => {
+                        if upstream.read_buffer().is_empty() {
+                            socket.send(message).await?;
+                        } else {
+                            socket.feed(message).await?;
+                        }
+                    }
+                    Ok(Forward::Drop) => {}
+                    Ok(Forward::Reply(messages)) => {
+                        if send_upstream(upstream, messages).await.is_err() {
+                            send_fatal(socket, upstream_lost()).await?;
+                            return Ok(DisconnectReason::Error);
+                        }
+                    }
+                    Err(e) => {
+                        send_fatal(socket, e).await?;
+                        let _ = upstream.close().await;
+                        return Ok(DisconnectReason::Error);
+                    }
+                }
+            }
+            Either::Right(_) => {
+                send_fatal(socket, upstream_lost()).await?;
+                return Ok(DisconnectReason::Error);
+            }
+        }
+    }
+}
+
+async fn send_upstream<U>(
+    upstream: &mut Framed<U, UpstreamCodec>,
+    messages: Vec<PgWireFrontendMessage>,
+) -> PgWireResult<()>
+where
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    for message in messages {
+        upstream.feed(message).await?;
+    }
+    upstream.flush().await
+}
+
+fn protocol_violation(message: &str) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        "FATAL".to_owned(),
+        "08P01".to_owned(),
+        message.to_owned(),
+    )))
+}
+
+fn upstream_lost() -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        "FATAL".to_owned(),
+        "08006".to_owned(),
+        "connection to upstream server lost".to_owned(),
+    )))
+}
+
+/// Send the error as `FATAL` and close the client connection. Errors other
+/// than `UserError`, like failing to connect upstream, are `08006`
+/// connection_failure.
+async fn send_fatal<S>(socket: &mut FrontendSocket<S>, error: PgWireError) -> Result<(), io::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    let mut error_info = match error {
+        PgWireError::UserError(error_info) => *error_info,
+        e => ErrorInfo::new("FATAL".to_owned(), "08006".to_owned(), e.to_string()),
+    };
+    "FATAL".clone_into(&mut error_info.severity);
+    socket
+        .send(PgWireBackendMessage::ErrorResponse(error_info.into()))
+        .await?;
+    socket.close().await
+}
+
+#[cfg(test)]
+mod test {
+    use async_trait::async_trait;
+    use tokio::io::DuplexStream;
+
+    use super::*;
+    use crate::api::proxy::FrontendForward;
+    use crate::api::results::Response;
+    use crate::messages::response::{CommandComplete, ReadyForQuery, TransactionStatus};
+    use crate::messages::startup::{Authentication, Startup};
+    use crate::testkit::fixture::{error_code, numbers, FnQueryHandler, TestHandlers};
+    use crate::testkit::MockClient;
+    use crate::tokio::process_stream;
+
+    /// Proxies to an upstream returning 3 rows for every query, and answers
+    /// `PING` itself
+    struct RowsProxy;
+
+    #[async_trait]
+    impl ProxyHandler for RowsProxy {
+        type Upstream = DuplexStream;
+
+        async fn connect(
+            &self,
+            _client: &DefaultClient<()>,
+            startup: &mut Startup,
+        ) -> PgWireResult<DuplexStream> {
+            if startup.parameters.get("user").map(String::as_str) == Some("nobody") {
+                return Err(PgWireError::ApiError("no upstream".into()));
+            }
+            let upstream = TestHandlers::new(FnQueryHandler::new(|_| {
+                Ok(vec![Response::Query(numbers(3))])
+            }));
+            let (proxy_end, upstream_end) = tokio::io::duplex(64 * 1024);
+            tokio::spawn(process_stream(
+                upstream_end,
+                MockClient::PEER_ADDR,
+                upstream,
+                Arc::new(ServerConfig::default()),
+            ));
+            Ok(proxy_end)
+        }
+
+        async fn on_frontend_message(
+            &self,
+            _client: &DefaultClient<()>,
+            message: PgWireFrontendMessage,
+        ) -> PgWireResult<FrontendForward> {
+            match message {
+                PgWireFrontendMessage::Query(query) if query.query == "PING" => {
+                    Ok(Forward::Reply(vec![
+                        PgWireBackendMessage::CommandComplete(CommandComplete::new(
+                            "PING".to_owned(),
+                        )),
+                        PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(
+                            TransactionStatus::Idle,
+                        )),
+                    ]))
+                }
+                message => Ok(Forward::Message(message)),
+            }
+        }
+    }
+
+    fn start_proxy() -> MockClient {
+        MockClient::spawn(|stream| {
+            proxy_stream(
+                stream,
+                MockClient::PEER_ADDR,
+                Arc::new(RowsProxy),
+                Arc::new(ServerConfig::default()),
+            )
+        })
+    }
+
+    #[tokio::test]
+    async fn test_proxy() {
+        let mut client = start_proxy();
+        let messages = client.startup("tom", None).await.unwrap();
+        assert_eq!(
+            PgWireBackendMessage::Authentication(Authentication::Ok),
+            messages[0]
+        );
+
+        // forwarded to upstream
+        let messages = client.simple_query("SELECT n").await.unwrap();
+        let rows = messages
+            .iter()
+            .filter(|m| matches!(m, PgWireBackendMessage::DataRow(_)))
+            .count();
+        assert_eq!(3, rows);
+
+        // answered by proxy
+        let messages = client.simple_query("PING").await.unwrap();
+        assert_eq!(
+            vec![
+                PgWireBackendMessage::CommandComplete(CommandComplete::new("PING".to_owned())),
+                PgWireBackendMessage::ReadyForQuery(ReadyForQuery::new(TransactionStatus::Idle)),
+            ],
+            messages
+        );
+
+        let summary = client.terminate().await.unwrap();
+        assert_eq!(Some(DisconnectReason::Terminate), summary.reason);
+
+        // failing to connect upstream
+        let mut client = start_proxy();
+        let messages = client.startup("nobody", None).await.unwrap();
+        assert_eq!(Some("08006".to_owned()), error_code(&messages[0]));
+        let summary = client.close().await.unwrap();
+        assert_eq!(Some(DisconnectReason::Error), summary.reason);
+    }
+}
@@ -1,39 +1,152 @@
+use std::any::Any;
+use std::future::Future;
 use std::io;
+use std::net::SocketAddr;
+use std::panic::AssertUnwindSafe;
+use std::pin::pin;
 use std::sync::Arc;
+use std::time::{Instant, SystemTime};
 
-use bytes::Buf;
-use futures::{SinkExt, StreamExt};
-use tokio::io::{AsyncRead, AsyncWrite};
+use bytes::{Buf, BytesMut};
+use futures::future::{self, Either};
+use futures::{FutureExt, SinkExt, StreamExt};
+use socket2::SockRef;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+#[cfg(any(feature = "_ring", feature = "_aws-lc-rs"))]
+use tokio_rustls::rustls::pki_types::CertificateDer;
 #[cfg(any(feature = "_ring", feature = "_aws-lc-rs"))]
 use tokio_rustls::server::TlsStream;
 use tokio_util::codec::{Decoder, Encoder, Framed};
+use tokio_util::sync::{CancellationToken, DropGuard};
 
+use super::write_timeout::{is_write_timeout, WriteTimeout};
+use crate::api::admission::AdmissionPermit;
 use crate::api::auth::StartupHandler;
+use crate::api::cancel::{CancelHandler, CancelRegistry};
+use crate::api::capture::{CaptureDirection, CaptureRecord};
+use crate::api::config::{ServerConfig, SocketOptions};
+use crate::api::connection::{
+    ConnectDecision, ConnectionHandler, ConnectionSummary, DisconnectReason, MessageStats,
+};
 use crate::api::copy::CopyHandler;
-use crate::api::query::SimpleQueryHandler;
+use crate::api::events::{self, ConnectionEventKind};
+use crate::api::extensions::Extensions;
+use crate::api::handle::{ClientHandle, OutOfBandReceiver};
+use crate::api::memory::BufferedMemory;
+use crate::api::overload::LoadGuard;
+use crate::api::pool;
+use crate::api::query::{self, SimpleQueryHandler};
 use crate::api::query::{send_ready_for_query, ExtendedQueryHandler};
+use crate::api::store::{MemPortalStore, PortalStore};
 use crate::api::{
     ClientInfo, ClientPortalStore, DefaultClient, ErrorHandler, PgWireConnectionState,
-    PgWireServerHandlers,
+    PgWireServerHandlers, SessionHandlers, METADATA_DATABASE, METADATA_USER,
 };
 use crate::error::{ErrorInfo, PgWireError, PgWireResult};
+use crate::messages::extendedquery::{
+    Bind, Execute, MESSAGE_TYPE_BYTE_BIND, MESSAGE_TYPE_BYTE_CLOSE, MESSAGE_TYPE_BYTE_DESCRIBE,
+    MESSAGE_TYPE_BYTE_EXECUTE, MESSAGE_TYPE_BYTE_FLUSH, MESSAGE_TYPE_BYTE_PARSE,
+    MESSAGE_TYPE_BYTE_SYNC,
+};
 use crate::messages::response::ReadyForQuery;
-use crate::messages::response::{SslResponse, TransactionStatus};
-use crate::messages::startup::{SslRequest, Startup};
-use crate::messages::{Message, PgWireBackendMessage, PgWireFrontendMessage};
+use crate::messages::response::{ErrorResponseV2, SslResponse, TransactionStatus};
+use crate::messages::startup::{CancelRequest, SslRequest, Startup};
+use crate::messages::{
+    InvalidMessage, Message, PgWireBackendMessage, PgWireFrontendMessage, UnknownMessage,
+};
 
 #[non_exhaustive]
 #[derive(Debug, new)]
-pub struct PgWireMessageServerCodec<S> {
-    pub client_info: DefaultClient<S>,
+pub struct PgWireMessageServerCodec<S, P = MemPortalStore<S>> {
+    pub client_info: DefaultClient<S, P>,
+    #[new(default)]
+    bytes_received: u64,
+    #[new(default)]
+    bytes_sent: u64,
+    #[new(default)]
+    queries: u64,
+    #[new(default)]
+    message_stats: MessageStats,
 }
 
-impl<S> Decoder for PgWireMessageServerCodec<S> {
+impl<S, P> Decoder for PgWireMessageServerCodec<S, P> {
+    /// Messages that failed to decode but have been consumed from the buffer
+    /// are yielded as `PgWireFrontendMessage::Invalid`, so the session can
+    /// continue with next message. Other errors close the stream.
     type Item = PgWireFrontendMessage;
     type Error = PgWireError;
 
     fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let remaining = src.remaining();
+        let message_type = src.first().copied().unwrap_or_default();
+        // startup packets have no type byte
+        let stats_type = match self.client_info.state() {
+            PgWireConnectionState::AwaitingSslRequest | PgWireConnectionState::AwaitingStartup => 0,
+            _ => message_type,
+        };
+        let result = self.decode_message(src);
+        if src.remaining() < remaining {
+            let consumed = remaining - src.remaining();
+            self.client_info.last_activity_at = SystemTime::now();
+            self.bytes_received += consumed as u64;
+            self.message_stats.record_received(stats_type, consumed);
+        }
+        let msg = match result {
+            Ok(msg) => msg,
+            Err(e) if src.remaining() < remaining && self.is_resync_supported() => {
+                return Ok(Some(PgWireFrontendMessage::Invalid(InvalidMessage::new(
+                    message_type,
+                    e.to_string(),
+                ))));
+            }
+            Err(e) => return Err(e),
+        };
+
+        if let (Some(capture), Some(msg)) = (&self.client_info.server_config.capture, &msg) {
+            let mut buf = bytes::BytesMut::new();
+            if msg.encode(&mut buf).is_ok() && !buf.is_empty() {
+                capture.record(CaptureRecord::now(
+                    self.client_info.socket_addr,
+                    CaptureDirection::Frontend,
+                    buf.freeze(),
+                ));
+            }
+        }
+
+        Ok(msg)
+    }
+}
+
+impl<S, P> PgWireMessageServerCodec<S, P> {
+    pub(super) fn summary(&self, reason: Option<DisconnectReason>) -> ConnectionSummary {
+        ConnectionSummary {
+            addr: self.client_info.socket_addr,
+            duration: self.client_info.connected_at.elapsed().unwrap_or_default(),
+            queries: self.queries,
+            bytes_received: self.bytes_received,
+            bytes_sent: self.bytes_sent,
+            reason,
+            messages: self.message_stats.clone(),
+        }
+    }
+
+    /// Postgres only keeps the connection after an invalid message in query
+    /// phase. Errors during startup and authentication are fatal.
+    fn is_resync_supported(&self) -> bool {
+        !matches!(
+            self.client_info.state(),
+            PgWireConnectionState::AwaitingSslRequest
+                | PgWireConnectionState::AwaitingStartup
+                | PgWireConnectionState::AuthenticationInProgress
+        )
+    }
+
+    fn decode_message(
+        &mut self,
+        src: &mut bytes::BytesMut,
+    ) -> PgWireResult<Option<PgWireFrontendMessage>> {
         match self.client_info.state() {
             PgWireConnectionState::AwaitingSslRequest => {
                 if src.remaining() >= SslRequest::BODY_SIZE {
@@ -53,6 +166,12 @@ impl<S> Decoder for PgWireMessageServerCodec<S> {
             }
 
             PgWireConnectionState::AwaitingStartup => {
+                if CancelRequest::is_cancel_request_packet(src) {
+                    return Ok(
+                        CancelRequest::decode(src)?.map(PgWireFrontendMessage::CancelRequest)
+                    );
+                }
+
                 if let Some(startup) = Startup::decode(src)? {
                     Ok(Some(PgWireFrontendMessage::Startup(startup)))
                 } else {
@@ -60,12 +179,17 @@ impl<S> Decoder for PgWireMessageServerCodec<S> {
                 }
             }
 
-            _ => PgWireFrontendMessage::decode(src),
+            _ => match PgWireFrontendMessage::decode(src) {
+                Err(PgWireError::InvalidMessageType(_)) => {
+                    Ok(UnknownMessage::decode(src)?.map(PgWireFrontendMessage::Unknown))
+                }
+                result => result,
+            },
         }
     }
 }
 
-impl<S> Encoder<PgWireBackendMessage> for PgWireMessageServerCodec<S> {
+impl<S, P> Encoder<PgWireBackendMessage> for PgWireMessageServerCodec<S, P> {
     type Error = io::Error;
 
     fn encode(
@@ -73,11 +197,33 @@ impl<S> Encoder<PgWireBackendMessage> for PgWireMessageServerCodec<S> {
         item: PgWireBackendMessage,
         dst: &mut bytes::BytesMut,
     ) -> Result<(), Self::Error> {
-        item.encode(dst).map_err(Into::into)
+        let offset = dst.len();
+        item.encode(dst)?;
+        self.bytes_sent += (dst.len() - offset) as u64;
+        let stats_type = match item {
+            PgWireBackendMessage::SslResponse(_) => 0,
+            _ => dst[offset],
+        };
+        self.message_stats
+            .record_sent(stats_type, dst.len() - offset);
+
+        if let Some(capture) = &self.client_info.server_config.capture {
+            capture.record(CaptureRecord::now(
+                self.client_info.socket_addr,
+                CaptureDirection::Backend,
+                bytes::Bytes::copy_from_slice(&dst[offset..]),
+            ));
+        }
+
+        if let PgWireBackendMessage::DataRow(row) = item {
+            pool::recycle(row.data);
+        }
+
+        Ok(())
     }
 }
 
-impl<T, S> ClientInfo for Framed<T, PgWireMessageServerCodec<S>> {
+impl<T, S, P> ClientInfo for Framed<T, PgWireMessageServerCodec<S, P>> {
     fn socket_addr(&self) -> std::net::SocketAddr {
         self.codec().client_info.socket_addr
     }
@@ -102,6 +248,14 @@ impl<T, S> ClientInfo for Framed<T, PgWireMessageServerCodec<S>> {
         self.codec_mut().client_info.metadata_mut()
     }
 
+    fn extensions(&self) -> &Extensions {
+        self.codec().client_info.extensions()
+    }
+
+    fn extensions_mut(&mut self) -> &mut Extensions {
+        self.codec_mut().client_info.extensions_mut()
+    }
+
     fn transaction_status(&self) -> TransactionStatus {
         self.codec().client_info.transaction_status()
     }
@@ -111,35 +265,186 @@ impl<T, S> ClientInfo for Framed<T, PgWireMessageServerCodec<S>> {
             .client_info
             .set_transaction_status(new_status);
     }
+
+    fn server_config(&self) -> &ServerConfig {
+        self.codec().client_info.server_config()
+    }
+
+    fn pid_and_secret_key(&self) -> (i32, i32) {
+        self.codec().client_info.pid_and_secret_key()
+    }
+
+    fn set_pid_and_secret_key(&mut self, pid: i32, secret_key: i32) {
+        self.codec_mut()
+            .client_info
+            .set_pid_and_secret_key(pid, secret_key);
+    }
+
+    fn connected_at(&self) -> SystemTime {
+        self.codec().client_info.connected_at()
+    }
+
+    fn authenticated_at(&self) -> Option<SystemTime> {
+        self.codec().client_info.authenticated_at()
+    }
+
+    fn last_activity_at(&self) -> SystemTime {
+        self.codec().client_info.last_activity_at()
+    }
+
+    fn cancellation_token(&self) -> Option<CancellationToken> {
+        self.codec().client_info.cancellation_token()
+    }
+
+    fn message_stats(&self) -> Option<&MessageStats> {
+        Some(&self.codec().message_stats)
+    }
+
+    fn buffered_memory(&self) -> BufferedMemory {
+        let mut memory = BufferedMemory::from_extensions(self.extensions());
+        memory.write_buffer = self.write_buffer().len();
+        memory
+    }
+
+    fn write_buffer_mut(&mut self) -> Option<&mut bytes::BytesMut> {
+        // captured messages are recorded by the encoder
+        if self.codec().client_info.server_config.capture.is_some() {
+            None
+        } else {
+            Some(Framed::write_buffer_mut(self))
+        }
+    }
+
+    fn record_sent(&mut self, message_type: u8, bytes: usize) {
+        let codec = self.codec_mut();
+        codec.bytes_sent += bytes as u64;
+        codec.message_stats.record_sent(message_type, bytes);
+    }
+
+    #[cfg(any(feature = "_ring", feature = "_aws-lc-rs"))]
+    fn client_certificates(&self) -> Option<&[CertificateDer<'static>]> {
+        self.codec().client_info.client_certificates()
+    }
 }
 
-impl<T, S> ClientPortalStore for Framed<T, PgWireMessageServerCodec<S>> {
-    type PortalStore = <DefaultClient<S> as ClientPortalStore>::PortalStore;
+impl<T, S, P> ClientPortalStore for Framed<T, PgWireMessageServerCodec<S, P>> {
+    type PortalStore = P;
 
     fn portal_store(&self) -> &Self::PortalStore {
         self.codec().client_info.portal_store()
     }
 }
 
-async fn process_message<S, A, Q, EQ, C>(
+/// Acquire a permit from `AdmissionController` of the server, if any
+async fn admit_query<C: ClientInfo>(client: &C) -> PgWireResult<Option<AdmissionPermit>> {
+    match client.server_config().admission_controller.clone() {
+        Some(controller) => controller.admit().await.map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Cancellation token of the query in progress, registered in
+/// `ServerConfig::cancel_registry` so a `CancelRequest` can trip it
+struct RunningQuery {
+    registry: Arc<CancelRegistry>,
+    pid: i32,
+    registration: Option<u64>,
+    guard: Option<DropGuard>,
+    started: Instant,
+    _load: Option<LoadGuard>,
+}
+
+impl RunningQuery {
+    fn start<S, ST, P>(
+        socket: &mut Framed<S, PgWireMessageServerCodec<ST, P>>,
+        query: Option<&str>,
+    ) -> RunningQuery {
+        events::publish(socket, || ConnectionEventKind::QueryStarted {
+            query: query.map(str::to_owned),
+        });
+
+        socket.codec_mut().queries += 1;
+        let token = CancellationToken::new();
+        let registry = socket.server_config().cancel_registry.clone();
+        let (pid, secret_key) = socket.pid_and_secret_key();
+        let registration = registry.insert(pid, secret_key, token.clone());
+        socket.codec_mut().client_info.cancellation_token = Some(token.clone());
+        let load = socket
+            .server_config()
+            .load_shedder
+            .as_ref()
+            .map(|shedder| shedder.query_started());
+
+        RunningQuery {
+            registry,
+            pid,
+            registration,
+            guard: Some(token.drop_guard()),
+            started: Instant::now(),
+            _load: load,
+        }
+    }
+
+    /// Finish the query with its result, the token is cancelled if writing to
+    /// the client failed.
+    fn finish<S, ST, P>(
+        mut self,
+        socket: &mut Framed<S, PgWireMessageServerCodec<ST, P>>,
+        result: &PgWireResult<()>,
+    ) {
+        socket.codec_mut().client_info.cancellation_token = None;
+        if let Some(guard) = self.guard.take() {
+            if !matches!(result, Err(PgWireError::IoError(_))) {
+                guard.disarm();
+            }
+        }
+        events::publish(socket, || ConnectionEventKind::QueryFinished {
+            duration: self.started.elapsed(),
+            success: result.is_ok(),
+        });
+    }
+}
+
+impl Drop for RunningQuery {
+    fn drop(&mut self) {
+        // when the connection task is dropped in the middle of a query, the
+        // token is cancelled by the drop guard
+        self.registry.remove(self.pid, self.registration);
+    }
+}
+
+async fn process_message<S, PS, A, Q, EQ, C, CN>(
     message: PgWireFrontendMessage,
-    socket: &mut Framed<S, PgWireMessageServerCodec<EQ::Statement>>,
+    socket: &mut Framed<S, PgWireMessageServerCodec<EQ::Statement, PS>>,
     authenticator: Arc<A>,
     query_handler: Arc<Q>,
     extended_query_handler: Arc<EQ>,
     copy_handler: Arc<C>,
+    connection_handler: Arc<CN>,
 ) -> PgWireResult<()>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    PS: PortalStore<Statement = EQ::Statement>,
     A: StartupHandler,
     Q: SimpleQueryHandler,
     EQ: ExtendedQueryHandler,
     C: CopyHandler,
+    CN: ConnectionHandler,
 {
+    if let PgWireFrontendMessage::Unknown(message) = message {
+        return connection_handler.on_unknown_message(socket, message).await;
+    }
+
     match socket.state() {
         PgWireConnectionState::AwaitingStartup
         | PgWireConnectionState::AuthenticationInProgress => {
             authenticator.on_startup(socket, message).await?;
+            if matches!(socket.state(), PgWireConnectionState::ReadyForQuery) {
+                events::publish(socket, || ConnectionEventKind::Authenticated {
+                    user: socket.metadata().get(METADATA_USER).cloned(),
+                    database: socket.metadata().get(METADATA_DATABASE).cloned(),
+                });
+            }
         }
         // From Postgres docs:
         // When an error is detected while processing any extended-query
@@ -149,6 +454,7 @@ where
         PgWireConnectionState::AwaitingSync => {
             if let PgWireFrontendMessage::Sync(sync) = message {
                 extended_query_handler.on_sync(socket, sync).await?;
+                end_sync(socket);
                 // TODO: confirm if we need to track transaction state there
                 socket.set_state(PgWireConnectionState::ReadyForQuery);
             }
@@ -203,7 +509,11 @@ where
             // query or query in progress
             match message {
                 PgWireFrontendMessage::Query(query) => {
-                    query_handler.on_query(socket, query).await?;
+                    let _permit = admit_query(socket).await?;
+                    let running_query = RunningQuery::start(socket, Some(&query.query));
+                    let result = query_handler.on_query(socket, query).await;
+                    running_query.finish(socket, &result);
+                    result?;
                 }
                 PgWireFrontendMessage::Parse(parse) => {
                     extended_query_handler.on_parse(socket, parse).await?;
@@ -212,7 +522,11 @@ where
                     extended_query_handler.on_bind(socket, bind).await?;
                 }
                 PgWireFrontendMessage::Execute(execute) => {
-                    extended_query_handler.on_execute(socket, execute).await?;
+                    let _permit = admit_query(socket).await?;
+                    let running_query = RunningQuery::start(socket, None);
+                    let result = extended_query_handler.on_execute(socket, execute).await;
+                    running_query.finish(socket, &result);
+                    result?;
                 }
                 PgWireFrontendMessage::Describe(describe) => {
                     extended_query_handler.on_describe(socket, describe).await?;
@@ -222,6 +536,7 @@ where
                 }
                 PgWireFrontendMessage::Sync(sync) => {
                     extended_query_handler.on_sync(socket, sync).await?;
+                    end_sync(socket);
                 }
                 PgWireFrontendMessage::Close(close) => {
                     extended_query_handler.on_close(socket, close).await?;
@@ -233,37 +548,209 @@ where
     Ok(())
 }
 
-async fn process_error<S, ST>(
-    socket: &mut Framed<S, PgWireMessageServerCodec<ST>>,
+/// Release suspended portals at `Sync` out of transaction, like postgres
+/// drops portals when the transaction ends, so their rows are not held until
+/// the connection closes.
+fn end_sync<C: ClientInfo>(client: &mut C) {
+    if client.transaction_status() == TransactionStatus::Idle {
+        query::clear_suspended_portals(client.extensions_mut());
+    }
+}
+
+/// `Bind`/`Execute` pairs of a statement buffered for
+/// `ExtendedQueryHandler::on_query_batch`
+#[derive(Default)]
+struct QueryBatch {
+    items: Vec<(Bind, Execute)>,
+    bind: Option<Bind>,
+}
+
+impl QueryBatch {
+    /// Buffer the message if it continues the batch, or give it back
+    fn push(&mut self, msg: PgWireFrontendMessage) -> Option<PgWireFrontendMessage> {
+        match msg {
+            PgWireFrontendMessage::Bind(bind)
+                if self.bind.is_none()
+                    && self.items.first().map_or(true, |(first, _)| {
+                        first.statement_name == bind.statement_name
+                    }) =>
+            {
+                self.bind = Some(bind);
+                None
+            }
+            PgWireFrontendMessage::Execute(execute)
+                if execute.max_rows == 0
+                    && self
+                        .bind
+                        .as_ref()
+                        .is_some_and(|bind| bind.portal_name == execute.name) =>
+            {
+                if let Some(bind) = self.bind.take() {
+                    self.items.push((bind, execute));
+                }
+                None
+            }
+            msg => Some(msg),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.items.is_empty() && self.bind.is_none()
+    }
+}
+
+/// Execute buffered `Bind`/`Execute` pairs, and a trailing `Bind` without
+/// its `Execute`
+async fn flush_query_batch<S, PS, EQ, E>(
+    socket: &mut Framed<S, PgWireMessageServerCodec<EQ::Statement, PS>>,
+    extended_query_handler: &EQ,
+    error_handler: &E,
+    batch: &mut QueryBatch,
+) -> Result<Option<DisconnectReason>, io::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    PS: PortalStore<Statement = EQ::Statement>,
+    EQ: ExtendedQueryHandler,
+    E: ErrorHandler,
+{
+    let items = std::mem::take(&mut batch.items);
+    let bind = batch.bind.take();
+
+    let mut result = Ok(());
+    if !items.is_empty() {
+        result = match admit_query(socket).await {
+            Ok(_permit) => {
+                let running_query = RunningQuery::start(socket, None);
+                let result =
+                    catch_panic(extended_query_handler.on_query_batch(socket, items)).await;
+                running_query.finish(socket, &result);
+                result
+            }
+            Err(e) => Err(e),
+        };
+    }
+    if let (Ok(()), Some(bind)) = (&result, bind) {
+        result = catch_panic(extended_query_handler.on_bind(socket, bind)).await;
+    }
+
+    match result {
+        Ok(()) => Ok(None),
+        Err(e) => handle_error(socket, error_handler, e, true).await,
+    }
+}
+
+/// Run a handler future, a panic of it becomes `PgWireError::HandlerPanicked`
+/// so the connection can report it to client.
+async fn catch_panic<F, T>(future: F) -> PgWireResult<T>
+where
+    F: Future<Output = PgWireResult<T>>,
+{
+    match AssertUnwindSafe(future).catch_unwind().await {
+        Ok(result) => result,
+        Err(payload) => Err(PgWireError::HandlerPanicked(panic_message(
+            payload.as_ref(),
+        ))),
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        (*message).to_owned()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_owned()
+    }
+}
+
+/// Pass a handler error to `ErrorHandler` and send it to client. Returns
+/// `DisconnectReason::Panic` if the error is from a panic, the connection is
+/// closed then.
+async fn handle_error<S, ST, P, E>(
+    socket: &mut Framed<S, PgWireMessageServerCodec<ST, P>>,
+    error_handler: &E,
+    mut error: PgWireError,
+    wait_for_sync: bool,
+) -> Result<Option<DisconnectReason>, io::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    E: ErrorHandler,
+{
+    error = unwrap_encode_error(error);
+    let panicked = matches!(error, PgWireError::HandlerPanicked(_));
+    error_handler.on_error(socket, &mut error);
+    process_error(socket, error, wait_for_sync).await?;
+    Ok(panicked.then_some(DisconnectReason::Panic))
+}
+
+/// Errors of encoding a message, like `MessageTooLarge`, are returned from
+/// the sink of `Framed` as `io::Error`. Get the original error back to
+/// classify it.
+fn unwrap_encode_error(error: PgWireError) -> PgWireError {
+    match error {
+        PgWireError::IoError(e) if e.get_ref().is_some_and(|e| e.is::<PgWireError>()) => *e
+            .into_inner()
+            .and_then(|e| e.downcast::<PgWireError>().ok())
+            .expect("checked to be PgWireError"),
+        e => e,
+    }
+}
+
+async fn process_error<S, ST, P>(
+    socket: &mut Framed<S, PgWireMessageServerCodec<ST, P>>,
     error: PgWireError,
     wait_for_sync: bool,
 ) -> Result<(), io::Error>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
 {
-    match error {
-        PgWireError::UserError(error_info) => {
-            socket
-                .feed(PgWireBackendMessage::ErrorResponse((*error_info).into()))
-                .await?;
-        }
+    let error = unwrap_encode_error(error);
+    let fatal = match &error {
+        PgWireError::UserError(error_info) => socket
+            .server_config()
+            .disconnect_policy
+            .should_disconnect(error_info),
+        e => e.is_fatal(),
+    };
+    let mut error_info = match error.into_inner() {
+        PgWireError::UserError(error_info) => *error_info,
         PgWireError::ApiError(e) => {
-            let error_info = ErrorInfo::new("ERROR".to_owned(), "XX000".to_owned(), e.to_string());
-            socket
-                .feed(PgWireBackendMessage::ErrorResponse(error_info.into()))
-                .await?;
+            ErrorInfo::new("ERROR".to_owned(), "XX000".to_owned(), e.to_string())
         }
-        _ => {
-            // Internal error
-            let error_info =
-                ErrorInfo::new("FATAL".to_owned(), "XX000".to_owned(), error.to_string());
-            socket
-                .send(PgWireBackendMessage::ErrorResponse(error_info.into()))
-                .await?;
-            return socket.close().await;
+        // program_limit_exceeded
+        e @ PgWireError::MessageTooLarge(_) => {
+            ErrorInfo::new("ERROR".to_owned(), "54000".to_owned(), e.to_string())
+        }
+        // invalid_binary_representation, like a numeric NaN for `Decimal`
+        e @ PgWireError::FailedToParseParameter(_) => {
+            ErrorInfo::new("ERROR".to_owned(), "22P03".to_owned(), e.to_string())
+        }
+        // datatype_mismatch
+        e @ PgWireError::InvalidRustTypeForField(..) => {
+            ErrorInfo::new("ERROR".to_owned(), "42804".to_owned(), e.to_string())
+        }
+        // system_error
+        e @ PgWireError::HandlerPanicked(_) => {
+            ErrorInfo::new("ERROR".to_owned(), "58000".to_owned(), e.to_string())
+        }
+        // Internal error
+        e => ErrorInfo::new("ERROR".to_owned(), "XX000".to_owned(), e.to_string()),
+    };
+
+    if fatal {
+        if error_info.severity == "ERROR" {
+            "FATAL".clone_into(&mut error_info.severity);
         }
+        socket
+            .send(PgWireBackendMessage::ErrorResponse(error_info.into()))
+            .await?;
+        return socket.close().await;
     }
 
+    socket
+        .feed(PgWireBackendMessage::ErrorResponse(error_info.into()))
+        .await?;
+
     let transaction_status = socket.transaction_status().to_error_state();
     socket.set_transaction_status(transaction_status);
 
@@ -283,7 +770,7 @@ where
 }
 
 #[derive(Debug, PartialEq, Eq)]
-enum SslNegotiationType {
+pub(super) enum SslNegotiationType {
     Postgres,
     Direct,
     None,
@@ -296,11 +783,11 @@ async fn check_ssl_direct_negotiation(tcp_socket: &TcpStream) -> Result<bool, io
     Ok(n > 0 && buf[0] == 0x16)
 }
 
-async fn peek_for_sslrequest<ST>(
-    socket: &mut Framed<TcpStream, PgWireMessageServerCodec<ST>>,
+pub(super) async fn peek_for_sslrequest<ST, P>(
+    socket: &mut Framed<WriteTimeout<TcpStream>, PgWireMessageServerCodec<ST, P>>,
     ssl_supported: bool,
 ) -> Result<SslNegotiationType, io::Error> {
-    if check_ssl_direct_negotiation(socket.get_ref()).await? {
+    if check_ssl_direct_negotiation(socket.get_ref().get_ref()).await? {
         Ok(SslNegotiationType::Direct)
     } else if let Some(Ok(PgWireFrontendMessage::SslRequest(Some(_)))) = socket.next().await {
         if ssl_supported {
@@ -319,47 +806,348 @@ async fn peek_for_sslrequest<ST>(
     }
 }
 
-async fn do_process_socket<S, A, Q, EQ, C, E>(
-    socket: &mut Framed<S, PgWireMessageServerCodec<EQ::Statement>>,
-    startup_handler: Arc<A>,
-    simple_query_handler: Arc<Q>,
-    extended_query_handler: Arc<EQ>,
-    copy_handler: Arc<C>,
-    error_handler: Arc<E>,
-) -> Result<(), io::Error>
+/// Statement type of the extended query handler of `H`
+type StatementOf<H> =
+    <<H as PgWireServerHandlers>::ExtendedQueryHandler as ExtendedQueryHandler>::Statement;
+
+async fn do_process_socket<S, PS, H>(
+    socket: &mut Framed<S, PgWireMessageServerCodec<StatementOf<H>, PS>>,
+    handlers: &H,
+) -> Result<ConnectionSummary, io::Error>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
-    A: StartupHandler,
-    Q: SimpleQueryHandler,
-    EQ: ExtendedQueryHandler,
-    C: CopyHandler,
-    E: ErrorHandler,
+    PS: PortalStore<Statement = StatementOf<H>>,
+    H: PgWireServerHandlers + Sync,
+{
+    let connection_handler = handlers.connection_handler();
+    let (client_handle, out_of_band) = ClientHandle::channel();
+    socket.extensions_mut().insert(client_handle);
+
+    match connection_handler.on_connect(socket).await {
+        ConnectDecision::Accept => {}
+        ConnectDecision::Reject(error_info) => {
+            socket
+                .send(PgWireBackendMessage::ErrorResponse((*error_info).into()))
+                .await?;
+            socket.close().await?;
+            return Ok(socket.codec().summary(None));
+        }
+        ConnectDecision::Close => {
+            socket.close().await?;
+            return Ok(socket.codec().summary(None));
+        }
+    }
+
+    let result =
+        do_process_messages(socket, out_of_band, handlers, connection_handler.clone()).await;
+
+    let reason = match &result {
+        // cancel request connection has no session to terminate
+        Ok(None) => return Ok(socket.codec().summary(None)),
+        Ok(Some(reason)) => *reason,
+        Err(e) if is_write_timeout(e) => DisconnectReason::WriteTimeout,
+        Err(_) => DisconnectReason::Error,
+    };
+    events::publish(socket, || ConnectionEventKind::Terminated(reason));
+    connection_handler.on_terminate(socket, reason).await;
+
+    result.map(|_| socket.codec().summary(Some(reason)))
+}
+
+async fn do_process_messages<S, PS, H, CN>(
+    socket: &mut Framed<S, PgWireMessageServerCodec<StatementOf<H>, PS>>,
+    out_of_band: OutOfBandReceiver,
+    handlers: &H,
+    connection_handler: Arc<CN>,
+) -> Result<Option<DisconnectReason>, io::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    PS: PortalStore<Statement = StatementOf<H>>,
+    H: PgWireServerHandlers + Sync,
+    CN: ConnectionHandler,
 {
-    while let Some(Ok(msg)) = socket.next().await {
+    let error_handler = handlers.error_handler();
+    let cancel_handler = handlers.cancel_handler();
+    // created on the startup message
+    let mut session: Option<SessionHandlers<H>> = None;
+    let mut out_of_band = Some(out_of_band);
+    // between the first message of an extended query batch and its `Sync`
+    let mut in_extended_batch = false;
+    let mut batch = QueryBatch::default();
+    // counted by load shedder until authenticated
+    let mut pending = socket
+        .server_config()
+        .load_shedder
+        .as_ref()
+        .map(|shedder| shedder.connection_pending());
+
+    loop {
+        let idle =
+            !in_extended_batch && matches!(socket.state(), PgWireConnectionState::ReadyForQuery);
+        let next = match out_of_band.as_mut() {
+            Some(receiver) if idle => {
+                match future::select(socket.next(), pin!(receiver.recv())).await {
+                    Either::Left((msg, _)) => Either::Left(msg),
+                    Either::Right((message, _)) => Either::Right(message),
+                }
+            }
+            _ => Either::Left(socket.next().await),
+        };
+        let msg = match next {
+            Either::Left(Some(msg)) => msg,
+            Either::Left(None) => break,
+            Either::Right(Some(message)) => {
+                send_out_of_band(socket, message, out_of_band.as_mut()).await?;
+                continue;
+            }
+            Either::Right(None) => {
+                // all handles are dropped
+                out_of_band = None;
+                continue;
+            }
+        };
+
+        let msg = match msg {
+            Ok(PgWireFrontendMessage::Invalid(invalid)) => {
+                // the invalid message is already skipped, report it and
+                // continue with next message
+                if let (false, Some(session)) = (batch.is_empty(), &session) {
+                    if let Some(reason) = flush_query_batch(
+                        socket,
+                        session.extended_query_handler.as_ref(),
+                        error_handler.as_ref(),
+                        &mut batch,
+                    )
+                    .await?
+                    {
+                        return Ok(Some(reason));
+                    }
+                }
+                if !matches!(socket.state(), PgWireConnectionState::AwaitingSync) {
+                    let wait_for_sync = match socket.state() {
+                        PgWireConnectionState::CopyInProgress(is_extended_query) => {
+                            is_extended_query
+                        }
+                        _ => is_extended_query_message_type(invalid.message_type),
+                    };
+                    let mut e = PgWireError::UserError(Box::new(ErrorInfo::new(
+                        "ERROR".to_owned(),
+                        "08P01".to_owned(),
+                        format!(
+                            "invalid frontend message: Invalid message of type {}: {}",
+                            char::from(invalid.message_type),
+                            invalid.reason
+                        ),
+                    )));
+                    error_handler.on_error(socket, &mut e);
+                    process_error(socket, e, wait_for_sync).await?;
+                }
+                continue;
+            }
+            Ok(msg) => msg,
+            Err(PgWireError::InvalidProtocolVersion(version)) => {
+                reject_protocol_version(socket, version).await?;
+                return Ok(Some(DisconnectReason::Error));
+            }
+            Err(e) => {
+                // the stream can not be recovered, for example the message
+                // length is invalid
+                let error_info = ErrorInfo::new(
+                    "FATAL".to_owned(),
+                    "08P01".to_owned(),
+                    format!("invalid frontend message: {e}"),
+                );
+                socket
+                    .send(PgWireBackendMessage::ErrorResponse(error_info.into()))
+                    .await?;
+                return Ok(Some(DisconnectReason::Error));
+            }
+        };
+
+        match msg {
+            PgWireFrontendMessage::CancelRequest(cancel_request) => {
+                // The connection is closed without any response after the
+                // cancel request is processed.
+                socket
+                    .server_config()
+                    .cancel_registry
+                    .cancel(cancel_request.pid, cancel_request.secret_key);
+                cancel_handler.on_cancel_request(cancel_request).await;
+                return Ok(None);
+            }
+            PgWireFrontendMessage::Terminate(_) => {
+                return Ok(Some(DisconnectReason::Terminate));
+            }
+            _ => {}
+        }
+
+        let session = match &session {
+            Some(session) => session.clone(),
+            None => match create_session(socket, handlers, &msg).await {
+                Ok(created) => session.insert(created).clone(),
+                Err(e) => {
+                    handle_error(socket, error_handler.as_ref(), e, false).await?;
+                    return Ok(Some(DisconnectReason::Error));
+                }
+            },
+        };
+
         let is_extended_query = match socket.state() {
             PgWireConnectionState::CopyInProgress(is_extended_query) => is_extended_query,
             _ => msg.is_extended_query(),
         };
-        if let Err(mut e) = process_message(
+        in_extended_batch =
+            msg.is_extended_query() && !matches!(msg, PgWireFrontendMessage::Sync(_));
+
+        let msg = if session.extended_query_handler.supports_query_batch()
+            && matches!(socket.state(), PgWireConnectionState::ReadyForQuery)
+        {
+            let Some(msg) = batch.push(msg) else {
+                continue;
+            };
+            if !batch.is_empty() {
+                if let Some(reason) = flush_query_batch(
+                    socket,
+                    session.extended_query_handler.as_ref(),
+                    error_handler.as_ref(),
+                    &mut batch,
+                )
+                .await?
+                {
+                    return Ok(Some(reason));
+                }
+            }
+            msg
+        } else {
+            msg
+        };
+        if let Err(e) = catch_panic(process_message(
             msg,
             socket,
-            startup_handler.clone(),
-            simple_query_handler.clone(),
-            extended_query_handler.clone(),
-            copy_handler.clone(),
-        )
+            session.startup_handler,
+            session.simple_query_handler,
+            session.extended_query_handler,
+            session.copy_handler,
+            connection_handler.clone(),
+        ))
         .await
         {
-            error_handler.on_error(socket, &mut e);
-            process_error(socket, e, is_extended_query).await?;
+            if let Some(reason) =
+                handle_error(socket, error_handler.as_ref(), e, is_extended_query).await?
+            {
+                return Ok(Some(reason));
+            }
+        }
+        if pending.is_some() && matches!(socket.state(), PgWireConnectionState::ReadyForQuery) {
+            pending = None;
         }
     }
 
-    Ok(())
+    Ok(Some(DisconnectReason::ConnectionClosed))
+}
+
+/// Create handlers of the connection on its startup message. Errors are
+/// always fatal.
+async fn create_session<S, PS, H>(
+    socket: &Framed<S, PgWireMessageServerCodec<StatementOf<H>, PS>>,
+    handlers: &H,
+    message: &PgWireFrontendMessage,
+) -> PgWireResult<SessionHandlers<H>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    PS: PortalStore<Statement = StatementOf<H>>,
+    H: PgWireServerHandlers + Sync,
+{
+    let PgWireFrontendMessage::Startup(startup) = message else {
+        return Err(fatal_error("08P01", "expected startup message".to_owned()));
+    };
+    // shed load before creating handlers, which may be expensive
+    if let Some(shedder) = &socket.server_config().load_shedder {
+        shedder.check()?;
+    }
+
+    match catch_panic(handlers.session_handlers(socket, startup)).await {
+        Ok(session) => Ok(session),
+        Err(PgWireError::UserError(mut error_info)) => {
+            "FATAL".clone_into(&mut error_info.severity);
+            Err(PgWireError::UserError(error_info))
+        }
+        Err(e) => Err(fatal_error("XX000", e.to_string())),
+    }
+}
+
+fn fatal_error(code: &str, message: String) -> PgWireError {
+    PgWireError::UserError(Box::new(ErrorInfo::new(
+        "FATAL".to_owned(),
+        code.to_owned(),
+        message,
+    )))
+}
+
+/// Send a message queued by `ClientHandle`, and others queued at the same
+/// time, when the connection is idle
+async fn send_out_of_band<S, ST, P>(
+    socket: &mut Framed<S, PgWireMessageServerCodec<ST, P>>,
+    message: PgWireBackendMessage,
+    mut receiver: Option<&mut OutOfBandReceiver>,
+) -> Result<(), io::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    let mut message = Some(message);
+    while let Some(m) = message {
+        if let PgWireBackendMessage::ParameterStatus(status) = &m {
+            socket
+                .metadata_mut()
+                .insert(status.name.clone(), status.value.clone());
+        }
+        socket.feed(m).await?;
+        message = receiver.as_mut().and_then(|r| r.try_recv().ok());
+    }
+    socket.flush().await
+}
+
+pub(super) async fn reject_protocol_version<S, ST, P>(
+    socket: &mut Framed<S, PgWireMessageServerCodec<ST, P>>,
+    version: i32,
+) -> Result<(), io::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+{
+    let (major, minor) = (version >> 16, version & 0xffff);
+    let message =
+        format!("unsupported frontend protocol {major}.{minor}: server supports 3.0 to 3.0");
+
+    if major < 3 {
+        // clients of protocol 2.0 cannot read the error response of 3.0
+        let mut buf = BytesMut::new();
+        ErrorResponseV2::new(format!("FATAL:  {message}\n")).encode(&mut buf);
+        socket.flush().await?;
+        socket.get_mut().write_all(&buf).await?;
+        socket.get_mut().flush().await
+    } else {
+        let error_info = ErrorInfo::new("FATAL".to_owned(), "0A000".to_owned(), message);
+        socket
+            .send(PgWireBackendMessage::ErrorResponse(error_info.into()))
+            .await
+    }
+}
+
+fn is_extended_query_message_type(message_type: u8) -> bool {
+    matches!(
+        message_type,
+        MESSAGE_TYPE_BYTE_PARSE
+            | MESSAGE_TYPE_BYTE_BIND
+            | MESSAGE_TYPE_BYTE_CLOSE
+            | MESSAGE_TYPE_BYTE_DESCRIBE
+            | MESSAGE_TYPE_BYTE_EXECUTE
+            | MESSAGE_TYPE_BYTE_FLUSH
+            | MESSAGE_TYPE_BYTE_SYNC
+    )
 }
 
 #[cfg(any(feature = "_ring", feature = "_aws-lc-rs"))]
-fn check_alpn_for_direct_ssl<IO>(tls_socket: &TlsStream<IO>) -> Result<(), io::Error> {
+pub(super) fn check_alpn_for_direct_ssl<IO>(tls_socket: &TlsStream<IO>) -> Result<(), io::Error> {
     let (_, the_conn) = tls_socket.get_ref();
     let mut accept = false;
 
@@ -379,51 +1167,137 @@ fn check_alpn_for_direct_ssl<IO>(tls_socket: &TlsStream<IO>) -> Result<(), io::E
     }
 }
 
+pub(super) fn apply_socket_options(socket: &TcpStream, options: &SocketOptions) -> io::Result<()> {
+    let socket = SockRef::from(socket);
+    socket.set_tcp_nodelay(options.nodelay)?;
+    if let Some(keepalive) = &options.keepalive {
+        let params = socket2::TcpKeepalive::new().with_time(keepalive.idle);
+        #[cfg(any(
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "ios",
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "netbsd",
+            target_os = "windows",
+        ))]
+        let params = match keepalive.interval {
+            Some(interval) => params.with_interval(interval),
+            None => params,
+        };
+        #[cfg(any(
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "ios",
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "netbsd",
+            target_os = "windows",
+        ))]
+        let params = match keepalive.retries {
+            Some(retries) => params.with_retries(retries),
+            None => params,
+        };
+        socket.set_tcp_keepalive(&params)?;
+    }
+    if let Some(size) = options.send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = options.recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    Ok(())
+}
+
+/// Process a TCP connection, resolves to its `ConnectionSummary` when the
+/// connection is closed.
 pub async fn process_socket<H>(
     tcp_socket: TcpStream,
     tls_acceptor: Option<crate::tokio::TlsAcceptor>,
     handlers: H,
-) -> Result<(), io::Error>
+) -> Result<ConnectionSummary, io::Error>
+where
+    H: PgWireServerHandlers + Sync,
+{
+    process_socket_with_config(
+        tcp_socket,
+        tls_acceptor,
+        handlers,
+        Arc::new(ServerConfig::default()),
+    )
+    .await
+}
+
+/// Same as `process_socket` but with custom `ServerConfig`.
+pub async fn process_socket_with_config<H>(
+    tcp_socket: TcpStream,
+    tls_acceptor: Option<crate::tokio::TlsAcceptor>,
+    handlers: H,
+    config: Arc<ServerConfig>,
+) -> Result<ConnectionSummary, io::Error>
 where
-    H: PgWireServerHandlers,
+    H: PgWireServerHandlers + Sync,
+{
+    let portal_store = MemPortalStore::with_limits(config.portal_store_limits);
+    process_socket_with_portal_store(tcp_socket, tls_acceptor, handlers, config, portal_store).await
+}
+
+/// Same as `process_socket_with_config` but statements and portals of the
+/// connection are kept in `portal_store`, for example a `LruPortalStore`.
+pub async fn process_socket_with_portal_store<H, P>(
+    tcp_socket: TcpStream,
+    tls_acceptor: Option<crate::tokio::TlsAcceptor>,
+    handlers: H,
+    config: Arc<ServerConfig>,
+    portal_store: P,
+) -> Result<ConnectionSummary, io::Error>
+where
+    H: PgWireServerHandlers + Sync,
+    P: PortalStore<Statement = <H::ExtendedQueryHandler as ExtendedQueryHandler>::Statement>,
 {
     let addr = tcp_socket.peer_addr()?;
-    tcp_socket.set_nodelay(true)?;
+    if let Some(limiter) = &config.handshake_rate_limiter {
+        if !limiter.try_acquire(addr.ip()) {
+            // drop the socket before any handshake
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("too many connection attempts from {}", addr.ip()),
+            ));
+        }
+    }
+    apply_socket_options(&tcp_socket, &config.socket_options)?;
+    let tcp_socket = WriteTimeout::new(tcp_socket, config.write_timeout);
 
-    let client_info = DefaultClient::new(addr, false);
+    let client_info = DefaultClient::with_portal_store(addr, false, config.clone(), portal_store);
     let mut tcp_socket = Framed::new(tcp_socket, PgWireMessageServerCodec::new(client_info));
+    tcp_socket.set_backpressure_boundary(config.write_buffer_high_watermark);
+    events::publish(&tcp_socket, || ConnectionEventKind::Accepted);
 
     let ssl = peek_for_sslrequest(&mut tcp_socket, tls_acceptor.is_some()).await?;
 
-    let startup_handler = handlers.startup_handler();
-    let simple_query_handler = handlers.simple_query_handler();
-    let extended_query_handler = handlers.extended_query_handler();
-    let copy_handler = handlers.copy_handler();
-    let error_handler = handlers.error_handler();
-
     if ssl == SslNegotiationType::None {
         // use an already configured socket.
         let mut socket = tcp_socket;
 
-        do_process_socket(
-            &mut socket,
-            startup_handler,
-            simple_query_handler,
-            extended_query_handler,
-            copy_handler,
-            error_handler,
-        )
-        .await
+        do_process_socket(&mut socket, &handlers).await
     } else {
         #[cfg(any(feature = "_ring", feature = "_aws-lc-rs"))]
         {
+            let parts = tcp_socket.into_parts();
             // mention the use of ssl
-            let client_info = DefaultClient::new(addr, true);
+            let mut client_info = DefaultClient::with_portal_store(
+                addr,
+                true,
+                config.clone(),
+                parts.codec.client_info.portal_store,
+            );
             // safe to unwrap tls_acceptor here
-            let ssl_socket = tls_acceptor
-                .unwrap()
-                .accept(tcp_socket.into_inner())
-                .await?;
+            let ssl_socket = tls_acceptor.unwrap().accept(parts.io).await?;
+            client_info.client_certificates = ssl_socket
+                .get_ref()
+                .1
+                .peer_certificates()
+                .map(|certs| certs.to_vec());
 
             // check alpn for direct ssl connection
             if ssl == SslNegotiationType::Direct {
@@ -431,19 +1305,340 @@ where
             }
 
             let mut socket = Framed::new(ssl_socket, PgWireMessageServerCodec::new(client_info));
+            socket.set_backpressure_boundary(config.write_buffer_high_watermark);
+            events::publish(&socket, || ConnectionEventKind::TlsEstablished);
 
-            do_process_socket(
-                &mut socket,
-                startup_handler,
-                simple_query_handler,
-                extended_query_handler,
-                copy_handler,
-                error_handler,
-            )
-            .await
+            do_process_socket(&mut socket, &handlers).await
         }
 
         #[cfg(not(any(feature = "_ring", feature = "_aws-lc-rs")))]
-        Ok(())
+        Ok(tcp_socket.codec().summary(None))
+    }
+}
+
+/// Spawn a connection task, like `tokio::spawn(process_socket(..))`.
+///
+/// A panic of handlers processing messages is already reported to client
+/// with `58000` and closes the connection with `DisconnectReason::Panic`.
+/// Panics elsewhere, like in `ConnectionHandler::on_connect` or a TLS
+/// acceptor, are caught by this wrapper and returned as an IO error with the
+/// panic message, so they don't surface as a `JoinError` with no context.
+pub fn spawn_supervised<F>(future: F) -> JoinHandle<Result<ConnectionSummary, io::Error>>
+where
+    F: Future<Output = Result<ConnectionSummary, io::Error>> + Send + 'static,
+{
+    tokio::spawn(async move {
+        match AssertUnwindSafe(future).catch_unwind().await {
+            Ok(result) => result,
+            Err(payload) => Err(io::Error::other(format!(
+                "connection task panicked: {}",
+                panic_message(payload.as_ref())
+            ))),
+        }
+    })
+}
+
+/// Process a connection over any byte stream, like unix domain socket or an
+/// in-memory duplex.
+///
+/// TLS is not supported on such streams so `SSLRequest` from client is always
+/// refused. `addr` is reported to handlers as the client address, use an
+/// unspecified address like `0.0.0.0:0` for unix domain socket.
+pub async fn process_stream<S, H>(
+    stream: S,
+    addr: SocketAddr,
+    handlers: H,
+    config: Arc<ServerConfig>,
+) -> Result<ConnectionSummary, io::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + Sync,
+    H: PgWireServerHandlers + Sync,
+{
+    let client_info = DefaultClient::with_config(addr, false, config.clone());
+    let stream = WriteTimeout::new(stream, config.write_timeout);
+    let mut socket = Framed::new(stream, PgWireMessageServerCodec::new(client_info));
+    socket.set_backpressure_boundary(config.write_buffer_high_watermark);
+    events::publish(&socket, || ConnectionEventKind::Accepted);
+
+    if let Some(Ok(PgWireFrontendMessage::SslRequest(Some(_)))) = socket.next().await {
+        socket
+            .send(PgWireBackendMessage::SslResponse(SslResponse::Refuse))
+            .await?;
+    }
+
+    do_process_socket(&mut socket, &handlers).await
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::api::config::TcpKeepalive;
+    use crate::api::events::EventBus;
+    use crate::api::results::Response;
+    use crate::messages::extendedquery::Sync as PgSync;
+    use crate::messages::response::CommandComplete;
+    use crate::messages::simplequery::Query;
+    use crate::testkit::fixture::{error_code, error_field, numbers, FnQueryHandler, TestHandlers};
+    use crate::testkit::MockClient;
+
+    #[tokio::test]
+    async fn test_apply_socket_options() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+
+        let options = SocketOptions {
+            keepalive: Some(
+                TcpKeepalive::new(Duration::from_secs(60))
+                    .with_interval(Duration::from_secs(10))
+                    .with_retries(3),
+            ),
+            ..Default::default()
+        };
+        apply_socket_options(&socket, &options).unwrap();
+
+        let socket = SockRef::from(&socket);
+        assert!(socket.tcp_nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(
+                Duration::from_secs(60),
+                socket.tcp_keepalive_time().unwrap()
+            );
+            assert_eq!(3, socket.tcp_keepalive_retries().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_unwrap_encode_error() {
+        let error = PgWireError::from(io::Error::from(PgWireError::MessageTooLarge(5)));
+        let error = unwrap_encode_error(error);
+        assert!(matches!(error, PgWireError::MessageTooLarge(5)));
+        assert!(!error.is_fatal());
+
+        let error = unwrap_encode_error(io::Error::from(io::ErrorKind::BrokenPipe).into());
+        assert!(matches!(error, PgWireError::IoError(_)));
+    }
+
+    #[tokio::test]
+    async fn test_event_bus() {
+        let bus = Arc::new(EventBus::default());
+        let mut events = bus.subscribe();
+        let config = Arc::new(ServerConfig {
+            event_bus: Some(bus),
+            ..Default::default()
+        });
+
+        let mut client = MockClient::start_with_config(TestHandlers::echo(), config);
+        client.startup("tom", Some("db")).await.unwrap();
+        client.simple_query("VACUUM").await.unwrap();
+        client.terminate().await.unwrap();
+
+        let mut kinds = vec![];
+        while let Ok(event) = events.try_recv() {
+            assert_eq!(MockClient::PEER_ADDR, event.addr);
+            kinds.push(event.kind);
+        }
+        assert_eq!(5, kinds.len());
+        assert_eq!(ConnectionEventKind::Accepted, kinds[0]);
+        assert_eq!(
+            ConnectionEventKind::Authenticated {
+                user: Some("tom".to_owned()),
+                database: Some("db".to_owned()),
+            },
+            kinds[1]
+        );
+        assert_eq!(
+            ConnectionEventKind::QueryStarted {
+                query: Some("VACUUM".to_owned())
+            },
+            kinds[2]
+        );
+        assert!(matches!(
+            kinds[3],
+            ConnectionEventKind::QueryFinished { success: true, .. }
+        ));
+        assert_eq!(
+            ConnectionEventKind::Terminated(DisconnectReason::Terminate),
+            kinds[4]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_write_timeout() {
+        let bus = Arc::new(EventBus::default());
+        let mut events = bus.subscribe();
+        let config = Arc::new(ServerConfig {
+            event_bus: Some(bus),
+            write_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        });
+        let handlers = TestHandlers::new(FnQueryHandler::new(|_| {
+            Ok(vec![Response::Query(numbers(100000))])
+        }));
+        let mut client = MockClient::start_with_config(handlers, config);
+        client.startup("tom", None).await.unwrap();
+
+        // far more than the stream buffer, and never read
+        client
+            .send(PgWireFrontendMessage::Query(Query::new(
+                "SELECT n".to_owned(),
+            )))
+            .await
+            .unwrap();
+        let reason = loop {
+            if let ConnectionEventKind::Terminated(reason) = events.recv().await.unwrap().kind {
+                break reason;
+            }
+        };
+        assert_eq!(DisconnectReason::WriteTimeout, reason);
+        let error = client.close().await.unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, error.kind());
+    }
+
+    #[tokio::test]
+    async fn test_handler_panic() {
+        let handlers = TestHandlers::new(FnQueryHandler::new(|_| panic!("handler bug")));
+        let mut client = MockClient::start(handlers);
+        client.startup("tom", None).await.unwrap();
+
+        // reported to client, and the connection is closed
+        let messages = client.simple_query("SELECT 1").await.unwrap();
+        assert_eq!(1, messages.len());
+        assert_eq!(Some("58000".to_owned()), error_code(&messages[0]));
+        assert_eq!(
+            Some("Handler panicked: handler bug".to_owned()),
+            error_field(&messages[0], b'M')
+        );
+
+        let summary = client.close().await.unwrap();
+        assert_eq!(Some(DisconnectReason::Panic), summary.reason);
+    }
+
+    #[tokio::test]
+    async fn test_spawn_supervised() {
+        let result = spawn_supervised(async { panic!("on connect") })
+            .await
+            .unwrap();
+        assert_eq!(
+            "connection task panicked: on connect",
+            result.unwrap_err().to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_on_error() {
+        let handlers = TestHandlers::new(FnQueryHandler::new(|_| {
+            Err(PgWireError::UserError(Box::new(ErrorInfo::new(
+                "ERROR".to_owned(),
+                "57P01".to_owned(),
+                "terminating connection due to administrator command".to_owned(),
+            ))))
+        }));
+        let mut client = MockClient::start(handlers);
+        client.startup("tom", None).await.unwrap();
+
+        client
+            .send(PgWireFrontendMessage::Query(Query::new(
+                "SELECT 1".to_owned(),
+            )))
+            .await
+            .unwrap();
+        let message = client.receive().await.unwrap().unwrap();
+        assert_eq!(Some("FATAL".to_owned()), error_field(&message, b'S'));
+        assert_eq!(Some("57P01".to_owned()), error_code(&message));
+        assert!(client.receive().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_message() {
+        let mut client = MockClient::start(TestHandlers::echo());
+        client.startup("tom", None).await.unwrap();
+
+        client
+            .send(PgWireFrontendMessage::Unknown(UnknownMessage::new(
+                b'!',
+                bytes::Bytes::from_static(b"vendor"),
+            )))
+            .await
+            .unwrap();
+        let message = client.receive().await.unwrap().unwrap();
+        assert_eq!(Some("FATAL".to_owned()), error_field(&message, b'S'));
+        assert!(client.receive().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_protocol_violation() {
+        let mut client = MockClient::start(TestHandlers::echo());
+        client.startup("tom", None).await.unwrap();
+
+        // query with invalid utf-8
+        client.send_raw(b"Q\0\0\0\x07\xff\xfe\0").await.unwrap();
+        let messages = client.receive_until_ready().await.unwrap();
+        assert_eq!(Some("08P01".to_owned()), error_code(&messages[0]));
+        assert_eq!(2, messages.len());
+
+        // parse with invalid utf-8, then messages are skipped until sync
+        client
+            .send_raw(b"P\0\0\0\x0a\0\xff\xfe\0\0\0")
+            .await
+            .unwrap();
+        client
+            .send_all([
+                PgWireFrontendMessage::Execute(Execute::new(None, 0)),
+                PgWireFrontendMessage::Sync(PgSync::new()),
+            ])
+            .await
+            .unwrap();
+        let messages = client.receive_until_ready().await.unwrap();
+        assert!(matches!(
+            messages[0],
+            PgWireBackendMessage::ErrorResponse(_)
+        ));
+        assert_eq!(2, messages.len());
+
+        // the session is still alive
+        let messages = client.simple_query("SELECT").await.unwrap();
+        assert_eq!(
+            PgWireBackendMessage::CommandComplete(CommandComplete::new("SELECT".to_owned())),
+            messages[0]
+        );
+
+        // invalid message length is fatal
+        client.send_raw(b"Q\0\0\0\x01").await.unwrap();
+        let message = client.receive().await.unwrap().unwrap();
+        assert_eq!(Some("FATAL".to_owned()), error_field(&message, b'S'));
+        assert!(client.receive().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_protocol_v2_startup() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        tokio::spawn(process_stream(
+            server,
+            MockClient::PEER_ADDR,
+            TestHandlers::echo(),
+            Arc::new(ServerConfig::default()),
+        ));
+        client
+            .write_all(b"\0\0\0\x11\0\x02\0\0user\0tom\0\0")
+            .await
+            .unwrap();
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(
+            b"EFATAL:  unsupported frontend protocol 2.0: server supports 3.0 to 3.0\n\0"
+                .as_slice(),
+            response.as_slice()
+        );
     }
 }
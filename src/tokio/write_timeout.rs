@@ -0,0 +1,164 @@
+use std::fmt;
+use std::future::Future;
+use std::io::{self, IoSlice};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, Sleep};
+
+/// Source of the `TimedOut` IO error returned when a write to client doesn't
+/// make progress within `ServerConfig::write_timeout`
+#[derive(Debug)]
+pub(crate) struct WriteTimedOut(Duration);
+
+impl fmt::Display for WriteTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "client did not read for {:?}", self.0)
+    }
+}
+
+impl std::error::Error for WriteTimedOut {}
+
+/// Test if the error is caused by write timeout
+pub(crate) fn is_write_timeout(error: &io::Error) -> bool {
+    error
+        .get_ref()
+        .is_some_and(|source| source.is::<WriteTimedOut>())
+}
+
+/// Stream failing writes and flushes that are blocked for longer than the
+/// timeout, because the client stopped reading and the socket buffer is
+/// full.
+///
+/// The deadline starts when a write is blocked and is reset whenever it
+/// makes progress, so large but steadily consumed responses are not
+/// affected. Once timed out, all following writes fail immediately, so
+/// sending the error to client doesn't wait again.
+pub(crate) struct WriteTimeout<S> {
+    inner: S,
+    timeout: Option<Duration>,
+    deadline: Option<Pin<Box<Sleep>>>,
+    timed_out: bool,
+}
+
+impl<S> WriteTimeout<S> {
+    pub(crate) fn new(inner: S, timeout: Option<Duration>) -> WriteTimeout<S> {
+        WriteTimeout {
+            inner,
+            timeout,
+            deadline: None,
+            timed_out: false,
+        }
+    }
+
+    pub(crate) fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    fn error(&self) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            WriteTimedOut(self.timeout.unwrap_or_default()),
+        )
+    }
+
+    /// Track the deadline of a write result
+    fn poll_deadline<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        poll: Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        if poll.is_ready() {
+            self.deadline = None;
+            return poll;
+        }
+        let Some(timeout) = self.timeout else {
+            return poll;
+        };
+
+        let deadline = self
+            .deadline
+            .get_or_insert_with(|| Box::pin(sleep(timeout)));
+        if deadline.as_mut().poll(cx).is_ready() {
+            self.deadline = None;
+            self.timed_out = true;
+            return Poll::Ready(Err(self.error()));
+        }
+        Poll::Pending
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for WriteTimeout<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for WriteTimeout<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.timed_out {
+            return Poll::Ready(Err(self.error()));
+        }
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.poll_deadline(cx, poll)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        if self.timed_out {
+            return Poll::Ready(Err(self.error()));
+        }
+        let poll = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        self.poll_deadline(cx, poll)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.timed_out {
+            return Poll::Ready(Err(self.error()));
+        }
+        let poll = Pin::new(&mut self.inner).poll_flush(cx);
+        self.poll_deadline(cx, poll)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_write_timeout() {
+        let (client, _server) = tokio::io::duplex(16);
+        let mut stream = WriteTimeout::new(client, Some(Duration::from_millis(50)));
+        // fits the buffer
+        stream.write_all(&[0; 16]).await.unwrap();
+
+        let error = stream.write_all(&[0; 16]).await.unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, error.kind());
+        assert!(is_write_timeout(&error));
+        // fails right away after timed out
+        assert!(is_write_timeout(&stream.flush().await.unwrap_err()));
+    }
+}
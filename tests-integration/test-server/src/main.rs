@@ -13,6 +13,8 @@ use tokio_rustls::TlsAcceptor;
 
 use pgwire::api::auth::scram::{gen_salted_password, SASLScramAuthStartupHandler};
 use pgwire::api::auth::{AuthSource, DefaultServerParameterProvider, LoginInfo, Password};
+use pgwire::api::cancel::NoopCancelHandler;
+use pgwire::api::connection::NoopConnectionHandler;
 use pgwire::api::copy::NoopCopyHandler;
 use pgwire::api::portal::{Format, Portal};
 use pgwire::api::query::{ExtendedQueryHandler, SimpleQueryHandler};
@@ -107,7 +109,7 @@ impl SimpleQueryHandler for DummyDatabase {
                 ),
                 (Some(2), None, None, None, None),
             ];
-            let data_row_stream = stream::iter(data.into_iter()).map(move |r| {
+            let data_row_stream = stream::iter(data).map(move |r| {
                 let mut encoder = DataRowEncoder::new(schema_ref.clone());
 
                 encoder.encode_field(&r.0)?;
@@ -169,7 +171,7 @@ impl ExtendedQueryHandler for DummyDatabase {
             ];
             let schema = Arc::new(self.schema(&portal.result_column_format));
             let schema_ref = schema.clone();
-            let data_row_stream = stream::iter(data.into_iter()).map(move |r| {
+            let data_row_stream = stream::iter(data).map(move |r| {
                 let mut encoder = DataRowEncoder::new(schema_ref.clone());
 
                 encoder.encode_field(&r.0)?;
@@ -224,6 +226,8 @@ impl PgWireServerHandlers for DummyDatabaseFactory {
     type ExtendedQueryHandler = DummyDatabase;
     type CopyHandler = NoopCopyHandler;
     type ErrorHandler = NoopErrorHandler;
+    type CancelHandler = NoopCancelHandler;
+    type ConnectionHandler = NoopConnectionHandler;
 
     fn simple_query_handler(&self) -> Arc<Self::SimpleQueryHandler> {
         self.0.clone()
@@ -250,6 +254,14 @@ impl PgWireServerHandlers for DummyDatabaseFactory {
     fn error_handler(&self) -> Arc<Self::ErrorHandler> {
         Arc::new(NoopErrorHandler)
     }
+
+    fn cancel_handler(&self) -> Arc<Self::CancelHandler> {
+        Arc::new(NoopCancelHandler)
+    }
+
+    fn connection_handler(&self) -> Arc<Self::ConnectionHandler> {
+        Arc::new(NoopConnectionHandler)
+    }
 }
 
 fn setup_tls() -> Result<TlsAcceptor, IOError> {